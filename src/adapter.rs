@@ -0,0 +1,332 @@
+use crate::error;
+use crate::learn_filter::{self, PhraseFilter};
+use crate::memory::{Memories, Memory};
+use crate::store::{self, LineAttributes};
+use feroldinhobot::generation::{self, SentenceConfig};
+use feroldinhobot::output::{LengthGuard, OverflowPolicy, ReplyStyler};
+use feroldinhobot::phrase_indexing::{self, NormalizationConfig};
+use feroldinhobot::sources::PhraseSource;
+use rand::Rng;
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tbot::types::chat;
+
+pub(crate) type AdapterFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A text message received through an adapter.
+#[derive(PartialEq, Debug)]
+pub(crate) struct IncomingText {
+    pub(crate) chat_id: chat::Id,
+    pub(crate) text: String,
+    /// Whether the message mentions the bot or replies to it.
+    pub(crate) mentions_bot: bool,
+}
+
+/// A chat platform the bot runs on by only receiving and sending text, which is
+/// all it takes to learn from chats and reply to them. Telegram has handlers of
+/// its own instead, for everything else it supports.
+pub(crate) trait ChatAdapter: Send {
+    /// Waits for the next text message, or `None` if no more will come.
+    fn receive(&mut self) -> AdapterFuture<'_, Option<IncomingText>>;
+    fn send_text<'a>(
+        &'a self,
+        chat_id: chat::Id,
+        text: &'a str,
+    ) -> AdapterFuture<'a, error::Result<()>>;
+    /// The longest text a single message may have, in UTF-16 code units.
+    fn max_message_len(&self) -> usize;
+}
+
+/// How messages received through an adapter are replied to.
+pub(crate) struct AdapterReplyConfig {
+    pub(crate) reply_probability: f32,
+    /// Replies only to messages that mention the bot, ignoring
+    /// `reply_probability`.
+    pub(crate) reply_to_mentions_only: bool,
+    pub(crate) sentence_config: SentenceConfig,
+    pub(crate) reply_styler: ReplyStyler,
+}
+
+/// Learns every message received through the adapter into the memory of its
+/// chat, and replies to it by chance, or when it mentions the bot if replying
+/// only to mentions, until no more messages come.
+pub(crate) async fn run(
+    mut adapter: impl ChatAdapter,
+    memories: &mut Memories,
+    phrase_filter: PhraseFilter,
+    reply_config: &AdapterReplyConfig,
+    rng: &mut (impl Rng + Send),
+) {
+    let normalization_config = NormalizationConfig::default();
+    let length_guard = LengthGuard {
+        max_len: adapter.max_message_len(),
+        overflow_policy: OverflowPolicy::Split,
+    };
+
+    while let Some(incoming_text) = adapter.receive().await {
+        let should_reply = if reply_config.reply_to_mentions_only {
+            incoming_text.mentions_bot
+        } else {
+            incoming_text.mentions_bot || rng.gen::<f32>() < reply_config.reply_probability
+        };
+
+        let chat_normalization_config =
+            memories.normalization_config_of(Some(incoming_text.chat_id), &normalization_config);
+        let reply = memories
+            .get_mut(Some(incoming_text.chat_id), &normalization_config)
+            .map(|mut memory| {
+                learn_and_reply(
+                    &mut memory,
+                    &incoming_text.text,
+                    should_reply,
+                    &chat_normalization_config,
+                    phrase_filter,
+                    reply_config,
+                    rng,
+                )
+            });
+
+        let reply = match reply {
+            Ok(Some(reply)) => reply,
+            Ok(None) => continue,
+            Err(err) => {
+                error::report_error(&err);
+                continue;
+            }
+        };
+
+        tracing::info!("generated response: `{}`", reply);
+
+        for message in length_guard.apply(&reply) {
+            if let Err(err) = adapter.send_text(incoming_text.chat_id, &message).await {
+                error::report_error(&err);
+            }
+        }
+    }
+}
+
+/// Learns the phrases of the text into the memory, the same way Telegram
+/// messages are learned, and generates a reply around their words if
+/// `should_reply`.
+fn learn_and_reply(
+    memory: &mut Memory,
+    text: &str,
+    should_reply: bool,
+    normalization_config: &NormalizationConfig,
+    phrase_filter: PhraseFilter,
+    reply_config: &AdapterReplyConfig,
+    rng: &mut impl Rng,
+) -> Option<String> {
+    if let Err(junk_kind) = learn_filter::check_text(text) {
+        tracing::info!("not learning text, it looks like {}", junk_kind);
+        return None;
+    }
+
+    let phrases = phrase_indexing::normalize_text_into_phrases(text.into(), normalization_config);
+
+    if phrases
+        .iter()
+        .any(|phrase| memory.indexed_phrases.has_blocked_word(phrase.as_ref()))
+    {
+        tracing::info!("not learning text, it has a blocked word");
+        return None;
+    }
+
+    let mut word_ids_from_phrases = HashSet::new();
+    let mut incoming_phrases = Vec::new();
+
+    for phrase in phrases {
+        let checked_phrase = learn_filter::check_phrase(phrase.as_ref())
+            .and_then(|()| phrase_filter.check_phrase(&phrase, &memory.indexed_phrases));
+        if checked_phrase.is_err() {
+            continue;
+        }
+
+        incoming_phrases.push(phrase.as_ref().to_string());
+
+        if memory.archived {
+            word_ids_from_phrases.extend(
+                phrase
+                    .as_ref()
+                    .split_ascii_whitespace()
+                    .filter_map(|word| memory.indexed_phrases.get_word_id(word)),
+            );
+            continue;
+        }
+
+        let insertion_res = memory
+            .indexed_phrases
+            .insert_phrase(phrase.clone().with_source(PhraseSource::Chat));
+
+        memory.answer_pools.invalidate(
+            &memory.indexed_phrases,
+            insertion_res.word_ids_from_phrase.iter().copied(),
+        );
+        word_ids_from_phrases.extend(insertion_res.word_ids_from_phrase);
+
+        if insertion_res.has_inserted_phrase {
+            let attributes = LineAttributes {
+                source: PhraseSource::Chat,
+                expires_at: None,
+                occurrences: 1,
+            };
+            let line = store::format_line(&phrase.to_line(), attributes);
+            if let Err(err) = memory.phrase_store.append(&line) {
+                error::report_error(&err);
+            }
+        }
+    }
+
+    if !should_reply {
+        return None;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+
+    let first_sentence = match generation::generate_phrase(
+        &memory.indexed_phrases,
+        &memory.answer_pools,
+        word_ids_from_phrases.into_iter().collect(),
+        &incoming_phrases
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>(),
+        now,
+        rng,
+    ) {
+        Ok(first_sentence) => first_sentence?,
+        Err(err) => {
+            error::report_error(&err.into());
+            return None;
+        }
+    };
+
+    let reply = generation::extend_into_sentences(
+        &memory.indexed_phrases,
+        &memory.answer_pools,
+        first_sentence,
+        &reply_config.sentence_config,
+        now,
+        rng,
+    );
+    let reply = memory.indexed_phrases.restore_acronyms(&reply);
+
+    Some(reply_config.reply_styler.style(&reply, rng))
+}
+
+#[cfg(test)]
+mod adapter_tests {
+    use super::{run, AdapterFuture, AdapterReplyConfig, ChatAdapter, IncomingText};
+    use crate::config::Config;
+    use crate::error;
+    use crate::memory::{Memories, Memory, MemoryScope};
+    use crate::store::FlatFileStore;
+    use feroldinhobot::generation::SentenceConfig;
+    use feroldinhobot::output::ReplyStyler;
+    use feroldinhobot::phrase_indexing::NormalizationConfig;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use tbot::types::chat;
+
+    /// Hands out the incoming texts in order, and keeps what's sent.
+    struct FakeAdapter {
+        incoming_texts: Vec<IncomingText>,
+        sent_texts: Mutex<Vec<(chat::Id, String)>>,
+    }
+
+    impl ChatAdapter for &mut FakeAdapter {
+        fn receive(&mut self) -> AdapterFuture<'_, Option<IncomingText>> {
+            let incoming_text = if self.incoming_texts.is_empty() {
+                None
+            } else {
+                Some(self.incoming_texts.remove(0))
+            };
+
+            Box::pin(async move { incoming_text })
+        }
+
+        fn send_text<'a>(
+            &'a self,
+            chat_id: chat::Id,
+            text: &'a str,
+        ) -> AdapterFuture<'a, error::Result<()>> {
+            self.sent_texts.lock().unwrap().push((chat_id, text.into()));
+
+            Box::pin(async { Ok(()) })
+        }
+
+        fn max_message_len(&self) -> usize {
+            2000
+        }
+    }
+
+    fn incoming_text(text: &str, mentions_bot: bool) -> IncomingText {
+        IncomingText {
+            chat_id: chat::Id(1),
+            text: text.into(),
+            mentions_bot,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_learn_messages_and_reply_to_mentions() {
+        let path: PathBuf =
+            std::env::temp_dir().join(format!("adapter_memory_{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let shared_memory = Memory::load(
+            Box::new(FlatFileStore::create_if_missing(&path).unwrap()),
+            &NormalizationConfig::default(),
+        )
+        .unwrap();
+        let mut memories = Memories::new(
+            MemoryScope::Global,
+            shared_memory,
+            Box::new(|_| unreachable!("chats share the memory")),
+        );
+
+        let mut adapter = FakeAdapter {
+            incoming_texts: vec![
+                incoming_text("my good friend is here", false),
+                incoming_text("hello there, my good friend", true),
+            ],
+            sent_texts: Mutex::new(Vec::new()),
+        };
+        let reply_config = AdapterReplyConfig {
+            reply_probability: 0.0,
+            reply_to_mentions_only: false,
+            sentence_config: SentenceConfig::default(),
+            reply_styler: ReplyStyler {
+                question_prob: 0.0,
+                exclamation_prob: 0.0,
+                interjection_prob: 0.0,
+                interjections: Vec::new(),
+            },
+        };
+
+        run(
+            &mut adapter,
+            &mut memories,
+            Config::default().phrase_filter(),
+            &reply_config,
+            &mut StdRng::seed_from_u64(42),
+        )
+        .await;
+
+        let sent_texts = adapter.sent_texts.into_inner().unwrap();
+        assert_eq!(sent_texts.len(), 1);
+        assert_eq!(sent_texts[0].0, chat::Id(1));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "my good friend is here\tsource=chat\nhello there my good friend\tsource=chat\n"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("snapshot")).unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+/// Two words said to mean the same thing, e.g. from "bob aka robert".
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct AliasPair {
+    pub word: String,
+    pub alias: String,
+}
+
+/// Finds words that the text says are other names for each other, as in "X,
+/// also known as Y", "X aka Y" or "X = Y". Only single words are paired, as
+/// only words can be pivots, and both are lowercased.
+pub fn find_alias_pairs(text: &str) -> Vec<AliasPair> {
+    lazy_static! {
+        static ref ALIAS_PATTERN: Regex = Regex::new(
+            r"(?i)(?:^|[\s(])(\w+),?\s+(?:also\s+known\s+as|aka|a\.k\.a\.?)\s+(\w+)(?:$|[\s.,;:!?)])"
+        )
+        .unwrap();
+        static ref EQUALS_PATTERN: Regex =
+            Regex::new(r"(?:^|[\s(])(\w+)\s*=\s*(\w+)(?:$|[\s.,;:!?)])").unwrap();
+    }
+
+    let mut alias_pairs = Vec::new();
+
+    for pattern in [&*ALIAS_PATTERN, &*EQUALS_PATTERN] {
+        for captures in pattern.captures_iter(text) {
+            let word = captures[1].to_lowercase();
+            let alias = captures[2].to_lowercase();
+
+            let is_number = |word: &str| word.chars().all(|c| c.is_ascii_digit());
+
+            // "x = 2" is more likely math than an alias.
+            if word == alias || is_number(&word) || is_number(&alias) {
+                continue;
+            }
+
+            let alias_pair = AliasPair { word, alias };
+            if !alias_pairs.contains(&alias_pair) {
+                alias_pairs.push(alias_pair);
+            }
+        }
+    }
+
+    alias_pairs
+}
+
+#[cfg(test)]
+mod alias_detection_tests {
+    use super::{find_alias_pairs, AliasPair};
+
+    fn pairs(text: &str) -> Vec<(String, String)> {
+        find_alias_pairs(text)
+            .into_iter()
+            .map(|AliasPair { word, alias }| (word, alias))
+            .collect()
+    }
+
+    #[test]
+    fn should_find_also_known_as() {
+        assert_eq!(
+            pairs("that's Robert, also known as Bob"),
+            [("robert".into(), "bob".into())]
+        );
+    }
+
+    #[test]
+    fn should_find_aka() {
+        assert_eq!(
+            pairs("the fridge aka geladeira is empty"),
+            [("fridge".into(), "geladeira".into())]
+        );
+        assert_eq!(
+            pairs("fridge a.k.a. geladeira"),
+            [("fridge".into(), "geladeira".into())]
+        );
+    }
+
+    #[test]
+    fn should_find_equals() {
+        assert_eq!(pairs("lol = kkkk"), [("lol".into(), "kkkk".into())]);
+    }
+
+    #[test]
+    fn should_ignore_math() {
+        assert!(pairs("x = 2").is_empty());
+        assert!(pairs("2=2").is_empty());
+    }
+
+    #[test]
+    fn should_ignore_words_within_other_words() {
+        assert!(pairs("my pakaya thing").is_empty());
+        assert!(pairs("hello there").is_empty());
+    }
+}
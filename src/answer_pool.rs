@@ -0,0 +1,235 @@
+use crate::phrase_indexing::{
+    EngineError, IndexedPhrase, IndexedPhraseContent, IndexedPhrases, WordId,
+};
+use rand::{seq::IteratorRandom, Rng};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+pub struct AnswerPoolConfig {
+    /// Pivot words with fewer phrases than this are always looked up directly.
+    pub min_phrases_to_cache: usize,
+    /// Number of phrases sampled into the pool of a hot pivot word.
+    pub pool_size: usize,
+    /// How long a pool is used before being sampled again.
+    pub max_age: Duration,
+}
+
+impl Default for AnswerPoolConfig {
+    fn default() -> Self {
+        AnswerPoolConfig {
+            min_phrases_to_cache: 256,
+            pool_size: 64,
+            max_age: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+struct AnswerPool {
+    indexed_phrases: Vec<IndexedPhrase>,
+    sampled_at: Instant,
+}
+
+/// Keeps a sample of candidate phrases for very common pivot words, so that
+/// generating a reply for them doesn't walk all of their phrases every time.
+pub struct AnswerPoolCache {
+    config: AnswerPoolConfig,
+    /// Behind a lock of its own, so that replies can be generated from the
+    /// same phrases at once.
+    pools: Mutex<HashMap<WordId, AnswerPool>>,
+}
+
+impl AnswerPoolCache {
+    pub fn new(config: AnswerPoolConfig) -> AnswerPoolCache {
+        AnswerPoolCache {
+            config,
+            pools: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn pools(&self) -> MutexGuard<'_, HashMap<WordId, AnswerPool>> {
+        self.pools.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Same as `IndexedPhrases::get_phrases_with_word_id_in_common`, except that
+    /// hot pivot words only yield their cached sample of phrases.
+    pub fn get_phrases_with_word_id_in_common<'s>(
+        &self,
+        indexed_phrases: &'s IndexedPhrases,
+        word_id: WordId,
+        rng: &mut impl Rng,
+    ) -> Result<Vec<IndexedPhraseContent<'s>>, EngineError> {
+        let max_age = self.config.max_age;
+
+        let cached_phrases = self
+            .pools()
+            .get(&word_id)
+            .filter(|pool| pool.sampled_at.elapsed() <= max_age)
+            .map(|pool| pool.indexed_phrases.clone());
+
+        let pool_phrases = match cached_phrases {
+            Some(pool_phrases) => pool_phrases,
+            None => {
+                let all_phrases: Vec<_> = indexed_phrases
+                    .get_indexed_phrases_with_word_id_in_common(word_id)?
+                    .collect();
+
+                if all_phrases.len() < self.config.min_phrases_to_cache {
+                    self.pools().remove(&word_id);
+                    all_phrases
+                } else {
+                    let sampled_phrases = all_phrases
+                        .into_iter()
+                        .choose_multiple(rng, self.config.pool_size);
+
+                    self.pools().insert(
+                        word_id,
+                        AnswerPool {
+                            indexed_phrases: sampled_phrases.clone(),
+                            sampled_at: Instant::now(),
+                        },
+                    );
+
+                    sampled_phrases
+                }
+            }
+        };
+
+        Ok(pool_phrases
+            .into_iter()
+            .map(|indexed_phrase| indexed_phrases.get_indexed_phrase_content(indexed_phrase))
+            .collect())
+    }
+
+    /// Drops the pools that could be missing phrases containing the given
+    /// words, which should be called after these words were inserted.
+    pub fn invalidate(
+        &self,
+        indexed_phrases: &IndexedPhrases,
+        word_ids: impl IntoIterator<Item = WordId>,
+    ) {
+        let mut pools = self.pools();
+
+        if pools.is_empty() {
+            return;
+        }
+
+        for word_id in word_ids {
+            for pivot_word_id in indexed_phrases.get_pivot_word_ids(word_id) {
+                pools.remove(&pivot_word_id);
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        self.pools().clear();
+    }
+}
+
+#[cfg(test)]
+mod answer_pool_tests {
+    use super::{AnswerPoolCache, AnswerPoolConfig};
+    use crate::phrase_indexing::{IndexedPhrases, Phrase};
+    use rand::SeedableRng;
+    use std::time::Duration;
+
+    fn indexed_phrases_of(phrases: &[&str]) -> IndexedPhrases {
+        let mut indexed_phrases = IndexedPhrases::new();
+
+        for &phrase in phrases {
+            indexed_phrases.insert_phrase(Phrase::from(phrase));
+        }
+
+        indexed_phrases
+    }
+
+    fn small_config() -> AnswerPoolConfig {
+        AnswerPoolConfig {
+            min_phrases_to_cache: 3,
+            pool_size: 2,
+            max_age: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn should_not_cache_phrases_of_rare_words() {
+        let indexed_phrases = indexed_phrases_of(&["hello world", "hello there"]);
+        let hello = indexed_phrases.get_word_id("hello").unwrap();
+        let cache = AnswerPoolCache::new(small_config());
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        let phrases = cache
+            .get_phrases_with_word_id_in_common(&indexed_phrases, hello, &mut rng)
+            .unwrap();
+
+        assert_eq!(phrases.len(), 2);
+        assert!(cache.pools().is_empty());
+    }
+
+    #[test]
+    fn should_reuse_sampled_pool_of_hot_words() {
+        let indexed_phrases =
+            indexed_phrases_of(&["hello world", "hello there", "hello you", "hello me"]);
+        let hello = indexed_phrases.get_word_id("hello").unwrap();
+        let cache = AnswerPoolCache::new(small_config());
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        let first_phrases = cache
+            .get_phrases_with_word_id_in_common(&indexed_phrases, hello, &mut rng)
+            .unwrap();
+
+        for _ in 0..10 {
+            let phrases = cache
+                .get_phrases_with_word_id_in_common(&indexed_phrases, hello, &mut rng)
+                .unwrap();
+
+            assert_eq!(phrases, first_phrases);
+        }
+
+        assert_eq!(first_phrases.len(), 2);
+    }
+
+    #[test]
+    fn should_drop_pool_when_its_word_is_inserted() {
+        let mut indexed_phrases = indexed_phrases_of(&["hello world", "hello there", "hello you"]);
+        let hello = indexed_phrases.get_word_id("hello").unwrap();
+        let cache = AnswerPoolCache::new(small_config());
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        cache
+            .get_phrases_with_word_id_in_common(&indexed_phrases, hello, &mut rng)
+            .unwrap();
+        assert!(cache.pools().contains_key(&hello));
+
+        let insertion_res = indexed_phrases.insert_phrase(Phrase::from("goodbye world"));
+        cache.invalidate(&indexed_phrases, insertion_res.word_ids_from_phrase);
+        assert!(cache.pools().contains_key(&hello));
+
+        let insertion_res = indexed_phrases.insert_phrase(Phrase::from("hello again"));
+        cache.invalidate(&indexed_phrases, insertion_res.word_ids_from_phrase);
+        assert!(!cache.pools().contains_key(&hello));
+    }
+
+    #[test]
+    fn should_sample_pool_again_once_it_is_stale() {
+        let indexed_phrases = indexed_phrases_of(&["hello world", "hello there", "hello you"]);
+        let hello = indexed_phrases.get_word_id("hello").unwrap();
+        let cache = AnswerPoolCache::new(AnswerPoolConfig {
+            max_age: Duration::ZERO,
+            ..small_config()
+        });
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        cache
+            .get_phrases_with_word_id_in_common(&indexed_phrases, hello, &mut rng)
+            .unwrap();
+        let first_sampled_at = cache.pools()[&hello].sampled_at;
+
+        std::thread::sleep(Duration::from_millis(1));
+        cache
+            .get_phrases_with_word_id_in_common(&indexed_phrases, hello, &mut rng)
+            .unwrap();
+
+        assert!(cache.pools()[&hello].sampled_at > first_sampled_at);
+    }
+}
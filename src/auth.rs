@@ -0,0 +1,177 @@
+use crate::config::{ApiTokenConfig, TokenScope};
+use crate::error::{self, Error, ResultExt};
+use std::collections::{HashMap, HashSet};
+use tbot::types::{chat, chat::member::Status, user};
+use tbot::Bot;
+
+/// Users that may change the state of the bot in any chat, on top of the
+/// admins of each group.
+#[derive(Default)]
+pub(crate) struct Admins {
+    user_ids: HashSet<user::Id>,
+}
+
+impl Admins {
+    /// Reads the ids of the admins, which are given as a comma separated list in
+    /// `ADMIN_USER_IDS`.
+    pub(crate) fn from_env() -> error::Result<Admins> {
+        match std::env::var("ADMIN_USER_IDS") {
+            Ok(user_ids) => Admins::parse(&user_ids),
+            Err(_) => Ok(Admins::default()),
+        }
+    }
+
+    fn parse(user_ids: &str) -> error::Result<Admins> {
+        let user_ids = user_ids
+            .split(',')
+            .map(str::trim)
+            .filter(|user_id| !user_id.is_empty())
+            .map(|user_id| {
+                user_id
+                    .parse()
+                    .map(user::Id)
+                    .map_err(|_| Error::parse("admin user id", user_id))
+            })
+            .collect::<error::Result<_>>()?;
+
+        Ok(Admins { user_ids })
+    }
+
+    pub(crate) fn contains(&self, user_id: user::Id) -> bool {
+        self.user_ids.contains(&user_id)
+    }
+
+    pub(crate) fn user_ids(&self) -> impl Iterator<Item = user::Id> + '_ {
+        self.user_ids.iter().copied()
+    }
+}
+
+/// Bearer tokens that an HTTP API may be called with, along with what each of
+/// them may do.
+#[derive(Default)]
+pub(crate) struct ApiTokens {
+    scopes_by_token: HashMap<String, TokenScope>,
+}
+
+impl ApiTokens {
+    /// Reads the tokens out of their environment variables, as read by `var`,
+    /// skipping the ones that aren't set.
+    pub(crate) fn from_config(
+        token_configs: &[ApiTokenConfig],
+        var: impl Fn(&str) -> Option<String>,
+    ) -> ApiTokens {
+        let mut api_tokens = ApiTokens::default();
+
+        for token_config in token_configs {
+            match var(&token_config.env_var).filter(|token| !token.is_empty()) {
+                Some(token) => api_tokens.insert(token, token_config.scope),
+                None => tracing::warn!("API token `{}` isn't set", token_config.env_var),
+            }
+        }
+
+        api_tokens
+    }
+
+    fn insert(&mut self, token: String, scope: TokenScope) {
+        let token_scope = self.scopes_by_token.entry(token).or_insert(scope);
+        *token_scope = (*token_scope).max(scope);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.scopes_by_token.is_empty()
+    }
+
+    /// What the token may do, or `None` if it isn't one of the tokens.
+    pub(crate) fn scope_of(&self, token: &str) -> Option<TokenScope> {
+        self.scopes_by_token.get(token).copied()
+    }
+}
+
+/// Asks Telegram whether the user is the creator or an admin of the chat.
+pub(crate) async fn is_chat_admin(
+    bot: &Bot,
+    chat_id: chat::Id,
+    user_id: user::Id,
+) -> error::Result<bool> {
+    let member = bot
+        .get_chat_member(chat_id, user_id)
+        .call()
+        .await
+        .context(|| {
+            format!(
+                "checking whether {} is an admin of chat {}",
+                user_id, chat_id
+            )
+        })?;
+
+    Ok(matches!(
+        member.status,
+        Status::Creator { .. } | Status::Administrator { .. }
+    ))
+}
+
+#[cfg(test)]
+mod admins_tests {
+    use super::Admins;
+    use tbot::types::user;
+
+    #[test]
+    fn should_parse_comma_separated_admin_ids() {
+        let admins = Admins::parse("42, 1337,").unwrap();
+
+        assert!(admins.contains(user::Id(42)));
+        assert!(admins.contains(user::Id(1337)));
+        assert!(!admins.contains(user::Id(7)));
+    }
+
+    #[test]
+    fn should_fail_to_parse_invalid_admin_id() {
+        assert!(Admins::parse("42,abc").is_err());
+    }
+}
+
+#[cfg(test)]
+mod api_tokens_tests {
+    use super::ApiTokens;
+    use crate::config::{ApiTokenConfig, TokenScope};
+    use std::collections::HashMap;
+
+    fn token_config(env_var: &str, scope: TokenScope) -> ApiTokenConfig {
+        ApiTokenConfig {
+            env_var: env_var.into(),
+            scope,
+        }
+    }
+
+    #[test]
+    fn should_read_tokens_from_environment() {
+        let vars = HashMap::from([("ADMIN_TOKEN", "secret"), ("VIEWER_TOKEN", "public")]);
+
+        let api_tokens = ApiTokens::from_config(
+            &[
+                token_config("ADMIN_TOKEN", TokenScope::Admin),
+                token_config("VIEWER_TOKEN", TokenScope::ReadOnly),
+                token_config("MISSING_TOKEN", TokenScope::Admin),
+            ],
+            |name| vars.get(name).map(|&value| value.into()),
+        );
+
+        assert_eq!(api_tokens.scope_of("secret"), Some(TokenScope::Admin));
+        assert_eq!(api_tokens.scope_of("public"), Some(TokenScope::ReadOnly));
+        assert_eq!(api_tokens.scope_of(""), None);
+        assert_eq!(api_tokens.scope_of("other"), None);
+    }
+
+    #[test]
+    fn should_keep_widest_scope_of_repeated_token() {
+        let api_tokens = ApiTokens::from_config(
+            &[
+                token_config("ADMIN_TOKEN", TokenScope::Admin),
+                token_config("VIEWER_TOKEN", TokenScope::ReadOnly),
+            ],
+            |_| Some("secret".into()),
+        );
+
+        assert_eq!(api_tokens.scope_of("secret"), Some(TokenScope::Admin));
+    }
+}
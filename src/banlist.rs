@@ -0,0 +1,100 @@
+use crate::error::{self, Error, ResultExt};
+use std::collections::HashSet;
+use tbot::types::Document;
+use tbot::Bot;
+
+/// Files of banned words larger than this aren't imported.
+pub(crate) const MAX_BANLIST_FILE_SIZE: u32 = 1024 * 1024;
+/// Words longer than this are taken for mistakes rather than banned.
+const MAX_BANNED_WORD_LEN: usize = 64;
+
+/// What importing a list of banned words made of each of its entries.
+#[derive(PartialEq, Debug, Default)]
+pub(crate) struct BanlistImport {
+    /// New banned words, lowercased, in the order they were listed.
+    pub(crate) added_words: Vec<String>,
+    /// Entries already banned, or listed before in the same file.
+    pub(crate) duplicate_count: usize,
+    /// Entries which can't be banned, as they aren't words.
+    pub(crate) invalid_count: usize,
+}
+
+impl BanlistImport {
+    /// Reads one entry per line, or per comma, leaving out blank lines and
+    /// comments starting with `#`. Only whole words can be banned, since
+    /// that's what phrases are matched against the blocklist by.
+    pub(crate) fn parse(text: &str, blocked_words: &[String]) -> BanlistImport {
+        let mut seen_words: HashSet<String> = blocked_words
+            .iter()
+            .map(|word| word.to_lowercase())
+            .collect();
+        let mut import = BanlistImport::default();
+
+        let entries = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with('#'))
+            .flat_map(|line| line.split(','))
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty());
+
+        for entry in entries {
+            let is_word = entry.chars().count() <= MAX_BANNED_WORD_LEN
+                && entry.chars().all(char::is_alphanumeric);
+
+            if !is_word {
+                import.invalid_count += 1;
+                continue;
+            }
+
+            let word = entry.to_lowercase();
+            if seen_words.contains(&word) {
+                import.duplicate_count += 1;
+            } else {
+                seen_words.insert(word.clone());
+                import.added_words.push(word);
+            }
+        }
+
+        import
+    }
+}
+
+/// Downloads the document, which must be UTF-8 text.
+pub(crate) async fn download_text(bot: &Bot, document: &Document) -> error::Result<String> {
+    let file_name = document.file_name.as_deref().unwrap_or("document");
+
+    let file = bot
+        .get_file(document)
+        .call()
+        .await
+        .context(|| format!("fetching file `{}`", file_name))?;
+    let bytes = bot
+        .download_file(&file)
+        .await
+        .map_err(|source| Error::Network {
+            context: format!("downloading file `{}`", file_name),
+            source: source.into(),
+        })?;
+
+    String::from_utf8(bytes).map_err(|_| Error::parse("banned words", file_name))
+}
+
+#[cfg(test)]
+mod banlist_tests {
+    use super::BanlistImport;
+
+    #[test]
+    fn should_add_new_words_counting_duplicates_and_invalid_entries() {
+        let text = "# banned words\nFoo\nbar, baz\n\nfoo\nnot a word\nqux!\nBAZ\nalready\n";
+
+        assert_eq!(
+            BanlistImport::parse(text, &["Already".to_string()]),
+            BanlistImport {
+                added_words: vec!["foo".into(), "bar".into(), "baz".into()],
+                duplicate_count: 3,
+                invalid_count: 2,
+            }
+        );
+    }
+}
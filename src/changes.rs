@@ -0,0 +1,217 @@
+use feroldinhobot::phrase_indexing::IndexedPhrases;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tbot::types::chat;
+
+/// What a chat taught the bot at some point in time.
+struct CorpusChange {
+    at: Instant,
+    new_phrase_count: usize,
+    new_words: Vec<String>,
+}
+
+pub(crate) struct ChangeSummary {
+    pub(crate) new_phrase_count: usize,
+    pub(crate) new_words: Vec<String>,
+}
+
+impl ChangeSummary {
+    /// The new words still known, along with how many phrases they're part of,
+    /// from the one that spread into the most phrases.
+    pub(crate) fn notable_words(&self, indexed_phrases: &IndexedPhrases) -> Vec<(&str, usize)> {
+        let mut notable_words: Vec<_> = self
+            .new_words
+            .iter()
+            .filter(|word| word.chars().count() > 2)
+            .filter_map(|word| {
+                let word_id = indexed_phrases.get_word_id(word)?;
+                let phrase_count = indexed_phrases.get_phrase_count_of_word(word_id);
+                Some((word.as_str(), phrase_count))
+            })
+            .collect();
+        notable_words.sort_by_key(|&(_, phrase_count)| std::cmp::Reverse(phrase_count));
+
+        notable_words
+    }
+}
+
+/// Remembers when each chat taught the bot new phrases and words, for as long
+/// as `retention`. Words are kept as text, since the ids of words evicted from
+/// memory are reused by words learned later.
+pub(crate) struct ChangeLog {
+    retention: Duration,
+    changes_by_chat: HashMap<chat::Id, VecDeque<CorpusChange>>,
+}
+
+impl Default for ChangeLog {
+    fn default() -> Self {
+        ChangeLog::new(Duration::from_secs(7 * 24 * 60 * 60))
+    }
+}
+
+impl ChangeLog {
+    pub(crate) fn new(retention: Duration) -> ChangeLog {
+        ChangeLog {
+            retention,
+            changes_by_chat: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        chat_id: chat::Id,
+        at: Instant,
+        new_phrase_count: usize,
+        new_words: Vec<String>,
+    ) {
+        if new_phrase_count == 0 && new_words.is_empty() {
+            return;
+        }
+
+        let changes = self.changes_by_chat.entry(chat_id).or_default();
+
+        while let Some(oldest) = changes.front() {
+            if at.duration_since(oldest.at) <= self.retention {
+                break;
+            }
+            changes.pop_front();
+        }
+
+        changes.push_back(CorpusChange {
+            at,
+            new_phrase_count,
+            new_words,
+        });
+    }
+
+    /// Sums up what the chat taught the bot from `since` onwards. New words are
+    /// listed from the most recent.
+    pub(crate) fn summarize(&self, chat_id: chat::Id, since: Instant) -> ChangeSummary {
+        let mut summary = ChangeSummary {
+            new_phrase_count: 0,
+            new_words: Vec::new(),
+        };
+
+        let changes = self.changes_by_chat.get(&chat_id).into_iter().flatten();
+
+        for change in changes.rev().take_while(|change| change.at >= since) {
+            summary.new_phrase_count += change.new_phrase_count;
+            summary
+                .new_words
+                .extend(change.new_words.iter().rev().cloned());
+        }
+
+        summary
+    }
+}
+
+/// Parses windows such as `90s`, `30m`, `24h` and `7d`.
+pub(crate) fn parse_window(window: &str) -> Option<Duration> {
+    let unit_pos = window.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = window.split_at(unit_pos);
+    let amount: u64 = amount.parse().ok()?;
+
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+
+    Some(Duration::from_secs(amount.checked_mul(unit_secs)?))
+}
+
+#[cfg(test)]
+mod change_log_tests {
+    use super::{parse_window, ChangeLog};
+    use feroldinhobot::phrase_indexing::{IndexedPhrases, Phrase};
+    use std::time::{Duration, Instant};
+    use tbot::types::chat;
+
+    #[test]
+    fn should_parse_windows() {
+        assert_eq!(parse_window("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_window("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_window("24h"), Some(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(
+            parse_window("7d"),
+            Some(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_window("24"), None);
+        assert_eq!(parse_window("h"), None);
+        assert_eq!(parse_window("2w"), None);
+    }
+
+    #[test]
+    fn should_only_summarize_changes_of_the_chat_within_window() {
+        let mut change_log = ChangeLog::default();
+        let start = Instant::now();
+        change_log.record(chat::Id(1), start, 3, vec!["hello".into()]);
+        change_log.record(
+            chat::Id(1),
+            start + Duration::from_secs(60),
+            2,
+            vec!["there".into()],
+        );
+        change_log.record(
+            chat::Id(2),
+            start + Duration::from_secs(60),
+            5,
+            vec!["hello".into()],
+        );
+
+        let summary = change_log.summarize(chat::Id(1), start + Duration::from_secs(30));
+
+        assert_eq!(summary.new_phrase_count, 2);
+        assert_eq!(summary.new_words, &["there"]);
+
+        let summary = change_log.summarize(chat::Id(1), start);
+
+        assert_eq!(summary.new_phrase_count, 5);
+        assert_eq!(summary.new_words, &["there", "hello"]);
+    }
+
+    #[test]
+    fn should_not_report_words_learned_after_eviction_as_new() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        let old_word_ids = indexed_phrases
+            .insert_phrase(Phrase::from("hello there"))
+            .word_ids_from_phrase;
+
+        let mut change_log = ChangeLog::default();
+        let start = Instant::now();
+        change_log.record(chat::Id(1), start, 1, vec!["hello".into(), "there".into()]);
+
+        indexed_phrases.evict_least_recently_learned(Some(0), None);
+        let new_word_ids = indexed_phrases
+            .insert_phrase(Phrase::from("good morning"))
+            .word_ids_from_phrase;
+        assert!(new_word_ids
+            .iter()
+            .all(|word_id| old_word_ids.contains(word_id)));
+
+        let summary = change_log.summarize(chat::Id(1), start);
+        assert!(summary.notable_words(&indexed_phrases).is_empty());
+
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        assert_eq!(
+            summary.notable_words(&indexed_phrases),
+            &[("there", 1), ("hello", 1)]
+        );
+    }
+
+    #[test]
+    fn should_forget_changes_past_retention() {
+        let mut change_log = ChangeLog::new(Duration::from_secs(60));
+        let start = Instant::now();
+        change_log.record(chat::Id(1), start, 3, vec![]);
+        change_log.record(chat::Id(1), start + Duration::from_secs(120), 1, vec![]);
+
+        assert_eq!(change_log.changes_by_chat[&chat::Id(1)].len(), 1);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::error::{self, Error};
+use crate::outgoing::{MessageSender, SendFuture};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::sync::Mutex;
+use std::time::Duration;
+use tbot::errors::MethodCall;
+use tbot::types::chat;
+
+/// How often, and how, sending messages should fail.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ChaosConfig {
+    /// Probability of a send failing as if Telegram were out of service.
+    pub(crate) failure_rate: f32,
+    /// Probability of a send being rejected for exceeding the flood limit.
+    pub(crate) flood_limit_rate: f32,
+    /// How long flood limited sends are told to wait before trying again.
+    pub(crate) flood_retry_after: Duration,
+    /// Sends take a random time up to this long, whether they fail or not.
+    pub(crate) max_latency: Duration,
+}
+
+impl ChaosConfig {
+    /// Reads the failure rates from `CHAOS_FAILURE_RATE` and
+    /// `CHAOS_FLOOD_LIMIT_RATE`, and the latency from `CHAOS_MAX_LATENCY_MS`.
+    /// Returns `None` if none of them is set, which is how the bot normally runs.
+    pub(crate) fn from_env() -> error::Result<Option<ChaosConfig>> {
+        fn var<T: std::str::FromStr>(name: &str, what: &str) -> error::Result<Option<T>> {
+            match std::env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| Error::parse(what, value)),
+                Err(_) => Ok(None),
+            }
+        }
+
+        let failure_rate = var("CHAOS_FAILURE_RATE", "chaos failure rate")?;
+        let flood_limit_rate = var("CHAOS_FLOOD_LIMIT_RATE", "chaos flood limit rate")?;
+        let max_latency_ms = var("CHAOS_MAX_LATENCY_MS", "chaos max latency")?;
+
+        if failure_rate.is_none() && flood_limit_rate.is_none() && max_latency_ms.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(ChaosConfig {
+            failure_rate: failure_rate.unwrap_or(0.0),
+            flood_limit_rate: flood_limit_rate.unwrap_or(0.0),
+            flood_retry_after: Duration::from_secs(1),
+            max_latency: Duration::from_millis(max_latency_ms.unwrap_or(0)),
+        }))
+    }
+}
+
+/// Wraps a sender, making its sends slow and unreliable the way Telegram can
+/// be, so that retries, queueing and flood limit handling can be exercised.
+pub(crate) struct ChaosSender<S> {
+    inner: S,
+    config: ChaosConfig,
+    rng: Mutex<StdRng>,
+}
+
+impl<S: MessageSender> ChaosSender<S> {
+    pub(crate) fn new(inner: S, config: ChaosConfig) -> ChaosSender<S> {
+        ChaosSender::with_rng(inner, config, StdRng::from_entropy())
+    }
+
+    /// Same as `new`, but failures are drawn from `rng`, so that they can be
+    /// reproduced.
+    pub(crate) fn with_rng(inner: S, config: ChaosConfig, rng: StdRng) -> ChaosSender<S> {
+        ChaosSender {
+            inner,
+            config,
+            rng: Mutex::new(rng),
+        }
+    }
+
+    fn draw_outcome(&self) -> (Duration, Option<MethodCall>) {
+        let mut rng = self.rng.lock().unwrap();
+
+        let latency = self.config.max_latency.mul_f32(rng.gen());
+        let roll: f32 = rng.gen();
+
+        let error = if roll < self.config.flood_limit_rate {
+            let retry_after = self.config.flood_retry_after.as_secs();
+
+            Some(MethodCall::RequestError {
+                description: format!("Too Many Requests: retry after {}", retry_after),
+                error_code: 429,
+                migrate_to_chat_id: None,
+                retry_after: Some(retry_after),
+            })
+        } else if roll < self.config.flood_limit_rate + self.config.failure_rate {
+            Some(MethodCall::OutOfService)
+        } else {
+            None
+        };
+
+        (latency, error)
+    }
+}
+
+impl<S: MessageSender> MessageSender for ChaosSender<S> {
+    fn send_text<'a>(&'a self, chat_id: chat::Id, text: &'a str) -> SendFuture<'a> {
+        let (latency, error) = self.draw_outcome();
+
+        Box::pin(async move {
+            tokio::time::delay_for(latency).await;
+
+            match error {
+                Some(error) => Err(error),
+                None => self.inner.send_text(chat_id, text).await,
+            }
+        })
+    }
+
+    fn send_typing(&self, chat_id: chat::Id) -> SendFuture<'_> {
+        self.inner.send_typing(chat_id)
+    }
+}
+
+#[cfg(test)]
+mod chaos_sender_tests {
+    use super::{ChaosConfig, ChaosSender};
+    use crate::outgoing::{MessageSender, OutgoingQueue, QueueConfig, SendFuture};
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tbot::errors::MethodCall;
+    use tbot::types::chat;
+
+    #[derive(Default)]
+    struct RecordingSender {
+        sent_messages: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MessageSender for RecordingSender {
+        fn send_text<'a>(&'a self, _chat_id: chat::Id, text: &'a str) -> SendFuture<'a> {
+            Box::pin(async move {
+                self.sent_messages.lock().unwrap().push(text.into());
+                Ok(())
+            })
+        }
+
+        fn send_typing(&self, _chat_id: chat::Id) -> SendFuture<'_> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn chaos_sender(
+        config: ChaosConfig,
+    ) -> (ChaosSender<RecordingSender>, Arc<Mutex<Vec<String>>>) {
+        let inner = RecordingSender::default();
+        let sent_messages = Arc::clone(&inner.sent_messages);
+
+        let sender = ChaosSender::with_rng(inner, config, StdRng::seed_from_u64(42));
+        (sender, sent_messages)
+    }
+
+    #[tokio::test]
+    async fn should_pass_sends_through_without_chaos() {
+        let (sender, sent_messages) = chaos_sender(ChaosConfig::default());
+
+        sender.send_text(chat::Id(1), "hello").await.unwrap();
+
+        assert_eq!(*sent_messages.lock().unwrap(), &["hello"]);
+    }
+
+    #[tokio::test]
+    async fn should_fail_sends_as_out_of_service() {
+        let (sender, sent_messages) = chaos_sender(ChaosConfig {
+            failure_rate: 1.0,
+            ..ChaosConfig::default()
+        });
+
+        let result = sender.send_text(chat::Id(1), "hello").await;
+
+        assert!(matches!(result, Err(MethodCall::OutOfService)));
+        assert!(sent_messages.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_reject_sends_for_exceeding_flood_limit() {
+        let (sender, _) = chaos_sender(ChaosConfig {
+            flood_limit_rate: 1.0,
+            flood_retry_after: Duration::from_secs(3),
+            ..ChaosConfig::default()
+        });
+
+        let result = sender.send_text(chat::Id(1), "hello").await;
+
+        assert!(matches!(
+            result,
+            Err(MethodCall::RequestError {
+                error_code: 429,
+                retry_after: Some(3),
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_delay_sends_up_to_max_latency() {
+        let (sender, _) = chaos_sender(ChaosConfig {
+            max_latency: Duration::from_millis(50),
+            ..ChaosConfig::default()
+        });
+
+        let start = Instant::now();
+        for _ in 0..10 {
+            sender.send_text(chat::Id(1), "hello").await.unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        assert!(elapsed > Duration::ZERO);
+        assert!(elapsed < Duration::from_millis(10 * 50 + 500));
+    }
+
+    #[tokio::test]
+    async fn should_deliver_every_message_in_order_through_queue_despite_failures() {
+        let (sender, sent_messages) = chaos_sender(ChaosConfig {
+            failure_rate: 0.3,
+            max_latency: Duration::from_millis(2),
+            ..ChaosConfig::default()
+        });
+        let mut queue = OutgoingQueue::new(
+            sender,
+            QueueConfig {
+                min_send_interval: Duration::ZERO,
+                max_retries: 20,
+                retry_delay: Duration::from_millis(1),
+                max_retry_delay: Duration::from_millis(1),
+                ..QueueConfig::default()
+            },
+        );
+
+        let texts: Vec<_> = (0..20).map(|i| i.to_string()).collect();
+        for text in &texts {
+            queue.enqueue(chat::Id(1), text.clone());
+        }
+
+        for _ in 0..1000 {
+            if sent_messages.lock().unwrap().len() >= texts.len() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(2)).await;
+        }
+
+        assert_eq!(*sent_messages.lock().unwrap(), texts);
+    }
+}
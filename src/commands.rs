@@ -0,0 +1,355 @@
+use crate::error::{self, Error};
+use crate::http;
+use hyper::{Body, Request};
+use serde::Serialize;
+use serde_json::json;
+use tbot::types::user;
+
+/// A command the bot answers to.
+pub(crate) struct CommandInfo {
+    pub(crate) name: &'static str,
+    pub(crate) description: &'static str,
+    /// Whether only admins may use it, which keeps it out of the menu of
+    /// everyone else.
+    pub(crate) admins_only: bool,
+}
+
+const fn command(name: &'static str, description: &'static str) -> CommandInfo {
+    CommandInfo {
+        name,
+        description,
+        admins_only: false,
+    }
+}
+
+const fn admin_command(name: &'static str, description: &'static str) -> CommandInfo {
+    CommandInfo {
+        name,
+        description,
+        admins_only: true,
+    }
+}
+
+/// Every command the bot answers to, in the order they're listed in menus.
+pub(crate) const COMMANDS: &[CommandInfo] = &[
+    command("think", "Say something from what was learned"),
+    command("speak", "Reply, starting from the message replied to"),
+    command("say", "Say something around a word"),
+    command("related", "List words that come up along with a word"),
+    command("quoteme", "Quote something that was learned"),
+    command("tag", "Tag the message replied to"),
+    command("generate", "Say something from the phrases with a tag"),
+    command("best", "Show the best voted replies of the month"),
+    command("trending", "Show the words used more than usual this week"),
+    command("changes", "Summarize what changed in memory lately"),
+    command("optout", "Stop learning from your messages"),
+    command("optin", "Learn from your messages again"),
+    command("deletemydata", "Forget everything you said"),
+    admin_command("stats", "Show how the bot is doing"),
+    admin_command("forget", "Forget a phrase"),
+    admin_command("protect", "Keep the spelling of a word in replies"),
+    admin_command("unprotect", "Stop keeping the spelling of a word"),
+    admin_command("banlist", "Ban the words of a replied text file"),
+    admin_command("nerf", "Make a word less likely to be replied around"),
+    admin_command("compact", "Compact the database"),
+    admin_command("archive", "Stop learning and keep memory as it is"),
+    admin_command("unarchive", "Learn again after archiving"),
+    admin_command("pending", "List changes waiting for approval"),
+    admin_command("approve", "Approve a pending change"),
+    admin_command("reject", "Reject a pending change"),
+    admin_command("setprob", "Set the probability of replying"),
+    admin_command(
+        "schedule",
+        "Set the probability of replying by weekday and hour",
+    ),
+    admin_command(
+        "setemojiprob",
+        "Set the probability of reacting with an emoji",
+    ),
+    admin_command("setmentionsonly", "Only reply when mentioned (on/off)"),
+    admin_command("setsentences", "Set how many sentences replies have"),
+    admin_command("setchaining", "Chain the sentences of replies (on/off)"),
+    admin_command("setsuppression", "Set the seconds to hold off replying"),
+    admin_command(
+        "setoverflow",
+        "Set what to do with replies that are too long",
+    ),
+    admin_command("setterminators", "Set the characters that end phrases"),
+    admin_command("setelongation", "Set how many repeated letters are kept"),
+    admin_command("setlaughter", "Set the pattern laughter is recognized by"),
+    admin_command(
+        "setsubwords",
+        "Set the min letters of words found inside others",
+    ),
+    admin_command("setlang", "Set the language of the chat"),
+    admin_command(
+        "setsourceweight",
+        "Set how likely phrases of a source are picked",
+    ),
+    admin_command(
+        "setfrequencytemperature",
+        "Set how much word frequency matters",
+    ),
+    admin_command("setsourcequota", "Set how many phrases a source may keep"),
+];
+
+/// Descriptions of commands in languages other than English, by language code.
+const TRANSLATED_DESCRIPTIONS: &[(&str, &[(&str, &str)])] = &[(
+    "pt",
+    &[
+        ("think", "Diz algo do que aprendeu"),
+        ("speak", "Responde, a partir da mensagem respondida"),
+        ("say", "Diz algo em torno de uma palavra"),
+        (
+            "related",
+            "Lista palavras que aparecem junto de uma palavra",
+        ),
+        ("quoteme", "Cita algo que aprendeu"),
+        ("tag", "Marca a mensagem respondida"),
+        ("generate", "Diz algo das frases com uma marca"),
+        ("best", "Mostra as respostas mais votadas do mês"),
+        (
+            "trending",
+            "Mostra as palavras mais usadas que o normal na semana",
+        ),
+        ("changes", "Resume o que mudou na memória ultimamente"),
+        ("optout", "Para de aprender com suas mensagens"),
+        ("optin", "Volta a aprender com suas mensagens"),
+        ("deletemydata", "Esquece tudo o que você disse"),
+        ("stats", "Mostra como o bot está"),
+        ("forget", "Esquece uma frase"),
+        ("protect", "Mantém a grafia de uma palavra nas respostas"),
+        ("unprotect", "Para de manter a grafia de uma palavra"),
+        (
+            "banlist",
+            "Bane as palavras de um arquivo de texto respondido",
+        ),
+        ("nerf", "Deixa uma palavra menos provável nas respostas"),
+        ("compact", "Compacta o banco de dados"),
+        ("archive", "Para de aprender e mantém a memória como está"),
+        ("unarchive", "Volta a aprender depois de arquivar"),
+        ("pending", "Lista as mudanças esperando aprovação"),
+        ("approve", "Aprova uma mudança pendente"),
+        ("reject", "Rejeita uma mudança pendente"),
+        ("setprob", "Define a probabilidade de responder"),
+        (
+            "schedule",
+            "Define a probabilidade de responder por dia e hora",
+        ),
+        (
+            "setemojiprob",
+            "Define a probabilidade de reagir com um emoji",
+        ),
+        ("setmentionsonly", "Só responde quando mencionado (on/off)"),
+        ("setsentences", "Define quantas frases as respostas têm"),
+        ("setchaining", "Encadeia as frases das respostas (on/off)"),
+        (
+            "setsuppression",
+            "Define os segundos de espera para responder",
+        ),
+        (
+            "setoverflow",
+            "Define o que fazer com respostas longas demais",
+        ),
+        ("setterminators", "Define os caracteres que terminam frases"),
+        (
+            "setelongation",
+            "Define quantas letras repetidas são mantidas",
+        ),
+        (
+            "setlaughter",
+            "Define o padrão pelo qual risadas são reconhecidas",
+        ),
+        (
+            "setsubwords",
+            "Define o mínimo de letras de palavras dentro de outras",
+        ),
+        ("setlang", "Define o idioma do chat"),
+        (
+            "setsourceweight",
+            "Define a chance de frases de uma fonte serem escolhidas",
+        ),
+        (
+            "setfrequencytemperature",
+            "Define o quanto a frequência das palavras importa",
+        ),
+        (
+            "setsourcequota",
+            "Define quantas frases uma fonte pode manter",
+        ),
+    ],
+)];
+
+impl CommandInfo {
+    /// The description in the language, falling back to English if there's no
+    /// translation of it.
+    fn description_in(&self, language: Option<&str>) -> &'static str {
+        translations_of(language)
+            .and_then(|translations| translations.iter().find(|(name, _)| *name == self.name))
+            .map_or(self.description, |(_, description)| description)
+    }
+}
+
+fn translations_of(language: Option<&str>) -> Option<&'static [(&'static str, &'static str)]> {
+    let language = language?;
+
+    TRANSLATED_DESCRIPTIONS
+        .iter()
+        .find(|(translated_language, _)| *translated_language == language)
+        .map(|(_, translations)| *translations)
+}
+
+#[derive(Serialize)]
+struct BotCommand {
+    command: &'static str,
+    description: &'static str,
+}
+
+fn bot_commands(include_admin_commands: bool, language: Option<&str>) -> Vec<BotCommand> {
+    COMMANDS
+        .iter()
+        .filter(|command| include_admin_commands || !command.admins_only)
+        .map(|command| BotCommand {
+            command: command.name,
+            description: command.description_in(language),
+        })
+        .collect()
+}
+
+/// Calls `setMyCommands`, which tbot only knows without a scope or language.
+async fn set_my_commands(
+    client: &http::HttpsClient,
+    token: &str,
+    scope: serde_json::Value,
+    language: Option<&str>,
+    commands: &[BotCommand],
+) -> error::Result<()> {
+    let mut params = json!({ "commands": commands, "scope": scope });
+    if let Some(language) = language {
+        params["language_code"] = language.into();
+    }
+
+    // The url contains the token, so it's kept out of errors.
+    let request = Request::post(format!(
+        "https://api.telegram.org/bot{}/setMyCommands",
+        token
+    ))
+    .header("Content-Type", "application/json")
+    .body(Body::from(params.to_string()))
+    .map_err(|_| Error::parse("command scope", scope.to_string()))?;
+
+    http::fetch(client, request, || {
+        format!("registering the commands of scope {}", scope)
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Registers the menu of commands in the language, which users of any other
+/// language are shown if it's `None`: everyone is shown the commands anyone may
+/// use, while chat admins, and bot admins in their private chats, are shown
+/// every command.
+async fn register_in(
+    client: &http::HttpsClient,
+    token: &str,
+    admin_user_ids: &[user::Id],
+    language: Option<&str>,
+) -> error::Result<()> {
+    set_my_commands(
+        client,
+        token,
+        json!({ "type": "default" }),
+        language,
+        &bot_commands(false, language),
+    )
+    .await?;
+
+    let admin_commands = bot_commands(true, language);
+    set_my_commands(
+        client,
+        token,
+        json!({ "type": "all_chat_administrators" }),
+        language,
+        &admin_commands,
+    )
+    .await?;
+
+    for user_id in admin_user_ids {
+        set_my_commands(
+            client,
+            token,
+            json!({ "type": "chat", "chat_id": user_id.0 }),
+            language,
+            &admin_commands,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Registers the menu of commands in English, and in each of the languages
+/// that there are translations for.
+pub(crate) async fn register(
+    token: &str,
+    admin_user_ids: &[user::Id],
+    languages: &[String],
+) -> error::Result<()> {
+    let client = http::new_client();
+
+    register_in(&client, token, admin_user_ids, None).await?;
+
+    for language in languages {
+        if translations_of(Some(language)).is_some() {
+            register_in(&client, token, admin_user_ids, Some(language)).await?;
+        } else {
+            tracing::warn!("commands have no translation to `{}`", language);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod command_tests {
+    use super::{COMMANDS, TRANSLATED_DESCRIPTIONS};
+    use std::collections::HashSet;
+
+    #[test]
+    fn should_only_have_commands_telegram_accepts() {
+        let mut names = HashSet::new();
+
+        for command in COMMANDS {
+            assert!(names.insert(command.name), "{} is repeated", command.name);
+            assert!((1..=32).contains(&command.name.len()));
+            assert!(command
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'));
+            assert!((3..=256).contains(&command.description.chars().count()));
+        }
+    }
+
+    #[test]
+    fn should_translate_every_command() {
+        let names: HashSet<_> = COMMANDS.iter().map(|command| command.name).collect();
+
+        for (_, translations) in TRANSLATED_DESCRIPTIONS {
+            let translated_names: HashSet<_> = translations.iter().map(|(name, _)| *name).collect();
+            assert_eq!(translated_names, names);
+        }
+    }
+
+    #[test]
+    fn should_fall_back_to_english_description() {
+        let think = COMMANDS
+            .iter()
+            .find(|command| command.name == "think")
+            .unwrap();
+
+        assert_eq!(think.description_in(Some("pt")), "Diz algo do que aprendeu");
+        assert_eq!(think.description_in(Some("eo")), think.description);
+        assert_eq!(think.description_in(None), think.description);
+    }
+}
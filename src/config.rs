@@ -0,0 +1,863 @@
+use crate::error::{self, Error, ResultExt};
+use crate::learn_filter::PhraseFilter;
+use crate::memory::MemoryScope;
+use crate::writer::{QueueOverflowPolicy, WriteQueueConfig};
+use feroldinhobot::output::ReplyStyler;
+use feroldinhobot::phrase_indexing::{self, JunctionDistribution};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+/// How phrases are kept on disk.
+#[derive(Deserialize, PartialEq, Eq, Debug, Default, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DatabaseKind {
+    /// One phrase per line in a text file.
+    #[default]
+    FlatFile,
+    Sqlite,
+}
+
+/// The chat platform the bot runs on.
+#[derive(Deserialize, PartialEq, Eq, Debug, Default, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Platform {
+    #[default]
+    Telegram,
+    /// Only learns from and replies to messages, through `DiscordAdapter`.
+    Discord,
+    /// Only learns from and replies to messages, through `MatrixAdapter`.
+    Matrix,
+}
+
+/// Words avoided as pivots when generating phrases, by primary language,
+/// replacing the built-in ones of the language.
+#[derive(Deserialize, PartialEq, Eq, Debug, Default, Clone)]
+#[serde(transparent)]
+pub(crate) struct StopWordsConfig(HashMap<String, Vec<String>>);
+
+impl StopWordsConfig {
+    /// The stop words of the language, or of every language known to have any if
+    /// there's no language.
+    pub(crate) fn of_language(&self, language_code: Option<&str>) -> Vec<String> {
+        match language_code {
+            Some(language_code) => {
+                self.stop_words_of(&phrase_indexing::primary_language_subtag(language_code))
+            }
+            None => {
+                let built_in_languages = phrase_indexing::stop_word_languages()
+                    .filter(|&language| !self.0.contains_key(language));
+
+                self.0
+                    .keys()
+                    .map(String::as_str)
+                    .flat_map(|language| self.stop_words_of(language))
+                    .chain(built_in_languages.flat_map(|language| self.stop_words_of(language)))
+                    .collect()
+            }
+        }
+    }
+
+    fn stop_words_of(&self, language: &str) -> Vec<String> {
+        match self.0.get(language) {
+            Some(stop_words) => stop_words.clone(),
+            None => phrase_indexing::default_stop_words(language)
+                .iter()
+                .map(|&stop_word| stop_word.into())
+                .collect(),
+        }
+    }
+}
+
+/// Where Telegram delivers updates to, instead of them being polled for.
+#[derive(Deserialize, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WebhookConfig {
+    /// Public HTTPS url that Telegram sends updates to, which is either served
+    /// by the bot itself, or by a reverse proxy in front of it.
+    pub(crate) url: String,
+    pub(crate) port: u16,
+    #[serde(default = "default_bind_address")]
+    pub(crate) bind_address: IpAddr,
+    /// Path the updates are accepted on, which must start with `/`.
+    #[serde(default = "default_webhook_path")]
+    pub(crate) path: String,
+    /// Serves the webhook over HTTPS, rather than leaving TLS to a reverse
+    /// proxy.
+    pub(crate) tls: Option<WebhookTlsConfig>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct WebhookTlsConfig {
+    /// PEM certificate chain.
+    pub(crate) certificate_path: PathBuf,
+    /// PEM private key, in PKCS #8.
+    pub(crate) key_path: PathBuf,
+    /// Whether the certificate is self-signed, in which case it's uploaded to
+    /// Telegram so that it can be trusted.
+    #[serde(default)]
+    pub(crate) self_signed: bool,
+}
+
+/// Where the operator dashboard is served, over plain HTTP.
+#[derive(Deserialize, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DashboardConfig {
+    pub(crate) port: u16,
+    #[serde(default = "default_bind_address")]
+    pub(crate) bind_address: IpAddr,
+    /// Tokens that the dashboard API may be called with.
+    #[serde(default = "default_dashboard_tokens")]
+    pub(crate) tokens: Vec<ApiTokenConfig>,
+}
+
+/// Where phrases are generated for anyone who asks, e.g. a website embedding
+/// the voice of the bot, over plain HTTP.
+#[derive(Deserialize, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PublicApiConfig {
+    pub(crate) port: u16,
+    #[serde(default = "default_bind_address")]
+    pub(crate) bind_address: IpAddr,
+    /// Chat whose memory phrases are generated from, or the shared memory if
+    /// unset. The memory of a chat is only used in per-chat scope.
+    pub(crate) brain_chat_id: Option<i64>,
+    #[serde(default = "default_max_public_requests_per_minute")]
+    pub(crate) max_requests_per_minute: usize,
+    /// Rate limits by the client address that a reverse proxy in front of the
+    /// endpoint puts last in `X-Forwarded-For`, rather than by the address of
+    /// the proxy itself.
+    #[serde(default)]
+    pub(crate) trust_forwarded_for: bool,
+    /// How many candidate phrases `GET /generate/stream` may be asked for.
+    #[serde(default = "default_max_streamed_candidates")]
+    pub(crate) max_streamed_candidates: usize,
+}
+
+fn default_max_public_requests_per_minute() -> usize {
+    10
+}
+
+fn default_max_streamed_candidates() -> usize {
+    50
+}
+
+/// Where the databases are backed up to, in a bucket of an object storage
+/// with an S3-compatible API, which is reached path-style, e.g.
+/// `{endpoint}/{bucket}/{key}`.
+#[derive(Deserialize, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BackupConfig {
+    pub(crate) endpoint: String,
+    #[serde(default = "default_backup_region")]
+    pub(crate) region: String,
+    pub(crate) bucket: String,
+    /// Put before the key of every object backed up, e.g. `feroldinhobot/`.
+    #[serde(default)]
+    pub(crate) prefix: String,
+    #[serde(default = "default_backup_interval_secs")]
+    pub(crate) interval_secs: u64,
+    /// How many of the latest backups are kept, past which older ones are
+    /// deleted.
+    #[serde(default = "default_backup_retention")]
+    pub(crate) retention: usize,
+    #[serde(default = "default_backup_access_key_env_var")]
+    pub(crate) access_key_env_var: String,
+    #[serde(default = "default_backup_secret_key_env_var")]
+    pub(crate) secret_key_env_var: String,
+}
+
+fn default_backup_region() -> String {
+    "us-east-1".into()
+}
+
+fn default_backup_interval_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_backup_retention() -> usize {
+    7
+}
+
+fn default_backup_access_key_env_var() -> String {
+    "BACKUP_ACCESS_KEY_ID".into()
+}
+
+fn default_backup_secret_key_env_var() -> String {
+    "BACKUP_SECRET_ACCESS_KEY".into()
+}
+
+/// Reply probabilities by weekday and hour, which take the place of
+/// `reply_probability` whenever they apply.
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ReplyScheduleConfig {
+    /// How many hours ahead of UTC the hours of the rules are, e.g. `-3`.
+    #[serde(default)]
+    pub(crate) utc_offset_hours: i32,
+    #[serde(default)]
+    pub(crate) rules: Vec<ScheduleRuleConfig>,
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ScheduleRuleConfig {
+    /// The chat the rule is for, or every chat if unset.
+    pub(crate) chat_id: Option<i64>,
+    /// E.g. `mon-fri`, `sat,sun`, or `*` for every day.
+    #[serde(default = "default_schedule_days")]
+    pub(crate) days: String,
+    /// E.g. `9-18`, up to but not including 18h, or `22-6` across midnight.
+    #[serde(default = "default_schedule_hours")]
+    pub(crate) hours: String,
+    pub(crate) probability: f32,
+}
+
+fn default_schedule_days() -> String {
+    "*".into()
+}
+
+fn default_schedule_hours() -> String {
+    "0-24".into()
+}
+
+/// What a token of an HTTP API may do.
+#[derive(Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum TokenScope {
+    /// Only looks at the state of the bot.
+    ReadOnly,
+    /// Also changes it.
+    Admin,
+}
+
+/// A bearer token of an HTTP API, which is read from the environment so that
+/// it's never written down in the config.
+#[derive(Deserialize, PartialEq, Eq, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ApiTokenConfig {
+    pub(crate) env_var: String,
+    pub(crate) scope: TokenScope,
+}
+
+fn default_bind_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+fn default_webhook_path() -> String {
+    "/".into()
+}
+
+fn default_dashboard_tokens() -> Vec<ApiTokenConfig> {
+    vec![ApiTokenConfig {
+        env_var: "DASHBOARD_TOKEN".into(),
+        scope: TokenScope::Admin,
+    }]
+}
+
+/// What the bot is set up with at startup. Every field is optional in
+/// `config.toml`, and some of them can be overridden by environment variables.
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) platform: Platform,
+    /// Name of the environment variable holding the bot token of the platform,
+    /// so that the token itself is never written down in the config.
+    pub(crate) token_env_var: String,
+    /// HTTP proxy through which the Bot API is reached, if any, e.g. to talk
+    /// to a fake Bot API in tests.
+    pub(crate) api_proxy: Option<String>,
+    /// Base url of the homeserver the bot logs in to on Matrix, e.g.
+    /// `https://matrix.org`. Must be set on Matrix.
+    pub(crate) matrix_homeserver_url: Option<String>,
+    pub(crate) database_path: PathBuf,
+    pub(crate) database_kind: DatabaseKind,
+    /// How many learned lines may wait to be written to the database before
+    /// `queue_overflow_policy` kicks in.
+    pub(crate) max_queued_lines: usize,
+    pub(crate) queue_overflow_policy: QueueOverflowPolicy,
+    pub(crate) memory_scope: MemoryScope,
+    pub(crate) reply_probability: f32,
+    pub(crate) emoji_reply_probability: f32,
+    /// Added to the reply probability of a chat that answers a reply of the
+    /// bot within `engagement_window_secs`, decaying back to the baseline over
+    /// `engagement_decay_secs`.
+    pub(crate) engagement_boost: f32,
+    pub(crate) engagement_window_secs: u64,
+    pub(crate) engagement_decay_secs: u64,
+    /// How many milliseconds the bot is shown typing before sending a message,
+    /// for each of its characters, up to `max_typing_delay_secs`.
+    pub(crate) typing_delay_ms_per_char: u64,
+    pub(crate) max_typing_delay_secs: u64,
+    /// Every this many seconds, each chat active in the last day is sent a
+    /// message unprompted with `unprompted_message_probability`.
+    pub(crate) unprompted_message_interval_secs: u64,
+    pub(crate) unprompted_message_probability: f32,
+    /// Replies only when mentioned, or replied to, instead of at random.
+    pub(crate) reply_to_mentions_only: bool,
+    /// Handling a message for longer than this many milliseconds is logged as
+    /// a warning, with how long each stage of it took.
+    pub(crate) slow_message_threshold_ms: u64,
+    pub(crate) settings_path: PathBuf,
+    pub(crate) favorites_path: PathBuf,
+    pub(crate) pending_changes_path: PathBuf,
+    /// Where the phrases learned in the last day are kept track of.
+    pub(crate) short_term_memory_path: PathBuf,
+    /// How many times as likely phrases learned in the last day are to be
+    /// picked, which makes replies lean towards what's being talked about.
+    pub(crate) recency_bonus: f32,
+    /// Where phrases tend to be spliced at, relative to their length.
+    pub(crate) junction_distribution: JunctionDistribution,
+    /// How likely replies are to be turned into questions or exclamations, and
+    /// to start with one of `interjections`.
+    pub(crate) question_probability: f32,
+    pub(crate) exclamation_probability: f32,
+    pub(crate) interjection_probability: f32,
+    pub(crate) interjections: Vec<String>,
+    /// How likely replies are to be garnished with a template of
+    /// `garnish_rules`, or of the built-in ones if there are none.
+    pub(crate) garnish_probability: f32,
+    pub(crate) garnish_rules: Option<HashMap<String, Vec<String>>>,
+    /// Where how often words are used lately is kept, for `/trending`.
+    pub(crate) word_trends_path: PathBuf,
+    /// Where who said each learned phrase is kept, for `/deletemydata`.
+    pub(crate) contributions_path: PathBuf,
+    /// Caps on the phrases and words of each memory, past which the least
+    /// recently learned phrases are forgotten.
+    pub(crate) max_phrase_count: Option<usize>,
+    pub(crate) max_word_count: Option<usize>,
+    pub(crate) stop_words: StopWordsConfig,
+    /// Words whose messages aren't learned, and which replies never have.
+    pub(crate) blocked_words: Vec<String>,
+    /// Phrases the quality scorer rates below this aren't learned.
+    pub(crate) min_phrase_quality: f32,
+    /// Once a memory has `vocabulary_warm_up_phrase_count` phrases, phrases
+    /// with fewer known words than `min_known_word_ratio` aren't learned when
+    /// their unknown words mostly don't look like words.
+    pub(crate) min_known_word_ratio: f32,
+    pub(crate) vocabulary_warm_up_phrase_count: usize,
+    /// Languages the command menu is registered in, besides English.
+    pub(crate) command_languages: Vec<String>,
+    /// Receives updates through a webhook if set, and polls for them otherwise.
+    pub(crate) webhook: Option<WebhookConfig>,
+    /// Serves the operator dashboard if set.
+    pub(crate) dashboard: Option<DashboardConfig>,
+    /// Serves the public endpoint generating phrases if set.
+    pub(crate) public_api: Option<PublicApiConfig>,
+    /// Backs up the databases regularly if set.
+    pub(crate) backup: Option<BackupConfig>,
+    pub(crate) reply_schedule: Option<ReplyScheduleConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            platform: Platform::default(),
+            token_env_var: "BOT_TOKEN".into(),
+            api_proxy: None,
+            matrix_homeserver_url: None,
+            database_path: "bot_memory.txt".into(),
+            database_kind: DatabaseKind::default(),
+            max_queued_lines: 10_000,
+            queue_overflow_policy: QueueOverflowPolicy::default(),
+            memory_scope: MemoryScope::default(),
+            reply_probability: 0.0,
+            emoji_reply_probability: 0.05,
+            engagement_boost: 0.0,
+            engagement_window_secs: 5 * 60,
+            engagement_decay_secs: 15 * 60,
+            typing_delay_ms_per_char: 0,
+            max_typing_delay_secs: 5,
+            unprompted_message_interval_secs: 60 * 60,
+            unprompted_message_probability: 0.0,
+            reply_to_mentions_only: false,
+            slow_message_threshold_ms: 1000,
+            settings_path: "settings.json".into(),
+            favorites_path: "favorite_replies.json".into(),
+            pending_changes_path: "pending_changes.json".into(),
+            short_term_memory_path: "short_term_memory.json".into(),
+            recency_bonus: 2.0,
+            junction_distribution: JunctionDistribution::default(),
+            question_probability: 0.15,
+            exclamation_probability: 0.15,
+            interjection_probability: 0.1,
+            interjections: ["well", "oh", "hmm"].map(String::from).to_vec(),
+            garnish_probability: 0.0,
+            garnish_rules: None,
+            word_trends_path: "word_trends.json".into(),
+            contributions_path: "contributions.json".into(),
+            max_phrase_count: None,
+            max_word_count: None,
+            stop_words: StopWordsConfig::default(),
+            blocked_words: Vec::new(),
+            min_phrase_quality: 0.4,
+            min_known_word_ratio: 0.3,
+            vocabulary_warm_up_phrase_count: 1000,
+            command_languages: Vec::new(),
+            webhook: None,
+            dashboard: None,
+            public_api: None,
+            backup: None,
+            reply_schedule: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config at `CONFIG_PATH`, or `config.toml` if it isn't set, and
+    /// then applies the overrides from the environment.
+    pub(crate) fn from_env() -> error::Result<Config> {
+        let path = std::env::var("CONFIG_PATH").unwrap_or_else(|_| "config.toml".into());
+
+        let mut config = Config::load(path)?;
+        config.apply_overrides(|name| std::env::var(name).ok())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub(crate) fn phrase_filter(&self) -> PhraseFilter {
+        PhraseFilter {
+            min_quality: self.min_phrase_quality,
+            min_known_word_ratio: self.min_known_word_ratio,
+            warm_up_phrase_count: self.vocabulary_warm_up_phrase_count,
+        }
+    }
+
+    pub(crate) fn reply_styler(&self) -> ReplyStyler {
+        ReplyStyler {
+            question_prob: self.question_probability,
+            exclamation_prob: self.exclamation_probability,
+            interjection_prob: self.interjection_probability,
+            interjections: self.interjections.clone(),
+        }
+    }
+
+    pub(crate) fn write_queue_config(&self) -> WriteQueueConfig {
+        WriteQueueConfig {
+            max_queued_lines: self.max_queued_lines,
+            overflow_policy: self.queue_overflow_policy,
+        }
+    }
+
+    /// Loads the config at `path`, or the default one if there's no such file.
+    pub(crate) fn load(path: impl AsRef<Path>) -> error::Result<Config> {
+        let path = path.as_ref();
+
+        match std::fs::read_to_string(path) {
+            Ok(toml) => toml::from_str(&toml).map_err(|err| {
+                Error::parse(format!("config ({})", err), path.display().to_string())
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                tracing::info!("no config at `{}`, using the default one", path.display());
+                Ok(Config::default())
+            }
+            Err(err) => Err(err).context(|| format!("reading config `{}`", path.display())),
+        }
+    }
+
+    /// Overrides fields with the environment variables `BOT_API_PROXY`,
+    /// `DATABASE_PATH`,
+    /// `SQLITE_DATABASE_PATH` (which also switches to SQLite), `MEMORY_SCOPE`,
+    /// `REPLY_PROBABILITY` and `EMOJI_REPLY_PROBABILITY`, as read by `var`.
+    fn apply_overrides(&mut self, var: impl Fn(&str) -> Option<String>) -> error::Result<()> {
+        if let Some(api_proxy) = var("BOT_API_PROXY") {
+            self.api_proxy = Some(api_proxy);
+        }
+
+        if let Some(database_path) = var("DATABASE_PATH") {
+            self.database_path = database_path.into();
+        }
+
+        if let Some(sqlite_database_path) = var("SQLITE_DATABASE_PATH") {
+            self.database_path = sqlite_database_path.into();
+            self.database_kind = DatabaseKind::Sqlite;
+        }
+
+        if let Some(memory_scope) = var("MEMORY_SCOPE") {
+            self.memory_scope = memory_scope
+                .parse()
+                .map_err(|_| Error::parse("memory scope", memory_scope))?;
+        }
+
+        if let Some(reply_probability) = var("REPLY_PROBABILITY") {
+            self.reply_probability = reply_probability
+                .parse()
+                .map_err(|_| Error::parse("reply probability", reply_probability))?;
+        }
+
+        if let Some(emoji_reply_probability) = var("EMOJI_REPLY_PROBABILITY") {
+            self.emoji_reply_probability = emoji_reply_probability
+                .parse()
+                .map_err(|_| Error::parse("emoji reply probability", emoji_reply_probability))?;
+        }
+
+        Ok(())
+    }
+
+    /// The url of the homeserver to log in to on Matrix.
+    pub(crate) fn matrix_homeserver_url(&self) -> error::Result<&str> {
+        self.matrix_homeserver_url
+            .as_deref()
+            .ok_or_else(|| Error::parse("matrix homeserver url", "matrix_homeserver_url"))
+    }
+
+    /// Rejects fields which parse, but which the bot can't run with.
+    fn validate(&self) -> error::Result<()> {
+        if self.platform == Platform::Matrix {
+            self.matrix_homeserver_url()?;
+        }
+
+        // Keeping no backups would delete the one just taken.
+        if let Some(backup_config) = self.backup.as_ref().filter(|config| config.retention < 1) {
+            return Err(Error::parse(
+                "backup retention (at least 1)",
+                backup_config.retention.to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::{
+        ApiTokenConfig, BackupConfig, Config, DashboardConfig, DatabaseKind, PublicApiConfig,
+        ReplyScheduleConfig, ScheduleRuleConfig, TokenScope, WebhookConfig, WebhookTlsConfig,
+    };
+    use crate::memory::MemoryScope;
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    #[test]
+    fn should_fill_missing_fields_with_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            database_path = "data/memory.db"
+            database_kind = "sqlite"
+            memory_scope = "per-chat"
+            reply_probability = 0.1
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                database_path: "data/memory.db".into(),
+                database_kind: DatabaseKind::Sqlite,
+                memory_scope: MemoryScope::PerChat,
+                reply_probability: 0.1,
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn should_style_replies_as_configured() {
+        let config: Config = toml::from_str(
+            r#"
+            question_probability = 0.5
+            interjections = ["eita", "né"]
+            "#,
+        )
+        .unwrap();
+
+        let reply_styler = config.reply_styler();
+        assert_eq!(reply_styler.question_prob, 0.5);
+        assert_eq!(reply_styler.exclamation_prob, 0.15);
+        assert_eq!(reply_styler.interjections, ["eita", "né"]);
+    }
+
+    #[test]
+    fn should_parse_webhook_section() {
+        let config: Config = toml::from_str(
+            r#"
+            [webhook]
+            url = "https://bot.example.com/updates"
+            port = 8443
+            bind_address = "0.0.0.0"
+            path = "/updates"
+
+            [webhook.tls]
+            certificate_path = "cert.pem"
+            key_path = "key.pem"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.webhook,
+            Some(WebhookConfig {
+                url: "https://bot.example.com/updates".into(),
+                port: 8443,
+                bind_address: "0.0.0.0".parse().unwrap(),
+                path: "/updates".into(),
+                tls: Some(WebhookTlsConfig {
+                    certificate_path: "cert.pem".into(),
+                    key_path: "key.pem".into(),
+                    self_signed: false,
+                }),
+            })
+        );
+    }
+
+    #[test]
+    fn should_fill_missing_webhook_fields_with_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            [webhook]
+            url = "https://bot.example.com"
+            port = 8080
+            "#,
+        )
+        .unwrap();
+
+        let webhook = config.webhook.unwrap();
+        assert_eq!(webhook.bind_address.to_string(), "127.0.0.1");
+        assert_eq!(webhook.path, "/");
+        assert_eq!(webhook.tls, None);
+    }
+
+    #[test]
+    fn should_parse_dashboard_section() {
+        let config: Config = toml::from_str(
+            r#"
+            [dashboard]
+            port = 8081
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.dashboard,
+            Some(DashboardConfig {
+                port: 8081,
+                bind_address: "127.0.0.1".parse().unwrap(),
+                tokens: vec![ApiTokenConfig {
+                    env_var: "DASHBOARD_TOKEN".into(),
+                    scope: TokenScope::Admin,
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn should_parse_dashboard_tokens() {
+        let config: Config = toml::from_str(
+            r#"
+            [dashboard]
+            port = 8081
+
+            [[dashboard.tokens]]
+            env_var = "DASHBOARD_VIEWER_TOKEN"
+            scope = "read-only"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.dashboard.unwrap().tokens,
+            [ApiTokenConfig {
+                env_var: "DASHBOARD_VIEWER_TOKEN".into(),
+                scope: TokenScope::ReadOnly,
+            }]
+        );
+    }
+
+    #[test]
+    fn should_parse_public_api_section() {
+        let config: Config = toml::from_str(
+            r#"
+            [public_api]
+            port = 8082
+            brain_chat_id = -100123
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.public_api,
+            Some(PublicApiConfig {
+                port: 8082,
+                bind_address: "127.0.0.1".parse().unwrap(),
+                brain_chat_id: Some(-100123),
+                max_requests_per_minute: 10,
+                trust_forwarded_for: false,
+                max_streamed_candidates: 50,
+            })
+        );
+    }
+
+    #[test]
+    fn should_parse_backup_section() {
+        let config: Config = toml::from_str(
+            r#"
+            [backup]
+            endpoint = "https://s3.eu-west-1.amazonaws.com"
+            region = "eu-west-1"
+            bucket = "backups"
+            retention = 3
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.backup,
+            Some(BackupConfig {
+                endpoint: "https://s3.eu-west-1.amazonaws.com".into(),
+                region: "eu-west-1".into(),
+                bucket: "backups".into(),
+                prefix: String::new(),
+                interval_secs: 24 * 60 * 60,
+                retention: 3,
+                access_key_env_var: "BACKUP_ACCESS_KEY_ID".into(),
+                secret_key_env_var: "BACKUP_SECRET_ACCESS_KEY".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn should_parse_reply_schedule_section() {
+        let config: Config = toml::from_str(
+            r#"
+            [reply_schedule]
+            utc_offset_hours = -3
+
+            [[reply_schedule.rules]]
+            days = "sat,sun"
+            probability = 0.2
+
+            [[reply_schedule.rules]]
+            chat_id = -100123
+            hours = "9-18"
+            probability = 0.0
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.reply_schedule,
+            Some(ReplyScheduleConfig {
+                utc_offset_hours: -3,
+                rules: vec![
+                    ScheduleRuleConfig {
+                        chat_id: None,
+                        days: "sat,sun".into(),
+                        hours: "0-24".into(),
+                        probability: 0.2,
+                    },
+                    ScheduleRuleConfig {
+                        chat_id: Some(-100123),
+                        days: "*".into(),
+                        hours: "9-18".into(),
+                        probability: 0.0,
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn should_parse_example_config_into_defaults() {
+        let config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn should_pick_stop_words_of_language() {
+        let config: Config = toml::from_str(
+            r#"
+            [stop_words]
+            pt = ["tipo", "né"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.stop_words.of_language(Some("pt-BR")), ["tipo", "né"]);
+        assert!(config
+            .stop_words
+            .of_language(Some("en"))
+            .contains(&"the".to_string()));
+        assert!(config.stop_words.of_language(Some("ja")).is_empty());
+
+        let stop_words = config.stop_words.of_language(None);
+        assert!(stop_words.contains(&"the".to_string()));
+        assert!(stop_words.contains(&"tipo".to_string()));
+    }
+
+    #[test]
+    fn should_reject_unknown_fields() {
+        assert!(toml::from_str::<Config>("reply_prob = 0.1").is_err());
+    }
+
+    #[test]
+    fn should_use_default_config_if_file_is_missing() {
+        let config = Config::load(Path::new("missing_config.toml")).unwrap();
+
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn should_override_fields_with_environment() {
+        let vars = HashMap::from([
+            ("SQLITE_DATABASE_PATH", "memory.db"),
+            ("MEMORY_SCOPE", "per-chat"),
+            ("REPLY_PROBABILITY", "0.5"),
+        ]);
+
+        let mut config = Config::default();
+        config
+            .apply_overrides(|name| vars.get(name).map(|&value| value.into()))
+            .unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                database_path: "memory.db".into(),
+                database_kind: DatabaseKind::Sqlite,
+                memory_scope: MemoryScope::PerChat,
+                reply_probability: 0.5,
+                ..Config::default()
+            }
+        );
+    }
+
+    #[test]
+    fn should_fail_to_override_with_invalid_value() {
+        let mut config = Config::default();
+
+        assert!(config
+            .apply_overrides(|name| (name == "REPLY_PROBABILITY").then(|| "often".into()))
+            .is_err());
+    }
+
+    #[test]
+    fn should_reject_backup_retention_below_one() {
+        let config: Config = toml::from_str(
+            r#"
+            [backup]
+            endpoint = "https://s3.eu-west-1.amazonaws.com"
+            bucket = "backups"
+            retention = 0
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.validate().is_err());
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn should_require_homeserver_url_on_matrix() {
+        let mut config: Config = toml::from_str(r#"platform = "matrix""#).unwrap();
+        assert!(config.validate().is_err());
+
+        config.matrix_homeserver_url = Some("https://matrix.org".into());
+        assert!(config.validate().is_ok());
+    }
+}
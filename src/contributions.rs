@@ -0,0 +1,134 @@
+use crate::error::{self, Error, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use tbot::types::{chat, user};
+
+/// The phrases each user contributed, by the chat they were said in.
+type PhrasesByChat = HashMap<i64, HashSet<String>>;
+
+/// Keeps track of who said each learned phrase, so that everything a user said
+/// can be deleted at their request. Saved to a JSON file with `save`, unless
+/// there's no file to save to.
+#[derive(Default)]
+pub(crate) struct Contributions {
+    path: Option<PathBuf>,
+    phrases_by_user: HashMap<i64, PhrasesByChat>,
+}
+
+impl Contributions {
+    /// Loads the contributions saved at `path`, or starts without any if
+    /// there's no such file yet.
+    pub(crate) fn load(path: impl Into<PathBuf>) -> error::Result<Contributions> {
+        let path = path.into();
+
+        let phrases_by_user = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|_| Error::parse("contributions", path.display().to_string()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).context(|| format!("reading contributions `{}`", path.display()))
+            }
+        };
+
+        Ok(Contributions {
+            path: Some(path),
+            phrases_by_user,
+        })
+    }
+
+    /// Counts the phrases as said by the user in the chat.
+    pub(crate) fn record<'p>(
+        &mut self,
+        user_id: user::Id,
+        chat_id: chat::Id,
+        phrases: impl IntoIterator<Item = &'p str>,
+    ) {
+        self.phrases_by_user
+            .entry(user_id.0)
+            .or_default()
+            .entry(chat_id.0)
+            .or_default()
+            .extend(phrases.into_iter().map(String::from));
+    }
+
+    /// Forgets the contributions of the user, returning the phrases only they
+    /// said, by chat. Phrases someone else said in the same chat too are left
+    /// out, as they're theirs just as much.
+    pub(crate) fn take(&mut self, user_id: user::Id) -> Vec<(chat::Id, HashSet<String>)> {
+        let phrases_by_chat = match self.phrases_by_user.remove(&user_id.0) {
+            Some(phrases_by_chat) => phrases_by_chat,
+            None => return Vec::new(),
+        };
+
+        phrases_by_chat
+            .into_iter()
+            .map(|(chat_id, mut phrases)| {
+                for other_phrases_by_chat in self.phrases_by_user.values() {
+                    if let Some(other_phrases) = other_phrases_by_chat.get(&chat_id) {
+                        phrases.retain(|phrase| !other_phrases.contains(phrase));
+                    }
+                }
+
+                (chat::Id(chat_id), phrases)
+            })
+            .collect()
+    }
+
+    pub(crate) fn save(&self) -> error::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let json =
+            serde_json::to_vec(&self.phrases_by_user).expect("contributions are serializable");
+
+        std::fs::write(path, json).context(|| format!("writing contributions `{}`", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod contributions_tests {
+    use super::Contributions;
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use tbot::types::{chat, user};
+
+    fn phrases(phrases: &[&str]) -> HashSet<String> {
+        phrases.iter().map(|&phrase| phrase.into()).collect()
+    }
+
+    #[test]
+    fn should_take_phrases_only_the_user_said() {
+        let mut contributions = Contributions::default();
+
+        contributions.record(user::Id(1), chat::Id(10), ["hello there", "my cat"]);
+        contributions.record(user::Id(2), chat::Id(10), ["hello there"]);
+        contributions.record(user::Id(2), chat::Id(20), ["my cat"]);
+
+        assert_eq!(
+            contributions.take(user::Id(1)),
+            &[(chat::Id(10), phrases(&["my cat"]))]
+        );
+        assert!(contributions.take(user::Id(1)).is_empty());
+    }
+
+    #[test]
+    fn should_keep_contributions_across_restarts() {
+        let path: PathBuf =
+            std::env::temp_dir().join(format!("contributions_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut contributions = Contributions::load(&path).unwrap();
+        contributions.record(user::Id(1), chat::Id(10), ["hello there"]);
+        contributions.save().unwrap();
+
+        let mut contributions = Contributions::load(&path).unwrap();
+        assert_eq!(
+            contributions.take(user::Id(1)),
+            &[(chat::Id(10), phrases(&["hello there"]))]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
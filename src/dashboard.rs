@@ -0,0 +1,343 @@
+use crate::auth::ApiTokens;
+use crate::config::{DashboardConfig, TokenScope};
+use crate::error::{self, Error};
+use crate::writer::{self, QueueMetrics};
+use hyper::header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+use tbot::types::chat;
+
+/// Setting holding the chats that are never replied to on their own.
+pub(crate) const MUTED_CHATS_SETTING: &str = "muted_chats";
+
+const DASHBOARD_PAGE: &str = include_str!("dashboard.html");
+const MAX_RECENT_REPLIES: usize = 20;
+
+/// A reply the bot sent.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub(crate) struct SentReply {
+    pub(crate) chat_id: i64,
+    pub(crate) text: String,
+    pub(crate) sent_at: i64,
+}
+
+/// The last replies the bot sent, latest first.
+#[derive(Default)]
+pub(crate) struct RecentReplies {
+    replies: VecDeque<SentReply>,
+}
+
+impl RecentReplies {
+    pub(crate) fn record(&mut self, chat_id: chat::Id, text: &str, sent_at: i64) {
+        if self.replies.len() == MAX_RECENT_REPLIES {
+            self.replies.pop_back();
+        }
+
+        self.replies.push_front(SentReply {
+            chat_id: chat_id.0,
+            text: text.into(),
+            sent_at,
+        });
+    }
+
+    pub(crate) fn to_vec(&self) -> Vec<SentReply> {
+        self.replies.iter().cloned().collect()
+    }
+}
+
+/// How many phrases a memory holds.
+#[derive(Serialize, PartialEq, Debug)]
+pub(crate) struct MemoryStatus {
+    /// Chat the memory belongs to, or `None` for the shared memory.
+    pub(crate) chat_id: Option<i64>,
+    pub(crate) phrase_count: usize,
+}
+
+/// What the bot tells the dashboard about itself.
+#[derive(Serialize, PartialEq, Debug)]
+pub(crate) struct BotStatus {
+    pub(crate) memories: Vec<MemoryStatus>,
+    pub(crate) muted_chats: Vec<i64>,
+    pub(crate) recent_replies: Vec<SentReply>,
+    pub(crate) settings: BTreeMap<&'static str, String>,
+}
+
+#[derive(Serialize)]
+struct ErrorRate {
+    category: String,
+    count: usize,
+    per_hour: f64,
+}
+
+#[derive(Serialize)]
+struct Status {
+    uptime_secs: u64,
+    errors: Vec<ErrorRate>,
+    write_queue: QueueMetrics,
+    #[serde(flatten)]
+    bot: BotStatus,
+}
+
+/// Something the dashboard asks the bot to do.
+#[derive(PartialEq, Debug)]
+pub(crate) enum Control {
+    Mute(chat::Id),
+    Unmute(chat::Id),
+    /// Flushes and compacts every memory.
+    Compact,
+}
+
+pub(crate) type DashboardFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// The bot as seen from the dashboard.
+pub(crate) trait DashboardBackend: Send + Sync + 'static {
+    fn status(&self) -> DashboardFuture<'_, BotStatus>;
+    fn control(&self, control: Control) -> DashboardFuture<'_, error::Result<()>>;
+}
+
+#[derive(PartialEq, Debug)]
+enum Route {
+    Health,
+    Page,
+    Status,
+    Control(Control),
+}
+
+impl Route {
+    /// What a token must be allowed to do to take the route, or `None` if
+    /// anyone may. The page itself holds no data, and asks for the token to
+    /// call the API with.
+    fn required_scope(&self) -> Option<TokenScope> {
+        match self {
+            Route::Health | Route::Page => None,
+            Route::Status => Some(TokenScope::ReadOnly),
+            Route::Control(_) => Some(TokenScope::Admin),
+        }
+    }
+}
+
+fn route(method: &Method, path: &str) -> Option<Route> {
+    match (method, path) {
+        (&Method::GET, "/health") => return Some(Route::Health),
+        (&Method::GET, "/") => return Some(Route::Page),
+        (&Method::GET, "/api/status") => return Some(Route::Status),
+        (&Method::POST, "/api/compact") => return Some(Route::Control(Control::Compact)),
+        _ => {}
+    }
+
+    if method != Method::POST {
+        return None;
+    }
+
+    let (chat_id, action) = path.strip_prefix("/api/chats/")?.split_once('/')?;
+    let chat_id = chat::Id(chat_id.parse().ok()?);
+
+    match action {
+        "mute" => Some(Route::Control(Control::Mute(chat_id))),
+        "unmute" => Some(Route::Control(Control::Unmute(chat_id))),
+        _ => None,
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn error_rates(uptime_secs: u64) -> Vec<ErrorRate> {
+    let uptime_hours = (uptime_secs.max(1) as f64) / 3600.0;
+
+    error::error_counts()
+        .into_iter()
+        .map(|(category, count)| ErrorRate {
+            category: category.to_string(),
+            count,
+            per_hour: count as f64 / uptime_hours,
+        })
+        .collect()
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn content_response(content_type: &'static str, body: impl Into<Body>) -> Response<Body> {
+    let mut response = Response::new(body.into());
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, content_type.parse().unwrap());
+    response
+}
+
+async fn respond(
+    request: Request<Body>,
+    api_tokens: &ApiTokens,
+    started_at: Instant,
+    backend: &dyn DashboardBackend,
+) -> Response<Body> {
+    let route = match route(request.method(), request.uri().path()) {
+        Some(route) => route,
+        None => return empty_response(StatusCode::NOT_FOUND),
+    };
+
+    if let Some(required_scope) = route.required_scope() {
+        match bearer_token(request.headers()).and_then(|token| api_tokens.scope_of(token)) {
+            None => return empty_response(StatusCode::UNAUTHORIZED),
+            Some(scope) if scope < required_scope => return empty_response(StatusCode::FORBIDDEN),
+            Some(_) => {}
+        }
+    }
+
+    match route {
+        Route::Health => content_response("text/plain", "ok"),
+        Route::Page => content_response("text/html; charset=utf-8", DASHBOARD_PAGE),
+        Route::Status => {
+            let uptime_secs = started_at.elapsed().as_secs();
+            let status = Status {
+                uptime_secs,
+                errors: error_rates(uptime_secs),
+                write_queue: writer::queue_metrics(),
+                bot: backend.status().await,
+            };
+            let json = serde_json::to_vec(&status).expect("status is serializable");
+
+            content_response("application/json", json)
+        }
+        Route::Control(control) => {
+            tracing::info!("dashboard asked for {:?}", control);
+
+            match backend.control(control).await {
+                Ok(()) => empty_response(StatusCode::NO_CONTENT),
+                Err(err) => {
+                    error::report_error(&err);
+                    empty_response(StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            }
+        }
+    }
+}
+
+/// Serves the dashboard, whose API must be called with one of `api_tokens`,
+/// until the server fails.
+pub(crate) async fn serve(
+    config: &DashboardConfig,
+    api_tokens: ApiTokens,
+    backend: impl DashboardBackend,
+) -> error::Result<()> {
+    let started_at = Instant::now();
+    let api_tokens = Arc::new(api_tokens);
+    let backend = Arc::new(backend);
+
+    let make_service = make_service_fn(move |_| {
+        let api_tokens = Arc::clone(&api_tokens);
+        let backend = Arc::clone(&backend);
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let api_tokens = Arc::clone(&api_tokens);
+                let backend = Arc::clone(&backend);
+
+                async move {
+                    let response = respond(request, &api_tokens, started_at, &*backend).await;
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+
+    let address = SocketAddr::new(config.bind_address, config.port);
+    let network_error = |source: hyper::Error| Error::Network {
+        context: "serving dashboard".into(),
+        source: source.into(),
+    };
+
+    let server = Server::try_bind(&address).map_err(network_error)?;
+    tracing::info!("serving dashboard on {}", address);
+
+    server.serve(make_service).await.map_err(network_error)
+}
+
+#[cfg(test)]
+mod dashboard_tests {
+    use super::{bearer_token, route, Control, RecentReplies, Route, MAX_RECENT_REPLIES};
+    use crate::config::TokenScope;
+    use hyper::header::{HeaderMap, AUTHORIZATION};
+    use hyper::Method;
+    use tbot::types::chat;
+
+    #[test]
+    fn should_route_requests() {
+        assert_eq!(route(&Method::GET, "/health"), Some(Route::Health));
+        assert_eq!(route(&Method::GET, "/"), Some(Route::Page));
+        assert_eq!(route(&Method::GET, "/api/status"), Some(Route::Status));
+        assert_eq!(
+            route(&Method::POST, "/api/compact"),
+            Some(Route::Control(Control::Compact))
+        );
+        assert_eq!(
+            route(&Method::POST, "/api/chats/-100123/mute"),
+            Some(Route::Control(Control::Mute(chat::Id(-100123))))
+        );
+        assert_eq!(
+            route(&Method::POST, "/api/chats/42/unmute"),
+            Some(Route::Control(Control::Unmute(chat::Id(42))))
+        );
+    }
+
+    #[test]
+    fn should_not_route_unknown_requests() {
+        assert_eq!(route(&Method::GET, "/api/compact"), None);
+        assert_eq!(route(&Method::GET, "/api/chats/42/mute"), None);
+        assert_eq!(route(&Method::POST, "/api/chats/abc/mute"), None);
+        assert_eq!(route(&Method::POST, "/api/chats/42/delete"), None);
+    }
+
+    #[test]
+    fn should_read_bearer_token() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+
+        headers.insert(AUTHORIZATION, "secret".parse().unwrap());
+        assert_eq!(bearer_token(&headers), None);
+
+        headers.insert(AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert_eq!(bearer_token(&headers), Some("secret"));
+    }
+
+    #[test]
+    fn should_require_admin_scope_for_controls_only() {
+        assert_eq!(Route::Health.required_scope(), None);
+        assert_eq!(Route::Page.required_scope(), None);
+        assert_eq!(Route::Status.required_scope(), Some(TokenScope::ReadOnly));
+        assert_eq!(
+            Route::Control(Control::Compact).required_scope(),
+            Some(TokenScope::Admin)
+        );
+    }
+
+    #[test]
+    fn should_keep_latest_replies_first() {
+        let mut recent_replies = RecentReplies::default();
+
+        for sent_at in 0..MAX_RECENT_REPLIES as i64 + 5 {
+            recent_replies.record(chat::Id(1), "hello", sent_at);
+        }
+
+        let replies = recent_replies.to_vec();
+        assert_eq!(replies.len(), MAX_RECENT_REPLIES);
+        assert_eq!(replies[0].sent_at, MAX_RECENT_REPLIES as i64 + 4);
+        assert_eq!(replies.last().unwrap().sent_at, 5);
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use tbot::types::{chat, message};
+
+#[derive(Default)]
+struct ChatMessages {
+    ids_in_arrival_order: VecDeque<message::Id>,
+    ids: HashSet<message::Id>,
+}
+
+/// Remembers the last messages handled in each chat, so that messages that are
+/// delivered again (e.g. after polling reconnects) aren't handled twice.
+pub(crate) struct RecentMessages {
+    max_messages_per_chat: usize,
+    messages_by_chat: HashMap<chat::Id, ChatMessages>,
+}
+
+impl Default for RecentMessages {
+    fn default() -> Self {
+        RecentMessages::new(256)
+    }
+}
+
+impl RecentMessages {
+    pub(crate) fn new(max_messages_per_chat: usize) -> RecentMessages {
+        RecentMessages {
+            max_messages_per_chat,
+            messages_by_chat: HashMap::new(),
+        }
+    }
+
+    /// Records the message as handled. Returns false if it already was.
+    pub(crate) fn insert(&mut self, chat_id: chat::Id, message_id: message::Id) -> bool {
+        let chat_messages = self.messages_by_chat.entry(chat_id).or_default();
+
+        if !chat_messages.ids.insert(message_id) {
+            return false;
+        }
+
+        chat_messages.ids_in_arrival_order.push_back(message_id);
+
+        if chat_messages.ids_in_arrival_order.len() > self.max_messages_per_chat {
+            if let Some(oldest_id) = chat_messages.ids_in_arrival_order.pop_front() {
+                chat_messages.ids.remove(&oldest_id);
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod recent_messages_tests {
+    use super::RecentMessages;
+    use tbot::types::{chat, message};
+
+    #[test]
+    fn should_reject_messages_handled_before() {
+        let mut recent_messages = RecentMessages::default();
+
+        assert!(recent_messages.insert(chat::Id(1), message::Id(1)));
+        assert!(recent_messages.insert(chat::Id(1), message::Id(2)));
+        assert!(!recent_messages.insert(chat::Id(1), message::Id(1)));
+    }
+
+    #[test]
+    fn should_tell_messages_apart_by_chat() {
+        let mut recent_messages = RecentMessages::default();
+
+        assert!(recent_messages.insert(chat::Id(1), message::Id(1)));
+        assert!(recent_messages.insert(chat::Id(2), message::Id(1)));
+    }
+
+    #[test]
+    fn should_only_remember_the_last_messages_of_each_chat() {
+        let mut recent_messages = RecentMessages::new(2);
+
+        recent_messages.insert(chat::Id(1), message::Id(1));
+        recent_messages.insert(chat::Id(1), message::Id(2));
+        recent_messages.insert(chat::Id(1), message::Id(3));
+
+        assert!(!recent_messages.insert(chat::Id(1), message::Id(3)));
+        assert!(recent_messages.insert(chat::Id(1), message::Id(1)));
+    }
+}
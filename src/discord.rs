@@ -0,0 +1,321 @@
+use crate::adapter::{AdapterFuture, ChatAdapter, IncomingText};
+use crate::error::{self, Error};
+use crate::http::{self, HttpsClient};
+use futures_util::{SinkExt, StreamExt};
+use hyper::{Body, Request};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+use tbot::types::chat;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+
+const GATEWAY_HOST: &str = "gateway.discord.gg";
+const GATEWAY_URL: &str = "wss://gateway.discord.gg/?v=10&encoding=json";
+const API_URL: &str = "https://discord.com/api/v10";
+/// Guild messages, direct messages, and their content.
+const GATEWAY_INTENTS: u64 = (1 << 9) | (1 << 12) | (1 << 15);
+/// Discord's limit for the content of a single message, in characters, which
+/// are never more than UTF-16 code units.
+const DISCORD_MAX_MESSAGE_LEN: usize = 2000;
+/// How long to wait before connecting to the gateway again after losing the
+/// connection to it.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Messages received but not handled yet, past which the gateway is no longer
+/// read from until they are.
+const MAX_QUEUED_MESSAGES: usize = 100;
+
+mod opcodes {
+    pub(super) const DISPATCH: u64 = 0;
+    pub(super) const HEARTBEAT: u64 = 1;
+    pub(super) const IDENTIFY: u64 = 2;
+    pub(super) const RECONNECT: u64 = 7;
+    pub(super) const INVALID_SESSION: u64 = 9;
+    pub(super) const HELLO: u64 = 10;
+}
+
+/// Runs the bot on Discord, receiving messages from its gateway and sending
+/// replies through its HTTP API. Discord channels stand for chats, by their id.
+pub(crate) struct DiscordAdapter {
+    token: String,
+    client: HttpsClient,
+    incoming_texts: mpsc::Receiver<IncomingText>,
+}
+
+impl DiscordAdapter {
+    /// Keeps connected to the gateway in the background, connecting again
+    /// whenever the connection is lost.
+    pub(crate) fn connect(token: String) -> DiscordAdapter {
+        let (sender, incoming_texts) = mpsc::channel(MAX_QUEUED_MESSAGES);
+        tokio::spawn(keep_connected(token.clone(), sender));
+
+        DiscordAdapter {
+            token,
+            client: http::new_client(),
+            incoming_texts,
+        }
+    }
+}
+
+impl ChatAdapter for DiscordAdapter {
+    fn receive(&mut self) -> AdapterFuture<'_, Option<IncomingText>> {
+        Box::pin(self.incoming_texts.recv())
+    }
+
+    fn send_text<'a>(
+        &'a self,
+        chat_id: chat::Id,
+        text: &'a str,
+    ) -> AdapterFuture<'a, error::Result<()>> {
+        Box::pin(async move {
+            let url = format!("{}/channels/{}/messages", API_URL, chat_id.0 as u64);
+            let request = Request::post(&url)
+                .header("Authorization", format!("Bot {}", self.token))
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({ "content": text }).to_string()))
+                .map_err(|_| Error::parse("discord bot token", "<redacted>"))?;
+
+            http::fetch(&self.client, request, || {
+                format!("sending message to discord channel {}", chat_id.0 as u64)
+            })
+            .await
+            .map(|_| ())
+        })
+    }
+
+    fn max_message_len(&self) -> usize {
+        DISCORD_MAX_MESSAGE_LEN
+    }
+}
+
+async fn keep_connected(token: String, mut sender: mpsc::Sender<IncomingText>) {
+    loop {
+        match run_gateway_session(&token, &mut sender).await {
+            Ok(()) => return,
+            Err(err) => error::report_error(&err),
+        }
+
+        tokio::time::delay_for(RECONNECT_DELAY).await;
+    }
+}
+
+/// A message of the gateway.
+#[derive(Deserialize)]
+struct GatewayPayload {
+    op: u64,
+    #[serde(default)]
+    d: serde_json::Value,
+    s: Option<u64>,
+    t: Option<String>,
+}
+
+fn gateway_error(
+    context: &str,
+    source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> Error {
+    Error::Network {
+        context: format!("{} the discord gateway", context),
+        source: source.into(),
+    }
+}
+
+/// Connects to the gateway, identifies, and forwards the messages sent to
+/// channels the bot is in until the connection is lost, which is an error, or
+/// until no more messages are wanted.
+async fn run_gateway_session(
+    token: &str,
+    sender: &mut mpsc::Sender<IncomingText>,
+) -> error::Result<()> {
+    let tcp_stream = TcpStream::connect((GATEWAY_HOST, 443))
+        .await
+        .map_err(|err| gateway_error("connecting to", err))?;
+    let tls_connector =
+        native_tls::TlsConnector::new().map_err(|err| gateway_error("connecting to", err))?;
+    let tls_stream = tokio_tls::TlsConnector::from(tls_connector)
+        .connect(GATEWAY_HOST, tcp_stream)
+        .await
+        .map_err(|err| gateway_error("connecting to", err))?;
+    let (mut socket, _) = tokio_tungstenite::client_async(GATEWAY_URL, tls_stream)
+        .await
+        .map_err(|err| gateway_error("connecting to", err))?;
+
+    let heartbeat_interval = loop {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => {
+                let payload: GatewayPayload = serde_json::from_str(&text)
+                    .map_err(|_| Error::parse("discord gateway payload", text.as_str()))?;
+
+                if payload.op == opcodes::HELLO {
+                    break payload.d["heartbeat_interval"].as_u64().unwrap_or(45_000);
+                }
+            }
+            Some(Ok(_)) => {}
+            Some(Err(err)) => return Err(gateway_error("reading from", err)),
+            None => return Err(gateway_error("reading from", "connection closed")),
+        }
+    };
+
+    let identify = json!({
+        "op": opcodes::IDENTIFY,
+        "d": {
+            "token": token,
+            "intents": GATEWAY_INTENTS,
+            "properties": {
+                "os": std::env::consts::OS,
+                "browser": "feroldinhobot",
+                "device": "feroldinhobot",
+            },
+        },
+    });
+    socket
+        .send(Message::Text(identify.to_string()))
+        .await
+        .map_err(|err| gateway_error("identifying to", err))?;
+
+    let mut heartbeat = tokio::time::interval(Duration::from_millis(heartbeat_interval));
+    let mut sequence = None;
+    let mut bot_user_id = None;
+
+    loop {
+        let payload = tokio::select! {
+            _ = heartbeat.tick() => {
+                let heartbeat = json!({ "op": opcodes::HEARTBEAT, "d": sequence });
+                socket
+                    .send(Message::Text(heartbeat.to_string()))
+                    .await
+                    .map_err(|err| gateway_error("sending a heartbeat to", err))?;
+                continue;
+            }
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(text))) => serde_json::from_str::<GatewayPayload>(&text)
+                    .map_err(|_| Error::parse("discord gateway payload", text.as_str()))?,
+                Some(Ok(Message::Close(frame))) => {
+                    let reason = format!("connection closed ({:?})", frame);
+                    return Err(gateway_error("reading from", reason));
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Err(gateway_error("reading from", err)),
+                None => return Err(gateway_error("reading from", "connection closed")),
+            },
+        };
+
+        if payload.s.is_some() {
+            sequence = payload.s;
+        }
+
+        match (payload.op, payload.t.as_deref()) {
+            (opcodes::DISPATCH, Some("READY")) => {
+                tracing::info!("connected to the discord gateway");
+                bot_user_id = payload.d["user"]["id"].as_str().map(String::from);
+            }
+            (opcodes::DISPATCH, Some("MESSAGE_CREATE")) => {
+                let incoming_text = match parse_message(payload.d, bot_user_id.as_deref()) {
+                    Some(incoming_text) => incoming_text,
+                    None => continue,
+                };
+
+                if sender.send(incoming_text).await.is_err() {
+                    return Ok(());
+                }
+            }
+            (opcodes::HEARTBEAT, _) => {
+                let heartbeat = json!({ "op": opcodes::HEARTBEAT, "d": sequence });
+                socket
+                    .send(Message::Text(heartbeat.to_string()))
+                    .await
+                    .map_err(|err| gateway_error("sending a heartbeat to", err))?;
+            }
+            (opcodes::RECONNECT, _) | (opcodes::INVALID_SESSION, _) => {
+                return Err(gateway_error("reading from", "asked to connect again"));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DiscordMessage {
+    channel_id: String,
+    content: String,
+    author: DiscordUser,
+    #[serde(default)]
+    mentions: Vec<DiscordUser>,
+    referenced_message: Option<Box<DiscordMessage>>,
+}
+
+#[derive(Deserialize)]
+struct DiscordUser {
+    id: String,
+    #[serde(default)]
+    bot: bool,
+}
+
+/// The text of a message sent to a channel, without the mentions in it, unless
+/// a bot sent it, the bot itself included.
+fn parse_message(message: serde_json::Value, bot_user_id: Option<&str>) -> Option<IncomingText> {
+    lazy_static! {
+        static ref MENTION_PATTERN: Regex = Regex::new(r"<(?:@[!&]?|#)\d+>").unwrap();
+    }
+
+    let message: DiscordMessage = serde_json::from_value(message).ok()?;
+
+    if message.author.bot {
+        return None;
+    }
+
+    let is_bot = |user: &DiscordUser| Some(user.id.as_str()) == bot_user_id;
+    let mentions_bot = message.mentions.iter().any(is_bot)
+        || message
+            .referenced_message
+            .as_ref()
+            .is_some_and(|replied_message| is_bot(&replied_message.author));
+
+    Some(IncomingText {
+        chat_id: chat::Id(message.channel_id.parse::<u64>().ok()? as i64),
+        text: MENTION_PATTERN
+            .replace_all(&message.content, "")
+            .into_owned(),
+        mentions_bot,
+    })
+}
+
+#[cfg(test)]
+mod discord_tests {
+    use super::parse_message;
+    use crate::adapter::IncomingText;
+    use serde_json::json;
+    use tbot::types::chat;
+
+    #[test]
+    fn should_parse_messages_mentioning_the_bot() {
+        let message = json!({
+            "channel_id": "1234567890123456789",
+            "content": "<@42> hello there",
+            "author": { "id": "7" },
+            "mentions": [{ "id": "42", "bot": true }],
+        });
+
+        assert_eq!(
+            parse_message(message, Some("42")),
+            Some(IncomingText {
+                chat_id: chat::Id(1234567890123456789),
+                text: " hello there".into(),
+                mentions_bot: true,
+            })
+        );
+    }
+
+    #[test]
+    fn should_ignore_messages_of_bots() {
+        let message = json!({
+            "channel_id": "1",
+            "content": "hello there",
+            "author": { "id": "42", "bot": true },
+        });
+
+        assert_eq!(parse_message(message, Some("42")), None);
+    }
+}
@@ -0,0 +1,275 @@
+use feroldinhobot::phrase_indexing::{IndexedPhrases, WordId};
+use lazy_static::lazy_static;
+use rand::{seq::SliceRandom, Rng};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use tbot::types::chat;
+
+/// How many of the words that appear most along with each pivot word are
+/// looked at for emojis when none was used along with the pivot word itself.
+const RELATED_WORDS_PER_PIVOT: usize = 3;
+
+/// Extracts the emojis of a text, keeping modifiers, variation selectors and
+/// joined sequences (e.g. families and flags) together as a single emoji.
+pub(crate) fn extract_emojis(text: &str) -> Vec<String> {
+    lazy_static! {
+        static ref EMOJI_PATTERN: Regex = Regex::new(
+            r"(?x)
+            \p{Regional_Indicator}{2}
+            | \p{Extended_Pictographic} [\x{FE0F}\p{Emoji_Modifier}]*
+              (?: \x{200D} \p{Extended_Pictographic} [\x{FE0F}\p{Emoji_Modifier}]* )*
+            "
+        )
+        .unwrap();
+    }
+
+    EMOJI_PATTERN
+        .find_iter(text)
+        .map(|emoji| emoji.as_str().into())
+        .collect()
+}
+
+#[derive(Default)]
+struct ChatEmojis {
+    emoji_counts: HashMap<String, usize>,
+    /// Kept by the text of the words, since the ids of words evicted from
+    /// memory are reused by words learned later.
+    cooccurrences_by_word: HashMap<String, HashMap<String, usize>>,
+}
+
+impl ChatEmojis {
+    /// Sums how often each emoji was used along with the words.
+    fn weigh_emojis(
+        &self,
+        indexed_phrases: &IndexedPhrases,
+        word_ids: impl Iterator<Item = WordId>,
+    ) -> HashMap<&str, usize> {
+        let mut emoji_weights = HashMap::new();
+
+        for word_id in word_ids {
+            let cooccurrences = indexed_phrases
+                .get_word(word_id)
+                .ok()
+                .and_then(|word| self.cooccurrences_by_word.get(&*word));
+            for (emoji, &count) in cooccurrences.into_iter().flatten() {
+                *emoji_weights.entry(emoji.as_str()).or_insert(0) += count;
+            }
+        }
+
+        emoji_weights
+    }
+}
+
+/// Keeps track of the emojis used in each chat, and of the words they were
+/// used along with.
+#[derive(Default)]
+pub(crate) struct EmojiTracker {
+    emojis_by_chat: HashMap<chat::Id, ChatEmojis>,
+}
+
+impl EmojiTracker {
+    pub(crate) fn record(
+        &mut self,
+        chat_id: chat::Id,
+        emojis: &[String],
+        indexed_phrases: &IndexedPhrases,
+        word_ids: &HashSet<WordId>,
+    ) {
+        if emojis.is_empty() {
+            return;
+        }
+
+        let chat_emojis = self.emojis_by_chat.entry(chat_id).or_default();
+
+        for emoji in emojis {
+            *chat_emojis.emoji_counts.entry(emoji.clone()).or_insert(0) += 1;
+
+            let words = word_ids
+                .iter()
+                .filter_map(|&word_id| indexed_phrases.get_word(word_id).ok());
+
+            for word in words {
+                *chat_emojis
+                    .cooccurrences_by_word
+                    .entry(word.to_string())
+                    .or_default()
+                    .entry(emoji.clone())
+                    .or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Picks one to three emojis learned in the chat, weighted by how often
+    /// they were used along with the pivot words. Falls back to the words that
+    /// appear the most in the same phrases as the pivot words, and then to how
+    /// often emojis were used at all.
+    pub(crate) fn pick_emojis(
+        &self,
+        chat_id: chat::Id,
+        indexed_phrases: &IndexedPhrases,
+        pivot_word_ids: &HashSet<WordId>,
+        rng: &mut impl Rng,
+    ) -> Option<String> {
+        let chat_emojis = self.emojis_by_chat.get(&chat_id)?;
+
+        let mut emoji_weights =
+            chat_emojis.weigh_emojis(indexed_phrases, pivot_word_ids.iter().copied());
+
+        if emoji_weights.is_empty() {
+            let related_word_ids = pivot_word_ids
+                .iter()
+                .filter_map(|&word_id| {
+                    indexed_phrases
+                        .get_cooccurring_words(word_id, RELATED_WORDS_PER_PIVOT)
+                        .ok()
+                })
+                .flatten()
+                .map(|(word_id, _)| word_id);
+
+            emoji_weights = chat_emojis.weigh_emojis(indexed_phrases, related_word_ids);
+        }
+
+        if emoji_weights.is_empty() {
+            emoji_weights = chat_emojis
+                .emoji_counts
+                .iter()
+                .map(|(emoji, &count)| (emoji.as_str(), count))
+                .collect();
+        }
+
+        let emoji_weights: Vec<_> = emoji_weights.into_iter().collect();
+        let emoji_count = rng.gen_range(1..=3);
+
+        let mut emojis = String::new();
+        for _ in 0..emoji_count {
+            let &(emoji, _) = emoji_weights
+                .choose_weighted(rng, |&(_, weight)| weight)
+                .ok()?;
+            emojis.push_str(emoji);
+        }
+
+        Some(emojis)
+    }
+}
+
+#[cfg(test)]
+mod emoji_tests {
+    use super::{extract_emojis, EmojiTracker};
+    use feroldinhobot::phrase_indexing::{IndexedPhrases, Phrase};
+    use rand::SeedableRng;
+    use std::collections::HashSet;
+    use tbot::types::chat;
+
+    #[test]
+    fn should_extract_emojis_from_text() {
+        assert_eq!(extract_emojis("lol😂 that's great 👍🏽!"), &["😂", "👍🏽"]);
+    }
+
+    #[test]
+    fn should_keep_joined_emojis_together() {
+        assert_eq!(extract_emojis("family 👨‍👩‍👧 from 🇧🇷 ❤️"), &["👨‍👩‍👧", "🇧🇷", "❤️"]);
+    }
+
+    #[test]
+    fn should_not_extract_anything_from_plain_text() {
+        assert!(extract_emojis("hello there #1").is_empty());
+    }
+
+    #[test]
+    fn should_pick_emojis_used_with_pivot_words() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        let word_ids = indexed_phrases
+            .insert_phrase(Phrase::from("pizza party"))
+            .word_ids_from_phrase;
+        let (pizza, party) = (word_ids[0], word_ids[1]);
+
+        let mut tracker = EmojiTracker::default();
+        tracker.record(
+            chat::Id(1),
+            &["🍕".into()],
+            &indexed_phrases,
+            &HashSet::from([pizza]),
+        );
+        tracker.record(
+            chat::Id(1),
+            &["🎉".into()],
+            &indexed_phrases,
+            &HashSet::from([party]),
+        );
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        for _ in 0..50 {
+            let emojis = tracker
+                .pick_emojis(
+                    chat::Id(1),
+                    &indexed_phrases,
+                    &HashSet::from([pizza]),
+                    &mut rng,
+                )
+                .unwrap();
+
+            assert!(emojis.chars().all(|c| c == '🍕'));
+            assert!((1..=3).contains(&emojis.chars().count()));
+        }
+    }
+
+    #[test]
+    fn should_only_pick_emojis_learned_in_the_chat() {
+        let indexed_phrases = IndexedPhrases::new();
+        let mut tracker = EmojiTracker::default();
+        tracker.record(
+            chat::Id(1),
+            &["🍕".into()],
+            &indexed_phrases,
+            &HashSet::new(),
+        );
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        assert!(tracker
+            .pick_emojis(chat::Id(2), &indexed_phrases, &HashSet::new(), &mut rng)
+            .is_none());
+        assert!(tracker
+            .pick_emojis(chat::Id(1), &indexed_phrases, &HashSet::new(), &mut rng)
+            .is_some());
+    }
+
+    #[test]
+    fn should_pick_emojis_used_with_related_words() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        let word_ids = indexed_phrases
+            .insert_phrase(Phrase::from("pizza party"))
+            .word_ids_from_phrase;
+        let (pizza, party) = (word_ids[0], word_ids[1]);
+        let cake = indexed_phrases
+            .insert_phrase(Phrase::from("cake time"))
+            .word_ids_from_phrase[0];
+
+        let mut tracker = EmojiTracker::default();
+        tracker.record(
+            chat::Id(1),
+            &["🎉".into()],
+            &indexed_phrases,
+            &HashSet::from([party]),
+        );
+        tracker.record(
+            chat::Id(1),
+            &["🍰".into()],
+            &indexed_phrases,
+            &HashSet::from([cake]),
+        );
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        for _ in 0..50 {
+            let emojis = tracker
+                .pick_emojis(
+                    chat::Id(1),
+                    &indexed_phrases,
+                    &HashSet::from([pizza]),
+                    &mut rng,
+                )
+                .unwrap();
+
+            assert!(emojis.chars().all(|c| c == '🎉'));
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use feroldinhobot::phrase_indexing::EngineError;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error("storage failure while {context}: {source}")]
+    Storage {
+        context: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("platform failure while {context}: {source}")]
+    Platform {
+        context: String,
+        #[source]
+        source: tbot::errors::MethodCall,
+    },
+    #[error("network failure while {context}: {source}")]
+    Network {
+        context: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("couldn't parse {context} from `{input}`")]
+    Parse { context: String, input: String },
+    #[error(transparent)]
+    Engine(#[from] EngineError),
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub(crate) enum ErrorCategory {
+    Storage,
+    Platform,
+    Network,
+    Parse,
+    Engine,
+}
+
+const ALL_ERROR_CATEGORIES: [ErrorCategory; 5] = [
+    ErrorCategory::Storage,
+    ErrorCategory::Platform,
+    ErrorCategory::Network,
+    ErrorCategory::Parse,
+    ErrorCategory::Engine,
+];
+
+impl fmt::Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCategory::Storage => "storage",
+            ErrorCategory::Platform => "platform",
+            ErrorCategory::Network => "network",
+            ErrorCategory::Parse => "parse",
+            ErrorCategory::Engine => "engine",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl Error {
+    pub(crate) fn parse(context: impl Into<String>, input: impl Into<String>) -> Error {
+        Error::Parse {
+            context: context.into(),
+            input: input.into(),
+        }
+    }
+
+    pub(crate) fn category(&self) -> ErrorCategory {
+        match self {
+            Error::Storage { .. } => ErrorCategory::Storage,
+            Error::Platform { .. } => ErrorCategory::Platform,
+            Error::Network { .. } => ErrorCategory::Network,
+            Error::Parse { .. } => ErrorCategory::Parse,
+            Error::Engine(_) => ErrorCategory::Engine,
+        }
+    }
+}
+
+/// Attaches context to errors coming from the layers below us.
+pub(crate) trait ResultExt<T> {
+    fn context(self, context: impl FnOnce() -> String) -> Result<T>;
+}
+
+impl<T> ResultExt<T> for std::result::Result<T, std::io::Error> {
+    fn context(self, context: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|source| Error::Storage {
+            context: context(),
+            source: source.into(),
+        })
+    }
+}
+
+impl<T> ResultExt<T> for std::result::Result<T, rusqlite::Error> {
+    fn context(self, context: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|source| Error::Storage {
+            context: context(),
+            source: source.into(),
+        })
+    }
+}
+
+impl<T> ResultExt<T> for std::result::Result<T, tbot::errors::MethodCall> {
+    fn context(self, context: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|source| Error::Platform {
+            context: context(),
+            source,
+        })
+    }
+}
+
+static ERROR_COUNTS: [AtomicUsize; ALL_ERROR_CATEGORIES.len()] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Logs the error along with its category, and counts it so that operators can
+/// tell which kind of failure is happening the most.
+pub(crate) fn report_error(err: &Error) {
+    let category = err.category();
+
+    ERROR_COUNTS[category as usize].fetch_add(1, Ordering::Relaxed);
+
+    match category {
+        ErrorCategory::Parse | ErrorCategory::Engine => tracing::warn!("[{}] {}", category, err),
+        ErrorCategory::Storage | ErrorCategory::Platform | ErrorCategory::Network => {
+            tracing::error!("[{}] {}", category, err)
+        }
+    }
+}
+
+/// Number of errors reported so far for each category.
+pub(crate) fn error_counts() -> Vec<(ErrorCategory, usize)> {
+    ALL_ERROR_CATEGORIES
+        .iter()
+        .map(|&category| {
+            let count = ERROR_COUNTS[category as usize].load(Ordering::Relaxed);
+            (category, count)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod error_tests {
+    use super::{Error, ErrorCategory, ResultExt};
+    use std::io;
+
+    #[test]
+    fn should_attach_context_to_io_errors() {
+        let result: Result<(), io::Error> = Err(io::Error::from(io::ErrorKind::NotFound));
+
+        let err = result.context(|| "opening database".into()).unwrap_err();
+
+        assert_eq!(err.category(), ErrorCategory::Storage);
+        assert_eq!(
+            err.to_string(),
+            "storage failure while opening database: entity not found"
+        );
+    }
+
+    #[test]
+    fn should_describe_parse_errors() {
+        let err = Error::parse("reply probability", "abc");
+
+        assert_eq!(err.category(), ErrorCategory::Parse);
+        assert_eq!(
+            err.to_string(),
+            "couldn't parse reply probability from `abc`"
+        );
+    }
+}
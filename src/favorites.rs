@@ -0,0 +1,219 @@
+use crate::emoji;
+use crate::error::{self, Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+use tbot::types::{chat, message, user};
+
+/// Emojis that, when replied to a reply of the bot, count as a vote for it.
+/// Skin tone variants count too.
+const POSITIVE_FEEDBACK_EMOJIS: [&str; 8] = ["👍", "❤️", "😂", "🤣", "🔥", "👏", "💯", "😍"];
+
+/// Votes older than this are dropped, as only the best replies of the month
+/// are shown.
+const RETENTION: Duration = Duration::from_secs(31 * 24 * 60 * 60);
+
+/// Whether a message replying to a reply of the bot says that it liked it,
+/// i.e. it's either `+1` or made of positive emojis only.
+pub(crate) fn is_positive_feedback(text: &str) -> bool {
+    let text = text.trim();
+
+    if text == "+1" {
+        return true;
+    }
+
+    let emojis = emoji::extract_emojis(text);
+    let emoji_len: usize = emojis.iter().map(String::len).sum();
+    let non_whitespace_len = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(char::len_utf8);
+
+    !emojis.is_empty()
+        && emoji_len == non_whitespace_len.sum::<usize>()
+        && emojis.iter().all(|emoji| {
+            POSITIVE_FEEDBACK_EMOJIS.iter().any(|positive_emoji| {
+                emoji.starts_with(positive_emoji.trim_end_matches('\u{FE0F}'))
+            })
+        })
+}
+
+#[derive(Serialize, Deserialize)]
+struct RatedReply {
+    chat_id: i64,
+    message_id: u32,
+    text: String,
+    /// Unix time at which the reply was sent.
+    sent_at: i64,
+    voter_ids: HashSet<i64>,
+}
+
+/// Keeps the votes that the replies of the bot received, so that chats can see
+/// which were their favorite ones. Votes are saved to a JSON file.
+pub(crate) struct FavoriteReplies {
+    path: PathBuf,
+    rated_replies: Vec<RatedReply>,
+}
+
+impl FavoriteReplies {
+    /// Loads the votes saved at `path`, or starts without votes if there's no
+    /// such file yet.
+    pub(crate) fn load(path: impl Into<PathBuf>) -> error::Result<FavoriteReplies> {
+        let path = path.into();
+
+        let rated_replies = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|_| Error::parse("favorite replies", path.display().to_string()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(err).context(|| format!("reading favorites `{}`", path.display()))
+            }
+        };
+
+        Ok(FavoriteReplies {
+            path,
+            rated_replies,
+        })
+    }
+
+    /// Counts a vote for a reply of the bot. Returns false if the voter had
+    /// already voted for it.
+    pub(crate) fn upvote(
+        &mut self,
+        chat_id: chat::Id,
+        message_id: message::Id,
+        text: &str,
+        sent_at: i64,
+        voter_id: user::Id,
+    ) -> bool {
+        let existing_reply = self
+            .rated_replies
+            .iter_mut()
+            .find(|reply| reply.chat_id == chat_id.0 && reply.message_id == message_id.0);
+
+        match existing_reply {
+            Some(reply) => reply.voter_ids.insert(voter_id.0),
+            None => {
+                self.rated_replies.push(RatedReply {
+                    chat_id: chat_id.0,
+                    message_id: message_id.0,
+                    text: text.into(),
+                    sent_at,
+                    voter_ids: HashSet::from([voter_id.0]),
+                });
+                true
+            }
+        }
+    }
+
+    /// Returns the replies of the chat sent since `since` with the most votes,
+    /// along with their vote counts.
+    pub(crate) fn best(&self, chat_id: chat::Id, since: i64, count: usize) -> Vec<(&str, usize)> {
+        let mut best_replies: Vec<_> = self
+            .rated_replies
+            .iter()
+            .filter(|reply| reply.chat_id == chat_id.0 && reply.sent_at >= since)
+            .collect();
+
+        best_replies.sort_by_key(|reply| {
+            (
+                std::cmp::Reverse(reply.voter_ids.len()),
+                std::cmp::Reverse(reply.sent_at),
+            )
+        });
+
+        best_replies
+            .into_iter()
+            .take(count)
+            .map(|reply| (reply.text.as_str(), reply.voter_ids.len()))
+            .collect()
+    }
+
+    /// Writes the votes to disk, dropping those that have expired by `now`.
+    pub(crate) fn save(&mut self, now: i64) -> error::Result<()> {
+        let oldest_sent_at = now - RETENTION.as_secs() as i64;
+        self.rated_replies
+            .retain(|reply| reply.sent_at >= oldest_sent_at);
+
+        let json = serde_json::to_vec(&self.rated_replies).expect("votes are serializable");
+
+        std::fs::write(&self.path, json)
+            .context(|| format!("writing favorites `{}`", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod favorite_replies_tests {
+    use super::{is_positive_feedback, FavoriteReplies};
+    use std::path::PathBuf;
+    use tbot::types::{chat, message, user};
+
+    fn temp_favorites_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("favorites_{}_{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn should_recognize_positive_feedback() {
+        assert!(is_positive_feedback("+1"));
+        assert!(is_positive_feedback(" 👍 "));
+        assert!(is_positive_feedback("👍🏽😂😂"));
+        assert!(is_positive_feedback("❤️"));
+    }
+
+    #[test]
+    fn should_not_take_other_messages_as_positive_feedback() {
+        assert!(!is_positive_feedback("👎"));
+        assert!(!is_positive_feedback("👍 but no"));
+        assert!(!is_positive_feedback("+10"));
+        assert!(!is_positive_feedback(""));
+    }
+
+    #[test]
+    fn should_rank_replies_by_vote_count() {
+        let mut favorites = FavoriteReplies::load(temp_favorites_path("rank")).unwrap();
+
+        favorites.upvote(chat::Id(1), message::Id(1), "first", 100, user::Id(1));
+        favorites.upvote(chat::Id(1), message::Id(2), "second", 100, user::Id(1));
+        favorites.upvote(chat::Id(1), message::Id(2), "second", 100, user::Id(2));
+        favorites.upvote(chat::Id(2), message::Id(3), "other chat", 100, user::Id(1));
+
+        assert_eq!(
+            favorites.best(chat::Id(1), 0, 10),
+            &[("second", 2), ("first", 1)]
+        );
+        assert_eq!(favorites.best(chat::Id(1), 0, 1), &[("second", 2)]);
+        assert!(favorites.best(chat::Id(1), 101, 10).is_empty());
+    }
+
+    #[test]
+    fn should_count_one_vote_per_voter() {
+        let mut favorites = FavoriteReplies::load(temp_favorites_path("voters")).unwrap();
+
+        assert!(favorites.upvote(chat::Id(1), message::Id(1), "reply", 100, user::Id(1)));
+        assert!(!favorites.upvote(chat::Id(1), message::Id(1), "reply", 100, user::Id(1)));
+
+        assert_eq!(favorites.best(chat::Id(1), 0, 10), &[("reply", 1)]);
+    }
+
+    #[test]
+    fn should_keep_votes_across_restarts() {
+        let path = temp_favorites_path("persistence");
+        let mut favorites = FavoriteReplies::load(&path).unwrap();
+
+        favorites.upvote(chat::Id(1), message::Id(1), "expired", 100, user::Id(1));
+        favorites.upvote(
+            chat::Id(1),
+            message::Id(2),
+            "recent",
+            10_000_000,
+            user::Id(1),
+        );
+        favorites.save(10_000_000).unwrap();
+
+        let favorites = FavoriteReplies::load(&path).unwrap();
+        assert_eq!(favorites.best(chat::Id(1), 0, 10), &[("recent", 1)]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
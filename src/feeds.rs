@@ -0,0 +1,282 @@
+use crate::error::{self, Error};
+use crate::http::{self, HttpsClient};
+use hyper::Body;
+use lazy_static::lazy_static;
+use quick_xml::events::Event;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::time::Duration;
+
+pub(crate) struct FeedConfig {
+    pub(crate) urls: Vec<String>,
+    pub(crate) poll_interval: Duration,
+    /// How long phrases learned from feeds are used for, or `None` to keep them
+    /// for good.
+    pub(crate) phrase_ttl: Option<Duration>,
+}
+
+impl FeedConfig {
+    /// Reads the feeds to poll from `FEED_URLS`, a comma separated list, and
+    /// how often to poll them from `FEED_POLL_INTERVAL_SECS`. Phrases learned
+    /// from feeds expire after `FEED_PHRASE_TTL_DAYS`, which is either a number
+    /// of days or `off`, and defaults to a month.
+    pub(crate) fn from_env() -> error::Result<FeedConfig> {
+        let urls = std::env::var("FEED_URLS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(String::from)
+            .collect();
+
+        let poll_interval = match std::env::var("FEED_POLL_INTERVAL_SECS") {
+            Ok(secs) => secs
+                .parse()
+                .map(Duration::from_secs)
+                .map_err(|_| Error::parse("feed poll interval", secs))?,
+            Err(_) => Duration::from_secs(15 * 60),
+        };
+
+        let phrase_ttl = match std::env::var("FEED_PHRASE_TTL_DAYS") {
+            Ok(days) if days == "off" => None,
+            Ok(days) => Some(
+                days.parse()
+                    .map(|days: u64| Duration::from_secs(days * 24 * 60 * 60))
+                    .map_err(|_| Error::parse("feed phrase TTL", days))?,
+            ),
+            Err(_) => Some(Duration::from_secs(30 * 24 * 60 * 60)),
+        };
+
+        Ok(FeedConfig {
+            urls,
+            poll_interval,
+            phrase_ttl,
+        })
+    }
+}
+
+#[derive(PartialEq, Debug, Default)]
+pub(crate) struct FeedItem {
+    pub(crate) id: String,
+    pub(crate) title: String,
+    pub(crate) summary: String,
+}
+
+/// Polls the configured feeds forever, passing the title and summary of every
+/// item that wasn't seen in the previous poll to `learn_text`.
+pub(crate) async fn poll_feeds<F, Fut>(config: FeedConfig, mut learn_text: F)
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let client = http::new_client();
+
+    // Only the items of the last poll are remembered, which is enough as feeds
+    // only ever drop their oldest items.
+    let mut seen_item_ids: HashMap<&str, HashSet<String>> = HashMap::new();
+
+    loop {
+        for url in &config.urls {
+            let items = match fetch_feed_items(&client, url).await {
+                Ok(items) => items,
+                Err(err) => {
+                    error::report_error(&err);
+                    continue;
+                }
+            };
+
+            let seen_ids = seen_item_ids.entry(url).or_default();
+            let mut learned_item_count = 0;
+
+            for item in &items {
+                if seen_ids.contains(&item.id) {
+                    continue;
+                }
+
+                learn_text(item.title.clone()).await;
+                learn_text(strip_html(&item.summary)).await;
+                learned_item_count += 1;
+            }
+
+            *seen_ids = items.into_iter().map(|item| item.id).collect();
+
+            tracing::info!(
+                "learned {} new items from feed `{}`",
+                learned_item_count,
+                url
+            );
+        }
+
+        tokio::time::delay_for(config.poll_interval).await;
+    }
+}
+
+async fn fetch_feed_items(client: &HttpsClient, url: &str) -> error::Result<Vec<FeedItem>> {
+    let request = http::get_request(url)?
+        .body(Body::empty())
+        .map_err(|_| Error::parse("feed url", url))?;
+
+    let body = http::fetch(client, request, || format!("fetching feed `{}`", url)).await?;
+
+    parse_feed_items(&String::from_utf8_lossy(&body)).map_err(|_| Error::parse("feed items", url))
+}
+
+/// Extracts the items of either an RSS or an Atom feed.
+pub(crate) fn parse_feed_items(xml: &str) -> Result<Vec<FeedItem>, quick_xml::Error> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut items = Vec::new();
+    let mut current_item: Option<FeedItem> = None;
+    let mut current_field: Option<Vec<u8>> = None;
+    let mut buf = Vec::new();
+
+    loop {
+        let text = match reader.read_event(&mut buf)? {
+            Event::Start(element) => {
+                let name = element.local_name();
+
+                if name == b"item" || name == b"entry" {
+                    current_item = Some(FeedItem::default());
+                } else if current_item.is_some() {
+                    current_field = Some(name.to_vec());
+                }
+
+                None
+            }
+            Event::End(element) => {
+                let name = element.local_name();
+
+                if name == b"item" || name == b"entry" {
+                    items.extend(current_item.take());
+                }
+
+                current_field = None;
+                None
+            }
+            // CDATA contents are handed to us escaped.
+            Event::Text(text) | Event::CData(text) => Some(text.unescape_and_decode(&reader)?),
+            Event::Eof => break,
+            _ => None,
+        };
+
+        if let (Some(item), Some(field), Some(text)) =
+            (current_item.as_mut(), current_field.as_deref(), text)
+        {
+            match field {
+                b"title" => item.title.push_str(&text),
+                b"description" | b"summary" | b"content" | b"encoded" => {
+                    item.summary.push_str(&text)
+                }
+                b"guid" | b"id" => item.id = text,
+                b"link" if item.id.is_empty() => item.id = text,
+                _ => {}
+            }
+        }
+
+        buf.clear();
+    }
+
+    for item in &mut items {
+        if item.id.is_empty() {
+            item.id = item.title.clone();
+        }
+    }
+
+    Ok(items)
+}
+
+/// Feed summaries are usually HTML, of which we only want the text.
+pub(crate) fn strip_html(html: &str) -> String {
+    lazy_static! {
+        static ref HTML_TAG_REGEX: Regex = Regex::new(r"<[^>]*>").unwrap();
+    }
+
+    HTML_TAG_REGEX
+        .replace_all(html, " ")
+        .replace("&nbsp;", " ")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod feed_parsing_tests {
+    use super::{parse_feed_items, strip_html, FeedItem};
+
+    #[test]
+    fn should_parse_rss_items() {
+        let xml = r#"<?xml version="1.0"?>
+            <rss version="2.0">
+              <channel>
+                <title>news</title>
+                <item>
+                  <title>rain is coming</title>
+                  <description><![CDATA[<p>bring an umbrella</p>]]></description>
+                  <guid>item-1</guid>
+                </item>
+                <item>
+                  <title>sun &amp; heat</title>
+                  <link>https://example.com/2</link>
+                </item>
+              </channel>
+            </rss>"#;
+
+        assert_eq!(
+            parse_feed_items(xml).unwrap(),
+            &[
+                FeedItem {
+                    id: "item-1".into(),
+                    title: "rain is coming".into(),
+                    summary: "<p>bring an umbrella</p>".into(),
+                },
+                FeedItem {
+                    id: "https://example.com/2".into(),
+                    title: "sun & heat".into(),
+                    summary: "".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_parse_atom_entries() {
+        let xml = r#"<?xml version="1.0"?>
+            <feed xmlns="http://www.w3.org/2005/Atom">
+              <title>news</title>
+              <entry>
+                <id>urn:entry:1</id>
+                <title>rain is coming</title>
+                <link href="https://example.com/1"/>
+                <summary>bring an umbrella</summary>
+              </entry>
+            </feed>"#;
+
+        assert_eq!(
+            parse_feed_items(xml).unwrap(),
+            &[FeedItem {
+                id: "urn:entry:1".into(),
+                title: "rain is coming".into(),
+                summary: "bring an umbrella".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn should_use_title_as_id_of_items_without_one() {
+        let xml = "<rss><channel><item><title>rain</title></item></channel></rss>";
+
+        assert_eq!(parse_feed_items(xml).unwrap()[0].id, "rain");
+    }
+
+    #[test]
+    fn should_strip_html_from_summaries() {
+        assert_eq!(
+            strip_html("<p>bring an <b>umbrella</b>&nbsp;&amp; boots</p>").trim(),
+            "bring an  umbrella  & boots"
+        );
+    }
+}
@@ -0,0 +1,340 @@
+//! Garnishes replies with small templates, such as "I heard that {phrase}",
+//! whose slots are filled from the reply and the index, for variety beyond
+//! splicing alone.
+
+use crate::phrase_indexing::{IndexedPhrases, WordId};
+use rand::{seq::SliceRandom, Rng};
+use std::collections::HashMap;
+
+/// The rule templates are picked from first.
+pub const START_RULE: &str = "garnish";
+/// Slot filled with the reply being garnished.
+pub const PHRASE_SLOT: &str = "phrase";
+/// Slot filled with a word of the reply that can be pivoted on.
+pub const WORD_SLOT: &str = "word";
+/// Slot filled with a random common word other than the one in `WORD_SLOT`.
+pub const OTHER_WORD_SLOT: &str = "other_word";
+
+/// How deep rules may refer to each other, past which the grammar is taken to
+/// loop forever.
+const MAX_EXPANSION_DEPTH: usize = 8;
+
+#[derive(PartialEq, Debug, thiserror::Error)]
+pub enum GrammarError {
+    #[error("there's no `{}` rule to start from", START_RULE)]
+    MissingStartRule,
+    #[error("rule `{0}` has no templates")]
+    EmptyRule(String),
+    #[error("template `{template}` refers to `{symbol}`, which is neither a rule nor a slot")]
+    UnknownSymbol { template: String, symbol: String },
+    #[error("template `{0}` has an unclosed brace")]
+    UnclosedBrace(String),
+}
+
+/// A context-free grammar whose rules map to templates, in which `{name}`
+/// stands for either another rule or a slot.
+pub struct Garnisher {
+    /// How likely replies are to be garnished.
+    pub probability: f32,
+    rules: HashMap<String, Vec<String>>,
+}
+
+impl Default for Garnisher {
+    fn default() -> Self {
+        let rules = [
+            (
+                START_RULE,
+                &[
+                    "{heard} that {phrase}",
+                    "{word}? more like {other_word}",
+                    "{phrase}, {hedge}",
+                ][..],
+            ),
+            ("heard", &["I heard", "someone told me", "rumor has it"][..]),
+            ("hedge", &["or so they say", "just saying", "trust me"][..]),
+        ]
+        .into_iter()
+        .map(|(rule, templates)| {
+            let templates = templates.iter().map(|&template| template.into()).collect();
+            (rule.into(), templates)
+        })
+        .collect();
+
+        Garnisher::new(0.0, rules).expect("default grammar is valid")
+    }
+}
+
+impl Garnisher {
+    pub fn new(
+        probability: f32,
+        rules: HashMap<String, Vec<String>>,
+    ) -> Result<Garnisher, GrammarError> {
+        if !rules.contains_key(START_RULE) {
+            return Err(GrammarError::MissingStartRule);
+        }
+
+        for (rule, templates) in &rules {
+            if templates.is_empty() {
+                return Err(GrammarError::EmptyRule(rule.clone()));
+            }
+
+            for template in templates {
+                for symbol in symbols_of(template)? {
+                    let is_slot = [PHRASE_SLOT, WORD_SLOT, OTHER_WORD_SLOT].contains(&symbol);
+
+                    if !is_slot && !rules.contains_key(symbol) {
+                        return Err(GrammarError::UnknownSymbol {
+                            template: template.clone(),
+                            symbol: symbol.into(),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(Garnisher { probability, rules })
+    }
+
+    /// Garnishes the reply by chance, giving it back as it is otherwise, or if
+    /// the slots of the picked template couldn't be filled.
+    pub fn garnish(
+        &self,
+        indexed_phrases: &IndexedPhrases,
+        reply: String,
+        rng: &mut impl Rng,
+    ) -> String {
+        if rng.gen::<f32>() >= self.probability {
+            return reply;
+        }
+
+        let mut slots = Slots {
+            indexed_phrases,
+            reply: &reply,
+            word: None,
+        };
+
+        match self.expand(START_RULE, &mut slots, 0, rng) {
+            Some(garnished_reply) => garnished_reply,
+            None => reply,
+        }
+    }
+
+    fn expand(
+        &self,
+        rule: &str,
+        slots: &mut Slots,
+        depth: usize,
+        rng: &mut impl Rng,
+    ) -> Option<String> {
+        if depth > MAX_EXPANSION_DEPTH {
+            return None;
+        }
+
+        let template = self.rules.get(rule)?.choose(rng)?;
+        let mut expansion = String::new();
+        let mut rest = template.as_str();
+
+        while let Some(open_pos) = rest.find('{') {
+            let close_pos = open_pos + rest[open_pos..].find('}')?;
+            let symbol = &rest[open_pos + 1..close_pos];
+
+            expansion.push_str(&rest[..open_pos]);
+            match symbol {
+                PHRASE_SLOT => expansion.push_str(slots.reply),
+                WORD_SLOT => expansion.push_str(slots.word(rng)?.1),
+                OTHER_WORD_SLOT => expansion.push_str(&slots.other_word(rng)?),
+                rule => expansion.push_str(&self.expand(rule, slots, depth + 1, rng)?),
+            }
+
+            rest = &rest[close_pos + 1..];
+        }
+        expansion.push_str(rest);
+
+        Some(expansion)
+    }
+}
+
+/// Fills slots, picking the word of the reply once, so that every `{word}` of
+/// a garnish is the same.
+struct Slots<'a> {
+    indexed_phrases: &'a IndexedPhrases,
+    reply: &'a str,
+    word: Option<(WordId, &'a str)>,
+}
+
+impl<'a> Slots<'a> {
+    fn word(&mut self, rng: &mut impl Rng) -> Option<(WordId, &'a str)> {
+        if self.word.is_none() {
+            let indexed_phrases = self.indexed_phrases;
+            let words: Vec<_> = self
+                .reply
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|word| word.chars().count() > 1)
+                .filter_map(|word| Some((indexed_phrases.get_word_id(word)?, word)))
+                .filter(|&(word_id, _)| {
+                    indexed_phrases.is_common_word(word_id)
+                        && !indexed_phrases.is_stop_word(word_id)
+                })
+                .collect();
+
+            self.word = words.choose(rng).copied();
+        }
+
+        self.word
+    }
+
+    fn other_word(&mut self, rng: &mut impl Rng) -> Option<String> {
+        let word_id = self.word(rng).map(|(word_id, _)| word_id);
+
+        // Only a few tries, as the index may have no other word to give.
+        (0..3).find_map(|_| {
+            let other_word_id = self.indexed_phrases.get_random_common_word(rng).ok()?;
+
+            if Some(other_word_id) == word_id || self.indexed_phrases.is_stop_word(other_word_id) {
+                return None;
+            }
+
+            self.indexed_phrases
+                .get_word(other_word_id)
+                .ok()
+                .map(|word| word.to_string())
+        })
+    }
+}
+
+/// The rules and slots the template refers to.
+fn symbols_of(template: &str) -> Result<Vec<&str>, GrammarError> {
+    let mut symbols = Vec::new();
+    let mut rest = template;
+
+    while let Some(open_pos) = rest.find('{') {
+        let close_pos = match rest[open_pos..].find('}') {
+            Some(close_pos) => open_pos + close_pos,
+            None => return Err(GrammarError::UnclosedBrace(template.into())),
+        };
+
+        symbols.push(&rest[open_pos + 1..close_pos]);
+        rest = &rest[close_pos + 1..];
+    }
+
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod garnish_tests {
+    use super::{Garnisher, GrammarError};
+    use crate::phrase_indexing::{IndexedPhrases, Phrase};
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::collections::HashMap;
+
+    fn rules(rules: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        rules
+            .iter()
+            .map(|&(rule, templates)| {
+                let templates = templates.iter().map(|&template| template.into()).collect();
+                (rule.into(), templates)
+            })
+            .collect()
+    }
+
+    fn index_phrases() -> IndexedPhrases {
+        let mut ip = IndexedPhrases::new();
+        ip.insert_phrase(Phrase::from("the cat sleeps"));
+        ip.insert_phrase(Phrase::from("my dog barks"));
+        ip
+    }
+
+    #[test]
+    fn should_fill_slots_and_expand_rules() {
+        let garnisher = Garnisher::new(
+            1.0,
+            rules(&[
+                ("garnish", &["{heard} {phrase}, {word}"]),
+                ("heard", &["rumor has it"]),
+            ]),
+        )
+        .unwrap();
+        let mut ip = index_phrases();
+        ip.set_stop_words(["the".to_string()]);
+
+        let garnished_reply =
+            garnisher.garnish(&ip, "the cat barks".into(), &mut StdRng::seed_from_u64(42));
+
+        assert!(
+            [
+                "rumor has it the cat barks, cat",
+                "rumor has it the cat barks, barks"
+            ]
+            .contains(&garnished_reply.as_str()),
+            "{}",
+            garnished_reply
+        );
+    }
+
+    #[test]
+    fn should_fill_other_word_with_a_different_word() {
+        let garnisher =
+            Garnisher::new(1.0, rules(&[("garnish", &["{word} {other_word}"])])).unwrap();
+        let ip = index_phrases();
+
+        for seed in 0..20 {
+            let garnished_reply =
+                garnisher.garnish(&ip, "cat".into(), &mut StdRng::seed_from_u64(seed));
+
+            assert_ne!(garnished_reply, "cat cat");
+        }
+    }
+
+    #[test]
+    fn should_leave_reply_alone_if_slots_cant_be_filled() {
+        let garnisher = Garnisher::new(1.0, rules(&[("garnish", &["{word}?"])])).unwrap();
+
+        let garnished_reply = garnisher.garnish(
+            &index_phrases(),
+            "unknown words".into(),
+            &mut StdRng::seed_from_u64(42),
+        );
+
+        assert_eq!(garnished_reply, "unknown words");
+    }
+
+    #[test]
+    fn should_not_garnish_without_probability() {
+        let garnished_reply = Garnisher::default().garnish(
+            &index_phrases(),
+            "the cat barks".into(),
+            &mut StdRng::seed_from_u64(42),
+        );
+
+        assert_eq!(garnished_reply, "the cat barks");
+    }
+
+    #[test]
+    fn should_reject_invalid_grammars() {
+        assert_eq!(
+            Garnisher::new(1.0, rules(&[("heard", &["I heard"])])).err(),
+            Some(GrammarError::MissingStartRule)
+        );
+        assert!(matches!(
+            Garnisher::new(1.0, rules(&[("garnish", &["{heard} {phrase}"])])).err(),
+            Some(GrammarError::UnknownSymbol { .. })
+        ));
+        assert!(matches!(
+            Garnisher::new(1.0, rules(&[("garnish", &["{phrase"])])).err(),
+            Some(GrammarError::UnclosedBrace(_))
+        ));
+    }
+
+    #[test]
+    fn should_give_up_on_rules_looping_forever() {
+        let garnisher = Garnisher::new(1.0, rules(&[("garnish", &["again {garnish}"])])).unwrap();
+
+        let garnished_reply = garnisher.garnish(
+            &index_phrases(),
+            "hi".into(),
+            &mut StdRng::seed_from_u64(42),
+        );
+
+        assert_eq!(garnished_reply, "hi");
+    }
+}
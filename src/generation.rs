@@ -0,0 +1,581 @@
+use crate::answer_pool::AnswerPoolCache;
+use crate::output;
+use crate::phrase_indexing::{self, EngineError, IndexedPhraseContent, IndexedPhrases, WordId};
+use rand::Rng;
+
+/// How many times phrases are spliced when they only give back phrases that
+/// are already stored, before giving up.
+pub const MAX_GENERATION_ATTEMPTS: usize = 10;
+
+/// How many sentences make up a reply, and how they relate to each other.
+#[derive(Clone)]
+pub struct SentenceConfig {
+    pub min_sentences: usize,
+    pub max_sentences: usize,
+    /// Whether each sentence pivots on a word of the previous one, instead of
+    /// on a random common word.
+    pub chained: bool,
+}
+
+impl Default for SentenceConfig {
+    fn default() -> Self {
+        SentenceConfig {
+            min_sentences: 1,
+            max_sentences: 1,
+            chained: true,
+        }
+    }
+}
+
+/// Splices phrases around one of the given words, picked among the common
+/// ones, leaving out stop words unless there's nothing else. Words that are in
+/// a single phrase give way to the longest common word inside them, if looking
+/// for those is enabled. Phrases that are stored or in `incoming_phrases` as
+/// they are would just be repeated back, so the word is joined at a bigram
+/// instead, and other words are tried if that fails too.
+/// Returns `None` if no word is common enough to pivot on, or if none gave a
+/// new phrase.
+pub fn generate_phrase(
+    indexed_phrases: &IndexedPhrases,
+    answer_pools: &AnswerPoolCache,
+    word_ids_from_phrases: Vec<WordId>,
+    incoming_phrases: &[&str],
+    now: i64,
+    rng: &mut impl Rng,
+) -> Result<Option<String>, EngineError> {
+    use rand::seq::SliceRandom;
+
+    let mut candidate_word_ids: Vec<WordId> = Vec::new();
+    for word_id in word_ids_from_phrases {
+        // A word with a single phrase can only give that phrase back, so a word
+        // inside it is pivoted on instead, if there's one.
+        let word_id = match indexed_phrases.get_phrase_count_of_word(word_id) {
+            0 | 1 => indexed_phrases.find_sub_word(word_id).unwrap_or(word_id),
+            _ => word_id,
+        };
+
+        if !candidate_word_ids.contains(&word_id) {
+            candidate_word_ids.push(word_id);
+        }
+    }
+
+    candidate_word_ids.retain(|&word_id| indexed_phrases.is_common_word(word_id));
+    candidate_word_ids.retain(|&word_id| {
+        indexed_phrases
+            .get_word(word_id)
+            .is_ok_and(|word| !indexed_phrases.has_blocked_word(&word))
+    });
+    candidate_word_ids.retain(|&word_id| {
+        indexed_phrases
+            .get_word(word_id)
+            .is_ok_and(|word| word.len() > 1)
+    });
+
+    if candidate_word_ids
+        .iter()
+        .any(|&word_id| !indexed_phrases.is_stop_word(word_id))
+    {
+        candidate_word_ids.retain(|&word_id| !indexed_phrases.is_stop_word(word_id));
+    }
+
+    // Phrases learned before their words were blocked may still be spliced
+    // into ones with them, which are discarded like repeated ones.
+    let is_new_phrase = |phrase: &str| {
+        let content = phrase.trim_end_matches(output::EXPRESSIVE_TERMINATORS);
+        !indexed_phrases.contains_phrase(content)
+            && !incoming_phrases.contains(&content)
+            && !indexed_phrases.has_blocked_word(content)
+    };
+
+    for _ in 0..MAX_GENERATION_ATTEMPTS {
+        let picked_word_id = match candidate_word_ids
+            .choose_weighted(rng, |&word_id| indexed_phrases.get_word_weight(word_id))
+        {
+            Ok(&word_id) => word_id,
+            Err(_) => return Ok(None),
+        };
+
+        let generated_phrase =
+            splice_phrases_around_word(indexed_phrases, answer_pools, picked_word_id, now, rng)?;
+
+        if is_new_phrase(&generated_phrase) {
+            return Ok(Some(generated_phrase));
+        }
+
+        tracing::debug!(
+            "generated `{}`, which is stored as is or blocked",
+            generated_phrase
+        );
+
+        let bigram_phrase = splice_phrases_at_bigram(indexed_phrases, picked_word_id, now, rng)?;
+        if let Some(bigram_phrase) = bigram_phrase.filter(|phrase| is_new_phrase(phrase)) {
+            return Ok(Some(bigram_phrase));
+        }
+
+        // A word with a single phrase can only give that phrase back.
+        if indexed_phrases.get_phrase_count_of_word(picked_word_id) < 2 {
+            candidate_word_ids.retain(|&word_id| word_id != picked_word_id);
+        }
+    }
+
+    Ok(None)
+}
+
+/// Splices phrases containing the word that haven't expired by `now`.
+pub fn splice_phrases_around_word(
+    indexed_phrases: &IndexedPhrases,
+    answer_pools: &AnswerPoolCache,
+    word_id: WordId,
+    now: i64,
+    rng: &mut impl Rng,
+) -> Result<String, EngineError> {
+    let phrases = answer_pools.get_phrases_with_word_id_in_common(indexed_phrases, word_id, rng)?;
+    let phrases = drop_expired_phrases(indexed_phrases, phrases, word_id, now)?;
+
+    Ok(splice_phrases(indexed_phrases, &phrases, rng))
+}
+
+/// Splices a phrase with the word, up to it, with another phrase from a word
+/// that follows the word somewhere in the corpus on, which joins phrases that
+/// have no word in common. Returns `None` if no word follows it, or if no other
+/// phrase has any of the words that do.
+pub fn splice_phrases_at_bigram(
+    indexed_phrases: &IndexedPhrases,
+    word_id: WordId,
+    now: i64,
+    rng: &mut impl Rng,
+) -> Result<Option<String>, EngineError> {
+    use rand::seq::SliceRandom;
+
+    let first_phrases = indexed_phrases
+        .get_phrases_with_word_id_in_common(word_id)?
+        .collect();
+    let first_phrases = drop_expired_phrases(indexed_phrases, first_phrases, word_id, now)?;
+    let first_phrase = choose_phrase_by_quality(indexed_phrases, &first_phrases, rng);
+
+    // The phrase may be at an alias of the word, which is followed by words of
+    // its own.
+    let following_word_ids = match indexed_phrases.get_word_id(first_phrase.word()) {
+        Some(pivot_word_id) => indexed_phrases.get_following_word_ids(pivot_word_id),
+        None => return Ok(None),
+    };
+
+    let following_word_id = match following_word_ids.choose(rng) {
+        Some(&following_word_id) => following_word_id,
+        None => return Ok(None),
+    };
+
+    let second_phrases: Vec<_> = indexed_phrases
+        .get_phrases_with_word_id_in_common(following_word_id)?
+        .filter(|&phrase| phrase.content() != first_phrase.content())
+        .filter(|&phrase| !indexed_phrases.is_phrase_expired(phrase, now))
+        .collect();
+
+    if second_phrases.is_empty() {
+        return Ok(None);
+    }
+
+    let second_phrase = choose_phrase_by_quality(indexed_phrases, &second_phrases, rng);
+
+    Ok(Some(
+        phrase_indexing::concatenate_indexed_phrases_at_bigram(first_phrase, second_phrase),
+    ))
+}
+
+/// Same as `splice_phrases_around_word`, except that both the pivot word and
+/// the spliced phrases come from phrases tagged with `tag`.
+pub fn splice_tagged_phrases(
+    indexed_phrases: &IndexedPhrases,
+    tag: &str,
+    now: i64,
+    rng: &mut impl Rng,
+) -> Result<String, EngineError> {
+    let word_id = indexed_phrases.get_random_tagged_word(tag, rng)?;
+
+    let phrases: Vec<_> = indexed_phrases
+        .get_phrases_with_word_id_in_common(word_id)?
+        .filter(|&phrase| indexed_phrases.is_phrase_tagged(phrase, tag))
+        .collect();
+    let phrases = drop_expired_phrases(indexed_phrases, phrases, word_id, now)?;
+
+    Ok(splice_phrases(indexed_phrases, &phrases, rng))
+}
+
+/// Appends further sentences to `first_sentence` until the configured sentence
+/// count is reached, or no more sentences can be generated.
+pub fn extend_into_sentences(
+    indexed_phrases: &IndexedPhrases,
+    answer_pools: &AnswerPoolCache,
+    first_sentence: String,
+    sentence_config: &SentenceConfig,
+    now: i64,
+    rng: &mut impl Rng,
+) -> String {
+    let max_sentences = sentence_config
+        .max_sentences
+        .max(sentence_config.min_sentences);
+    let sentence_count = rng.gen_range(sentence_config.min_sentences..=max_sentences);
+
+    let mut sentences = vec![first_sentence];
+
+    while sentences.len() < sentence_count {
+        let next_sentence = if sentence_config.chained {
+            let previous_sentence = sentences.last().unwrap();
+            let word_ids = previous_sentence
+                .split_ascii_whitespace()
+                .map(|word| word.trim_end_matches(output::EXPRESSIVE_TERMINATORS))
+                .filter_map(|word| indexed_phrases.get_word_id(word))
+                .collect();
+
+            generate_phrase(indexed_phrases, answer_pools, word_ids, &[], now, rng)
+        } else {
+            indexed_phrases
+                .get_random_common_word(rng)
+                .and_then(|word_id| {
+                    splice_phrases_around_word(indexed_phrases, answer_pools, word_id, now, rng)
+                })
+                .map(Some)
+        };
+
+        match next_sentence {
+            Ok(Some(sentence)) => sentences.push(sentence),
+            Ok(None) => break,
+            Err(err) => {
+                tracing::debug!("stopped at {} sentences: {}", sentences.len(), err);
+                break;
+            }
+        }
+    }
+
+    output::join_sentences(&sentences)
+}
+
+/// Leaves out the phrases that expired by `now`, which are only removed from
+/// the index from time to time. Fails if no phrase with the word is left.
+fn drop_expired_phrases<'s>(
+    indexed_phrases: &IndexedPhrases,
+    mut phrases: Vec<IndexedPhraseContent<'s>>,
+    word_id: WordId,
+    now: i64,
+) -> Result<Vec<IndexedPhraseContent<'s>>, EngineError> {
+    phrases.retain(|&phrase| !indexed_phrases.is_phrase_expired(phrase, now));
+
+    if phrases.is_empty() {
+        let word = indexed_phrases.get_word(word_id)?;
+        return Err(EngineError::ExpiredWord(word.to_string()));
+    }
+
+    Ok(phrases)
+}
+
+/// Splices two phrases picked from `phrases`, which must share the pivot word.
+fn splice_phrases(
+    indexed_phrases: &IndexedPhrases,
+    phrases: &[IndexedPhraseContent],
+    rng: &mut impl Rng,
+) -> String {
+    let first_phrase = choose_phrase_by_quality(indexed_phrases, phrases, rng);
+
+    // Splicing a phrase with itself just gives it back, so it's spliced with
+    // another one whenever there's another.
+    let other_phrases: Vec<_> = phrases
+        .iter()
+        .copied()
+        .filter(|&phrase| phrase != first_phrase)
+        .collect();
+    let second_phrase = if other_phrases.is_empty() {
+        first_phrase
+    } else {
+        choose_phrase_by_quality(indexed_phrases, &other_phrases, rng)
+    };
+
+    let mut generated_phrase =
+        phrase_indexing::concatenate_indexed_phrases(first_phrase, second_phrase);
+
+    // The generated phrase ends the way the second phrase did, so it keeps its
+    // terminator if that one says something about the tone of the phrase.
+    if let Some(terminator) = indexed_phrases.get_phrase_terminator(second_phrase) {
+        if output::EXPRESSIVE_TERMINATORS.contains(&terminator) {
+            generated_phrase.push(terminator);
+        }
+    }
+
+    generated_phrase
+}
+
+/// Picks a phrase with probability proportional to its quality score, scaled
+/// by the weight of its source, falling back to a uniform choice if every
+/// candidate weighs zero.
+fn choose_phrase_by_quality<'s>(
+    indexed_phrases: &IndexedPhrases,
+    phrases: &[IndexedPhraseContent<'s>],
+    rng: &mut impl Rng,
+) -> IndexedPhraseContent<'s> {
+    use rand::seq::SliceRandom;
+
+    *phrases
+        .choose_weighted(rng, |phrase| indexed_phrases.get_phrase_weight(*phrase))
+        .unwrap_or_else(|_| phrases.choose(rng).unwrap())
+}
+
+#[cfg(test)]
+mod generation_tests {
+    use super::{extend_into_sentences, generate_phrase, splice_tagged_phrases, SentenceConfig};
+    use crate::answer_pool::{AnswerPoolCache, AnswerPoolConfig};
+    use crate::phrase_indexing::{
+        normalize_text_into_phrases, EngineError, IndexedPhrases, NormalizationConfig, Phrase,
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn index_texts(texts: &[&str]) -> IndexedPhrases {
+        let mut ip = IndexedPhrases::new();
+        for &text in texts {
+            for phrase in normalize_text_into_phrases(text.into(), &NormalizationConfig::default())
+            {
+                ip.insert_phrase(phrase);
+            }
+        }
+        ip
+    }
+
+    #[test]
+    fn should_generate_phrase_around_word_in_common() {
+        let ip = index_texts(&["the cat sleeps", "my cat eats"]);
+        let answer_pools = AnswerPoolCache::new(AnswerPoolConfig::default());
+        let word_ids = vec![ip.get_word_id("cat").unwrap()];
+
+        let generated_phrase = generate_phrase(
+            &ip,
+            &answer_pools,
+            word_ids,
+            &[],
+            0,
+            &mut StdRng::seed_from_u64(42),
+        )
+        .unwrap()
+        .unwrap();
+
+        assert!(generated_phrase.contains("cat"));
+    }
+
+    #[test]
+    fn should_avoid_pivoting_on_stop_words() {
+        let mut ip = index_texts(&["the cat sleeps", "my cat eats", "the dog barks"]);
+        ip.set_stop_words(["the".to_string()]);
+        let word_ids = vec![
+            ip.get_word_id("the").unwrap(),
+            ip.get_word_id("cat").unwrap(),
+        ];
+
+        for seed in 0..20 {
+            let answer_pools = AnswerPoolCache::new(AnswerPoolConfig::default());
+            let generated_phrase = generate_phrase(
+                &ip,
+                &answer_pools,
+                word_ids.clone(),
+                &[],
+                0,
+                &mut StdRng::seed_from_u64(seed),
+            )
+            .unwrap()
+            .unwrap();
+
+            assert!(["the cat eats", "my cat sleeps"].contains(&generated_phrase.as_str()));
+        }
+    }
+
+    #[test]
+    fn should_pivot_on_stop_words_if_nothing_else_is_common() {
+        let mut ip = index_texts(&["feed the cat", "walk the dog"]);
+        ip.set_stop_words(["the".to_string()]);
+        let answer_pools = AnswerPoolCache::new(AnswerPoolConfig::default());
+
+        let generated_phrase = generate_phrase(
+            &ip,
+            &answer_pools,
+            vec![ip.get_word_id("the").unwrap()],
+            &[],
+            0,
+            &mut StdRng::seed_from_u64(42),
+        )
+        .unwrap();
+
+        assert!(generated_phrase.is_some());
+    }
+
+    #[test]
+    fn should_pivot_on_word_inside_compound_word() {
+        let mut ip = index_texts(&[
+            "pizza night was fun",
+            "movie night again",
+            "see you at gamenightfriday",
+        ]);
+        ip.set_min_sub_word_len(Some(3));
+        let answer_pools = AnswerPoolCache::new(AnswerPoolConfig::default());
+
+        let generated_phrase = generate_phrase(
+            &ip,
+            &answer_pools,
+            vec![ip.get_word_id("gamenightfriday").unwrap()],
+            &["see you at gamenightfriday"],
+            0,
+            &mut StdRng::seed_from_u64(42),
+        )
+        .unwrap()
+        .unwrap();
+
+        assert!(generated_phrase.contains("night"));
+    }
+
+    #[test]
+    fn should_join_phrases_at_bigram_when_word_is_in_a_single_phrase() {
+        let ip = index_texts(&["my grandma bakes bread", "she bakes cakes"]);
+        let answer_pools = AnswerPoolCache::new(AnswerPoolConfig::default());
+
+        let generated_phrase = generate_phrase(
+            &ip,
+            &answer_pools,
+            vec![ip.get_word_id("grandma").unwrap()],
+            &[],
+            0,
+            &mut StdRng::seed_from_u64(42),
+        )
+        .unwrap();
+
+        assert_eq!(generated_phrase.as_deref(), Some("my grandma bakes cakes"));
+    }
+
+    #[test]
+    fn should_discard_generated_phrases_with_blocked_words() {
+        let mut ip = index_texts(&["the cat sleeps", "my cat eats"]);
+        ip.set_blocked_words(["Sleeps".to_string()]);
+        let answer_pools = AnswerPoolCache::new(AnswerPoolConfig::default());
+
+        for seed in 0..20 {
+            let generated_phrase = generate_phrase(
+                &ip,
+                &answer_pools,
+                vec![ip.get_word_id("cat").unwrap()],
+                &[],
+                0,
+                &mut StdRng::seed_from_u64(seed),
+            )
+            .unwrap();
+
+            assert_eq!(generated_phrase.as_deref(), Some("the cat eats"));
+        }
+    }
+
+    #[test]
+    fn should_not_generate_phrase_without_candidate_words() {
+        let ip = index_texts(&["the cat sleeps"]);
+        let answer_pools = AnswerPoolCache::new(AnswerPoolConfig::default());
+
+        let generated_phrase = generate_phrase(
+            &ip,
+            &answer_pools,
+            Vec::new(),
+            &[],
+            0,
+            &mut StdRng::seed_from_u64(42),
+        )
+        .unwrap();
+
+        assert_eq!(generated_phrase, None);
+    }
+
+    #[test]
+    fn should_fail_to_generate_around_word_whose_phrases_expired() {
+        let mut ip = IndexedPhrases::new();
+        ip.insert_phrase(Phrase::from("breaking news today").with_expiry(100));
+        let answer_pools = AnswerPoolCache::new(AnswerPoolConfig::default());
+        let word_ids = vec![ip.get_word_id("news").unwrap()];
+
+        let result = generate_phrase(
+            &ip,
+            &answer_pools,
+            word_ids,
+            &[],
+            200,
+            &mut StdRng::seed_from_u64(42),
+        );
+
+        assert_eq!(result, Err(EngineError::ExpiredWord("news".into())));
+    }
+
+    #[test]
+    fn should_not_echo_stored_phrase() {
+        let ip = index_texts(&["the cat sleeps", "my dog eats"]);
+        let answer_pools = AnswerPoolCache::new(AnswerPoolConfig::default());
+        let word_ids = vec![ip.get_word_id("cat").unwrap()];
+
+        let generated_phrase = generate_phrase(
+            &ip,
+            &answer_pools,
+            word_ids,
+            &[],
+            0,
+            &mut StdRng::seed_from_u64(42),
+        )
+        .unwrap();
+
+        assert_eq!(generated_phrase, None);
+    }
+
+    #[test]
+    fn should_try_other_words_when_one_only_echoes() {
+        let ip = index_texts(&["the cat sleeps", "my cat eats", "the dog barks"]);
+        let answer_pools = AnswerPoolCache::new(AnswerPoolConfig::default());
+        let word_ids = vec![
+            ip.get_word_id("dog").unwrap(),
+            ip.get_word_id("cat").unwrap(),
+        ];
+
+        for seed in 0..10 {
+            let generated_phrase = generate_phrase(
+                &ip,
+                &answer_pools,
+                word_ids.clone(),
+                &["the cat eats"],
+                0,
+                &mut StdRng::seed_from_u64(seed),
+            )
+            .unwrap();
+
+            assert_eq!(generated_phrase.as_deref(), Some("my cat sleeps"));
+        }
+    }
+
+    #[test]
+    fn should_splice_only_tagged_phrases() {
+        let mut ip = index_texts(&["i want pizza", "pizza is hot"]);
+        ip.tag_phrases_of_text("i want pizza", "food");
+
+        let generated_phrase =
+            splice_tagged_phrases(&ip, "food", 0, &mut StdRng::seed_from_u64(42)).unwrap();
+
+        assert_eq!(generated_phrase, "i want pizza");
+    }
+
+    #[test]
+    fn should_extend_into_configured_number_of_sentences() {
+        let ip = index_texts(&["the cat sleeps", "my cat eats"]);
+        let answer_pools = AnswerPoolCache::new(AnswerPoolConfig::default());
+        let sentence_config = SentenceConfig {
+            min_sentences: 3,
+            max_sentences: 3,
+            chained: true,
+        };
+
+        let response = extend_into_sentences(
+            &ip,
+            &answer_pools,
+            "the cat eats".into(),
+            &sentence_config,
+            0,
+            &mut StdRng::seed_from_u64(42),
+        );
+
+        assert_eq!(response.matches("cat").count(), 3);
+    }
+}
@@ -0,0 +1,61 @@
+use crate::error::{self, Error};
+use hyper::client::HttpConnector;
+use hyper::{body::Bytes, Body, Request};
+use hyper_tls::HttpsConnector;
+
+pub(crate) type HttpsClient = hyper::Client<HttpsConnector<HttpConnector>>;
+
+pub(crate) fn new_client() -> HttpsClient {
+    hyper::Client::builder().build(HttpsConnector::new())
+}
+
+/// Sends the request and returns the response body, treating non-success
+/// statuses as failures. `context` describes what is being fetched.
+pub(crate) async fn fetch(
+    client: &HttpsClient,
+    request: Request<Body>,
+    context: impl FnOnce() -> String,
+) -> error::Result<Bytes> {
+    let result = async {
+        let response = client.request(request).await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(format!("unexpected status {}", status).into());
+        }
+
+        Ok(hyper::body::to_bytes(response.into_body()).await?)
+    };
+
+    result.await.map_err(
+        |source: Box<dyn std::error::Error + Send + Sync>| Error::Network {
+            context: context(),
+            source,
+        },
+    )
+}
+
+/// Builds a GET request, reporting malformed urls as parse errors.
+pub(crate) fn get_request(url: &str) -> error::Result<hyper::http::request::Builder> {
+    let uri: hyper::Uri = url.parse().map_err(|_| Error::parse("url", url))?;
+
+    Ok(Request::get(uri))
+}
+
+/// Percent-encodes everything but unreserved characters, and slashes too if
+/// `encode_slash`.
+pub(crate) fn uri_encode(text: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(text.len());
+
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
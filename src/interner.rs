@@ -0,0 +1,249 @@
+use hashbrown::HashTable;
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+/// Refers to a text of an `Interner`.
+pub type Symbol = u32;
+
+/// Where the text of a symbol is in the arena.
+#[derive(Copy, Clone, Default)]
+struct Span {
+    start: u32,
+    len: u32,
+}
+
+/// Keeps each text once, back to back in a single arena, and refers to them by
+/// `u32` symbols, which are reused once their texts are forgotten. Serializing
+/// it keeps the texts by symbol, forgotten ones as empty texts.
+pub struct Interner {
+    arena: String,
+    /// Spans of the texts by symbol, which are empty for forgotten texts.
+    spans: Vec<Span>,
+    /// Symbols of the texts, by the hash of their texts.
+    symbols: HashTable<Symbol>,
+    free_symbols: Vec<Symbol>,
+    /// Bytes of the arena left by forgotten texts, which are compacted away
+    /// once they make up half of it.
+    forgotten_len: usize,
+    hasher: RandomState,
+}
+
+impl Default for Interner {
+    fn default() -> Self {
+        Interner::new()
+    }
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner {
+            arena: String::new(),
+            spans: Vec::new(),
+            symbols: HashTable::new(),
+            free_symbols: Vec::new(),
+            forgotten_len: 0,
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// The symbol of the text, if it's interned.
+    pub fn get(&self, text: &str) -> Option<Symbol> {
+        let hash = self.hasher.hash_one(text);
+
+        self.symbols
+            .find(hash, |&symbol| self.resolve(symbol) == text)
+            .copied()
+    }
+
+    /// The text of the symbol, which is empty if it was forgotten.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the symbol was never handed out.
+    pub fn resolve(&self, symbol: Symbol) -> &str {
+        resolve_in(&self.arena, &self.spans, symbol)
+    }
+
+    /// The text of the symbol, unless it was never handed out or was
+    /// forgotten.
+    pub fn try_resolve(&self, symbol: Symbol) -> Option<&str> {
+        let span = self.spans.get(symbol as usize)?;
+        let start = span.start as usize;
+
+        (span.len > 0).then(|| &self.arena[start..start + span.len as usize])
+    }
+
+    /// Returns the symbol of the text, interning it first if needed.
+    pub fn intern(&mut self, text: &str) -> Symbol {
+        if let Some(symbol) = self.get(text) {
+            return symbol;
+        }
+
+        let span = Span {
+            start: u32::try_from(self.arena.len()).expect("interned texts exceed 4 GiB"),
+            len: text.len() as u32,
+        };
+        self.arena.push_str(text);
+
+        let symbol = match self.free_symbols.pop() {
+            Some(free_symbol) => {
+                self.spans[free_symbol as usize] = span;
+                free_symbol
+            }
+            None => {
+                self.spans.push(span);
+                Symbol::try_from(self.spans.len() - 1).expect("interned over 4G texts")
+            }
+        };
+
+        let (arena, spans, hasher) = (&self.arena, &self.spans, &self.hasher);
+        self.symbols
+            .insert_unique(hasher.hash_one(text), symbol, |&symbol| {
+                hasher.hash_one(resolve_in(arena, spans, symbol))
+            });
+
+        symbol
+    }
+
+    /// Forgets the text, so that its symbol is handed out again for another
+    /// text. Returns the symbol it had, if it was interned.
+    pub fn forget(&mut self, text: &str) -> Option<Symbol> {
+        let hash = self.hasher.hash_one(text);
+        let (arena, spans) = (&self.arena, &self.spans);
+        let (symbol, _) = self
+            .symbols
+            .find_entry(hash, |&symbol| resolve_in(arena, spans, symbol) == text)
+            .ok()?
+            .remove();
+
+        self.spans[symbol as usize] = Span::default();
+        self.free_symbols.push(symbol);
+        self.forgotten_len += text.len();
+
+        if self.forgotten_len * 2 > self.arena.len() {
+            self.compact();
+        }
+
+        Some(symbol)
+    }
+
+    /// Number of texts interned.
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Number of symbols handed out, those of forgotten texts included.
+    pub fn symbol_count(&self) -> usize {
+        self.spans.len()
+    }
+
+    /// Moves the texts together, dropping the bytes of forgotten ones. Symbols
+    /// are left as they were.
+    fn compact(&mut self) {
+        let mut arena = String::with_capacity(self.arena.len() - self.forgotten_len);
+
+        for span in &mut self.spans {
+            let start = span.start as usize;
+            let text = &self.arena[start..start + span.len as usize];
+
+            span.start = arena.len() as u32;
+            arena.push_str(text);
+        }
+
+        self.arena = arena;
+        self.forgotten_len = 0;
+    }
+}
+
+fn resolve_in<'a>(arena: &'a str, spans: &[Span], symbol: Symbol) -> &'a str {
+    let span = spans[symbol as usize];
+    let start = span.start as usize;
+
+    &arena[start..start + span.len as usize]
+}
+
+impl Serialize for Interner {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq((0..self.spans.len() as Symbol).map(|symbol| self.resolve(symbol)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Interner {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let texts = Vec::<String>::deserialize(deserializer)?;
+        let mut interner = Interner::new();
+        interner.arena.reserve(texts.iter().map(String::len).sum());
+
+        for (symbol, text) in texts.iter().enumerate() {
+            let symbol = symbol as Symbol;
+            interner.spans.push(Span {
+                start: interner.arena.len() as u32,
+                len: text.len() as u32,
+            });
+            interner.arena.push_str(text);
+
+            if text.is_empty() {
+                interner.free_symbols.push(symbol);
+                continue;
+            }
+
+            let (arena, spans, hasher) = (&interner.arena, &interner.spans, &interner.hasher);
+            interner
+                .symbols
+                .insert_unique(hasher.hash_one(text), symbol, |&symbol| {
+                    hasher.hash_one(resolve_in(arena, spans, symbol))
+                });
+        }
+
+        Ok(interner)
+    }
+}
+
+#[cfg(test)]
+mod interner_tests {
+    use super::Interner;
+
+    #[test]
+    fn should_reuse_symbols_of_forgotten_texts() {
+        let mut interner = Interner::new();
+        let hello = interner.intern("hello");
+        let hi = interner.intern("hi");
+
+        assert_eq!(interner.intern("hello"), hello);
+        assert_eq!(interner.forget("hello"), Some(hello));
+        assert_eq!(interner.forget("hello"), None);
+        assert_eq!(interner.get("hello"), None);
+        assert_eq!(interner.try_resolve(hello), None);
+
+        // Forgetting "hello" left most of the arena unused, so it was compacted.
+        assert_eq!(interner.resolve(hi), "hi");
+        assert_eq!(interner.intern("world"), hello);
+        assert_eq!(interner.resolve(hello), "world");
+        assert_eq!(interner.get("hi"), Some(hi));
+        assert_eq!((interner.len(), interner.symbol_count()), (2, 2));
+    }
+
+    #[test]
+    fn should_keep_symbols_across_serialization() {
+        let mut interner = Interner::new();
+        let hello = interner.intern("hello");
+        interner.intern("there");
+        let world = interner.intern("world");
+        interner.forget("there");
+
+        let serialized = bincode::serialize(&interner).unwrap();
+        let mut interner: Interner = bincode::deserialize(&serialized).unwrap();
+
+        assert_eq!(interner.get("hello"), Some(hello));
+        assert_eq!(interner.get("world"), Some(world));
+        assert_eq!(interner.get("there"), None);
+        assert_eq!(interner.get(""), None);
+        assert_eq!(interner.intern("again"), 1);
+    }
+}
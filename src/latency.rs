@@ -0,0 +1,249 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// The span that each message is handled in.
+const MESSAGE_SPAN: &str = "message";
+/// The spans of the stages of handling a message, which are timed.
+const STAGE_SPANS: [&str; 7] = [
+    "lock",
+    "load",
+    "learn",
+    "normalize",
+    "persist",
+    "generate",
+    "send",
+];
+
+/// Past this many milliseconds, handling a message is logged as slow. Never, by
+/// default.
+static SLOW_MESSAGE_THRESHOLD_MS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+pub(crate) fn set_slow_message_threshold(threshold: Duration) {
+    SLOW_MESSAGE_THRESHOLD_MS.store(threshold.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Times the stages of handling each message, and warns when handling one
+/// takes longer than the slow message threshold, with how long each stage took
+/// and how large the memory was. Messages are handled once their span is last
+/// exited, while their replies are sent later on, so sending them doesn't count
+/// towards the threshold, but it's still timed, and the warning only comes
+/// once their replies are sent.
+pub(crate) struct SlowMessageLayer;
+
+struct StartedAt(Instant);
+
+/// When the handler of the message last returned.
+struct LastExitedAt(Instant);
+
+/// How long each stage of handling a message took, in the order they were
+/// first reached, along with the fields of the span of the message.
+#[derive(Default)]
+struct MessageTimings {
+    stages: Vec<(&'static str, Duration)>,
+    chat_id: Option<i64>,
+    message_id: Option<i64>,
+    command: Option<String>,
+    phrase_count: Option<u64>,
+}
+
+impl MessageTimings {
+    fn add(&mut self, stage: &'static str, duration: Duration) {
+        match self.stages.iter_mut().find(|(name, _)| *name == stage) {
+            Some((_, total)) => *total += duration,
+            None => self.stages.push((stage, duration)),
+        }
+    }
+}
+
+impl Visit for MessageTimings {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        match field.name() {
+            "chat_id" => self.chat_id = Some(value),
+            "message_id" => self.message_id = Some(value),
+            "phrase_count" => self.phrase_count = Some(value as u64),
+            _ => {}
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "phrase_count" => self.phrase_count = Some(value),
+            _ => self.record_i64(field, value as i64),
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "command" {
+            self.command = Some(value.into());
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+impl<S> Layer<S> for SlowMessageLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attributes: &Attributes<'_>, id: &Id, context: Context<'_, S>) {
+        let span = match context.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut extensions = span.extensions_mut();
+
+        if span.name() == MESSAGE_SPAN {
+            let mut timings = MessageTimings::default();
+            attributes.record(&mut timings);
+            extensions.insert(timings);
+        } else if !STAGE_SPANS.contains(&span.name()) {
+            return;
+        }
+
+        extensions.insert(StartedAt(Instant::now()));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, context: Context<'_, S>) {
+        if let Some(span) = context.span(id) {
+            if let Some(timings) = span.extensions_mut().get_mut::<MessageTimings>() {
+                values.record(timings);
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, context: Context<'_, S>) {
+        if let Some(span) = context.span(id) {
+            if span.name() == MESSAGE_SPAN {
+                span.extensions_mut().replace(LastExitedAt(Instant::now()));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, context: Context<'_, S>) {
+        let span = match context.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let started_at = match span.extensions().get::<StartedAt>() {
+            Some(started_at) => started_at.0,
+            None => return,
+        };
+
+        if span.name() != MESSAGE_SPAN {
+            let message_span = span
+                .scope()
+                .skip(1)
+                .find(|ancestor| ancestor.name() == MESSAGE_SPAN);
+
+            if let Some(message_span) = message_span {
+                if let Some(timings) = message_span.extensions_mut().get_mut::<MessageTimings>() {
+                    timings.add(span.name(), started_at.elapsed());
+                }
+            }
+
+            return;
+        }
+
+        let handled_at = span
+            .extensions()
+            .get::<LastExitedAt>()
+            .map_or_else(Instant::now, |last_exited_at| last_exited_at.0);
+        let elapsed = handled_at.saturating_duration_since(started_at);
+        let timings = match span.extensions_mut().remove::<MessageTimings>() {
+            Some(timings) => timings,
+            None => return,
+        };
+        drop(span);
+
+        if elapsed.as_millis() as u64 <= SLOW_MESSAGE_THRESHOLD_MS.load(Ordering::Relaxed) {
+            return;
+        }
+
+        tracing::warn!(
+            parent: None,
+            chat_id = timings.chat_id,
+            message_id = timings.message_id,
+            command = timings.command.as_deref(),
+            phrase_count = timings.phrase_count,
+            elapsed_ms = elapsed.as_millis() as u64,
+            stages = %format_stages(&timings.stages),
+            "handling message took {:?}",
+            elapsed
+        );
+    }
+}
+
+/// E.g. `lock=0ms learn=12ms normalize=1ms persist=8ms`.
+fn format_stages(stages: &[(&'static str, Duration)]) -> String {
+    stages
+        .iter()
+        .map(|(stage, duration)| format!("{}={}ms", stage, duration.as_millis()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod latency_tests {
+    use super::{set_slow_message_threshold, SlowMessageLayer};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tracing::field::{Field, Visit};
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+    /// Keeps the fields of every event, formatted.
+    #[derive(Clone, Default)]
+    struct EventCollector(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for EventCollector {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for EventCollector {
+        fn on_event(&self, event: &Event<'_>, _context: Context<'_, S>) {
+            event.record(&mut self.clone());
+        }
+    }
+
+    #[test]
+    fn should_warn_about_slow_messages_with_timings_of_their_stages() {
+        let events = EventCollector::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(SlowMessageLayer)
+            .with(events.clone());
+        set_slow_message_threshold(Duration::ZERO);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let message_span = tracing::info_span!(
+                "message",
+                chat_id = -100123,
+                phrase_count = tracing::field::Empty
+            );
+            let _handling = message_span.enter();
+
+            for _ in 0..2 {
+                let _persisting = tracing::info_span!("persist").entered();
+                std::thread::sleep(Duration::from_millis(2));
+            }
+            message_span.record("phrase_count", 42);
+        });
+
+        let fields = events.0.lock().unwrap().join(" ");
+        assert!(fields.contains("chat_id=-100123"));
+        assert!(fields.contains("phrase_count=42"));
+        assert!(fields.contains("stages=persist="));
+        assert!(!fields.contains("persist=0ms"));
+        assert!(!fields.contains("persist=1ms"));
+    }
+}
@@ -0,0 +1,356 @@
+use feroldinhobot::phrase_indexing::{IndexedPhrases, Phrase};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Kinds of text that would only pollute the vocabulary if learned.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub(crate) enum JunkKind {
+    AsciiArt,
+    StretchedText,
+    KeyboardMashing,
+    /// Words mostly unknown to the memory, which don't look like words either,
+    /// e.g. random strings or encoded blobs.
+    Garbage,
+    /// Phrases the quality scorer of the memory rates too low to learn from.
+    LowQuality,
+}
+
+const ALL_JUNK_KINDS: [JunkKind; 5] = [
+    JunkKind::AsciiArt,
+    JunkKind::StretchedText,
+    JunkKind::KeyboardMashing,
+    JunkKind::Garbage,
+    JunkKind::LowQuality,
+];
+
+impl fmt::Display for JunkKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            JunkKind::AsciiArt => "ascii art",
+            JunkKind::StretchedText => "stretched text",
+            JunkKind::KeyboardMashing => "keyboard mashing",
+            JunkKind::Garbage => "garbage",
+            JunkKind::LowQuality => "low quality",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// Texts with at least this many drawing characters, which make up more than
+/// the ratio below of the text, are considered drawings.
+const ASCII_ART_MIN_DRAWING_CHARS: usize = 10;
+const ASCII_ART_MIN_DRAWING_RATIO: f32 = 0.5;
+
+/// Ratio of characters that merely stretch a previous one (e.g. the extra
+/// "o"s in "looooool") above which a phrase is considered stretched.
+const STRETCHED_TEXT_MIN_REPETITION_RATIO: f32 = 0.3;
+
+/// Ratio of words that don't look like words above which a phrase is
+/// considered keyboard mashing.
+const KEYBOARD_MASHING_MIN_NON_WORD_RATIO: f32 = 0.5;
+
+/// Words longer than this don't look like words, but like encoded blobs.
+const GARBAGE_MIN_WORD_LEN: usize = 25;
+/// Words at least this long that mix letters and digits look like random
+/// strings, rather than like e.g. "mp3" or "4k".
+const GARBAGE_MIN_ALPHANUMERIC_LEN: usize = 6;
+
+static REJECTION_COUNTS: [AtomicUsize; ALL_JUNK_KINDS.len()] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Checks a whole message before it's split into phrases, as drawings only
+/// make sense as a whole.
+pub(crate) fn check_text(text: &str) -> Result<(), JunkKind> {
+    let visible_chars = text.chars().filter(|c| !c.is_whitespace());
+    let (visible_count, drawing_count) = visible_chars.fold((0, 0), |(visible, drawing), c| {
+        (visible + 1, drawing + is_drawing_char(c) as usize)
+    });
+
+    if drawing_count >= ASCII_ART_MIN_DRAWING_CHARS
+        && drawing_count as f32 / visible_count as f32 > ASCII_ART_MIN_DRAWING_RATIO
+    {
+        return reject(JunkKind::AsciiArt);
+    }
+
+    Ok(())
+}
+
+/// Checks a normalized phrase.
+pub(crate) fn check_phrase(phrase: &str) -> Result<(), JunkKind> {
+    if repetition_ratio(phrase) > STRETCHED_TEXT_MIN_REPETITION_RATIO {
+        return reject(JunkKind::StretchedText);
+    }
+
+    if non_word_ratio(phrase) >= KEYBOARD_MASHING_MIN_NON_WORD_RATIO {
+        return reject(JunkKind::KeyboardMashing);
+    }
+
+    Ok(())
+}
+
+/// Judges phrases against the memory learning them: by the quality its
+/// `QualityScorer` rates them with, and by how many of their words it already
+/// knows, once it knows enough phrases for its vocabulary to tell.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub(crate) struct PhraseFilter {
+    /// Phrases rated below this quality are rejected. Zero never rejects any.
+    pub(crate) min_quality: f32,
+    /// Phrases with fewer known words than this ratio are rejected when most
+    /// of their unknown words don't look like words. Zero never rejects any.
+    pub(crate) min_known_word_ratio: f32,
+    /// Memories with fewer phrases than this learn every phrase.
+    pub(crate) warm_up_phrase_count: usize,
+}
+
+impl PhraseFilter {
+    pub(crate) fn check_phrase(
+        &self,
+        phrase: &Phrase,
+        indexed_phrases: &IndexedPhrases,
+    ) -> Result<(), JunkKind> {
+        if indexed_phrases.quality_scorer().score(phrase) < self.min_quality {
+            return reject(JunkKind::LowQuality);
+        }
+
+        if indexed_phrases.phrase_count() < self.warm_up_phrase_count {
+            return Ok(());
+        }
+
+        let words: Vec<_> = phrase.as_ref().split_ascii_whitespace().collect();
+
+        if words.is_empty() {
+            return Ok(());
+        }
+
+        let unknown_words: Vec<_> = words
+            .iter()
+            .filter(|word| indexed_phrases.get_word_id(word).is_none())
+            .collect();
+
+        let known_word_ratio = (words.len() - unknown_words.len()) as f32 / words.len() as f32;
+        let non_word_count = unknown_words
+            .iter()
+            .filter(|word| looks_like_garbage(word))
+            .count();
+
+        if known_word_ratio < self.min_known_word_ratio && non_word_count * 2 > unknown_words.len()
+        {
+            return reject(JunkKind::Garbage);
+        }
+
+        Ok(())
+    }
+}
+
+/// Number of texts and phrases rejected so far for each kind of junk.
+pub(crate) fn rejection_counts() -> Vec<(JunkKind, usize)> {
+    ALL_JUNK_KINDS
+        .iter()
+        .map(|&kind| {
+            (
+                kind,
+                REJECTION_COUNTS[kind as usize].load(Ordering::Relaxed),
+            )
+        })
+        .collect()
+}
+
+fn reject(kind: JunkKind) -> Result<(), JunkKind> {
+    REJECTION_COUNTS[kind as usize].fetch_add(1, Ordering::Relaxed);
+    Err(kind)
+}
+
+/// ASCII symbols, as well as box drawing, block and braille characters.
+fn is_drawing_char(c: char) -> bool {
+    c.is_ascii_punctuation()
+        || ('\u{2500}'..='\u{259F}').contains(&c)
+        || ('\u{2800}'..='\u{28FF}').contains(&c)
+}
+
+fn repetition_ratio(phrase: &str) -> f32 {
+    let mut char_count = 0;
+    let mut repeated_count = 0;
+    let mut previous_chars = (None, None);
+
+    for c in phrase.chars().filter(|c| !c.is_whitespace()) {
+        // Doubled letters are common in most languages, so only the third one
+        // onwards counts as stretching.
+        if previous_chars == (Some(c), Some(c)) {
+            repeated_count += 1;
+        }
+
+        previous_chars = (previous_chars.1, Some(c));
+        char_count += 1;
+    }
+
+    if char_count == 0 {
+        return 0.0;
+    }
+
+    repeated_count as f32 / char_count as f32
+}
+
+/// Only judges words written in ASCII letters, which is where mashing happens
+/// on most keyboards. Other words are considered fine.
+fn non_word_ratio(phrase: &str) -> f32 {
+    let words: Vec<_> = phrase.split_ascii_whitespace().collect();
+
+    if words.is_empty() {
+        return 0.0;
+    }
+
+    let non_word_count = words.iter().filter(|word| looks_like_mashing(word)).count();
+
+    non_word_count as f32 / words.len() as f32
+}
+
+fn looks_like_mashing(word: &str) -> bool {
+    const MIN_JUDGED_LEN: usize = 5;
+    // Long enough for words like "strengths".
+    const MAX_CONSONANT_RUN: usize = 5;
+
+    if word.len() < MIN_JUDGED_LEN || !word.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return false;
+    }
+
+    let is_vowel = |b: u8| b"aeiouyAEIOUY".contains(&b);
+
+    let mut consonant_run = 0;
+    for b in word.bytes() {
+        if is_vowel(b) {
+            consonant_run = 0;
+        } else {
+            consonant_run += 1;
+
+            if consonant_run > MAX_CONSONANT_RUN {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+fn looks_like_garbage(word: &str) -> bool {
+    let has_letters = word.chars().any(char::is_alphabetic);
+    let has_digits = word.chars().any(|c| c.is_ascii_digit());
+
+    word.chars().count() >= GARBAGE_MIN_WORD_LEN
+        || (has_letters && has_digits && word.len() >= GARBAGE_MIN_ALPHANUMERIC_LEN)
+        || looks_like_mashing(word)
+}
+
+#[cfg(test)]
+mod learn_filter_tests {
+    use super::{check_phrase, check_text, JunkKind, PhraseFilter};
+    use feroldinhobot::phrase_indexing::{IndexedPhrases, Phrase};
+
+    #[test]
+    fn should_reject_ascii_art() {
+        let text = "  (\\_/)\n  (o.o)\n (> <)\n=========";
+
+        assert_eq!(check_text(text), Err(JunkKind::AsciiArt));
+        assert_eq!(
+            check_text("┌──────┐\n│ hi   │\n└──────┘"),
+            Err(JunkKind::AsciiArt)
+        );
+    }
+
+    #[test]
+    fn should_accept_text_with_some_punctuation() {
+        assert_eq!(check_text("well... that's it, isn't it?! (yes)"), Ok(()));
+        assert_eq!(check_text("!!!"), Ok(()));
+    }
+
+    #[test]
+    fn should_reject_stretched_text() {
+        assert_eq!(check_phrase("looooooool"), Err(JunkKind::StretchedText));
+        assert_eq!(check_phrase("aaaaaaaaaa"), Err(JunkKind::StretchedText));
+    }
+
+    #[test]
+    fn should_accept_phrases_with_doubled_or_slightly_stretched_letters() {
+        assert_eq!(check_phrase("good coffee"), Ok(()));
+        assert_eq!(check_phrase("that was sooo good my friend"), Ok(()));
+    }
+
+    #[test]
+    fn should_reject_keyboard_mashing() {
+        assert_eq!(
+            check_phrase("asdfgh jklqwrt"),
+            Err(JunkKind::KeyboardMashing)
+        );
+        assert_eq!(
+            check_phrase("lol sdfkjhsdf"),
+            Err(JunkKind::KeyboardMashing)
+        );
+    }
+
+    #[test]
+    fn should_accept_regular_phrases() {
+        assert_eq!(check_phrase("strengths of the rhythm"), Ok(()));
+        assert_eq!(check_phrase("strengths"), Ok(()));
+        assert_eq!(check_phrase("você está aqui"), Ok(()));
+        assert_eq!(check_phrase("ok"), Ok(()));
+    }
+
+    #[test]
+    fn should_reject_garbage_of_unknown_words_once_warmed_up() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("check out this song"));
+        indexed_phrases.insert_phrase(Phrase::from("what a good song"));
+
+        let filter = PhraseFilter {
+            min_quality: 0.0,
+            min_known_word_ratio: 0.5,
+            warm_up_phrase_count: 2,
+        };
+
+        assert_eq!(
+            filter.check_phrase(&Phrase::from("sgvsbg8gd29ybgq x7fk2q9z"), &indexed_phrases),
+            Err(JunkKind::Garbage)
+        );
+        assert_eq!(
+            filter.check_phrase(&Phrase::from("check out x7fk2q9z"), &indexed_phrases),
+            Ok(())
+        );
+        assert_eq!(
+            filter.check_phrase(&Phrase::from("brand new words"), &indexed_phrases),
+            Ok(())
+        );
+
+        let cold_filter = PhraseFilter {
+            warm_up_phrase_count: 3,
+            ..filter
+        };
+        assert_eq!(
+            cold_filter.check_phrase(&Phrase::from("sgvsbg8gd29ybgq x7fk2q9z"), &indexed_phrases),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn should_reject_phrases_scored_below_min_quality() {
+        let indexed_phrases = IndexedPhrases::new();
+        let filter = PhraseFilter {
+            min_quality: 0.5,
+            min_known_word_ratio: 0.0,
+            warm_up_phrase_count: 0,
+        };
+
+        assert_eq!(
+            filter.check_phrase(&Phrase::from("k k k k k k k k"), &indexed_phrases),
+            Err(JunkKind::LowQuality)
+        );
+        assert_eq!(
+            filter.check_phrase(&Phrase::from("what a good song"), &indexed_phrases),
+            Ok(())
+        );
+    }
+}
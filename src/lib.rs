@@ -0,0 +1,12 @@
+//! The phrase-splicing engine behind the bot: phrases are indexed by the words
+//! in them, and new ones are made by splicing two phrases at a word they share.
+
+pub mod aliases;
+pub mod answer_pool;
+pub mod garnish;
+pub mod generation;
+pub mod interner;
+pub mod output;
+pub mod phrase_indexing;
+pub mod scoring;
+pub mod sources;
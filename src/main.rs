@@ -1,221 +1,3466 @@
-mod phrase_indexing;
+mod adapter;
+mod auth;
+mod backup;
+mod banlist;
+mod changes;
+mod chaos;
+mod commands;
+mod config;
+mod contributions;
+mod dashboard;
+mod dedup;
+mod discord;
+mod emoji;
+mod error;
+mod favorites;
+mod feeds;
+mod http;
+mod latency;
+mod learn_filter;
+mod matrix;
+mod memory;
+mod mentions;
+mod outgoing;
+mod pending;
+mod public_api;
+mod repl;
+mod schedule;
+mod settings;
+mod setup;
+mod short_term;
+mod shutdown;
+mod snapshot;
+mod social;
+mod store;
+mod telemetry;
+mod trends;
+mod updates;
+mod webhook;
+mod writer;
 
-use crate::phrase_indexing::{IndexedPhrases, WordIndex};
+use crate::adapter::{AdapterReplyConfig, ChatAdapter};
+use crate::auth::{Admins, ApiTokens};
+use crate::backup::{BackupTarget, Credentials};
+use crate::banlist::BanlistImport;
+use crate::changes::ChangeLog;
+use crate::chaos::{ChaosConfig, ChaosSender};
+use crate::config::{Config, DatabaseKind, Platform, StopWordsConfig};
+use crate::contributions::Contributions;
+use crate::dashboard::{
+    BotStatus, Control, DashboardBackend, DashboardFuture, MemoryStatus, RecentReplies,
+};
+use crate::dedup::RecentMessages;
+use crate::discord::DiscordAdapter;
+use crate::emoji::EmojiTracker;
+use crate::error::Error;
+use crate::favorites::FavoriteReplies;
+use crate::feeds::FeedConfig;
+use crate::learn_filter::PhraseFilter;
+use crate::matrix::MatrixAdapter;
+use crate::memory::{
+    read_memory, write_memory, ChatLanguage, LanguageRejection, Memories, Memory, MemoryScope,
+    SharedMemory,
+};
+use crate::outgoing::{
+    EngagementBoost, MessageSender, OutgoingQueue, QueueConfig, ReplySuppression,
+    StartupReplayGuard,
+};
+use crate::pending::{PendingChanges, ProposedChange};
+use crate::public_api::Candidate;
+use crate::schedule::{ReplySchedule, ScheduleRule};
+use crate::settings::SettingsStore;
+use crate::social::SocialConfig;
+use crate::store::{FlatFileStore, LineAttributes, PhraseStore, SqliteStore};
+use crate::trends::WordTrends;
+use crate::writer::{BackgroundWriteStore, WriteQueueConfig};
+use feroldinhobot::aliases;
+use feroldinhobot::garnish::Garnisher;
+use feroldinhobot::generation::{self, SentenceConfig};
+use feroldinhobot::output::{self, LengthGuard, OverflowPolicy, ReplyStyler};
+use feroldinhobot::phrase_indexing::{self, EngineError, NormalizationConfig, WordId};
+use feroldinhobot::sources::{PhraseSource, SourceQuotas};
 use rand::{self, Rng, SeedableRng};
-use std::collections::HashSet;
-use std::io;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
 use std::path::Path;
-use tbot::{prelude::*, Bot};
-use tokio::sync::Mutex;
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tbot::{
+    contexts,
+    state::StatefulEventLoop,
+    types::{chat, message, user, Message, User},
+    Bot,
+};
+use tokio::sync::{mpsc, Mutex};
+use tracing::Instrument;
+
+const NOTABLE_NEW_WORD_COUNT: usize = 10;
+const BEST_REPLY_COUNT: usize = 5;
+const TRENDING_WORD_COUNT: usize = 5;
+/// How many of the words said lately in a chat seed its unprompted messages.
+const UNPROMPTED_SEED_WORD_COUNT: usize = 20;
+const RELATED_WORD_COUNT: usize = 5;
+const BEST_REPLIES_WINDOW_SECS: i64 = 30 * 24 * 60 * 60;
+/// How often expired phrases are removed from memory and from disk, until which
+/// they are only left out when generating replies, and how often short-term
+/// memory is folded into long-term memory.
+const MEMORY_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How often the offset of the handled updates is saved, so that at most the
+/// updates handled meanwhile are received again after a crash.
+const UPDATE_OFFSET_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Setting holding the users whose messages are never learned.
+const OPTED_OUT_USERS_SETTING: &str = "opted_out_users";
+/// Setting holding the words whose spelling replies keep, by chat.
+const PROTECTED_WORDS_SETTING: &str = "protected_words";
+/// Setting holding the words banned with `/banlist import`, on top of the
+/// configured blocked words.
+const BANNED_WORDS_SETTING: &str = "banned_words";
+/// Setting holding the chats whose memories are archived, see `/archive`.
+const ARCHIVED_CHATS_SETTING: &str = "archived_chats";
+/// Setting holding the reply schedules set with `/schedule`, by chat.
+const REPLY_SCHEDULES_SETTING: &str = "reply_schedules";
+/// Setting holding the words nerfed with `/nerf`, by chat.
+const WORD_NERFS_SETTING: &str = "word_nerfs";
+/// Setting holding the languages set with `/setlang`, by chat.
+const CHAT_LANGUAGES_SETTING: &str = "chat_languages";
 
 struct BotState {
-    indexed_phrases: IndexedPhrases,
+    memories: Memories,
+    source_quotas: Arc<std::sync::Mutex<SourceQuotas>>,
+    change_log: ChangeLog,
+    emoji_tracker: Arc<std::sync::Mutex<EmojiTracker>>,
+    favorite_replies: FavoriteReplies,
+    normalization_config: NormalizationConfig,
+    phrase_filter: PhraseFilter,
     reply_prob: f32,
+    /// Probabilities that take the place of `reply_prob` at some times.
+    reply_schedule: ReplySchedule,
+    /// Whether to reply only when mentioned or replied to, rather than with
+    /// `reply_prob`.
+    mentions_only: bool,
+    /// Who the bot is, as long as it could be fetched at startup.
+    bot_user: Option<User>,
+    /// Probability of a reply being made of emojis only.
+    emoji_reply_prob: f32,
+    sentence_config: SentenceConfig,
+    reply_styler: Arc<ReplyStyler>,
+    garnisher: Arc<Garnisher>,
+    length_guard: LengthGuard,
+    outgoing_queue: OutgoingQueue<Box<dyn MessageSender>>,
+    reply_suppression: ReplySuppression,
+    engagement_boost: EngagementBoost,
+    startup_replay_guard: StartupReplayGuard,
+    /// Channels whose posts the bot learns from, without ever replying there.
+    followed_channels: HashSet<chat::Id>,
+    admins: Admins,
+    recent_messages: RecentMessages,
+    settings: SettingsStore,
+    pending_changes: PendingChanges,
+    /// Chats that the bot still learns from, but never replies to on its own.
+    muted_chats: HashSet<chat::Id>,
+    /// Users who asked not to be learned from, see `/optout`.
+    opted_out_users: HashSet<user::Id>,
+    /// Words, such as nicknames, that replies always spell as they were
+    /// protected, by chat, see `/protect`.
+    protected_words: HashMap<chat::Id, Vec<String>>,
+    /// Words banned with `/banlist import`.
+    banned_words: Vec<String>,
+    recent_replies: RecentReplies,
+    word_trends: WordTrends,
+    contributions: Contributions,
+    /// Stop words set up for each language, which `/setlang` picks from.
+    stop_words_config: StopWordsConfig,
+    /// Offset of the update after the last one that was handled, which is only
+    /// saved every `UPDATE_OFFSET_SAVE_INTERVAL` and at shutdown.
+    handled_update_offset: Option<isize>,
     rng: rand::rngs::StdRng,
 }
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    env_logger::init();
+/// What was learned from a text.
+#[derive(Default)]
+struct LearnedText {
+    word_ids_from_phrases: HashSet<WordId>,
+    /// The phrases of the text that passed the learn filter.
+    phrases: Vec<String>,
+    /// The phrases indexed into memory, whether they were known already or not.
+    indexed_phrases: Vec<String>,
+    new_phrase_count: usize,
+    /// Words that weren't part of any phrase before.
+    new_words: Vec<String>,
+}
 
-    let database_path = Path::new("bot_memory.txt");
+/// Indexes the phrases of `text` into `memory`, and stores the new ones.
+fn learn_into_memory(
+    memory: &mut Memory,
+    text: &str,
+    source: PhraseSource,
+    expires_at: Option<i64>,
+    normalization_config: &NormalizationConfig,
+    phrase_filter: PhraseFilter,
+    source_quotas: &std::sync::Mutex<SourceQuotas>,
+) -> LearnedText {
+    let mut learned_text = LearnedText::default();
 
-    let state = BotState {
-        indexed_phrases: init_indexed_phrases(database_path)?,
-        reply_prob: 0.0,
-        rng: rand::rngs::StdRng::from_entropy(),
-    };
+    if let Err(junk_kind) = learn_filter::check_text(text) {
+        tracing::info!("not learning text, it looks like {}", junk_kind);
+        return learned_text;
+    }
 
-    let mut bot = Bot::from_env("BOT_TOKEN").stateful_event_loop(Mutex::new(state));
+    // How large the memory is, for telling apart messages handled slowly
+    // because of it.
+    tracing::Span::current().record("phrase_count", memory.indexed_phrases.phrase_count());
 
-    bot.text(move |context, state| async move {
-        let state = &mut *state.lock().await;
+    let _learning = tracing::info_span!("learn").entered();
+
+    let phrases = tracing::info_span!("normalize").in_scope(|| {
+        phrase_indexing::normalize_text_into_phrases(text.into(), normalization_config)
+    });
 
-        let mut word_indices_from_phrases = HashSet::new();
+    // Replies are still made around the words already known.
+    if memory.archived {
+        tracing::info!("not learning text, the memory is archived");
+        learned_text.word_ids_from_phrases = phrases
+            .iter()
+            .flat_map(|phrase| phrase.as_ref().split_ascii_whitespace())
+            .filter_map(|word| memory.indexed_phrases.get_word_id(word))
+            .collect();
+        return learned_text;
+    }
 
-        let msg_text = &context.text.value;
-        for phrase in phrase_indexing::normalize_text_into_phrases(msg_text.into()) {
-            let insertion_res = state.indexed_phrases.insert_phrase(phrase.clone());
+    if phrases
+        .iter()
+        .any(|phrase| memory.indexed_phrases.has_blocked_word(phrase.as_ref()))
+    {
+        tracing::info!("not learning text, it has a blocked word");
+        return learned_text;
+    }
 
-            word_indices_from_phrases.extend(insertion_res.word_indices_from_phrase);
+    for phrase in phrases {
+        let checked_phrase = learn_filter::check_phrase(phrase.as_ref())
+            .and_then(|()| phrase_filter.check_phrase(&phrase, &memory.indexed_phrases));
+        if let Err(junk_kind) = checked_phrase {
+            tracing::info!("not learning phrase, it looks like {}", junk_kind);
+            continue;
+        }
 
-            if !insertion_res.has_inserted_phrase {
-                continue;
+        learned_text.phrases.push(phrase.as_ref().to_string());
+
+        let has_quota = source_quotas
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .try_learn(source);
+        if !has_quota {
+            tracing::info!(
+                "dropping phrase from {} source, its quota is exhausted",
+                source
+            );
+            continue;
+        }
+
+        let uncommon_words: Vec<String> = phrase
+            .as_ref()
+            .split_ascii_whitespace()
+            .filter(|word| {
+                memory
+                    .indexed_phrases
+                    .get_word_id(word)
+                    .is_none_or(|word_id| !memory.indexed_phrases.is_common_word(word_id))
+            })
+            .map(String::from)
+            .collect();
+        let phrase_count_before = memory.indexed_phrases.phrase_count();
+
+        let mut phrase_to_insert = phrase.clone().with_source(source);
+        if let Some(expires_at) = expires_at {
+            phrase_to_insert = phrase_to_insert.with_expiry(expires_at);
+        }
+
+        let insertion_res = memory.indexed_phrases.insert_phrase(phrase_to_insert);
+        learned_text
+            .indexed_phrases
+            .push(phrase.as_ref().to_string());
+
+        memory.answer_pools.invalidate(
+            &memory.indexed_phrases,
+            insertion_res.word_ids_from_phrase.iter().copied(),
+        );
+        learned_text
+            .word_ids_from_phrases
+            .extend(insertion_res.word_ids_from_phrase);
+
+        if !insertion_res.has_inserted_phrase {
+            continue;
+        }
+
+        learned_text.new_phrase_count +=
+            memory.indexed_phrases.phrase_count() - phrase_count_before;
+
+        for word in uncommon_words {
+            let is_known = memory.indexed_phrases.get_word_id(&word).is_some();
+            if is_known && !learned_text.new_words.contains(&word) {
+                learned_text.new_words.push(word);
             }
+        }
 
-            if let Err(err) = store_line_in_database(database_path, phrase.as_ref()) {
-                log::error!(
-                    "couldn't store line in database: `{}`, due to error: {}",
-                    phrase.as_ref(),
-                    err
-                )
+        let _persisting = tracing::info_span!("persist").entered();
+        let attributes = LineAttributes {
+            source,
+            expires_at,
+            occurrences: 1,
+        };
+        let line = store::format_line(&phrase.to_line(), attributes);
+        if let Err(err) = memory.phrase_store.append(&line) {
+            error::report_error(&err);
+        }
+    }
+
+    learned_text
+}
+
+/// What generating replies for a chat takes out of the state, so that the state
+/// isn't held while generating, and the memory of the chat is only read, which
+/// lets replies for many chats be generated at once.
+struct Speaker {
+    memory: SharedMemory,
+    sentence_config: SentenceConfig,
+    garnisher: Arc<Garnisher>,
+    reply_styler: Arc<ReplyStyler>,
+    protected_words: Vec<String>,
+    normalization_config: NormalizationConfig,
+    rng: rand::rngs::StdRng,
+}
+
+impl Speaker {
+    /// Makes up a phrase around a random word of the memory. Returns `None`
+    /// if the memory is empty.
+    fn think(&mut self, now: i64) -> error::Result<Option<String>> {
+        Ok(self.think_candidate(now)?.map(|candidate| candidate.text))
+    }
+
+    /// Makes up a reply like `think` as a candidate for the public API, rated
+    /// like the phrases learned by the phrase spliced around the word.
+    fn think_candidate(&mut self, now: i64) -> error::Result<Option<Candidate>> {
+        let (response, score) = {
+            let memory = &*read_memory(&self.memory);
+
+            let response = memory
+                .indexed_phrases
+                .get_random_common_word(&mut self.rng)
+                .and_then(|word| {
+                    generation::splice_phrases_around_word(
+                        &memory.indexed_phrases,
+                        &memory.answer_pools,
+                        word,
+                        now,
+                        &mut self.rng,
+                    )
+                });
+
+            let response = match response {
+                Ok(response) => response,
+                Err(EngineError::EmptyCorpus) => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+            let score = memory.indexed_phrases.score_phrase(&response);
+
+            let response = generation::extend_into_sentences(
+                &memory.indexed_phrases,
+                &memory.answer_pools,
+                response,
+                &self.sentence_config,
+                now,
+                &mut self.rng,
+            );
+            let response = self
+                .garnisher
+                .garnish(&memory.indexed_phrases, response, &mut self.rng);
+            (memory.indexed_phrases.restore_acronyms(&response), score)
+        };
+
+        Ok(Some(Candidate {
+            text: self.render(&response),
+            score,
+        }))
+    }
+
+    /// Generates a reply around the words of `seed_text`, without learning it,
+    /// or around random common words if there's no seed, or if nothing could be
+    /// generated from it.
+    fn speak(&mut self, seed_text: Option<&str>, now: i64) -> error::Result<Option<String>> {
+        let seed_text = match seed_text {
+            Some(seed_text) => seed_text,
+            None => return self.think(now),
+        };
+
+        let response = {
+            let memory = &*read_memory(&self.memory);
+
+            let seed_phrases = phrase_indexing::normalize_text_into_phrases(
+                seed_text.into(),
+                &self.normalization_config,
+            );
+            let seed_phrases: Vec<_> = seed_phrases.iter().map(AsRef::as_ref).collect();
+            let word_ids = seed_phrases
+                .iter()
+                .flat_map(|phrase| phrase.split_ascii_whitespace())
+                .filter_map(|word| memory.indexed_phrases.get_word_id(word))
+                .collect();
+
+            let response = generation::generate_phrase(
+                &memory.indexed_phrases,
+                &memory.answer_pools,
+                word_ids,
+                &seed_phrases,
+                now,
+                &mut self.rng,
+            )?;
+
+            response.map(|response| {
+                let response = generation::extend_into_sentences(
+                    &memory.indexed_phrases,
+                    &memory.answer_pools,
+                    response,
+                    &self.sentence_config,
+                    now,
+                    &mut self.rng,
+                );
+                let response =
+                    self.garnisher
+                        .garnish(&memory.indexed_phrases, response, &mut self.rng);
+                memory.indexed_phrases.restore_acronyms(&response)
+            })
+        };
+
+        match response {
+            Some(response) => Ok(Some(self.render(&response))),
+            None => self.think(now),
+        }
+    }
+
+    /// Puts back the spelling of the words protected in the chat, and styles
+    /// the response into a reply.
+    fn render(&mut self, response: &str) -> String {
+        let response = phrase_indexing::restore_protected_words(
+            response,
+            &self.protected_words,
+            &self.normalization_config,
+        );
+
+        self.reply_styler.style(&response, &mut self.rng)
+    }
+}
+
+impl BotState {
+    /// Saves the offset of the handled updates, unless it was saved already.
+    fn save_update_offset(&mut self) {
+        if let Some(update_offset) = self.handled_update_offset {
+            let last_update_offset = self.settings.get::<isize>(updates::UPDATE_OFFSET_SETTING);
+
+            if last_update_offset
+                .is_none_or(|last_update_offset| last_update_offset < update_offset)
+            {
+                if let Err(err) = self
+                    .settings
+                    .set(updates::UPDATE_OFFSET_SETTING, update_offset)
+                {
+                    error::report_error(&err);
+                }
             }
         }
+    }
+
+    /// Takes what generating replies for the chat needs, loading its memory if
+    /// needed.
+    fn speaker(&mut self, chat_id: Option<chat::Id>) -> error::Result<Speaker> {
+        let memory = self
+            .memories
+            .get_shared(chat_id, &self.normalization_config)?;
+        let protected_words = chat_id
+            .and_then(|chat_id| self.protected_words.get(&chat_id))
+            .cloned()
+            .unwrap_or_default();
+
+        Ok(Speaker {
+            memory,
+            sentence_config: self.sentence_config.clone(),
+            garnisher: Arc::clone(&self.garnisher),
+            reply_styler: Arc::clone(&self.reply_styler),
+            protected_words,
+            normalization_config: self.normalization_config_of(chat_id),
+            rng: rand::rngs::StdRng::from_seed(self.rng.gen()),
+        })
+    }
+
+    /// Indexes the phrases of `text` into the memory of the chat, or into the
+    /// shared memory if there's no chat, and stores the new ones.
+    fn learn_text(
+        &mut self,
+        chat_id: Option<chat::Id>,
+        text: &str,
+        source: PhraseSource,
+        expires_at: Option<i64>,
+    ) -> error::Result<LearnedText> {
+        let memory = tracing::info_span!("load").in_scope(|| {
+            self.memories
+                .get_shared(chat_id, &self.normalization_config)
+        })?;
+        let normalization_config = self.normalization_config_of(chat_id);
+        let learned_text = learn_into_memory(
+            &mut write_memory(&memory),
+            text,
+            source,
+            expires_at,
+            &normalization_config,
+            self.phrase_filter,
+            &self.source_quotas,
+        );
+
+        if let (Some(chat_id), false) = (chat_id, learned_text.indexed_phrases.is_empty()) {
+            self.memories.remember_short_term(
+                chat_id,
+                &learned_text.indexed_phrases,
+                unix_now(),
+                &normalization_config,
+            )?;
+        }
+
+        Ok(learned_text)
+    }
 
-        if state.rng.gen::<f32>() >= state.reply_prob {
+    /// Counts a message sent at `now` as a vote for the reply of the bot it
+    /// replies to, if it's positive feedback.
+    fn record_feedback(&mut self, text: &str, reply_to: &Message, voter: &User, now: i64) {
+        let replied_text = match &reply_to.kind {
+            message::Kind::Text(replied_text) => &replied_text.value,
+            _ => return,
+        };
+
+        let is_reply_of_bot = reply_to.from.as_ref().is_some_and(|user| user.is_bot);
+
+        if !is_reply_of_bot || voter.is_bot || !favorites::is_positive_feedback(text) {
             return;
         }
 
-        let generated_response = generate_phrase(
-            &state.indexed_phrases,
-            word_indices_from_phrases.into_iter().collect(),
-            &mut state.rng,
+        let has_voted = self.favorite_replies.upvote(
+            reply_to.chat.id,
+            reply_to.id,
+            replied_text,
+            reply_to.date,
+            voter.id,
         );
 
-        let generated_response = match generated_response {
-            Some(response) => response,
-            None => {
-                log::info!("couldn't generate a response");
-                return;
+        if has_voted {
+            if let Err(err) = self.favorite_replies.save(now) {
+                error::report_error(&err);
             }
-        };
+        }
+    }
 
-        let call_result = context.send_message(&generated_response).call().await;
+    /// Boosts the reply probability of the chat if a message sent at `now`
+    /// answers a recent reply of the bot.
+    fn record_engagement(&mut self, chat_id: chat::Id, reply_to: &Message, now: i64) {
+        let is_reply_of_bot = match (&self.bot_user, &reply_to.from) {
+            (Some(bot_user), Some(author)) => author.id == bot_user.id,
+            _ => false,
+        };
 
-        if let Err(err) = call_result {
-            log::error!(
-                "couldn't send message `{}`, due to error: {}",
-                generated_response,
-                err
+        if is_reply_of_bot
+            && self
+                .engagement_boost
+                .record_answer(chat_id, reply_to.date, now)
+        {
+            tracing::info!(
+                "chat {} answered a reply, boosting its reply probability",
+                chat_id
             );
+        }
+    }
+
+    /// The probability of replying to a message sent to the chat at `now`, by
+    /// its schedule, raised by whatever is left of its engagement boost.
+    fn reply_probability(&self, chat_id: chat::Id, now: i64) -> f32 {
+        let reply_prob = self
+            .reply_schedule
+            .reply_probability(chat_id, self.reply_prob, now);
+
+        self.engagement_boost
+            .reply_probability(chat_id, reply_prob, now)
+    }
+
+    /// Whether to reply to the message, which must be addressed to the bot if
+    /// it replies only to mentions, or else is replied to by chance, unless it
+    /// was sent long before starting, or its chat was replied to just now.
+    fn is_reply_due<C>(&mut self, context: &C) -> bool
+    where
+        C: tbot::contexts::fields::AnyText,
+    {
+        if self.mentions_only {
+            let is_addressed_to_bot = self.bot_user.as_ref().is_some_and(|bot_user| {
+                mentions::is_addressed_to(context.text(), context.reply_to(), bot_user)
+            });
+
+            if !is_addressed_to_bot {
+                return false;
+            }
         } else {
-            log::info!("generated response: `{}`", generated_response);
+            let reply_prob = self.reply_probability(context.chat().id, context.date());
+
+            if self.rng.gen::<f32>() >= reply_prob {
+                return false;
+            }
         }
-    });
 
-    bot.command("think", |context, state| async move {
-        use rand::seq::SliceRandom;
+        if self.startup_replay_guard.is_stale(context.date()) {
+            tracing::info!(
+                "not replying, message {} was sent long before starting",
+                context.message_id()
+            );
+            return false;
+        }
 
-        let state = &mut *state.lock().await;
+        if self
+            .reply_suppression
+            .is_suppressed(context.chat().id, Instant::now())
+        {
+            tracing::info!(
+                "not replying, chat {} was replied to just now",
+                context.chat().id
+            );
+            return false;
+        }
+
+        true
+    }
+
+    /// Queues the aliases the text defines for an admin of the chat to review.
+    fn propose_aliases(&mut self, chat_id: chat::Id, text: &str) {
+        for alias_pair in aliases::find_alias_pairs(text) {
+            let change = ProposedChange::Alias {
+                word: alias_pair.word,
+                alias: alias_pair.alias,
+            };
+
+            match self.pending_changes.propose(chat_id, change) {
+                Ok(Some(id)) => tracing::info!("proposed change {} in chat {}", id, chat_id),
+                Ok(None) => {}
+                Err(err) => error::report_error(&err),
+            }
+        }
+    }
+
+    fn apply_change(&mut self, chat_id: chat::Id, change: &ProposedChange) -> error::Result<()> {
+        match change {
+            ProposedChange::Alias { word, alias } => {
+                let mut memory = self
+                    .memories
+                    .get_mut(Some(chat_id), &self.normalization_config)?;
+
+                memory.indexed_phrases.add_alias(word, alias);
+                memory.answer_pools.clear();
+            }
+        }
 
-        let all_common_words = state.indexed_phrases.get_common_words().collect::<Vec<_>>();
+        Ok(())
+    }
 
-        if all_common_words.is_empty() {
+    /// Sends a message, with `probability`, to each chat that said something
+    /// lately, made up around the words said there the most.
+    fn speak_unprompted(&mut self, probability: f32, now: i64) {
+        if self.mentions_only {
             return;
         }
 
-        let picked_word = all_common_words.choose(&mut state.rng).unwrap();
+        let chat_ids: Vec<_> = self.word_trends.chat_ids().collect();
 
-        let phrases = state
-            .indexed_phrases
-            .get_phrases_with_word_in_common(*picked_word)
-            .collect::<Vec<_>>();
+        for chat_id in chat_ids {
+            if self.muted_chats.contains(&chat_id) || self.followed_channels.contains(&chat_id) {
+                continue;
+            }
 
-        let first_phrase = phrases.choose(&mut state.rng).unwrap();
-        let second_phrase = phrases.choose(&mut state.rng).unwrap();
+            let seed_text = self
+                .word_trends
+                .recent_words(chat_id, now, UNPROMPTED_SEED_WORD_COUNT)
+                .join(" ");
 
-        let generated_response =
-            phrase_indexing::concatenate_indexed_phrases(*first_phrase, *second_phrase);
+            if seed_text.is_empty() || self.rng.gen::<f32>() >= probability {
+                continue;
+            }
 
-        let call_result = context.send_message(&generated_response).call().await;
+            let message = self
+                .speaker(Some(chat_id))
+                .and_then(|mut speaker| speaker.speak(Some(&seed_text), now));
 
-        if let Err(err) = call_result {
-            log::error!(
-                "couldn't send message `{}`, due to error: {}",
-                generated_response,
-                err
-            );
+            match message {
+                Ok(Some(message)) => {
+                    tracing::info!("generated unprompted message: `{}`", message);
+                    self.send_reply(chat_id, &message);
+                }
+                Ok(None) => {}
+                Err(err) => error::report_error(&err),
+            }
+        }
+    }
+
+    fn set_chat_muted(&mut self, chat_id: chat::Id, muted: bool) -> error::Result<()> {
+        if muted {
+            self.muted_chats.insert(chat_id);
         } else {
-            log::info!("generated response: `{}`", generated_response);
+            self.muted_chats.remove(&chat_id);
         }
-    });
 
-    bot.command("setprob", |context, state| async move {
-        let msg_text = &context.text.value;
+        let mut muted_chats: Vec<i64> = self.muted_chats.iter().map(|chat_id| chat_id.0).collect();
+        muted_chats.sort_unstable();
+
+        self.settings
+            .set(dashboard::MUTED_CHATS_SETTING, muted_chats)
+    }
 
-        if let Ok(new_prob) = msg_text.parse::<f32>() {
-            state.lock().await.reply_prob = new_prob;
+    fn set_user_opted_out(&mut self, user_id: user::Id, opted_out: bool) -> error::Result<()> {
+        if opted_out {
+            self.opted_out_users.insert(user_id);
+        } else {
+            self.opted_out_users.remove(&user_id);
         }
-    });
 
-    log::info!("starting to poll");
+        let mut opted_out_users: Vec<i64> = self
+            .opted_out_users
+            .iter()
+            .map(|user_id| user_id.0)
+            .collect();
+        opted_out_users.sort_unstable();
 
-    bot.polling().start().await.unwrap();
+        self.settings.set(OPTED_OUT_USERS_SETTING, opted_out_users)
+    }
 
-    Ok(())
-}
+    /// Protects the word in the chat, or stops protecting it. Returns whether
+    /// that changed anything.
+    fn set_word_protected(
+        &mut self,
+        chat_id: chat::Id,
+        word: &str,
+        protected: bool,
+    ) -> error::Result<bool> {
+        let words = self.protected_words.entry(chat_id).or_default();
+        let was_protected = words.iter().any(|protected_word| protected_word == word);
 
-fn init_indexed_phrases(database_path: &Path) -> std::io::Result<IndexedPhrases> {
-    use std::fs::File;
-    use std::io::{prelude::*, BufReader};
+        if protected == was_protected {
+            return Ok(false);
+        }
 
-    let file = File::open(database_path)?;
-    let lines = BufReader::new(file).lines();
+        if protected {
+            words.push(word.into());
+        } else {
+            words.retain(|protected_word| protected_word != word);
+        }
 
-    let mut indexed_phrases = IndexedPhrases::new();
-    let mut corrected_lines = Vec::new();
+        let protected_words: BTreeMap<i64, &Vec<String>> = self
+            .protected_words
+            .iter()
+            .filter(|(_, words)| !words.is_empty())
+            .map(|(chat_id, words)| (chat_id.0, words))
+            .collect();
 
-    for line in lines {
-        let line = line?;
-        for phrase in phrase_indexing::normalize_text_into_phrases(line.clone()) {
-            if indexed_phrases.insert_phrase(phrase).has_inserted_phrase {
-                corrected_lines.push(line.clone());
-            }
+        self.settings
+            .set(PROTECTED_WORDS_SETTING, protected_words)
+            .map(|()| true)
+    }
+
+    /// Bans the words everywhere, on top of those already blocked.
+    fn ban_words(&mut self, words: &[String]) -> error::Result<()> {
+        let blocked_words = self
+            .memories
+            .blocked_words()
+            .iter()
+            .chain(words)
+            .cloned()
+            .collect();
+        self.memories.set_blocked_words(blocked_words);
+        self.banned_words.extend_from_slice(words);
+
+        self.settings.set(BANNED_WORDS_SETTING, &self.banned_words)
+    }
+
+    /// Replaces the reply schedule of the chat, and saves it.
+    fn set_reply_schedule(
+        &mut self,
+        chat_id: chat::Id,
+        rules: Vec<ScheduleRule>,
+    ) -> error::Result<()> {
+        self.reply_schedule.set_rules_of(chat_id, rules);
+        self.settings.set(
+            REPLY_SCHEDULES_SETTING,
+            self.reply_schedule.scheduled_rules(),
+        )
+    }
+
+    /// Nerfs the word in the chat by `factor`, and saves it.
+    fn set_word_nerf(&mut self, chat_id: chat::Id, word: &str, factor: f32) -> error::Result<()> {
+        self.memories.set_word_nerf(chat_id, word, factor);
+        self.settings
+            .set(WORD_NERFS_SETTING, self.memories.word_nerfs())
+    }
+
+    /// Normalizes the memory of the chat for the language from now on, see
+    /// `Memories::set_chat_language`.
+    fn set_chat_language(
+        &mut self,
+        chat_id: chat::Id,
+        language_code: &str,
+    ) -> error::Result<Result<(), LanguageRejection>> {
+        let language = ChatLanguage {
+            code: language_code.into(),
+            stop_words: self.stop_words_config.of_language(Some(language_code)),
+        };
+
+        if let Err(rejection) =
+            self.memories
+                .set_chat_language(chat_id, language, &self.normalization_config)?
+        {
+            return Ok(Err(rejection));
         }
+
+        self.settings
+            .set(CHAT_LANGUAGES_SETTING, self.memories.chat_languages())
+            .map(Ok)
     }
 
-    let mut file = File::create(database_path.with_extension("new"))?;
-    for line in corrected_lines {
-        writeln!(file, "{}", line)?;
+    /// Archives the memory of the chat, or unarchives it. Returns `false` if
+    /// chats share their memory, which can't be archived.
+    fn set_chat_archived(&mut self, chat_id: chat::Id, archived: bool) -> error::Result<bool> {
+        if !self.memories.set_archived(chat_id, archived) {
+            return Ok(false);
+        }
+
+        let mut archived_chats: Vec<i64> = self
+            .memories
+            .archived_chats()
+            .map(|chat_id| chat_id.0)
+            .collect();
+        archived_chats.sort_unstable();
+
+        self.settings
+            .set(ARCHIVED_CHATS_SETTING, archived_chats)
+            .map(|()| true)
     }
 
-    Ok(indexed_phrases)
-}
+    /// Puts back the spelling of the words protected in the chat.
+    fn restore_protected_words(&self, chat_id: Option<chat::Id>, text: &str) -> String {
+        let protected_words = chat_id
+            .and_then(|chat_id| self.protected_words.get(&chat_id))
+            .map_or(&[][..], Vec::as_slice);
+
+        phrase_indexing::restore_protected_words(
+            text,
+            protected_words,
+            &self.normalization_config_of(chat_id),
+        )
+    }
+
+    /// How the text of the chat is normalized, see
+    /// `Memories::normalization_config_of`.
+    fn normalization_config_of(&self, chat_id: Option<chat::Id>) -> NormalizationConfig {
+        self.memories
+            .normalization_config_of(chat_id, &self.normalization_config)
+    }
+
+    /// Forgets the phrases only the user said, from memory and from disk.
+    /// Returns how many phrases were forgotten.
+    fn delete_user_data(&mut self, user_id: user::Id) -> error::Result<usize> {
+        let mut forgotten_phrase_count = 0;
+
+        for (chat_id, phrases) in self.contributions.take(user_id) {
+            let normalization_config = self.normalization_config_of(Some(chat_id));
+            let mut memory = self
+                .memories
+                .get_mut(Some(chat_id), &self.normalization_config)?;
+            forgotten_phrase_count += memory.forget_phrases(&phrases, &normalization_config)?;
+        }
+
+        self.contributions.save()?;
 
-fn store_line_in_database(database_path: &Path, line: &str) -> io::Result<()> {
-    use std::fs::File;
-    use std::io::prelude::*;
+        Ok(forgotten_phrase_count)
+    }
+
+    fn dashboard_status(&self) -> BotStatus {
+        let memories = self
+            .memories
+            .phrase_counts()
+            .into_iter()
+            .map(|(chat_id, phrase_count)| MemoryStatus {
+                chat_id: chat_id.map(|chat_id| chat_id.0),
+                phrase_count,
+            })
+            .collect();
+
+        let mut muted_chats: Vec<i64> = self.muted_chats.iter().map(|chat_id| chat_id.0).collect();
+        muted_chats.sort_unstable();
+
+        let settings = [
+            ("memory scope", self.memories.scope().to_string()),
+            ("reply probability", self.reply_prob.to_string()),
+            ("emoji reply probability", self.emoji_reply_prob.to_string()),
+            ("mentions only", self.mentions_only.to_string()),
+            ("engagement boost", self.engagement_boost.boost.to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        BotStatus {
+            memories,
+            muted_chats,
+            recent_replies: self.recent_replies.to_vec(),
+            settings,
+        }
+    }
 
-    let mut file = File::options()
-        .write(true)
-        .append(true)
-        .open(database_path)?;
+    fn send_reply(&mut self, chat_id: chat::Id, text: &str) {
+        if self.followed_channels.contains(&chat_id) {
+            tracing::info!("not replying to followed channel {}", chat_id);
+            return;
+        }
 
-    writeln!(file, "{}", line)?;
-    file.flush()?;
+        self.recent_replies.record(chat_id, text, unix_now());
 
-    Ok(())
+        for message in self.length_guard.apply(text) {
+            self.outgoing_queue.enqueue(chat_id, message);
+        }
+    }
 }
 
-fn generate_phrase(
-    indexed_phrases: &IndexedPhrases,
-    word_indices_from_phrases: Vec<WordIndex>,
-    rng: &mut impl Rng,
-) -> Option<String> {
-    use rand::seq::SliceRandom;
+impl DashboardBackend for Arc<Mutex<BotState>> {
+    fn status(&self) -> DashboardFuture<'_, BotStatus> {
+        Box::pin(async move { self.lock().await.dashboard_status() })
+    }
+
+    fn control(&self, control: Control) -> DashboardFuture<'_, error::Result<()>> {
+        Box::pin(async move {
+            let state = &mut *self.lock().await;
 
-    if word_indices_from_phrases.is_empty() {
-        return None;
+            match control {
+                Control::Mute(chat_id) => state.set_chat_muted(chat_id, true),
+                Control::Unmute(chat_id) => state.set_chat_muted(chat_id, false),
+                Control::Compact => state.memories.flush(&state.normalization_config),
+            }
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> error::Result<()> {
+    let _telemetry = telemetry::init();
+
+    let started_at = unix_now();
+
+    // Walks through writing the config, instead of starting the bot.
+    if std::env::args().nth(1).as_deref() == Some("init") {
+        let config_path = std::env::var("CONFIG_PATH").unwrap_or_else(|_| "config.toml".into());
+        return setup::run(Path::new(&config_path)).await;
+    }
+
+    let config = Config::from_env()?;
+    latency::set_slow_message_threshold(Duration::from_millis(config.slow_message_threshold_ms));
+
+    let mut shared_memory = Memory::load(
+        open_phrase_store(
+            config.database_kind,
+            &config.database_path,
+            None,
+            config.write_queue_config(),
+        )?,
+        &NormalizationConfig::default(),
+    )?;
+
+    // Tries out generation on the stored phrases, without ever talking to
+    // Telegram.
+    if std::env::args().skip(1).any(|arg| arg == "--repl") {
+        let mut rng = rand::rngs::StdRng::from_entropy();
+
+        return repl::run(
+            &mut shared_memory,
+            std::io::stdin().lock(),
+            std::io::stdout(),
+            unix_now(),
+            &mut rng,
+        );
+    }
+
+    let database_kind = config.database_kind;
+    let database_path = config.database_path.clone();
+    let write_queue_config = config.write_queue_config();
+    let mut memories = Memories::new(
+        config.memory_scope,
+        shared_memory,
+        Box::new(move |chat_id| {
+            open_phrase_store(
+                database_kind,
+                &database_path,
+                Some(chat_id),
+                write_queue_config,
+            )
+        }),
+    );
+    memories.set_recency_bonus(config.recency_bonus);
+    memories.set_junction_distribution(config.junction_distribution);
+    memories.set_caps(config.max_phrase_count, config.max_word_count);
+    memories.set_stop_words(config.stop_words.of_language(None));
+    memories.evict_least_recently_learned(&NormalizationConfig::default())?;
+    memories.load_short_term_log(&config.short_term_memory_path)?;
+
+    let token = std::env::var(&config.token_env_var)
+        .map_err(|_| Error::parse("bot token", &config.token_env_var))?;
+
+    match config.platform {
+        Platform::Telegram => {}
+        Platform::Discord => {
+            return run_on_adapter(DiscordAdapter::connect(token), &config, memories).await;
+        }
+        Platform::Matrix => {
+            let homeserver_url = config.matrix_homeserver_url()?;
+
+            return run_on_adapter(
+                MatrixAdapter::connect(homeserver_url, token),
+                &config,
+                memories,
+            )
+            .await;
+        }
     }
 
-    // TODO(feroldi): Improve this mess.
-    let all_common_words = indexed_phrases
-        .get_common_words()
-        .filter(|w| w.len() > 1)
-        .collect::<HashSet<_>>();
-    let mut words: HashSet<_> = indexed_phrases
-        .get_words_for_indices(&word_indices_from_phrases)
+    let bot = match &config.api_proxy {
+        Some(api_proxy) => {
+            let proxy_uri = api_proxy
+                .parse()
+                .map_err(|_| Error::parse("Bot API proxy", api_proxy))?;
+            let proxy = tbot::proxy::Proxy::new(tbot::proxy::Intercept::All, proxy_uri);
+
+            Bot::with_proxy(token.clone(), proxy)
+        }
+        None => Bot::new(token.clone()),
+    };
+
+    let bot_user = match bot.get_me().call().await {
+        Ok(me) => Some(me.user),
+        Err(source) => {
+            error::report_error(&Error::Platform {
+                context: "fetching the bot user".into(),
+                source,
+            });
+            None
+        }
+    };
+
+    let settings = SettingsStore::load(&config.settings_path)?;
+    let muted_chats = settings
+        .get::<Vec<i64>>(dashboard::MUTED_CHATS_SETTING)
+        .unwrap_or_default()
+        .into_iter()
+        .map(chat::Id)
+        .collect();
+    let opted_out_users = settings
+        .get::<Vec<i64>>(OPTED_OUT_USERS_SETTING)
+        .unwrap_or_default()
+        .into_iter()
+        .map(user::Id)
+        .collect();
+    let protected_words = settings
+        .get::<BTreeMap<i64, Vec<String>>>(PROTECTED_WORDS_SETTING)
+        .unwrap_or_default()
         .into_iter()
+        .map(|(chat_id, words)| (chat::Id(chat_id), words))
         .collect();
+    let banned_words = settings
+        .get::<Vec<String>>(BANNED_WORDS_SETTING)
+        .unwrap_or_default();
+    memories.set_blocked_words(
+        config
+            .blocked_words
+            .iter()
+            .chain(&banned_words)
+            .cloned()
+            .collect(),
+    );
+    let mut reply_schedule = match &config.reply_schedule {
+        Some(reply_schedule_config) => ReplySchedule::from_config(reply_schedule_config)?,
+        None => ReplySchedule::default(),
+    };
+    for (chat_id, rules) in settings
+        .get::<BTreeMap<i64, Vec<String>>>(REPLY_SCHEDULES_SETTING)
+        .unwrap_or_default()
+    {
+        let rules = rules
+            .iter()
+            .map(|rule| ScheduleRule::parse(rule))
+            .collect::<error::Result<_>>()?;
+        reply_schedule.set_rules_of(chat::Id(chat_id), rules);
+    }
+    for chat_id in settings
+        .get::<Vec<i64>>(ARCHIVED_CHATS_SETTING)
+        .unwrap_or_default()
+    {
+        memories.set_archived(chat::Id(chat_id), true);
+    }
+    memories.restore_chat_languages(
+        settings
+            .get::<BTreeMap<i64, String>>(CHAT_LANGUAGES_SETTING)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(chat_id, code)| {
+                let language = ChatLanguage {
+                    stop_words: config.stop_words.of_language(Some(&code)),
+                    code,
+                };
+                (chat::Id(chat_id), language)
+            }),
+    );
+    for (chat_id, nerfs) in settings
+        .get::<BTreeMap<i64, BTreeMap<String, f32>>>(WORD_NERFS_SETTING)
+        .unwrap_or_default()
+    {
+        for (word, factor) in nerfs {
+            memories.set_word_nerf(chat::Id(chat_id), &word, factor);
+        }
+    }
 
-    words.retain(|w| all_common_words.contains(w));
-    let words: Vec<_> = words.into_iter().collect();
+    // Webhooks are sent every update until they're handled, so there's nothing
+    // to confirm when receiving updates through one.
+    let update_offset = settings
+        .get::<isize>(updates::UPDATE_OFFSET_SETTING)
+        .filter(|_| config.webhook.is_none());
 
-    if words.is_empty() {
-        return None;
+    if let Some(update_offset) = update_offset {
+        match updates::confirm_updates_before(&token, update_offset).await {
+            Ok(()) => tracing::info!("resuming from update {}", update_offset),
+            Err(err) => error::report_error(&err),
+        }
+    }
+
+    let message_sender: Box<dyn MessageSender> = match ChaosConfig::from_env()? {
+        Some(chaos_config) => {
+            tracing::warn!("injecting failures into sent messages: {:?}", chaos_config);
+            Box::new(ChaosSender::new(bot.clone(), chaos_config))
+        }
+        None => Box::new(bot.clone()),
+    };
+
+    let mut garnisher = match config.garnish_rules.clone() {
+        Some(rules) => Garnisher::new(config.garnish_probability, rules)
+            .map_err(|err| Error::parse(format!("garnish rules ({})", err), "garnish_rules"))?,
+        None => Garnisher::default(),
+    };
+    garnisher.probability = config.garnish_probability;
+
+    let mut state = BotState {
+        memories,
+        source_quotas: Arc::default(),
+        change_log: ChangeLog::default(),
+        emoji_tracker: Arc::default(),
+        favorite_replies: FavoriteReplies::load(&config.favorites_path)?,
+        normalization_config: NormalizationConfig::default(),
+        phrase_filter: config.phrase_filter(),
+        reply_prob: config.reply_probability,
+        reply_schedule,
+        mentions_only: config.reply_to_mentions_only,
+        bot_user,
+        emoji_reply_prob: config.emoji_reply_probability,
+        sentence_config: SentenceConfig::default(),
+        reply_styler: Arc::new(config.reply_styler()),
+        garnisher: Arc::new(garnisher),
+        length_guard: LengthGuard::default(),
+        outgoing_queue: OutgoingQueue::new(
+            message_sender,
+            QueueConfig {
+                typing_delay_per_char: Duration::from_millis(config.typing_delay_ms_per_char),
+                max_typing_delay: Duration::from_secs(config.max_typing_delay_secs),
+                ..QueueConfig::default()
+            },
+        ),
+        reply_suppression: ReplySuppression::default(),
+        engagement_boost: EngagementBoost::new(
+            config.engagement_boost,
+            Duration::from_secs(config.engagement_window_secs),
+            Duration::from_secs(config.engagement_decay_secs),
+        ),
+        startup_replay_guard: StartupReplayGuard::from_env(started_at)?,
+        followed_channels: followed_channels_from_env()?,
+        admins: Admins::from_env()?,
+        recent_messages: RecentMessages::default(),
+        settings,
+        pending_changes: PendingChanges::load(&config.pending_changes_path)?,
+        muted_chats,
+        opted_out_users,
+        protected_words,
+        banned_words,
+        recent_replies: RecentReplies::default(),
+        word_trends: WordTrends::load(&config.word_trends_path)?,
+        contributions: Contributions::load(&config.contributions_path)?,
+        stop_words_config: config.stop_words.clone(),
+        handled_update_offset: None,
+        rng: rand::rngs::StdRng::from_entropy(),
+    };
+
+    let approved_changes: Vec<_> = state
+        .pending_changes
+        .approved()
+        .map(|(chat_id, change)| (chat_id, change.clone()))
+        .collect();
+    for (chat_id, change) in approved_changes {
+        state.apply_change(chat_id, &change)?;
+    }
+
+    let feed_config = FeedConfig::from_env()?;
+    let social_config = SocialConfig::from_env()?;
+
+    let admin_user_ids: Vec<_> = state.admins.user_ids().collect();
+    let bot_username = state
+        .bot_user
+        .as_ref()
+        .and_then(|bot_user| bot_user.username.clone());
+
+    let command_languages = config.command_languages.clone();
+    tokio::spawn(async move {
+        if let Err(err) = commands::register(&token, &admin_user_ids, &command_languages).await {
+            error::report_error(&err);
+        }
+    });
+
+    let mut bot = bot.stateful_event_loop(Mutex::new(state));
+
+    // Without it, commands suffixed with `@username` in groups are ignored.
+    match bot_username {
+        Some(username) => bot.username(username),
+        None => tracing::warn!("commands suffixed with the bot username will be ignored"),
+    }
+
+    if !feed_config.urls.is_empty() {
+        let state = bot.get_state();
+        let phrase_ttl = feed_config.phrase_ttl;
+
+        tokio::spawn(feeds::poll_feeds(feed_config, move |text| {
+            let state = Arc::clone(&state);
+            async move {
+                let state = &mut *state.lock().await;
+                let expires_at = phrase_ttl.map(|ttl| unix_now() + ttl.as_secs() as i64);
+
+                if let Err(err) = state.learn_text(None, &text, PhraseSource::Feed, expires_at) {
+                    error::report_error(&err);
+                }
+            }
+        }));
+    }
+
+    if let Some(dashboard_config) = config.dashboard {
+        let api_tokens =
+            ApiTokens::from_config(&dashboard_config.tokens, |name| std::env::var(name).ok());
+        if api_tokens.is_empty() {
+            let token_env_vars: Vec<_> = dashboard_config
+                .tokens
+                .iter()
+                .map(|token_config| token_config.env_var.as_str())
+                .collect();
+            return Err(Error::parse("dashboard tokens", token_env_vars.join(", ")));
+        }
+        let state = bot.get_state();
+
+        tokio::spawn(async move {
+            if let Err(err) = dashboard::serve(&dashboard_config, api_tokens, state).await {
+                error::report_error(&err);
+            }
+        });
+    }
+
+    if let Some(public_api_config) = config.public_api {
+        let state = bot.get_state();
+        let stream_state = bot.get_state();
+        let brain_chat_id = public_api_config.brain_chat_id.map(chat::Id);
+
+        tokio::spawn(async move {
+            let generate = move || {
+                let state = Arc::clone(&state);
+                async move {
+                    let speaker = state.lock().await.speaker(brain_chat_id);
+
+                    match speaker.and_then(|mut speaker| speaker.think(unix_now())) {
+                        Ok(response) => response,
+                        Err(err) => {
+                            error::report_error(&err);
+                            None
+                        }
+                    }
+                }
+            };
+
+            let stream_candidates = move |count, mut candidates: mpsc::Sender<Candidate>| {
+                let state = Arc::clone(&stream_state);
+                async move {
+                    // Only the memory is locked while generating each candidate,
+                    // so that streaming many of them doesn't hold up the chats.
+                    let speaker = state.lock().await.speaker(brain_chat_id);
+                    let mut speaker = match speaker {
+                        Ok(speaker) => speaker,
+                        Err(err) => {
+                            error::report_error(&err);
+                            return;
+                        }
+                    };
+
+                    for _ in 0..count {
+                        let candidate = speaker.think_candidate(unix_now());
+
+                        match candidate {
+                            Ok(Some(candidate)) => {
+                                if candidates.send(candidate).await.is_err() {
+                                    return;
+                                }
+                            }
+                            Ok(None) => return,
+                            Err(err) => {
+                                error::report_error(&err);
+                                return;
+                            }
+                        }
+                    }
+                }
+            };
+
+            if let Err(err) =
+                public_api::serve(&public_api_config, generate, stream_candidates).await
+            {
+                error::report_error(&err);
+            }
+        });
     }
 
-    let picked_word = words.choose(rng).unwrap();
+    if let Some(backup_config) = config.backup {
+        let credentials = Credentials::from_config(&backup_config, |name| std::env::var(name).ok())
+            .ok_or_else(|| {
+                let key_env_vars = format!(
+                    "{}, {}",
+                    backup_config.access_key_env_var, backup_config.secret_key_env_var
+                );
+                Error::parse("backup access keys", key_env_vars)
+            })?;
+        let interval = Duration::from_secs(backup_config.interval_secs.max(1));
+        let database_path = config.database_path.clone();
+        let backup_target = BackupTarget::new(backup_config, credentials);
+        let state = bot.get_state();
 
-    let phrases = indexed_phrases
-        .get_phrases_with_word_in_common(*picked_word)
-        .collect::<Vec<_>>();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(interval).await;
 
-    let first_phrase = phrases.choose(rng).unwrap();
-    let second_phrase = phrases.choose(rng).unwrap();
+                // Reading the databases blocks on disk, so it's left to a
+                // thread of its own, which only locks the memories.
+                let memories = state.lock().await.memories.loaded();
+                let database_path = database_path.clone();
+                let databases = tokio::task::spawn_blocking(move || {
+                    memory::read_flushed(&memories, || backup::read_databases(&database_path))
+                })
+                .await;
+
+                let result = match databases {
+                    Ok(Ok(databases)) => backup_target.back_up(databases, unix_now()).await,
+                    Ok(Err(err)) => Err(err),
+                    Err(err) => {
+                        tracing::error!("reading the databases to back up failed: {}", err);
+                        continue;
+                    }
+                };
+
+                match result {
+                    Ok(expired_backup_count) => tracing::info!(
+                        "backed up the databases, deleting {} expired backups",
+                        expired_backup_count
+                    ),
+                    Err(err) => error::report_error(&err),
+                }
+            }
+        });
+    }
+
+    if social_config.source.is_some() {
+        let state = bot.get_state();
+
+        tokio::spawn(social::poll_social_posts(social_config, move |text| {
+            let state = Arc::clone(&state);
+            async move {
+                let state = &mut *state.lock().await;
+
+                if let Err(err) = state.learn_text(None, &text, PhraseSource::Social, None) {
+                    error::report_error(&err);
+                }
+            }
+        }));
+    }
+
+    {
+        let state = bot.get_state();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(MEMORY_SWEEP_INTERVAL).await;
+
+                let state = &mut *state.lock().await;
+
+                match state
+                    .memories
+                    .remove_expired_phrases(unix_now(), &state.normalization_config)
+                {
+                    Ok(0) => {}
+                    Ok(expired_phrase_count) => {
+                        tracing::info!("removed {} expired phrases", expired_phrase_count)
+                    }
+                    Err(err) => error::report_error(&err),
+                }
+
+                match state
+                    .memories
+                    .evict_least_recently_learned(&state.normalization_config)
+                {
+                    Ok(0) => {}
+                    Ok(evicted_phrase_count) => tracing::info!(
+                        "evicted {} least recently learned phrases",
+                        evicted_phrase_count
+                    ),
+                    Err(err) => error::report_error(&err),
+                }
+
+                match state.memories.fold_short_term_phrases(unix_now()) {
+                    Ok(0) => {}
+                    Ok(folded_phrase_count) => tracing::info!(
+                        "folded {} phrases into long-term memory",
+                        folded_phrase_count
+                    ),
+                    Err(err) => error::report_error(&err),
+                }
+
+                if let Err(err) = state.word_trends.save() {
+                    error::report_error(&err);
+                }
+
+                if let Err(err) = state.contributions.save() {
+                    error::report_error(&err);
+                }
+            }
+        });
+    }
+
+    if config.unprompted_message_probability > 0.0 {
+        let state = bot.get_state();
+        let interval = Duration::from_secs(config.unprompted_message_interval_secs.max(1));
+        let probability = config.unprompted_message_probability;
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(interval).await;
+
+                state.lock().await.speak_unprompted(probability, unix_now());
+            }
+        });
+    }
+
+    // Handlers are spawned as updates are dispatched, so this runs once they
+    // got the update rather than once they're done with it.
+    bot.after_update(|context, state| async move {
+        let update_offset = context.update_id.0 + 1;
+        let handled_update_offset = &mut state.lock().await.handled_update_offset;
+
+        if handled_update_offset.is_none_or(|handled| handled < update_offset) {
+            *handled_update_offset = Some(update_offset);
+        }
+    });
+
+    {
+        let state = bot.get_state();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(UPDATE_OFFSET_SAVE_INTERVAL).await;
+                state.lock().await.save_update_offset();
+            }
+        });
+    }
+
+    bot.text(handle_text);
+    bot.photo(handle_text);
+    bot.video(handle_text);
+    bot.animation(handle_text);
+
+    on_command(&mut bot, "think", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        let speaker = state.lock().await.speaker(Some(context.chat.id));
+
+        match speaker.and_then(|mut speaker| speaker.think(context.date)) {
+            Ok(Some(response)) => {
+                tracing::info!("generated response: `{}`", response);
+                record_outcome("generated");
+                state.lock().await.send_reply(context.chat.id, &response);
+            }
+            Ok(None) => {
+                tracing::info!("couldn't think of anything, the corpus is empty");
+                record_outcome("nothing generated");
+            }
+            Err(err) => {
+                record_outcome("failed");
+                error::report_error(&err);
+            }
+        }
+    });
+
+    on_command(&mut bot, "speak", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        let seed_text = context
+            .reply_to
+            .as_ref()
+            .and_then(|reply_to| match &reply_to.kind {
+                message::Kind::Text(text) => Some(text.value.as_str()),
+                _ => None,
+            });
+
+        let speaker = state.lock().await.speaker(Some(context.chat.id));
+
+        match speaker.and_then(|mut speaker| speaker.speak(seed_text, context.date)) {
+            Ok(Some(response)) => {
+                tracing::info!("generated response: `{}`", response);
+                record_outcome("generated");
+                state.lock().await.send_reply(context.chat.id, &response);
+            }
+            Ok(None) => {
+                tracing::info!("couldn't speak, the corpus is empty");
+                record_outcome("nothing generated");
+            }
+            Err(err) => {
+                record_outcome("failed");
+                error::report_error(&err);
+            }
+        }
+    });
+
+    on_command(&mut bot, "quoteme", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        let state = &mut *state.lock().await;
+
+        let memory = match state
+            .memories
+            .get_shared(Some(context.chat.id), &state.normalization_config)
+        {
+            Ok(memory) => memory,
+            Err(err) => {
+                error::report_error(&err);
+                return;
+            }
+        };
+        let memory = &*read_memory(&memory);
+
+        let phrase = match memory.indexed_phrases.get_random_phrase(&mut state.rng) {
+            Ok(phrase) => phrase.to_string(),
+            Err(EngineError::EmptyCorpus) => {
+                tracing::info!("couldn't quote anything, the corpus is empty");
+                return;
+            }
+            Err(err) => {
+                error::report_error(&err.into());
+                return;
+            }
+        };
+
+        let phrase = state.restore_protected_words(Some(context.chat.id), &phrase);
+        let quote = output::quote(&phrase);
+
+        tracing::info!("quoting: `{}`", quote);
+        state.send_reply(context.chat.id, &quote);
+    });
+
+    on_command(&mut bot, "related", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        let (word, memory) = {
+            let state = &mut *state.lock().await;
+
+            let normalization_config = state.normalization_config_of(Some(context.chat.id));
+            let word = match parse_word(&context.text.value, &normalization_config) {
+                Some(word) => word,
+                None => {
+                    error::report_error(&Error::parse("word", &context.text.value));
+                    return;
+                }
+            };
+
+            let memory = state
+                .memories
+                .get_shared(Some(context.chat.id), &state.normalization_config);
+            (word, memory)
+        };
+        let memory = match memory {
+            Ok(memory) => memory,
+            Err(err) => {
+                error::report_error(&err);
+                return;
+            }
+        };
+
+        let reply = {
+            let memory = &*read_memory(&memory);
+
+            let related_words = memory
+                .indexed_phrases
+                .get_word_id(&word)
+                .and_then(|word_id| {
+                    memory
+                        .indexed_phrases
+                        .get_cooccurring_words(word_id, RELATED_WORD_COUNT)
+                        .ok()
+                })
+                .unwrap_or_default();
+
+            if related_words.is_empty() {
+                format!("nothing comes to mind about {}", word)
+            } else {
+                let related_words: Vec<_> = related_words
+                    .into_iter()
+                    .filter_map(|(word_id, count)| {
+                        let related_word = memory.indexed_phrases.get_word(word_id).ok()?;
+                        Some(format!("{} ({})", &*related_word, count))
+                    })
+                    .collect();
+
+                format!("{}: {}", word, related_words.join(", "))
+            }
+        };
+
+        state.lock().await.send_reply(context.chat.id, &reply);
+    });
+
+    on_command(&mut bot, "say", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        let (word, speaker) = {
+            let state = &mut *state.lock().await;
+
+            let normalization_config = state.normalization_config_of(Some(context.chat.id));
+            let word = match parse_word(&context.text.value, &normalization_config) {
+                Some(word) => word,
+                None => {
+                    error::report_error(&Error::parse("word", &context.text.value));
+                    return;
+                }
+            };
+
+            (word, state.speaker(Some(context.chat.id)))
+        };
+        let mut speaker = match speaker {
+            Ok(speaker) => speaker,
+            Err(err) => {
+                error::report_error(&err);
+                return;
+            }
+        };
+
+        let generated_response = {
+            let memory = &*read_memory(&speaker.memory);
+
+            let word_id = memory
+                .indexed_phrases
+                .get_word_id(&word)
+                .filter(|&word_id| memory.indexed_phrases.is_common_word(word_id));
+
+            word_id.map(|word_id| {
+                generation::splice_phrases_around_word(
+                    &memory.indexed_phrases,
+                    &memory.answer_pools,
+                    word_id,
+                    context.date,
+                    &mut speaker.rng,
+                )
+            })
+        };
+
+        let reply = match generated_response {
+            Some(Ok(response)) => speaker.reply_styler.style(&response, &mut speaker.rng),
+            None | Some(Err(EngineError::ExpiredWord(_))) => {
+                format!("i don't know anything about {}", word)
+            }
+            Some(Err(err)) => {
+                error::report_error(&err.into());
+                return;
+            }
+        };
+
+        tracing::info!("generated response around `{}`: `{}`", word, reply);
+        state.lock().await.send_reply(context.chat.id, &reply);
+    });
+
+    on_command(&mut bot, "tag", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        let tag = parse_tag(&context.text.value);
+
+        let replied_text = match context.reply_to.as_ref().map(|message| &message.kind) {
+            Some(message::Kind::Text(text)) => &text.value,
+            _ => {
+                tracing::info!("not tagging, the command must reply to a text message");
+                return;
+            }
+        };
+
+        let tag = match tag {
+            Some(tag) => tag,
+            None => {
+                error::report_error(&Error::parse("tag", &context.text.value));
+                return;
+            }
+        };
+
+        let (memory, normalization_config) = {
+            let state = &mut *state.lock().await;
+
+            let memory = state
+                .memories
+                .get_shared(Some(context.chat.id), &state.normalization_config);
+            (memory, state.normalization_config_of(Some(context.chat.id)))
+        };
+        let memory = match memory {
+            Ok(memory) => memory,
+            Err(err) => {
+                error::report_error(&err);
+                return;
+            }
+        };
+
+        let tagged_phrase_count: usize = {
+            let memory = &mut *write_memory(&memory);
+
+            phrase_indexing::normalize_text_into_phrases(
+                replied_text.clone(),
+                &normalization_config,
+            )
+            .iter()
+            .map(|phrase| {
+                memory
+                    .indexed_phrases
+                    .tag_phrases_of_text(phrase.as_ref(), &tag)
+            })
+            .sum()
+        };
+
+        let reply = format!("tagged {} phrases as #{}", tagged_phrase_count, tag);
+        state.lock().await.send_reply(context.chat.id, &reply);
+    });
+
+    on_command(&mut bot, "generate", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        let tag = match parse_tag(&context.text.value) {
+            Some(tag) => tag,
+            None => {
+                error::report_error(&Error::parse("tag", &context.text.value));
+                return;
+            }
+        };
+
+        let speaker = state.lock().await.speaker(Some(context.chat.id));
+        let mut speaker = match speaker {
+            Ok(speaker) => speaker,
+            Err(err) => {
+                error::report_error(&err);
+                return;
+            }
+        };
+
+        let generated_response = generation::splice_tagged_phrases(
+            &read_memory(&speaker.memory).indexed_phrases,
+            &tag,
+            context.date,
+            &mut speaker.rng,
+        );
+
+        let generated_response = match generated_response {
+            Ok(response) => speaker.render(&response),
+            Err(EngineError::UnknownTag(tag)) => {
+                tracing::info!("couldn't generate anything, no phrase is tagged #{}", tag);
+                return;
+            }
+            Err(err) => {
+                error::report_error(&err.into());
+                return;
+            }
+        };
+
+        tracing::info!("generated response: `{}`", generated_response);
+        state
+            .lock()
+            .await
+            .send_reply(context.chat.id, &generated_response);
+    });
+
+    on_command(&mut bot, "forget", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::ChatMemory).await {
+            return;
+        }
+
+        let text = context.text.value.trim();
+
+        if text.is_empty() {
+            return;
+        }
+
+        let state = &mut *state.lock().await;
+
+        let normalization_config = state.normalization_config_of(Some(context.chat.id));
+        let forget_result = state
+            .memories
+            .get_mut(Some(context.chat.id), &state.normalization_config)
+            .and_then(|mut memory| memory.forget(text, &normalization_config));
+
+        match forget_result {
+            Ok(forgotten_phrase_count) => {
+                let reply = format!("forgot {} phrases", forgotten_phrase_count);
+                state.send_reply(context.chat.id, &reply);
+            }
+            Err(err) => error::report_error(&err),
+        }
+    });
+
+    for (command, protected) in [("protect", true), ("unprotect", false)] {
+        on_command(&mut bot, command, move |context, state| async move {
+            if is_duplicate(&*context, &state).await {
+                return;
+            }
+
+            if !require_admin(&*context, &state, AdminScope::Chat).await {
+                return;
+            }
+
+            let word = context.text.value.trim();
+            let state = &mut *state.lock().await;
+
+            if word.is_empty() {
+                let reply = match state.protected_words.get(&context.chat.id) {
+                    Some(words) if !words.is_empty() => {
+                        format!("protected words: {}", words.join(", "))
+                    }
+                    _ => "no words are protected".into(),
+                };
+                state.send_reply(context.chat.id, &reply);
+                return;
+            }
+
+            // Phrases are split into words at anything else, so that's all a
+            // word of a reply can be made of.
+            if !word.chars().all(char::is_alphanumeric) {
+                error::report_error(&Error::parse("protected word", word));
+                return;
+            }
+
+            match state.set_word_protected(context.chat.id, word, protected) {
+                Ok(changed) => {
+                    let reply = match (protected, changed) {
+                        (true, true) => format!("ok, I'll always spell it {}", word),
+                        (true, false) => format!("{} is already protected", word),
+                        (false, true) => format!("ok, {} is no longer protected", word),
+                        (false, false) => format!("{} isn't protected", word),
+                    };
+                    state.send_reply(context.chat.id, &reply);
+                }
+                Err(err) => error::report_error(&err),
+            }
+        });
+    }
+
+    on_command(&mut bot, "banlist", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        if context.text.value.trim() != "import" {
+            let state = &mut *state.lock().await;
+            let reply = format!(
+                "{} words are banned, reply to a text file of words with /banlist import to ban \
+                 more",
+                state.memories.blocked_words().len()
+            );
+            state.send_reply(context.chat.id, &reply);
+            return;
+        }
+
+        let document = match context.reply_to.as_ref().map(|reply_to| &reply_to.kind) {
+            Some(message::Kind::Document(document, _)) => document,
+            _ => {
+                let reply = "reply to a text file of words, one per line, to ban them";
+                state.lock().await.send_reply(context.chat.id, reply);
+                return;
+            }
+        };
+
+        if document
+            .file_size
+            .is_some_and(|file_size| file_size > banlist::MAX_BANLIST_FILE_SIZE)
+        {
+            let reply = "that file is too large to be a list of words";
+            state.lock().await.send_reply(context.chat.id, reply);
+            return;
+        }
+
+        // Downloaded before locking the state, so that other messages are
+        // handled meanwhile.
+        let text = match banlist::download_text(&context.bot, document).await {
+            Ok(text) => text,
+            Err(err) => {
+                error::report_error(&err);
+                let reply = "couldn't read that file, is it a text file?";
+                state.lock().await.send_reply(context.chat.id, reply);
+                return;
+            }
+        };
+
+        let state = &mut *state.lock().await;
+        let import = BanlistImport::parse(&text, state.memories.blocked_words());
+
+        if let Err(err) = state.ban_words(&import.added_words) {
+            error::report_error(&err);
+            return;
+        }
+
+        tracing::info!("banned {} words", import.added_words.len());
+        let reply = format!(
+            "banned {} words, {} were already banned and {} aren't words",
+            import.added_words.len(),
+            import.duplicate_count,
+            import.invalid_count
+        );
+        state.send_reply(context.chat.id, &reply);
+    });
+
+    on_command(&mut bot, "nerf", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::ChatMemory).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+        let state = &mut *state.lock().await;
+        let chat_id = context.chat.id;
+
+        let (word, factor) = match msg_text.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => {
+                let reply = match state.memories.word_nerfs_of(chat_id) {
+                    Some(nerfs) => {
+                        let nerfs: Vec<_> = nerfs
+                            .iter()
+                            .map(|(word, factor)| format!("{} ({})", word, factor))
+                            .collect();
+                        format!("nerfed words: {}", nerfs.join(", "))
+                    }
+                    None => "no words are nerfed".into(),
+                };
+                state.send_reply(chat_id, &reply);
+                return;
+            }
+            &[word, factor] => (word, factor),
+            _ => {
+                error::report_error(&Error::parse("nerf", msg_text));
+                return;
+            }
+        };
+
+        if !word.chars().all(char::is_alphanumeric) {
+            error::report_error(&Error::parse("nerfed word", word));
+            return;
+        }
+
+        let factor = match factor.parse::<f32>() {
+            Ok(factor) if (0.0..=1.0).contains(&factor) => factor,
+            _ => {
+                error::report_error(&Error::parse("nerf factor", factor));
+                return;
+            }
+        };
+
+        let word =
+            phrase_indexing::normalize_word(word, &state.normalization_config_of(Some(chat_id)));
+
+        match state.set_word_nerf(chat_id, &word, factor) {
+            Ok(()) if factor == 1.0 => {
+                state.send_reply(chat_id, &format!("ok, {} is no longer nerfed", word))
+            }
+            Ok(()) => state.send_reply(
+                chat_id,
+                &format!("ok, I'll pivot on {} {} times as often", word, factor),
+            ),
+            Err(err) => error::report_error(&err),
+        }
+    });
+
+    for (command, archived) in [("archive", true), ("unarchive", false)] {
+        on_command(&mut bot, command, move |context, state| async move {
+            if is_duplicate(&*context, &state).await {
+                return;
+            }
+
+            if !require_admin(&*context, &state, AdminScope::Chat).await {
+                return;
+            }
+
+            let state = &mut *state.lock().await;
+
+            match state.set_chat_archived(context.chat.id, archived) {
+                Ok(true) => {
+                    let confirmation = if archived {
+                        "ok, I'll keep my memory of this chat as it is"
+                    } else {
+                        "ok, I'll learn from this chat again"
+                    };
+                    state.send_reply(context.chat.id, confirmation);
+                }
+                Ok(false) => {
+                    state.send_reply(
+                        context.chat.id,
+                        "chats share my memory, so it can't be archived",
+                    );
+                }
+                Err(err) => error::report_error(&err),
+            }
+        });
+    }
+
+    on_command(&mut bot, "compact", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let state = &mut *state.lock().await;
+
+        let normalization_config = state.normalization_config_of(Some(context.chat.id));
+        let compact_result = state
+            .memories
+            .get_mut(Some(context.chat.id), &state.normalization_config)
+            .and_then(|mut memory| memory.flush(&normalization_config));
+
+        match compact_result {
+            Ok(()) => state.send_reply(context.chat.id, "memory compacted"),
+            Err(err) => error::report_error(&err),
+        }
+    });
+
+    on_command(&mut bot, "setprob", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = &context.text.value;
+
+        match msg_text.parse::<f32>() {
+            Ok(new_prob) => state.lock().await.reply_prob = new_prob,
+            Err(_) => error::report_error(&Error::parse("reply probability", msg_text)),
+        }
+    });
+
+    on_command(&mut bot, "schedule", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Chat).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+        let state = &mut *state.lock().await;
+        let chat_id = context.chat.id;
+
+        if msg_text.is_empty() {
+            let rules = state.reply_schedule.rules_of(chat_id);
+            let reply = if rules.is_empty() {
+                "this chat has no reply schedule of its own".into()
+            } else {
+                let rules: Vec<_> = rules.iter().map(ToString::to_string).collect();
+                format!(
+                    "reply schedule (the last rule that applies wins):\n{}",
+                    rules.join("\n")
+                )
+            };
+            state.send_reply(chat_id, &reply);
+            return;
+        }
+
+        let rules = if msg_text == "clear" {
+            Vec::new()
+        } else {
+            match ScheduleRule::parse(msg_text) {
+                Ok(rule) => {
+                    let mut rules = state.reply_schedule.rules_of(chat_id).to_vec();
+                    rules.push(rule);
+                    rules
+                }
+                Err(err) => {
+                    error::report_error(&err);
+                    return;
+                }
+            }
+        };
+
+        match state.set_reply_schedule(chat_id, rules) {
+            Ok(()) => state.send_reply(chat_id, "ok, reply schedule updated"),
+            Err(err) => error::report_error(&err),
+        }
+    });
+
+    on_command(&mut bot, "setsentences", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+
+        let counts = msg_text
+            .split_whitespace()
+            .map(str::parse::<usize>)
+            .collect::<Result<Vec<_>, _>>();
+
+        let (min_sentences, max_sentences) = match counts.as_deref() {
+            Ok(&[count]) if count > 0 => (count, count),
+            Ok(&[min, max]) if min > 0 && min <= max => (min, max),
+            _ => {
+                error::report_error(&Error::parse("sentence count", msg_text));
+                return;
+            }
+        };
+
+        let sentence_config = &mut state.lock().await.sentence_config;
+        sentence_config.min_sentences = min_sentences;
+        sentence_config.max_sentences = max_sentences;
+    });
+
+    on_command(&mut bot, "setchaining", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+
+        let chained = match msg_text {
+            "on" => true,
+            "off" => false,
+            _ => {
+                error::report_error(&Error::parse("sentence chaining", msg_text));
+                return;
+            }
+        };
+
+        state.lock().await.sentence_config.chained = chained;
+    });
+
+    on_command(&mut bot, "setmentionsonly", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+
+        let mentions_only = match msg_text {
+            "on" => true,
+            "off" => false,
+            _ => {
+                error::report_error(&Error::parse("mentions only mode", msg_text));
+                return;
+            }
+        };
+
+        state.lock().await.mentions_only = mentions_only;
+    });
+
+    on_command(&mut bot, "setemojiprob", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = &context.text.value;
+
+        match msg_text.parse::<f32>() {
+            Ok(new_prob) => state.lock().await.emoji_reply_prob = new_prob,
+            Err(_) => error::report_error(&Error::parse("emoji reply probability", msg_text)),
+        }
+    });
+
+    on_command(&mut bot, "setsuppression", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+
+        match msg_text.parse::<u64>() {
+            Ok(secs) => state.lock().await.reply_suppression.window = Duration::from_secs(secs),
+            Err(_) => error::report_error(&Error::parse("reply suppression window", msg_text)),
+        }
+    });
+
+    on_command(&mut bot, "setoverflow", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+
+        match msg_text.parse::<OverflowPolicy>() {
+            Ok(overflow_policy) => {
+                state.lock().await.length_guard.overflow_policy = overflow_policy
+            }
+            Err(_) => error::report_error(&Error::parse("overflow policy", msg_text)),
+        }
+    });
+
+    on_command(&mut bot, "setterminators", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+
+        if msg_text.is_empty() {
+            error::report_error(&Error::parse("phrase terminators", msg_text));
+            return;
+        }
+
+        let phrase_terminators = msg_text
+            .replace("\\n", "\n")
+            .chars()
+            .filter(|&c| c == '\n' || !c.is_whitespace())
+            .collect();
+
+        state.lock().await.normalization_config.phrase_terminators = phrase_terminators;
+    });
+
+    on_command(&mut bot, "setelongation", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+
+        let max_letter_run = match msg_text {
+            "off" => None,
+            max_letter_run => match max_letter_run.parse::<usize>() {
+                Ok(max_letter_run) if max_letter_run >= 2 => Some(max_letter_run),
+                _ => {
+                    error::report_error(&Error::parse("max letter run", msg_text));
+                    return;
+                }
+            },
+        };
+
+        state.lock().await.normalization_config.max_letter_run = max_letter_run;
+    });
+
+    on_command(&mut bot, "setsubwords", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+
+        let min_sub_word_len = match msg_text {
+            "off" => None,
+            min_sub_word_len => match min_sub_word_len.parse::<usize>() {
+                Ok(min_sub_word_len) if min_sub_word_len >= 2 => Some(min_sub_word_len),
+                _ => {
+                    error::report_error(&Error::parse("min sub-word length", msg_text));
+                    return;
+                }
+            },
+        };
+
+        let state = &mut *state.lock().await;
+
+        state.normalization_config.min_sub_word_len = min_sub_word_len;
+        state
+            .memories
+            .apply_normalization_config(&state.normalization_config);
+    });
+
+    on_command(&mut bot, "setlaughter", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+
+        let laughter_pattern = match msg_text {
+            "off" => None,
+            pattern => match phrase_indexing::laughter_pattern(pattern) {
+                Ok(pattern) => Some(pattern),
+                Err(_) => {
+                    error::report_error(&Error::parse("laughter pattern", msg_text));
+                    return;
+                }
+            },
+        };
+
+        let state = &mut *state.lock().await;
+
+        state.normalization_config.laughter_pattern = laughter_pattern;
+        state
+            .memories
+            .apply_normalization_config(&state.normalization_config);
+    });
+
+    on_command(&mut bot, "setlang", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::ChatMemory).await {
+            return;
+        }
+
+        let language_code = context.text.value.trim();
+
+        if !phrase_indexing::is_language_code(language_code) {
+            error::report_error(&Error::parse("language code", language_code));
+            state.lock().await.send_reply(
+                context.chat.id,
+                "usage: /setlang <language code>, e.g. pt-BR",
+            );
+            return;
+        }
+
+        let state = &mut *state.lock().await;
+
+        match state.set_chat_language(context.chat.id, language_code) {
+            Ok(Ok(())) => {
+                let confirmation = format!("ok, I'll read this chat as {}", language_code);
+                state.send_reply(context.chat.id, &confirmation);
+            }
+            Ok(Err(LanguageRejection::SharedMemory)) => {
+                state.send_reply(
+                    context.chat.id,
+                    "chats share my memory, so it can't have a language of its own",
+                );
+            }
+            Ok(Err(LanguageRejection::CaseFolding)) => {
+                let rejection = format!(
+                    "my memory of this chat already holds words whose case {} would fold otherwise",
+                    language_code
+                );
+                state.send_reply(context.chat.id, &rejection);
+            }
+            Err(err) => error::report_error(&err),
+        }
+    });
+
+    on_command(&mut bot, "pending", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Chat).await {
+            return;
+        }
+
+        let state = &mut *state.lock().await;
+
+        let pending = state.pending_changes.pending(context.chat.id);
+
+        let reply = if pending.is_empty() {
+            "no pending changes".into()
+        } else {
+            pending
+                .iter()
+                .map(|(id, change)| format!("{}. {}", id, change))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+
+        state.send_reply(context.chat.id, &reply);
+    });
+
+    on_command(&mut bot, "approve", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::ChatMemory).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+        let id = match msg_text.parse::<usize>() {
+            Ok(id) => id,
+            Err(_) => {
+                error::report_error(&Error::parse("pending change id", msg_text));
+                return;
+            }
+        };
+
+        let state = &mut *state.lock().await;
+
+        let reply = match state.pending_changes.approve(context.chat.id, id) {
+            Ok(Some(change)) => match state.apply_change(context.chat.id, &change) {
+                Ok(()) => format!("approved {}", change),
+                Err(err) => {
+                    error::report_error(&err);
+                    return;
+                }
+            },
+            Ok(None) => format!("no pending change {}", id),
+            Err(err) => {
+                error::report_error(&err);
+                return;
+            }
+        };
+
+        state.send_reply(context.chat.id, &reply);
+    });
+
+    on_command(&mut bot, "reject", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Chat).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+        let id = match msg_text.parse::<usize>() {
+            Ok(id) => id,
+            Err(_) => {
+                error::report_error(&Error::parse("pending change id", msg_text));
+                return;
+            }
+        };
+
+        let state = &mut *state.lock().await;
+
+        let reply = match state.pending_changes.reject(context.chat.id, id) {
+            Ok(Some(change)) => format!("rejected {}", change),
+            Ok(None) => format!("no pending change {}", id),
+            Err(err) => {
+                error::report_error(&err);
+                return;
+            }
+        };
+
+        state.send_reply(context.chat.id, &reply);
+    });
+
+    on_command(&mut bot, "setsourceweight", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+
+        let parsed = msg_text.split_once(' ').and_then(|(source, weight)| {
+            let source = source.parse::<PhraseSource>().ok()?;
+            let weight = weight.trim().parse::<f32>().ok()?;
+            Some((source, weight))
+        });
+
+        match parsed {
+            Some((source, weight)) if weight >= 0.0 => {
+                state
+                    .lock()
+                    .await
+                    .memories
+                    .set_source_weight(source, weight);
+            }
+            _ => error::report_error(&Error::parse("source weight", msg_text)),
+        }
+    });
+
+    on_command(
+        &mut bot,
+        "setfrequencytemperature",
+        |context, state| async move {
+            if is_duplicate(&*context, &state).await {
+                return;
+            }
+
+            if !require_admin(&*context, &state, AdminScope::Bot).await {
+                return;
+            }
+
+            let msg_text = context.text.value.trim();
+
+            let temperature = match msg_text {
+                "off" => Some(None),
+                temperature => temperature
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|&temperature| temperature > 0.0)
+                    .map(Some),
+            };
+
+            match temperature {
+                Some(temperature) => {
+                    state
+                        .lock()
+                        .await
+                        .memories
+                        .set_frequency_temperature(temperature);
+                }
+                None => error::report_error(&Error::parse("frequency temperature", msg_text)),
+            }
+        },
+    );
+
+    on_command(&mut bot, "setsourcequota", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        if !require_admin(&*context, &state, AdminScope::Bot).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+
+        let parsed = msg_text.split_once(' ').and_then(|(source, max_phrases)| {
+            let source = source.parse::<PhraseSource>().ok()?;
+            let max_phrases = match max_phrases.trim() {
+                "none" => None,
+                max_phrases => Some(max_phrases.parse::<usize>().ok()?),
+            };
+            Some((source, max_phrases))
+        });
+
+        match parsed {
+            Some((source, max_phrases)) => state
+                .lock()
+                .await
+                .source_quotas
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .set_max_phrases_per_hour(source, max_phrases),
+            None => error::report_error(&Error::parse("source quota", msg_text)),
+        }
+    });
+
+    on_command(&mut bot, "changes", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        let msg_text = context.text.value.trim();
+        let window = if msg_text.is_empty() { "24h" } else { msg_text };
+
+        let state = &mut *state.lock().await;
+
+        let window_duration = match changes::parse_window(window) {
+            Some(duration) if duration <= state.change_log.retention() => duration,
+            _ => {
+                error::report_error(&Error::parse("changes window", window));
+                return;
+            }
+        };
+
+        let since = Instant::now()
+            .checked_sub(window_duration)
+            .unwrap_or_else(Instant::now);
+        let summary = state.change_log.summarize(context.chat.id, since);
+
+        let memory = match state
+            .memories
+            .get_shared(Some(context.chat.id), &state.normalization_config)
+        {
+            Ok(memory) => memory,
+            Err(err) => {
+                error::report_error(&err);
+                return;
+            }
+        };
+        let memory = read_memory(&memory);
+        let indexed_phrases = &memory.indexed_phrases;
+
+        // The words that spread into the most phrases are the notable ones.
+        let new_words = summary.notable_words(indexed_phrases);
+
+        let notable_words = new_words
+            .iter()
+            .take(NOTABLE_NEW_WORD_COUNT)
+            .map(|&(word, _)| word)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let changes = format!(
+            "in the last {}: {} new phrases, {} new words\nnotable new words: {}",
+            window,
+            summary.new_phrase_count,
+            new_words.len(),
+            if notable_words.is_empty() {
+                "none"
+            } else {
+                &notable_words
+            },
+        );
+
+        state.send_reply(context.chat.id, &changes);
+    });
+
+    on_command(&mut bot, "best", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        let state = &mut *state.lock().await;
+
+        let best_replies = state.favorite_replies.best(
+            context.chat.id,
+            context.date - BEST_REPLIES_WINDOW_SECS,
+            BEST_REPLY_COUNT,
+        );
+
+        let board = if best_replies.is_empty() {
+            "no reply got any votes this month".into()
+        } else {
+            let ranking = best_replies
+                .iter()
+                .enumerate()
+                .map(|(i, (text, vote_count))| {
+                    format!("{}. {} ({} votes)", i + 1, text, vote_count)
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            format!("best replies of the month:\n{}", ranking)
+        };
+
+        state.send_reply(context.chat.id, &board);
+    });
+
+    on_command(&mut bot, "trending", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        let (trending_words, memory) = {
+            let state = &mut *state.lock().await;
+
+            let trending_words =
+                state
+                    .word_trends
+                    .trending(context.chat.id, context.date, usize::MAX);
+            let memory = state
+                .memories
+                .get_shared(Some(context.chat.id), &state.normalization_config);
+            (trending_words, memory)
+        };
+        let memory = match memory {
+            Ok(memory) => memory,
+            Err(err) => {
+                error::report_error(&err);
+                return;
+            }
+        };
+
+        // Stop words are left out before picking the top ones, so that they
+        // don't take up the chart.
+        let trending_words: Vec<_> = {
+            let memory = &*read_memory(&memory);
+
+            trending_words
+                .into_iter()
+                .filter(|trending_word| {
+                    memory
+                        .indexed_phrases
+                        .get_word_id(&trending_word.word)
+                        .is_none_or(|word_id| !memory.indexed_phrases.is_stop_word(word_id))
+                })
+                .take(TRENDING_WORD_COUNT)
+                .collect()
+        };
+
+        let chart = if trending_words.is_empty() {
+            "nothing is trending this week".into()
+        } else {
+            let ranking = trending_words
+                .iter()
+                .enumerate()
+                .map(|(i, trending_word)| {
+                    format!(
+                        "{}. {} ({} uses, {:.1}x as usual)",
+                        i + 1,
+                        trending_word.word,
+                        trending_word.uses,
+                        trending_word.spike
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            format!("trending this week:\n{}", ranking)
+        };
+
+        state.lock().await.send_reply(context.chat.id, &chart);
+    });
+
+    for (command, opted_out) in [("optout", true), ("optin", false)] {
+        on_command(&mut bot, command, move |context, state| async move {
+            if is_duplicate(&*context, &state).await {
+                return;
+            }
+
+            let user_id = match &context.from {
+                Some(user) => user.id,
+                None => return,
+            };
+
+            let state = &mut *state.lock().await;
+
+            if let Err(err) = state.set_user_opted_out(user_id, opted_out) {
+                error::report_error(&err);
+                return;
+            }
+
+            let confirmation = if opted_out {
+                "ok, I won't learn from your messages anymore"
+            } else {
+                "ok, I'll learn from your messages again"
+            };
+
+            state.send_reply(context.chat.id, confirmation);
+        });
+    }
+
+    on_command(&mut bot, "deletemydata", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        let user_id = match &context.from {
+            Some(user) => user.id,
+            None => return,
+        };
+
+        let state = &mut *state.lock().await;
+
+        match state.delete_user_data(user_id) {
+            Ok(forgotten_phrase_count) => {
+                let reply = format!("forgot {} phrases of yours", forgotten_phrase_count);
+                state.send_reply(context.chat.id, &reply);
+            }
+            Err(err) => error::report_error(&err),
+        }
+    });
+
+    on_command(&mut bot, "stats", |context, state| async move {
+        if is_duplicate(&*context, &state).await {
+            return;
+        }
+
+        let state = &mut *state.lock().await;
+
+        let memory_scope = state.memories.scope();
+        let memory = match state
+            .memories
+            .get_shared(Some(context.chat.id), &state.normalization_config)
+        {
+            Ok(memory) => memory,
+            Err(err) => {
+                error::report_error(&err);
+                return;
+            }
+        };
+        let memory = read_memory(&memory);
+        let indexed_phrases = &memory.indexed_phrases;
+
+        let average_quality = match indexed_phrases.average_quality() {
+            Some(quality) => format!("{:.2}", quality),
+            None => "n/a".into(),
+        };
+
+        let error_counts = error::error_counts()
+            .into_iter()
+            .map(|(category, count)| format!("{}: {}", category, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let phrase_counts_by_source = indexed_phrases
+            .phrase_count_by_source()
+            .into_iter()
+            .map(|(source, count)| {
+                let rejected_count = state
+                    .source_quotas
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .rejected_count(source);
+                format!("{}: {} ({} over quota)", source, count, rejected_count)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let rejection_counts = learn_filter::rejection_counts()
+            .into_iter()
+            .map(|(junk_kind, count)| format!("{}: {}", junk_kind, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let queue_metrics = writer::queue_metrics();
+
+        let stats = format!(
+            "memory: {}\nphrases: {}\nmerged duplicates: {}\nwords: {}\naverage quality: {}\n\
+             sources: {}\nrejected junk: {}\nwrite queue: {} lines (peak {}, dropped {})\n\
+             unavailable databases: {}\nerrors: {}",
+            memory_scope,
+            indexed_phrases.phrase_count(),
+            indexed_phrases.total_phrase_occurrences() - indexed_phrases.phrase_count(),
+            indexed_phrases.word_count(),
+            average_quality,
+            phrase_counts_by_source,
+            rejection_counts,
+            queue_metrics.queued_lines,
+            queue_metrics.peak_queued_lines,
+            queue_metrics.dropped_lines,
+            queue_metrics.unavailable_stores,
+            error_counts,
+        );
+
+        state.send_reply(context.chat.id, &stats);
+    });
+
+    let state = bot.get_state();
+
+    let handle_updates = async move {
+        if let Some(webhook_config) = &config.webhook {
+            return webhook::serve(bot.into_stateless(), webhook_config).await;
+        }
+
+        tracing::info!("starting to poll");
+
+        bot.polling().start().await.unwrap();
+
+        Ok(())
+    };
+
+    tokio::select! {
+        result = handle_updates => result?,
+        result = shutdown::signal() => result?,
+    }
+
+    tracing::info!("shutting down");
+
+    // Handlers only write while holding the state, or the memory of a chat,
+    // which flushing locks as well, so none is midway through a write then.
+    let state = &mut *state.lock().await;
+    state.save_update_offset();
+    if let Err(err) = state.word_trends.save() {
+        error::report_error(&err);
+    }
+    if let Err(err) = state.contributions.save() {
+        error::report_error(&err);
+    }
+    state.memories.flush(&state.normalization_config)
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs() as i64)
+}
+
+/// Reads the ids of the channels to learn from, which are given as a comma
+/// separated list in `FOLLOWED_CHANNELS`.
+fn followed_channels_from_env() -> error::Result<HashSet<chat::Id>> {
+    let followed_channels = match std::env::var("FOLLOWED_CHANNELS") {
+        Ok(followed_channels) => followed_channels,
+        Err(_) => return Ok(HashSet::new()),
+    };
+
+    followed_channels
+        .split(',')
+        .map(str::trim)
+        .filter(|channel_id| !channel_id.is_empty())
+        .map(|channel_id| {
+            channel_id
+                .parse()
+                .map(chat::Id)
+                .map_err(|_| Error::parse("followed channel id", channel_id))
+        })
+        .collect()
+}
+
+/// Learns from the text of a message, or the caption of a media message, and
+/// replies to it by chance, or when addressed if replying only to mentions.
+async fn handle_text<C>(context: Arc<C>, state: Arc<Mutex<BotState>>)
+where
+    C: tbot::contexts::fields::AnyText,
+{
+    let span = message_span(&*context);
+    learn_and_reply(context, state).instrument(span).await
+}
+
+/// What learning from a message and replying to it takes besides the memory
+/// of its chat, so that the state can be unlocked meanwhile.
+struct MessageWork {
+    memory: SharedMemory,
+    source: PhraseSource,
+    should_reply: bool,
+    replies_with_emojis: bool,
+    normalization_config: NormalizationConfig,
+    phrase_filter: PhraseFilter,
+    sentence_config: SentenceConfig,
+    source_quotas: Arc<std::sync::Mutex<SourceQuotas>>,
+    emoji_tracker: Arc<std::sync::Mutex<EmojiTracker>>,
+    garnisher: Arc<Garnisher>,
+    rng: rand::rngs::StdRng,
+}
+
+/// What was made up to reply to a message with, while its chat was locked.
+enum Reply {
+    Emojis(String),
+    Generated(String),
+    NothingGenerated,
+    Failed(Error),
+}
+
+async fn learn_and_reply<C>(context: Arc<C>, state: Arc<Mutex<BotState>>)
+where
+    C: tbot::contexts::fields::AnyText,
+{
+    let text = context.text();
+
+    // Media are often sent without a caption.
+    if text.value.trim().is_empty() || is_duplicate(&*context, &state).await {
+        return;
+    }
+
+    let chat_id = context.chat().id;
+    let is_channel_post = matches!(context.chat().kind, chat::Kind::Channel { .. });
+
+    // Only the memory of the chat is locked while learning and generating, so
+    // that busy chats don't hold up the others. The state is never locked
+    // while the memory is, but only before or after, so they can't deadlock.
+    let work = {
+        let state = &mut *state.lock().instrument(tracing::info_span!("lock")).await;
+
+        if is_channel_post && !state.followed_channels.contains(&chat_id) {
+            return;
+        }
+
+        let source = if is_channel_post {
+            PhraseSource::Channel
+        } else {
+            PhraseSource::Chat
+        };
+
+        if let (Some(reply_to), Some(voter)) = (context.reply_to(), context.from()) {
+            state.record_feedback(&text.value, reply_to, voter, context.date());
+        }
+
+        if let Some(reply_to) = context.reply_to() {
+            state.record_engagement(chat_id, reply_to, context.date());
+        }
+
+        // Replies are made around what was just learned, so there's nothing to
+        // reply with either.
+        let is_opted_out = context
+            .from()
+            .is_some_and(|user| state.opted_out_users.contains(&user.id));
+
+        if is_opted_out {
+            tracing::info!(
+                "not learning message {}, its sender opted out",
+                context.message_id()
+            );
+            return;
+        }
+
+        let memory = tracing::info_span!("load").in_scope(|| {
+            state
+                .memories
+                .get_shared(Some(chat_id), &state.normalization_config)
+        });
+        let memory = match memory {
+            Ok(memory) => memory,
+            Err(err) => {
+                error::report_error(&err);
+                return;
+            }
+        };
+
+        let should_reply = !is_channel_post
+            && !state.muted_chats.contains(&chat_id)
+            && state.is_reply_due(&*context);
+
+        MessageWork {
+            memory,
+            source,
+            should_reply,
+            replies_with_emojis: should_reply && state.rng.gen::<f32>() < state.emoji_reply_prob,
+            normalization_config: state.normalization_config_of(Some(chat_id)),
+            phrase_filter: state.phrase_filter,
+            sentence_config: state.sentence_config.clone(),
+            source_quotas: Arc::clone(&state.source_quotas),
+            emoji_tracker: Arc::clone(&state.emoji_tracker),
+            garnisher: Arc::clone(&state.garnisher),
+            rng: rand::rngs::StdRng::from_seed(state.rng.gen()),
+        }
+    };
+
+    let learned_at = unix_now();
+    let (learned_text, reply) = {
+        let MessageWork {
+            memory,
+            source,
+            should_reply,
+            replies_with_emojis,
+            normalization_config,
+            phrase_filter,
+            sentence_config,
+            source_quotas,
+            emoji_tracker,
+            garnisher,
+            mut rng,
+        } = work;
+
+        // Learning takes the memory only briefly, so that replies to other
+        // messages can be generated from it meanwhile.
+        let learned_text = {
+            let memory = &mut *write_memory(&memory);
+            let learned_text = learn_into_memory(
+                memory,
+                &text.value,
+                source,
+                None,
+                &normalization_config,
+                phrase_filter,
+                &source_quotas,
+            );
+            memory.remember_short_term(&learned_text.indexed_phrases, learned_at);
+            learned_text
+        };
+        let memory = &*read_memory(&memory);
+
+        let mut emoji_tracker = emoji_tracker.lock().unwrap_or_else(PoisonError::into_inner);
+        emoji_tracker.record(
+            chat_id,
+            &emoji::extract_emojis(&text.value),
+            &memory.indexed_phrases,
+            &learned_text.word_ids_from_phrases,
+        );
+
+        let emojis = if replies_with_emojis {
+            emoji_tracker.pick_emojis(
+                chat_id,
+                &memory.indexed_phrases,
+                &learned_text.word_ids_from_phrases,
+                &mut rng,
+            )
+        } else {
+            None
+        };
+        drop(emoji_tracker);
+
+        let reply = match emojis {
+            _ if !should_reply => None,
+            Some(emojis) => Some(Reply::Emojis(emojis)),
+            None => {
+                let _generating = tracing::info_span!("generate").entered();
+                let incoming_phrases: Vec<_> =
+                    learned_text.phrases.iter().map(String::as_str).collect();
+                let generated_response = generation::generate_phrase(
+                    &memory.indexed_phrases,
+                    &memory.answer_pools,
+                    learned_text.word_ids_from_phrases.iter().copied().collect(),
+                    &incoming_phrases,
+                    context.date(),
+                    &mut rng,
+                );
+
+                Some(match generated_response {
+                    Ok(Some(response)) => {
+                        let response = generation::extend_into_sentences(
+                            &memory.indexed_phrases,
+                            &memory.answer_pools,
+                            response,
+                            &sentence_config,
+                            context.date(),
+                            &mut rng,
+                        );
+                        let response =
+                            garnisher.garnish(&memory.indexed_phrases, response, &mut rng);
+                        Reply::Generated(memory.indexed_phrases.restore_acronyms(&response))
+                    }
+                    Ok(None) => Reply::NothingGenerated,
+                    Err(err) => Reply::Failed(err.into()),
+                })
+            }
+        };
+
+        (learned_text, reply)
+    };
+
+    let state = &mut *state.lock().instrument(tracing::info_span!("lock")).await;
+
+    if !learned_text.indexed_phrases.is_empty() {
+        let logged =
+            state
+                .memories
+                .log_short_term(chat_id, &learned_text.indexed_phrases, learned_at);
+
+        if let Err(err) = logged {
+            error::report_error(&err);
+        }
+    }
+
+    state.change_log.record(
+        chat_id,
+        Instant::now(),
+        learned_text.new_phrase_count,
+        learned_text.new_words,
+    );
+
+    state.word_trends.record(
+        chat_id,
+        learned_text
+            .phrases
+            .iter()
+            .flat_map(|phrase| phrase.split_whitespace()),
+        context.date(),
+    );
+
+    if let (false, Some(user)) = (is_channel_post, context.from()) {
+        state.contributions.record(
+            user.id,
+            chat_id,
+            learned_text.phrases.iter().map(String::as_str),
+        );
+    }
+
+    if !is_channel_post {
+        state.propose_aliases(chat_id, &text.value);
+    }
+
+    let reply = match reply {
+        Some(reply) => reply,
+        None => return,
+    };
+
+    // Another message of the chat may have been replied to meanwhile.
+    if state
+        .reply_suppression
+        .is_suppressed(chat_id, Instant::now())
+    {
+        tracing::info!("not replying, chat {} was replied to just now", chat_id);
+        return;
+    }
+
+    let reply = match reply {
+        Reply::Emojis(emojis) => {
+            tracing::info!("generated emoji response: `{}`", emojis);
+            record_outcome("emoji");
+            emojis
+        }
+        Reply::Generated(response) => {
+            let response = state.restore_protected_words(Some(chat_id), &response);
+            let response = state.reply_styler.style(&response, &mut state.rng);
+            tracing::info!("generated response: `{}`", response);
+            record_outcome("generated");
+            response
+        }
+        Reply::NothingGenerated => {
+            tracing::info!("couldn't generate a response");
+            record_outcome("nothing generated");
+            return;
+        }
+        Reply::Failed(err) => {
+            record_outcome("failed");
+            error::report_error(&err);
+            return;
+        }
+    };
+
+    state.send_reply(chat_id, &reply);
+    state
+        .reply_suppression
+        .record_reply(chat_id, Instant::now());
+}
+
+/// Runs the bot through the adapter instead of on Telegram, until it's asked
+/// to stop.
+async fn run_on_adapter(
+    adapter: impl ChatAdapter,
+    config: &Config,
+    mut memories: Memories,
+) -> error::Result<()> {
+    let reply_config = AdapterReplyConfig {
+        reply_probability: config.reply_probability,
+        reply_to_mentions_only: config.reply_to_mentions_only,
+        sentence_config: SentenceConfig::default(),
+        reply_styler: config.reply_styler(),
+    };
+    let mut rng = rand::rngs::StdRng::from_entropy();
+
+    tokio::select! {
+        () = adapter::run(
+            adapter,
+            &mut memories,
+            config.phrase_filter(),
+            &reply_config,
+            &mut rng,
+        ) => {}
+        result = shutdown::signal() => result?,
+    }
+
+    tracing::info!("shutting down");
+
+    memories.flush(&NormalizationConfig::default())
+}
+
+/// Registers the handler of the command, which handles each message within a
+/// span of it.
+fn on_command<H, F>(bot: &mut StatefulEventLoop<Mutex<BotState>>, command: &'static str, handler: H)
+where
+    H: Fn(Arc<contexts::Command<contexts::Text>>, Arc<Mutex<BotState>>) -> F
+        + Send
+        + Sync
+        + 'static,
+    F: Future<Output = ()> + Send + 'static,
+{
+    bot.command(command, move |context, state| {
+        let span = message_span(&*context);
+        span.record("command", command);
+
+        handler(context, state).instrument(span)
+    });
+}
+
+/// A span for handling a message, so that what's logged meanwhile can be told
+/// apart from what's logged for other messages of busy chats. The outcome of
+/// generating a reply, if any, is recorded in it.
+fn message_span<C>(context: &C) -> tracing::Span
+where
+    C: tbot::contexts::fields::Message,
+{
+    tracing::info_span!(
+        "message",
+        chat_id = context.chat().id.0,
+        message_id = context.message_id().0,
+        command = tracing::field::Empty,
+        outcome = tracing::field::Empty,
+        phrase_count = tracing::field::Empty,
+    )
+}
+
+/// Records the outcome of generating a reply in the span of the message.
+fn record_outcome(outcome: &str) {
+    tracing::Span::current().record("outcome", outcome);
+}
+
+/// Whether the message was already handled, in which case it must be ignored.
+async fn is_duplicate<C>(context: &C, state: &Mutex<BotState>) -> bool
+where
+    C: tbot::contexts::fields::Message,
+{
+    let is_new = state
+        .lock()
+        .await
+        .recent_messages
+        .insert(context.chat().id, context.message_id());
+
+    if !is_new {
+        tracing::info!(
+            "ignoring message {} of chat {}, it was already handled",
+            context.message_id(),
+            context.chat().id
+        );
+    }
+
+    !is_new
+}
+
+/// What a command changes, which decides who may send it.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+enum AdminScope {
+    /// Something only the chat the command was sent to sees.
+    Chat,
+    /// The memory of the chat, which is only the chat's own if chats don't
+    /// share their memory.
+    ChatMemory,
+    /// Something every chat sees.
+    Bot,
+}
+
+/// Checks that the sender of a command that changes the state of the bot is a
+/// configured admin, or, for changes to the chat alone, an admin of the group
+/// the command was sent to.
+async fn require_admin<C>(context: &C, state: &Mutex<BotState>, scope: AdminScope) -> bool
+where
+    C: tbot::contexts::fields::Message,
+{
+    let user_id = match context.from() {
+        Some(user) => user.id,
+        None => return false,
+    };
+
+    let is_chat_scoped = {
+        let state = state.lock().await;
+
+        if state.admins.contains(user_id) {
+            return true;
+        }
+
+        match scope {
+            AdminScope::Chat => true,
+            AdminScope::ChatMemory => state.memories.scope() == MemoryScope::PerChat,
+            AdminScope::Bot => false,
+        }
+    };
+
+    if !is_chat_scoped {
+        tracing::info!(
+            "ignoring command of {}, who isn't a configured admin",
+            user_id
+        );
+        return false;
+    }
+
+    let chat = context.chat();
+
+    let is_group = matches!(
+        chat.kind,
+        chat::Kind::Group { .. } | chat::Kind::Supergroup { .. }
+    );
+
+    let is_admin = is_group
+        && match auth::is_chat_admin(context.bot(), chat.id, user_id).await {
+            Ok(is_admin) => is_admin,
+            Err(err) => {
+                error::report_error(&err);
+                false
+            }
+        };
+
+    if !is_admin {
+        tracing::info!("ignoring command of {}, who isn't an admin", user_id);
+    }
+
+    is_admin
+}
+
+/// Normalizes the text the way phrases are, which must leave a single word.
+fn parse_word(text: &str, normalization_config: &NormalizationConfig) -> Option<String> {
+    match phrase_indexing::normalize_text_into_phrases(text.into(), normalization_config).as_slice()
+    {
+        [phrase] if !phrase.as_ref().contains(' ') => Some(phrase.as_ref().to_string()),
+        _ => None,
+    }
+}
+
+/// Tags are given as single words, optionally prefixed with `#`, e.g. `#meme`.
+fn parse_tag(text: &str) -> Option<String> {
+    let tag = text.trim();
+    let tag = tag.strip_prefix('#').unwrap_or(tag).to_lowercase();
+
+    if tag.is_empty() || !tag.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+
+    Some(tag)
+}
+
+/// Opens the shared memory at `path`, or the memory of the chat, which is kept
+/// next to the shared one. Appends are written in the background.
+fn open_phrase_store(
+    database_kind: DatabaseKind,
+    path: &Path,
+    chat_id: Option<chat::Id>,
+    write_queue_config: WriteQueueConfig,
+) -> error::Result<Box<dyn PhraseStore>> {
+    let store: Box<dyn PhraseStore> = match (database_kind, chat_id) {
+        (DatabaseKind::Sqlite, None) => Box::new(SqliteStore::open(path)?),
+        (DatabaseKind::Sqlite, Some(chat_id)) => {
+            Box::new(SqliteStore::open(store::chat_store_path(path, chat_id))?)
+        }
+        (DatabaseKind::FlatFile, None) => Box::new(FlatFileStore::new(path)),
+        // Chats show up over time, so their files may not exist yet.
+        (DatabaseKind::FlatFile, Some(chat_id)) => Box::new(FlatFileStore::create_if_missing(
+            store::chat_store_path(path, chat_id),
+        )?),
+    };
 
-    Some(phrase_indexing::concatenate_indexed_phrases(
-        *first_phrase,
-        *second_phrase,
-    ))
+    Ok(Box::new(BackgroundWriteStore::spawn(
+        store,
+        write_queue_config,
+    )))
 }
@@ -0,0 +1,404 @@
+use crate::adapter::{AdapterFuture, ChatAdapter, IncomingText};
+use crate::error::{self, Error};
+use crate::http::{self, HttpsClient};
+use hyper::{Body, Method, Request};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tbot::types::chat;
+use tokio::sync::mpsc;
+
+/// How long the homeserver may hold each sync request until something happens.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// Skips the history of every room on the first sync, so that old messages
+/// aren't replied to.
+const INITIAL_SYNC_FILTER: &str = r#"{"room":{"timeline":{"limit":0}}}"#;
+/// Matrix caps events at 64 KiB, which leaves room for about this much text
+/// along with the rest of the event.
+const MATRIX_MAX_MESSAGE_LEN: usize = 30_000;
+/// How long to wait before calling the homeserver again after failing to.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Messages received but not handled yet, past which the homeserver is no
+/// longer synced with until they are.
+const MAX_QUEUED_MESSAGES: usize = 100;
+
+/// Runs the bot on Matrix, through the client-server API of a homeserver, as
+/// the user owning the access token. Rooms stand for chats, by a hash of their
+/// id, and the bot joins every room it's invited to.
+pub(crate) struct MatrixAdapter {
+    client: MatrixClient,
+    incoming_texts: mpsc::Receiver<IncomingText>,
+    room_ids: Arc<Mutex<HashMap<chat::Id, String>>>,
+    /// Transaction ids must be unique for the access token, restarts included.
+    transaction_id_prefix: u128,
+    next_transaction_id: AtomicU64,
+}
+
+impl MatrixAdapter {
+    /// Keeps syncing with the homeserver in the background, retrying whenever
+    /// it fails to.
+    pub(crate) fn connect(homeserver_url: &str, access_token: String) -> MatrixAdapter {
+        let client = MatrixClient {
+            homeserver_url: homeserver_url.trim_end_matches('/').into(),
+            access_token,
+            client: http::new_client(),
+        };
+        let (sender, incoming_texts) = mpsc::channel(MAX_QUEUED_MESSAGES);
+        let room_ids = Arc::new(Mutex::new(HashMap::new()));
+        tokio::spawn(keep_syncing(client.clone(), Arc::clone(&room_ids), sender));
+
+        MatrixAdapter {
+            client,
+            incoming_texts,
+            room_ids,
+            transaction_id_prefix: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_millis()),
+            next_transaction_id: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ChatAdapter for MatrixAdapter {
+    fn receive(&mut self) -> AdapterFuture<'_, Option<IncomingText>> {
+        Box::pin(self.incoming_texts.recv())
+    }
+
+    fn send_text<'a>(
+        &'a self,
+        chat_id: chat::Id,
+        text: &'a str,
+    ) -> AdapterFuture<'a, error::Result<()>> {
+        Box::pin(async move {
+            let room_id = self
+                .room_ids
+                .lock()
+                .unwrap()
+                .get(&chat_id)
+                .cloned()
+                .ok_or_else(|| Error::parse("matrix room", chat_id.0.to_string()))?;
+            let transaction_id = format!(
+                "{}-{}",
+                self.transaction_id_prefix,
+                self.next_transaction_id.fetch_add(1, Ordering::Relaxed)
+            );
+
+            let path = format!(
+                "/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+                http::uri_encode(&room_id, true),
+                transaction_id
+            );
+            let content = json!({ "msgtype": "m.text", "body": text });
+
+            self.client
+                .call(Method::PUT, &path, Some(content), || {
+                    format!("sending message to matrix room `{}`", room_id)
+                })
+                .await
+                .map(|_| ())
+        })
+    }
+
+    fn max_message_len(&self) -> usize {
+        MATRIX_MAX_MESSAGE_LEN
+    }
+}
+
+#[derive(Clone)]
+struct MatrixClient {
+    homeserver_url: String,
+    access_token: String,
+    client: HttpsClient,
+}
+
+impl MatrixClient {
+    /// Calls the endpoint at `path` of the homeserver, and returns what it
+    /// answers with.
+    async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        content: Option<serde_json::Value>,
+        context: impl FnOnce() -> String,
+    ) -> error::Result<serde_json::Value> {
+        let url = format!("{}{}", self.homeserver_url, path);
+        let body = match content {
+            Some(content) => Body::from(content.to_string()),
+            None => Body::empty(),
+        };
+        let request = Request::builder()
+            .method(method)
+            .uri(&url)
+            .header("Authorization", format!("Bearer {}", self.access_token))
+            .header("Content-Type", "application/json")
+            .body(body)
+            .map_err(|_| Error::parse("matrix url", &url))?;
+
+        let response = http::fetch(&self.client, request, context).await?;
+
+        serde_json::from_slice(&response)
+            .map_err(|_| Error::parse("matrix response", String::from_utf8_lossy(&response)))
+    }
+}
+
+#[derive(Deserialize)]
+struct SyncResponse {
+    next_batch: String,
+    #[serde(default)]
+    rooms: SyncRooms,
+}
+
+#[derive(Deserialize, Default)]
+struct SyncRooms {
+    #[serde(default)]
+    join: HashMap<String, JoinedRoom>,
+    #[serde(default)]
+    invite: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct JoinedRoom {
+    #[serde(default)]
+    timeline: Timeline,
+}
+
+#[derive(Deserialize, Default)]
+struct Timeline {
+    #[serde(default)]
+    events: Vec<serde_json::Value>,
+}
+
+async fn keep_syncing(
+    client: MatrixClient,
+    room_ids: Arc<Mutex<HashMap<chat::Id, String>>>,
+    mut sender: mpsc::Sender<IncomingText>,
+) {
+    let user_id = loop {
+        let whoami = client
+            .call(
+                Method::GET,
+                "/_matrix/client/v3/account/whoami",
+                None,
+                || "fetching the matrix bot user".into(),
+            )
+            .await;
+
+        match whoami.map(|whoami| whoami["user_id"].as_str().map(String::from)) {
+            Ok(Some(user_id)) => break user_id,
+            Ok(None) => error::report_error(&Error::parse("matrix bot user", "whoami")),
+            Err(err) => error::report_error(&err),
+        }
+
+        tokio::time::delay_for(RETRY_DELAY).await;
+    };
+
+    let mut since: Option<String> = None;
+
+    loop {
+        let path = match &since {
+            Some(since) => format!(
+                "/_matrix/client/v3/sync?timeout={}&since={}",
+                SYNC_TIMEOUT.as_millis(),
+                http::uri_encode(since, true)
+            ),
+            None => format!(
+                "/_matrix/client/v3/sync?filter={}",
+                http::uri_encode(INITIAL_SYNC_FILTER, true)
+            ),
+        };
+
+        let sync = client
+            .call(Method::GET, &path, None, || {
+                "syncing with the matrix homeserver".into()
+            })
+            .await
+            .and_then(|sync| {
+                serde_json::from_value::<SyncResponse>(sync)
+                    .map_err(|_| Error::parse("matrix sync response", path.as_str()))
+            });
+
+        let sync = match sync {
+            Ok(sync) => sync,
+            Err(err) => {
+                error::report_error(&err);
+                tokio::time::delay_for(RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        for room_id in sync.rooms.invite.keys() {
+            let path = format!(
+                "/_matrix/client/v3/join/{}",
+                http::uri_encode(room_id, true)
+            );
+            let joined = client
+                .call(Method::POST, &path, Some(json!({})), || {
+                    format!("joining matrix room `{}`", room_id)
+                })
+                .await;
+
+            match joined {
+                Ok(_) => tracing::info!("joined matrix room `{}`", room_id),
+                Err(err) => error::report_error(&err),
+            }
+        }
+
+        for (room_id, room) in sync.rooms.join {
+            for event in room.timeline.events {
+                let incoming_text = match parse_event(&room_id, event, &user_id) {
+                    Some(incoming_text) => incoming_text,
+                    None => continue,
+                };
+
+                room_ids
+                    .lock()
+                    .unwrap()
+                    .insert(incoming_text.chat_id, room_id.clone());
+
+                if sender.send(incoming_text).await.is_err() {
+                    return;
+                }
+            }
+        }
+
+        since = Some(sync.next_batch);
+    }
+}
+
+/// The chat standing for the room, which is the same across runs.
+fn room_chat_id(room_id: &str) -> chat::Id {
+    let hash = Sha256::digest(room_id.as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hash[..8]);
+
+    chat::Id(i64::from_be_bytes(bytes))
+}
+
+#[derive(Deserialize)]
+struct RoomEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    sender: String,
+    content: MessageContent,
+}
+
+#[derive(Deserialize)]
+struct MessageContent {
+    msgtype: String,
+    body: String,
+    #[serde(rename = "m.mentions", default)]
+    mentions: Mentions,
+    #[serde(rename = "m.relates_to")]
+    relates_to: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Default)]
+struct Mentions {
+    #[serde(default)]
+    user_ids: Vec<String>,
+}
+
+/// The text of a message sent to a room, without the quote of the message it
+/// replies to, unless the bot itself sent it, or it's a notice, which is what
+/// bots send, or an edit of an earlier message.
+fn parse_event(room_id: &str, event: serde_json::Value, user_id: &str) -> Option<IncomingText> {
+    let event: RoomEvent = serde_json::from_value(event).ok()?;
+
+    if event.kind != "m.room.message" || event.content.msgtype != "m.text" {
+        return None;
+    }
+
+    if event.sender == user_id {
+        return None;
+    }
+
+    let is_edit = event
+        .content
+        .relates_to
+        .as_ref()
+        .is_some_and(|relates_to| relates_to["rel_type"] == "m.replace");
+    if is_edit {
+        return None;
+    }
+
+    // Replies quote the message they reply to, and who sent it, e.g.
+    // `> <@alice:example.org> hello there`, followed by an empty line.
+    let (quote, text): (Vec<_>, Vec<_>) = event
+        .content
+        .body
+        .lines()
+        .partition(|line| line.starts_with("> "));
+    let replies_to_bot = quote
+        .first()
+        .is_some_and(|line| line.starts_with(&format!("> <{}>", user_id)));
+    let mentions_bot = replies_to_bot
+        || event
+            .content
+            .mentions
+            .user_ids
+            .iter()
+            .any(|id| id == user_id)
+        || event.content.body.contains(user_id);
+
+    Some(IncomingText {
+        chat_id: room_chat_id(room_id),
+        text: text.join("\n").trim().into(),
+        mentions_bot,
+    })
+}
+
+#[cfg(test)]
+mod matrix_tests {
+    use super::{parse_event, room_chat_id};
+    use crate::adapter::IncomingText;
+    use serde_json::json;
+
+    #[test]
+    fn should_parse_replies_to_the_bot() {
+        let event = json!({
+            "type": "m.room.message",
+            "sender": "@alice:example.org",
+            "content": {
+                "msgtype": "m.text",
+                "body": "> <@bot:example.org> my good friend\n\nhello there",
+                "m.relates_to": { "m.in_reply_to": { "event_id": "$1" } },
+            },
+        });
+
+        assert_eq!(
+            parse_event("!room:example.org", event, "@bot:example.org"),
+            Some(IncomingText {
+                chat_id: room_chat_id("!room:example.org"),
+                text: "hello there".into(),
+                mentions_bot: true,
+            })
+        );
+    }
+
+    #[test]
+    fn should_ignore_notices_and_messages_of_the_bot() {
+        let notice = json!({
+            "type": "m.room.message",
+            "sender": "@other_bot:example.org",
+            "content": { "msgtype": "m.notice", "body": "hello there" },
+        });
+        let own_message = json!({
+            "type": "m.room.message",
+            "sender": "@bot:example.org",
+            "content": { "msgtype": "m.text", "body": "hello there" },
+        });
+
+        assert_eq!(
+            parse_event("!room:example.org", notice, "@bot:example.org"),
+            None
+        );
+        assert_eq!(
+            parse_event("!room:example.org", own_message, "@bot:example.org"),
+            None
+        );
+    }
+}
@@ -0,0 +1,1503 @@
+use crate::error;
+use crate::learn_filter;
+use crate::short_term::{ShortTermLog, SHORT_TERM_WINDOW_SECS};
+use crate::snapshot;
+use crate::store::{self, LineAttributes, PhraseStore};
+use feroldinhobot::answer_pool::{AnswerPoolCache, AnswerPoolConfig};
+use feroldinhobot::phrase_indexing::{
+    self, IndexedPhrases, JunctionDistribution, NormalizationConfig,
+};
+use feroldinhobot::sources::{self, PhraseSource};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tbot::types::chat;
+
+const NEAR_DUPLICATE_MIN_SIMILARITY: f32 = 0.9;
+
+/// Whether chats share what they learn.
+#[derive(Deserialize, PartialEq, Eq, Debug, Default, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum MemoryScope {
+    /// Every chat learns into, and replies from, the shared memory.
+    #[default]
+    Global,
+    /// Every chat has a memory of its own, so nothing said in a chat shows up
+    /// in the replies of another. Feeds and social posts are still learned into
+    /// the shared memory, which no chat replies from in this scope.
+    PerChat,
+}
+
+/// The language the memory of a chat is normalized for, instead of the
+/// configured one.
+#[derive(Clone, Debug)]
+pub(crate) struct ChatLanguage {
+    pub(crate) code: String,
+    pub(crate) stop_words: Vec<String>,
+}
+
+/// Why the language of a chat wasn't set.
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) enum LanguageRejection {
+    /// Chats share their memory, which isn't any chat's to set the language of.
+    SharedMemory,
+    /// The memory of the chat holds phrases case folded otherwise, which can't
+    /// be folded again, as only their folded form is stored.
+    CaseFolding,
+}
+
+impl fmt::Display for MemoryScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MemoryScope::Global => "global",
+            MemoryScope::PerChat => "per-chat",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for MemoryScope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(MemoryScope::Global),
+            "per-chat" => Ok(MemoryScope::PerChat),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A corpus along with where it's persisted.
+pub(crate) struct Memory {
+    pub(crate) indexed_phrases: IndexedPhrases,
+    pub(crate) phrase_store: Box<dyn PhraseStore>,
+    pub(crate) answer_pools: AnswerPoolCache,
+    /// Archived memories are only replied from. Nothing is learned into them,
+    /// nor evicted from them, and their store is never compacted, so that it's
+    /// kept exactly as it was.
+    pub(crate) archived: bool,
+}
+
+impl Memory {
+    /// Indexes the stored phrases, from the snapshot of the store if possible.
+    pub(crate) fn load(
+        mut phrase_store: Box<dyn PhraseStore>,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<Memory> {
+        let indexed_phrases = init_indexed_phrases(&mut *phrase_store, normalization_config, true)?;
+
+        Ok(Memory {
+            indexed_phrases,
+            phrase_store,
+            answer_pools: AnswerPoolCache::new(AnswerPoolConfig::default()),
+            archived: false,
+        })
+    }
+
+    /// Indexes the stored phrases like `load`, but as an archived memory, whose
+    /// store is left untouched.
+    pub(crate) fn load_archived(
+        mut phrase_store: Box<dyn PhraseStore>,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<Memory> {
+        let indexed_phrases =
+            init_indexed_phrases(&mut *phrase_store, normalization_config, false)?;
+
+        Ok(Memory {
+            indexed_phrases,
+            phrase_store,
+            answer_pools: AnswerPoolCache::new(AnswerPoolConfig::default()),
+            archived: true,
+        })
+    }
+
+    /// Removes the phrases of the text from the index and from the store. A
+    /// single word removes every phrase containing it. Returns how many phrases
+    /// were removed.
+    pub(crate) fn forget(
+        &mut self,
+        text: &str,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<usize> {
+        let mut forgotten_phrases = HashSet::new();
+
+        for phrase in
+            phrase_indexing::normalize_text_into_phrases(text.into(), normalization_config)
+        {
+            let phrase_content = phrase.as_ref();
+
+            if phrase_content.contains(' ') {
+                if self.indexed_phrases.remove_phrase(phrase_content) {
+                    forgotten_phrases.insert(phrase_content.to_string());
+                }
+            } else {
+                forgotten_phrases.extend(self.indexed_phrases.remove_word(phrase_content));
+            }
+        }
+
+        self.delete_stored_phrases(&forgotten_phrases, normalization_config)?;
+
+        Ok(forgotten_phrases.len())
+    }
+
+    /// Removes the phrases from the index and from the store. Returns how many
+    /// of them were indexed.
+    pub(crate) fn forget_phrases(
+        &mut self,
+        phrases: &HashSet<String>,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<usize> {
+        let forgotten_phrases: HashSet<_> = phrases
+            .iter()
+            .filter(|phrase| self.indexed_phrases.remove_phrase(phrase))
+            .cloned()
+            .collect();
+
+        self.delete_stored_phrases(&forgotten_phrases, normalization_config)?;
+
+        Ok(forgotten_phrases.len())
+    }
+
+    /// Makes sure the store is on disk, and compacts it the way loading
+    /// without a snapshot does, so that it's left in canonical form, with a
+    /// fresh snapshot, unless the memory is archived.
+    pub(crate) fn flush(
+        &mut self,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<()> {
+        self.phrase_store.flush()?;
+
+        if self.archived {
+            return Ok(());
+        }
+
+        let lines = self.phrase_store.load()?;
+        compact_indexed_phrases(&mut *self.phrase_store, lines, normalization_config)?;
+
+        Ok(())
+    }
+
+    /// Puts phrases just learned into short-term memory, as learned at
+    /// `learned_at`.
+    pub(crate) fn remember_short_term(&mut self, phrases: &[String], learned_at: i64) {
+        for phrase in phrases {
+            self.indexed_phrases
+                .mark_phrase_short_term(phrase, learned_at);
+        }
+    }
+
+    /// Removes the phrases that have expired by `now` from the index and from
+    /// the store. Returns how many phrases were removed.
+    pub(crate) fn remove_expired_phrases(
+        &mut self,
+        now: i64,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<usize> {
+        if self.archived {
+            return Ok(0);
+        }
+
+        let expired_phrases: HashSet<_> = self
+            .indexed_phrases
+            .remove_expired_phrases(now)
+            .into_iter()
+            .collect();
+
+        self.delete_stored_phrases(&expired_phrases, normalization_config)?;
+
+        Ok(expired_phrases.len())
+    }
+
+    /// Removes the least recently learned phrases past the caps from the index
+    /// and from the store. Returns how many phrases were removed.
+    pub(crate) fn evict_least_recently_learned(
+        &mut self,
+        max_phrase_count: Option<usize>,
+        max_word_count: Option<usize>,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<usize> {
+        if self.archived {
+            return Ok(0);
+        }
+
+        let evicted_phrases: HashSet<_> = self
+            .indexed_phrases
+            .evict_least_recently_learned(max_phrase_count, max_word_count)
+            .into_iter()
+            .collect();
+
+        self.delete_stored_phrases(&evicted_phrases, normalization_config)?;
+
+        Ok(evicted_phrases.len())
+    }
+
+    /// Deletes the stored lines with any of the phrases, which must have been
+    /// removed from the index already.
+    fn delete_stored_phrases(
+        &mut self,
+        phrases: &HashSet<String>,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<()> {
+        if phrases.is_empty() {
+            return Ok(());
+        }
+
+        self.answer_pools.clear();
+        self.phrase_store.retain(&mut |line| {
+            let (text, _) = store::parse_line(line);
+            phrase_indexing::normalize_text_into_phrases(text.into(), normalization_config)
+                .iter()
+                .all(|phrase| !phrases.contains(phrase.as_ref()))
+        })
+    }
+}
+
+type OpenPhraseStore = dyn Fn(chat::Id) -> error::Result<Box<dyn PhraseStore>> + Send;
+
+/// A memory behind a lock of its own, so that chats with memories of their own
+/// can learn and reply at the same time, and replies can be generated from the
+/// same memory at once, while learning holds it only briefly.
+pub(crate) type SharedMemory = Arc<RwLock<Memory>>;
+
+/// Locks the memory for generating from it, regardless of whether it was last
+/// held by a panicking thread, which only ever leaves behind phrases that
+/// weren't fully learned.
+pub(crate) fn read_memory(memory: &RwLock<Memory>) -> RwLockReadGuard<'_, Memory> {
+    memory.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Locks the memory for learning into it, like `read_memory`.
+pub(crate) fn write_memory(memory: &RwLock<Memory>) -> RwLockWriteGuard<'_, Memory> {
+    memory.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Makes sure that every line learned by the memories is on disk, without
+/// compacting their stores, and reads what's on disk with `read` while they're
+/// all locked, so that nothing is written to it midway. Blocks on disk.
+pub(crate) fn read_flushed<T>(
+    memories: &[SharedMemory],
+    read: impl FnOnce() -> error::Result<T>,
+) -> error::Result<T> {
+    let mut memories: Vec<_> = memories.iter().map(|memory| write_memory(memory)).collect();
+
+    for memory in &mut memories {
+        memory.phrase_store.flush()?;
+    }
+
+    read()
+}
+
+/// The memories of the chats, which are loaded as the chats show up.
+pub(crate) struct Memories {
+    scope: MemoryScope,
+    shared_memory: SharedMemory,
+    memories_by_chat: HashMap<chat::Id, SharedMemory>,
+    open_chat_store: Box<OpenPhraseStore>,
+    source_weights: HashMap<PhraseSource, f32>,
+    frequency_temperature: Option<f32>,
+    recency_bonus: f32,
+    junction_distribution: JunctionDistribution,
+    max_phrase_count: Option<usize>,
+    max_word_count: Option<usize>,
+    stop_words: Vec<String>,
+    blocked_words: Vec<String>,
+    archived_chats: HashSet<chat::Id>,
+    /// How much less likely words are to be pivoted on, by chat.
+    word_nerfs: HashMap<chat::Id, BTreeMap<String, f32>>,
+    chat_languages: HashMap<chat::Id, ChatLanguage>,
+    short_term_log: ShortTermLog,
+}
+
+impl Memories {
+    pub(crate) fn new(
+        scope: MemoryScope,
+        mut shared_memory: Memory,
+        open_chat_store: Box<OpenPhraseStore>,
+    ) -> Memories {
+        let source_weights: HashMap<_, _> = sources::DEFAULT_SOURCE_WEIGHTS.into_iter().collect();
+
+        for (&source, &weight) in &source_weights {
+            shared_memory
+                .indexed_phrases
+                .set_source_weight(source, weight);
+        }
+
+        Memories {
+            scope,
+            shared_memory: Arc::new(RwLock::new(shared_memory)),
+            memories_by_chat: HashMap::new(),
+            open_chat_store,
+            source_weights,
+            frequency_temperature: None,
+            recency_bonus: 1.0,
+            junction_distribution: JunctionDistribution::default(),
+            max_phrase_count: None,
+            max_word_count: None,
+            stop_words: Vec::new(),
+            blocked_words: Vec::new(),
+            archived_chats: HashSet::new(),
+            word_nerfs: HashMap::new(),
+            chat_languages: HashMap::new(),
+            short_term_log: ShortTermLog::default(),
+        }
+    }
+
+    /// Puts the phrases saved to the short-term memory at `path` back into
+    /// short-term memory, and saves the phrases learned from now on there.
+    pub(crate) fn load_short_term_log(&mut self, path: impl Into<PathBuf>) -> error::Result<()> {
+        self.short_term_log = ShortTermLog::load(path)?;
+
+        for (phrase, learned_at) in self.short_term_log.phrases_of(None) {
+            write_memory(&self.shared_memory)
+                .indexed_phrases
+                .mark_phrase_short_term(phrase, learned_at);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn scope(&self) -> MemoryScope {
+        self.scope
+    }
+
+    /// How many phrases each loaded memory holds, with the shared memory first
+    /// as `None`. The memories of chats are only loaded once they show up.
+    pub(crate) fn phrase_counts(&self) -> Vec<(Option<chat::Id>, usize)> {
+        let mut chat_phrase_counts: Vec<_> = self
+            .memories_by_chat
+            .iter()
+            .map(|(&chat_id, memory)| {
+                let phrase_count = write_memory(memory).indexed_phrases.phrase_count();
+                (Some(chat_id), phrase_count)
+            })
+            .collect();
+        chat_phrase_counts.sort_by_key(|&(chat_id, _)| chat_id.map(|chat_id| chat_id.0));
+
+        let shared_phrase_count = write_memory(&self.shared_memory)
+            .indexed_phrases
+            .phrase_count();
+
+        std::iter::once((None, shared_phrase_count))
+            .chain(chat_phrase_counts)
+            .collect()
+    }
+
+    /// Locks the memory the chat learns into and replies from, loading it if
+    /// needed, or the shared memory if there's no chat.
+    pub(crate) fn get_mut(
+        &mut self,
+        chat_id: Option<chat::Id>,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<RwLockWriteGuard<'_, Memory>> {
+        Ok(write_memory(self.load(chat_id, normalization_config)?))
+    }
+
+    /// Returns the memory the chat learns into and replies from, loading it if
+    /// needed, or the shared memory if there's no chat, to be locked later on,
+    /// without holding onto the other memories.
+    pub(crate) fn get_shared(
+        &mut self,
+        chat_id: Option<chat::Id>,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<SharedMemory> {
+        self.load(chat_id, normalization_config).map(Arc::clone)
+    }
+
+    fn load(
+        &mut self,
+        chat_id: Option<chat::Id>,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<&SharedMemory> {
+        let chat_id = match self.memory_chat_id(chat_id) {
+            Some(chat_id) => chat_id,
+            None => return Ok(&self.shared_memory),
+        };
+
+        if !self.memories_by_chat.contains_key(&chat_id) {
+            let normalization_config =
+                &self.normalization_config_of(Some(chat_id), normalization_config);
+            let phrase_store = (self.open_chat_store)(chat_id)?;
+            let mut memory = if self.archived_chats.contains(&chat_id) {
+                Memory::load_archived(phrase_store, normalization_config)?
+            } else {
+                Memory::load(phrase_store, normalization_config)?
+            };
+
+            memory
+                .indexed_phrases
+                .set_pivot_diacritic_folding(normalization_config.fold_pivot_diacritics);
+            memory
+                .indexed_phrases
+                .set_laughter_pattern(normalization_config.laughter_pattern.clone());
+            memory
+                .indexed_phrases
+                .set_min_sub_word_len(normalization_config.min_sub_word_len);
+            for (&source, &weight) in &self.source_weights {
+                memory.indexed_phrases.set_source_weight(source, weight);
+            }
+            memory
+                .indexed_phrases
+                .set_frequency_temperature(self.frequency_temperature);
+            memory.indexed_phrases.set_recency_bonus(self.recency_bonus);
+            memory
+                .indexed_phrases
+                .set_junction_distribution(self.junction_distribution);
+            memory
+                .indexed_phrases
+                .set_stop_words(self.stop_words_of(chat_id).iter().cloned());
+            memory
+                .indexed_phrases
+                .set_blocked_words(self.blocked_words.iter().cloned());
+            for (word, &factor) in self.word_nerfs.get(&chat_id).into_iter().flatten() {
+                memory.indexed_phrases.set_word_nerf(word, factor);
+            }
+            for (phrase, learned_at) in self.short_term_log.phrases_of(Some(chat_id)) {
+                memory
+                    .indexed_phrases
+                    .mark_phrase_short_term(phrase, learned_at);
+            }
+
+            memory.evict_least_recently_learned(
+                self.max_phrase_count,
+                self.max_word_count,
+                normalization_config,
+            )?;
+
+            tracing::info!("loaded memory of chat {}", chat_id);
+            self.memories_by_chat
+                .insert(chat_id, Arc::new(RwLock::new(memory)));
+        }
+
+        Ok(&self.memories_by_chat[&chat_id])
+    }
+
+    /// Archives the memory of the chat, or unarchives it, see
+    /// `Memory::archived`. Returns `false` if chats share their memory, which
+    /// isn't any chat's to archive.
+    pub(crate) fn set_archived(&mut self, chat_id: chat::Id, archived: bool) -> bool {
+        let chat_id = match self.memory_chat_id(Some(chat_id)) {
+            Some(chat_id) => chat_id,
+            None => return false,
+        };
+
+        if archived {
+            self.archived_chats.insert(chat_id);
+        } else {
+            self.archived_chats.remove(&chat_id);
+        }
+
+        if let Some(memory) = self.memories_by_chat.get(&chat_id) {
+            write_memory(memory).archived = archived;
+        }
+
+        true
+    }
+
+    /// The chats whose memories are archived.
+    pub(crate) fn archived_chats(&self) -> impl Iterator<Item = chat::Id> + '_ {
+        self.archived_chats.iter().copied()
+    }
+
+    /// Multiplies how likely the word is to be pivoted on in the chat by
+    /// `factor`, or lifts its nerf if one. Chats sharing their memory share
+    /// their nerfs too, with the strongest nerf of each word applying.
+    pub(crate) fn set_word_nerf(&mut self, chat_id: chat::Id, word: &str, factor: f32) {
+        let nerfs = self.word_nerfs.entry(chat_id).or_default();
+        if factor == 1.0 {
+            nerfs.remove(word);
+        } else {
+            nerfs.insert(word.into(), factor);
+        }
+        if nerfs.is_empty() {
+            self.word_nerfs.remove(&chat_id);
+        }
+
+        // Memories of chats not loaded yet are nerfed once they are.
+        let (memory, factor) = match self.memory_chat_id(Some(chat_id)) {
+            Some(chat_id) => (self.memories_by_chat.get(&chat_id), factor),
+            None => {
+                let strongest_factor = self
+                    .word_nerfs
+                    .values()
+                    .filter_map(|nerfs| nerfs.get(word))
+                    .copied()
+                    .fold(1.0, f32::min);
+                (Some(&self.shared_memory), strongest_factor)
+            }
+        };
+
+        if let Some(memory) = memory {
+            write_memory(memory)
+                .indexed_phrases
+                .set_word_nerf(word, factor);
+        }
+    }
+
+    /// The words nerfed in the chat, by their factors.
+    pub(crate) fn word_nerfs_of(&self, chat_id: chat::Id) -> Option<&BTreeMap<String, f32>> {
+        self.word_nerfs.get(&chat_id)
+    }
+
+    /// The words nerfed in every chat, as they're saved.
+    pub(crate) fn word_nerfs(&self) -> BTreeMap<i64, &BTreeMap<String, f32>> {
+        self.word_nerfs
+            .iter()
+            .map(|(chat_id, nerfs)| (chat_id.0, nerfs))
+            .collect()
+    }
+
+    /// How the text of the chat is normalized: as configured, unless the chat
+    /// has a memory of its own, set to a language of its own.
+    pub(crate) fn normalization_config_of(
+        &self,
+        chat_id: Option<chat::Id>,
+        normalization_config: &NormalizationConfig,
+    ) -> NormalizationConfig {
+        let language = self
+            .memory_chat_id(chat_id)
+            .and_then(|chat_id| self.chat_languages.get(&chat_id));
+
+        match language {
+            Some(language) => NormalizationConfig {
+                phrase_terminators: normalization_config.phrase_terminators.clone(),
+                max_letter_run: normalization_config.max_letter_run,
+                min_sub_word_len: normalization_config.min_sub_word_len,
+                ..NormalizationConfig::for_language(&language.code)
+            },
+            None => normalization_config.clone(),
+        }
+    }
+
+    /// Normalizes the memory of the chat for the language from now on. The
+    /// phrases it already holds are kept as they were indexed, so the language
+    /// is rejected if it would fold their case otherwise.
+    pub(crate) fn set_chat_language(
+        &mut self,
+        chat_id: chat::Id,
+        language: ChatLanguage,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<Result<(), LanguageRejection>> {
+        let chat_id = match self.memory_chat_id(Some(chat_id)) {
+            Some(chat_id) => chat_id,
+            None => return Ok(Err(LanguageRejection::SharedMemory)),
+        };
+
+        let case_folding = self
+            .normalization_config_of(Some(chat_id), normalization_config)
+            .case_folding;
+        let memory = self.get_shared(Some(chat_id), normalization_config)?;
+        let mut memory = write_memory(&memory);
+
+        let language_config = NormalizationConfig::for_language(&language.code);
+        if language_config.case_folding != case_folding && memory.indexed_phrases.phrase_count() > 0
+        {
+            return Ok(Err(LanguageRejection::CaseFolding));
+        }
+
+        memory
+            .indexed_phrases
+            .set_pivot_diacritic_folding(language_config.fold_pivot_diacritics);
+        memory
+            .indexed_phrases
+            .set_laughter_pattern(language_config.laughter_pattern);
+        memory
+            .indexed_phrases
+            .set_stop_words(language.stop_words.iter().cloned());
+        memory.answer_pools.clear();
+        drop(memory);
+
+        self.chat_languages.insert(chat_id, language);
+
+        Ok(Ok(()))
+    }
+
+    /// Sets the languages chats were set to, as they're saved, before their
+    /// memories are loaded.
+    pub(crate) fn restore_chat_languages(
+        &mut self,
+        chat_languages: impl IntoIterator<Item = (chat::Id, ChatLanguage)>,
+    ) {
+        self.chat_languages.extend(chat_languages);
+    }
+
+    /// The languages of every chat set to one, as they're saved.
+    pub(crate) fn chat_languages(&self) -> BTreeMap<i64, &str> {
+        self.chat_languages
+            .iter()
+            .map(|(chat_id, language)| (chat_id.0, language.code.as_str()))
+            .collect()
+    }
+
+    /// Applies the pivot settings of the configuration to every memory, as
+    /// adjusted for the languages of their chats.
+    pub(crate) fn apply_normalization_config(
+        &mut self,
+        normalization_config: &NormalizationConfig,
+    ) {
+        for (mut memory, normalization_config) in self.iter_mut_normalized(normalization_config) {
+            memory
+                .indexed_phrases
+                .set_pivot_diacritic_folding(normalization_config.fold_pivot_diacritics);
+            memory
+                .indexed_phrases
+                .set_laughter_pattern(normalization_config.laughter_pattern.clone());
+            memory
+                .indexed_phrases
+                .set_min_sub_word_len(normalization_config.min_sub_word_len);
+            memory.answer_pools.clear();
+        }
+    }
+
+    /// Removes the expired phrases of every memory. Returns how many phrases
+    /// were removed.
+    pub(crate) fn remove_expired_phrases(
+        &mut self,
+        now: i64,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<usize> {
+        let mut expired_phrase_count = 0;
+
+        for (mut memory, normalization_config) in self.iter_mut_normalized(normalization_config) {
+            expired_phrase_count += memory.remove_expired_phrases(now, &normalization_config)?;
+        }
+
+        Ok(expired_phrase_count)
+    }
+
+    /// Caps the phrases and words of every memory, which takes effect on the
+    /// next call to `evict_least_recently_learned`, or as memories are loaded.
+    pub(crate) fn set_caps(
+        &mut self,
+        max_phrase_count: Option<usize>,
+        max_word_count: Option<usize>,
+    ) {
+        self.max_phrase_count = max_phrase_count;
+        self.max_word_count = max_word_count;
+    }
+
+    /// Removes the least recently learned phrases of every memory past the
+    /// caps. Returns how many phrases were removed.
+    pub(crate) fn evict_least_recently_learned(
+        &mut self,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<usize> {
+        let (max_phrase_count, max_word_count) = (self.max_phrase_count, self.max_word_count);
+        let mut evicted_phrase_count = 0;
+
+        for (mut memory, normalization_config) in self.iter_mut_normalized(normalization_config) {
+            evicted_phrase_count += memory.evict_least_recently_learned(
+                max_phrase_count,
+                max_word_count,
+                &normalization_config,
+            )?;
+        }
+
+        Ok(evicted_phrase_count)
+    }
+
+    /// The memories loaded so far, the shared one first, which can be locked
+    /// without holding onto the others, e.g. by `read_flushed`.
+    pub(crate) fn loaded(&self) -> Vec<SharedMemory> {
+        std::iter::once(&self.shared_memory)
+            .chain(self.memories_by_chat.values())
+            .map(Arc::clone)
+            .collect()
+    }
+
+    /// Flushes every loaded memory.
+    pub(crate) fn flush(
+        &mut self,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<()> {
+        for (mut memory, normalization_config) in self.iter_mut_normalized(normalization_config) {
+            memory.flush(&normalization_config)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn set_source_weight(&mut self, source: PhraseSource, weight: f32) {
+        self.source_weights.insert(source, weight);
+
+        for mut memory in self.iter_mut() {
+            memory.indexed_phrases.set_source_weight(source, weight);
+            memory.answer_pools.clear();
+        }
+    }
+
+    pub(crate) fn set_frequency_temperature(&mut self, temperature: Option<f32>) {
+        self.frequency_temperature = temperature;
+
+        for mut memory in self.iter_mut() {
+            memory
+                .indexed_phrases
+                .set_frequency_temperature(temperature);
+        }
+    }
+
+    pub(crate) fn set_junction_distribution(
+        &mut self,
+        junction_distribution: JunctionDistribution,
+    ) {
+        self.junction_distribution = junction_distribution;
+
+        for mut memory in self.iter_mut() {
+            memory
+                .indexed_phrases
+                .set_junction_distribution(junction_distribution);
+            memory.answer_pools.clear();
+        }
+    }
+
+    pub(crate) fn set_recency_bonus(&mut self, recency_bonus: f32) {
+        self.recency_bonus = recency_bonus;
+
+        for mut memory in self.iter_mut() {
+            memory.indexed_phrases.set_recency_bonus(recency_bonus);
+        }
+    }
+
+    /// Sets the stop words of every memory but those of chats set to a
+    /// language of their own.
+    pub(crate) fn set_stop_words(&mut self, stop_words: Vec<String>) {
+        let chat_languages = &self.chat_languages;
+        let memories = std::iter::once(&self.shared_memory).chain(
+            self.memories_by_chat
+                .iter()
+                .filter(|(chat_id, _)| !chat_languages.contains_key(chat_id))
+                .map(|(_, memory)| memory),
+        );
+
+        for memory in memories {
+            write_memory(memory)
+                .indexed_phrases
+                .set_stop_words(stop_words.iter().cloned());
+        }
+
+        self.stop_words = stop_words;
+    }
+
+    fn stop_words_of(&self, chat_id: chat::Id) -> &[String] {
+        match self.chat_languages.get(&chat_id) {
+            Some(language) => &language.stop_words,
+            None => &self.stop_words,
+        }
+    }
+
+    pub(crate) fn blocked_words(&self) -> &[String] {
+        &self.blocked_words
+    }
+
+    pub(crate) fn set_blocked_words(&mut self, blocked_words: Vec<String>) {
+        for mut memory in self.iter_mut() {
+            memory
+                .indexed_phrases
+                .set_blocked_words(blocked_words.iter().cloned());
+        }
+
+        self.blocked_words = blocked_words;
+    }
+
+    /// Puts phrases the chat just learned into short-term memory, as learned at
+    /// `learned_at`.
+    pub(crate) fn remember_short_term(
+        &mut self,
+        chat_id: chat::Id,
+        phrases: &[String],
+        learned_at: i64,
+        normalization_config: &NormalizationConfig,
+    ) -> error::Result<()> {
+        self.get_mut(Some(chat_id), normalization_config)?
+            .remember_short_term(phrases, learned_at);
+
+        self.log_short_term(chat_id, phrases, learned_at)
+    }
+
+    /// Keeps track of phrases the memory of the chat already put into
+    /// short-term memory, so that they are still there after restarting.
+    pub(crate) fn log_short_term(
+        &mut self,
+        chat_id: chat::Id,
+        phrases: &[String],
+        learned_at: i64,
+    ) -> error::Result<()> {
+        let memory_chat_id = self.memory_chat_id(Some(chat_id));
+        self.short_term_log
+            .record(memory_chat_id, phrases, learned_at)
+    }
+
+    /// Folds the phrases that have been in short-term memory for long enough by
+    /// `now` into long-term memory. Returns how many phrases were folded.
+    pub(crate) fn fold_short_term_phrases(&mut self, now: i64) -> error::Result<usize> {
+        let learned_before = now - SHORT_TERM_WINDOW_SECS;
+
+        let folded_phrase_count = self
+            .iter_mut()
+            .map(|mut memory| {
+                memory
+                    .indexed_phrases
+                    .fold_short_term_phrases(learned_before)
+            })
+            .sum();
+
+        self.short_term_log.fold(learned_before)?;
+
+        Ok(folded_phrase_count)
+    }
+
+    /// The chat whose memory the chat learns into, or `None` if it's the shared
+    /// memory.
+    fn memory_chat_id(&self, chat_id: Option<chat::Id>) -> Option<chat::Id> {
+        match (self.scope, chat_id) {
+            (MemoryScope::PerChat, Some(chat_id)) => Some(chat_id),
+            _ => None,
+        }
+    }
+
+    /// Locks every loaded memory, one at a time, along with how it's
+    /// normalized.
+    fn iter_mut_normalized<'a>(
+        &'a self,
+        normalization_config: &'a NormalizationConfig,
+    ) -> impl Iterator<Item = (RwLockWriteGuard<'a, Memory>, NormalizationConfig)> + 'a {
+        std::iter::once((None, &self.shared_memory))
+            .chain(
+                self.memories_by_chat
+                    .iter()
+                    .map(|(&chat_id, memory)| (Some(chat_id), memory)),
+            )
+            .map(move |(chat_id, memory)| {
+                let normalization_config =
+                    self.normalization_config_of(chat_id, normalization_config);
+                (write_memory(memory), normalization_config)
+            })
+    }
+
+    /// Locks every loaded memory, one at a time.
+    fn iter_mut(&mut self) -> impl Iterator<Item = RwLockWriteGuard<'_, Memory>> {
+        std::iter::once(&self.shared_memory)
+            .chain(self.memories_by_chat.values())
+            .map(|memory| write_memory(memory))
+    }
+}
+
+/// Indexes the stored phrases, starting from the snapshot of the store if it
+/// was taken from the lines still stored, in which case only the lines stored
+/// since then are indexed. Otherwise, every line is indexed, and the store is
+/// compacted if `compact` is set.
+fn init_indexed_phrases(
+    phrase_store: &mut dyn PhraseStore,
+    normalization_config: &NormalizationConfig,
+    compact: bool,
+) -> error::Result<IndexedPhrases> {
+    let lines = phrase_store.load()?;
+
+    let restored_snapshot = phrase_store
+        .load_snapshot()?
+        .and_then(|snapshot| snapshot::restore(&snapshot, &lines, normalization_config));
+
+    match restored_snapshot {
+        Some((mut indexed_phrases, line_count)) => {
+            tracing::info!(
+                "restored snapshot of {} lines, indexing {} more",
+                line_count,
+                lines.len() - line_count
+            );
+
+            index_lines(
+                &mut indexed_phrases,
+                lines.into_iter().skip(line_count),
+                normalization_config,
+            );
+
+            Ok(indexed_phrases)
+        }
+        None if compact => compact_indexed_phrases(phrase_store, lines, normalization_config),
+        None => {
+            let mut indexed_phrases = IndexedPhrases::new();
+            index_lines(&mut indexed_phrases, lines, normalization_config);
+
+            Ok(indexed_phrases)
+        }
+    }
+}
+
+/// Indexes every line, then replaces the stored lines with their canonical
+/// form, and snapshots the index.
+fn compact_indexed_phrases(
+    phrase_store: &mut dyn PhraseStore,
+    lines: Vec<String>,
+    normalization_config: &NormalizationConfig,
+) -> error::Result<IndexedPhrases> {
+    let mut indexed_phrases = IndexedPhrases::new();
+    let corrected_lines = index_lines(&mut indexed_phrases, lines, normalization_config);
+
+    phrase_store.compact(&corrected_lines)?;
+    phrase_store.save_snapshot(&snapshot::take(
+        &indexed_phrases,
+        &corrected_lines,
+        normalization_config,
+    ))?;
+
+    Ok(indexed_phrases)
+}
+
+/// Indexes the phrases of the lines, along with their attributes, returning
+/// the lines of the phrases that made it into the index, as they were indexed.
+fn index_lines(
+    indexed_phrases: &mut IndexedPhrases,
+    lines: impl IntoIterator<Item = String>,
+    normalization_config: &NormalizationConfig,
+) -> Vec<String> {
+    let mut canonical_phrases = Vec::new();
+
+    for line in lines {
+        let (text, attributes) = store::parse_line(&line);
+
+        if learn_filter::check_text(text).is_err() {
+            continue;
+        }
+
+        for phrase in
+            phrase_indexing::normalize_text_into_phrases(text.into(), normalization_config)
+        {
+            if learn_filter::check_phrase(phrase.as_ref()).is_err() {
+                continue;
+            }
+
+            let mut phrase_to_insert = phrase.clone().with_source(attributes.source);
+            if let Some(expires_at) = attributes.expires_at {
+                phrase_to_insert = phrase_to_insert.with_expiry(expires_at);
+            }
+
+            let insertion_res = indexed_phrases.insert_phrase_merging_near_duplicates(
+                phrase_to_insert.clone(),
+                NEAR_DUPLICATE_MIN_SIMILARITY,
+            );
+
+            // Lines of compacted phrases stand for every time they were learned.
+            for _ in 1..attributes.occurrences {
+                indexed_phrases.insert_phrase_merging_near_duplicates(
+                    phrase_to_insert.clone(),
+                    NEAR_DUPLICATE_MIN_SIMILARITY,
+                );
+            }
+
+            if insertion_res.has_inserted_phrase {
+                canonical_phrases.push(phrase);
+            }
+        }
+    }
+
+    // Phrases found again in later lines take their attributes from them.
+    canonical_phrases
+        .iter()
+        .filter_map(|phrase| {
+            let indexed_phrase = indexed_phrases.find_phrase(phrase.as_ref())?;
+            let attributes = LineAttributes {
+                source: indexed_phrases.get_phrase_source(indexed_phrase),
+                expires_at: indexed_phrases.get_phrase_expiry(indexed_phrase),
+                occurrences: indexed_phrases.get_phrase_occurrences(indexed_phrase),
+            };
+
+            Some(store::format_line(&phrase.to_line(), attributes))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod memory_tests {
+    use super::{ChatLanguage, LanguageRejection, Memories, Memory, MemoryScope};
+    use crate::error;
+    use crate::short_term::SHORT_TERM_WINDOW_SECS;
+    use crate::store::PhraseStore;
+    use feroldinhobot::phrase_indexing::{NormalizationConfig, Phrase};
+    use feroldinhobot::sources::PhraseSource;
+    use tbot::types::chat;
+
+    struct InMemoryStore(Vec<String>);
+
+    impl PhraseStore for InMemoryStore {
+        fn load(&mut self) -> error::Result<Vec<String>> {
+            Ok(self.0.clone())
+        }
+
+        fn append(&mut self, line: &str) -> error::Result<()> {
+            self.0.push(line.into());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> error::Result<()> {
+            Ok(())
+        }
+
+        fn compact(&mut self, lines: &[String]) -> error::Result<()> {
+            self.0 = lines.to_vec();
+            Ok(())
+        }
+
+        fn retain(&mut self, keep: &mut dyn FnMut(&str) -> bool) -> error::Result<()> {
+            self.0.retain(|line| keep(line));
+            Ok(())
+        }
+    }
+
+    /// Keeps snapshots, unlike `InMemoryStore`.
+    struct SnapshottingStore {
+        lines: InMemoryStore,
+        snapshot: Option<Vec<u8>>,
+    }
+
+    impl PhraseStore for SnapshottingStore {
+        fn load(&mut self) -> error::Result<Vec<String>> {
+            self.lines.load()
+        }
+
+        fn append(&mut self, line: &str) -> error::Result<()> {
+            self.lines.append(line)
+        }
+
+        fn flush(&mut self) -> error::Result<()> {
+            self.lines.flush()
+        }
+
+        fn compact(&mut self, lines: &[String]) -> error::Result<()> {
+            self.lines.compact(lines)
+        }
+
+        fn retain(&mut self, keep: &mut dyn FnMut(&str) -> bool) -> error::Result<()> {
+            self.lines.retain(keep)
+        }
+
+        fn load_snapshot(&mut self) -> error::Result<Option<Vec<u8>>> {
+            Ok(self.snapshot.clone())
+        }
+
+        fn save_snapshot(&mut self, snapshot: &[u8]) -> error::Result<()> {
+            self.snapshot = Some(snapshot.to_vec());
+            Ok(())
+        }
+    }
+
+    fn memories(scope: MemoryScope) -> Memories {
+        let shared_store = InMemoryStore(vec!["shared phrase".into()]);
+        let shared_memory =
+            Memory::load(Box::new(shared_store), &NormalizationConfig::default()).unwrap();
+
+        Memories::new(
+            scope,
+            shared_memory,
+            Box::new(|chat_id: chat::Id| -> error::Result<Box<dyn PhraseStore>> {
+                Ok(Box::new(InMemoryStore(vec![format!(
+                    "phrase of {}",
+                    chat_id
+                )])))
+            }),
+        )
+    }
+
+    fn phrase_count(memories: &mut Memories, chat_id: Option<chat::Id>) -> usize {
+        memories
+            .get_mut(chat_id, &NormalizationConfig::default())
+            .unwrap()
+            .indexed_phrases
+            .phrase_count()
+    }
+
+    #[test]
+    fn should_only_index_lines_stored_since_snapshot() {
+        let config = NormalizationConfig::default();
+        let store = SnapshottingStore {
+            lines: InMemoryStore(vec!["hello there".into(), "hello there".into()]),
+            snapshot: None,
+        };
+
+        let mut memory = Memory::load(Box::new(store), &config).unwrap();
+        assert_eq!(
+            memory.phrase_store.load().unwrap(),
+            ["hello there\tcount=2"]
+        );
+
+        memory.phrase_store.append("hello there").unwrap();
+        memory.phrase_store.append("how are you").unwrap();
+
+        // Restoring the snapshot leaves the store as it is, unlike compaction.
+        let mut memory = Memory::load(memory.phrase_store, &config).unwrap();
+        assert_eq!(memory.indexed_phrases.phrase_count(), 2);
+        assert_eq!(memory.phrase_store.load().unwrap().len(), 3);
+
+        memory.flush(&config).unwrap();
+        assert_eq!(
+            memory.phrase_store.load().unwrap(),
+            ["hello there\tcount=3", "how are you"]
+        );
+    }
+
+    #[test]
+    fn should_keep_sources_of_phrases_across_compaction() {
+        let config = NormalizationConfig::default();
+        let store = InMemoryStore(vec![
+            "hello there\tsource=feed".into(),
+            "how are you".into(),
+            "good morning\tsource=feed".into(),
+            "good morning\tsource=channel".into(),
+        ]);
+
+        let mut memory = Memory::load(Box::new(store), &config).unwrap();
+        memory.flush(&config).unwrap();
+        let memory = Memory::load(memory.phrase_store, &config).unwrap();
+
+        let source_of = |phrase| {
+            let indexed_phrases = &memory.indexed_phrases;
+            indexed_phrases.get_phrase_source(indexed_phrases.find_phrase(phrase).unwrap())
+        };
+        assert_eq!(source_of("hello there"), PhraseSource::Feed);
+        assert_eq!(source_of("how are you"), PhraseSource::Import);
+        assert_eq!(source_of("good morning"), PhraseSource::Channel);
+    }
+
+    #[test]
+    fn should_keep_occurrences_of_phrases_across_compaction() {
+        let config = NormalizationConfig::default();
+        let store = InMemoryStore(vec![
+            "hello there\tcount=2".into(),
+            "hello there".into(),
+            "how are you".into(),
+        ]);
+
+        let mut memory = Memory::load(Box::new(store), &config).unwrap();
+        memory.flush(&config).unwrap();
+        memory.flush(&config).unwrap();
+        assert_eq!(
+            memory.phrase_store.load().unwrap(),
+            ["hello there\tcount=3", "how are you"]
+        );
+
+        // Stores without snapshots, such as SQLite, index the lines again.
+        let memory = Memory::load(memory.phrase_store, &config).unwrap();
+        let indexed_phrases = &memory.indexed_phrases;
+        let hello_there = indexed_phrases.find_phrase("hello there").unwrap();
+        assert_eq!(indexed_phrases.get_phrase_occurrences(hello_there), 3);
+        assert_eq!(indexed_phrases.total_phrase_occurrences(), 4);
+    }
+
+    #[test]
+    fn should_forget_phrases_along_with_their_lines() {
+        let phrase_store = InMemoryStore(vec![
+            "hello there. how are you?".into(),
+            "hello world".into(),
+            "good morning".into(),
+        ]);
+        let config = NormalizationConfig::default();
+        let mut memory = Memory::load(Box::new(phrase_store), &config).unwrap();
+
+        assert_eq!(memory.forget("Hello there!", &config).unwrap(), 1);
+        assert_eq!(memory.forget("hello there", &config).unwrap(), 0);
+        assert_eq!(memory.indexed_phrases.phrase_count(), 3);
+        assert_eq!(
+            memory.phrase_store.load().unwrap(),
+            &["how are you", "hello world", "good morning"]
+        );
+
+        assert_eq!(memory.forget("hello", &config).unwrap(), 1);
+        assert_eq!(
+            memory.phrase_store.load().unwrap(),
+            &["how are you", "good morning"]
+        );
+    }
+
+    #[test]
+    fn should_evict_least_recently_learned_phrases_along_with_their_lines() {
+        let phrase_store = InMemoryStore(vec![
+            "hello there".into(),
+            "hello world".into(),
+            "good morning".into(),
+            "hello there".into(),
+        ]);
+        let config = NormalizationConfig::default();
+        let mut memory = Memory::load(Box::new(phrase_store), &config).unwrap();
+
+        assert_eq!(
+            memory
+                .evict_least_recently_learned(Some(2), None, &config)
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            memory.phrase_store.load().unwrap(),
+            &["hello there\tcount=2", "good morning"]
+        );
+    }
+
+    #[test]
+    fn should_leave_store_of_archived_memory_untouched() {
+        let lines = vec![
+            "Hello there.".to_string(),
+            "hello world".into(),
+            "hello there".into(),
+        ];
+        let config = NormalizationConfig::default();
+        let mut memory =
+            Memory::load_archived(Box::new(InMemoryStore(lines.clone())), &config).unwrap();
+
+        assert_eq!(memory.indexed_phrases.phrase_count(), 2);
+        assert_eq!(
+            memory
+                .evict_least_recently_learned(Some(1), None, &config)
+                .unwrap(),
+            0
+        );
+        memory.flush(&config).unwrap();
+        assert_eq!(memory.phrase_store.load().unwrap(), lines);
+    }
+
+    #[test]
+    fn should_only_archive_memories_of_chats_in_per_chat_scope() {
+        assert!(!memories(MemoryScope::Global).set_archived(chat::Id(1), true));
+
+        let mut memories = memories(MemoryScope::PerChat);
+        assert!(memories.set_archived(chat::Id(1), true));
+
+        let config = NormalizationConfig::default();
+        assert!(
+            memories
+                .get_mut(Some(chat::Id(1)), &config)
+                .unwrap()
+                .archived
+        );
+        assert!(
+            !memories
+                .get_mut(Some(chat::Id(2)), &config)
+                .unwrap()
+                .archived
+        );
+    }
+
+    #[test]
+    fn should_set_languages_of_chats_without_refolding_their_memories() {
+        let config = NormalizationConfig::default();
+        let language = |code: &str| ChatLanguage {
+            code: code.into(),
+            stop_words: Vec::new(),
+        };
+
+        assert_eq!(
+            memories(MemoryScope::Global)
+                .set_chat_language(chat::Id(1), language("pt"), &config)
+                .unwrap(),
+            Err(LanguageRejection::SharedMemory)
+        );
+
+        let mut memories = memories(MemoryScope::PerChat);
+        assert_eq!(
+            memories
+                .set_chat_language(chat::Id(1), language("tr"), &config)
+                .unwrap(),
+            Err(LanguageRejection::CaseFolding)
+        );
+        assert_eq!(
+            memories
+                .set_chat_language(chat::Id(1), language("pt"), &config)
+                .unwrap(),
+            Ok(())
+        );
+
+        let chat_config = memories.normalization_config_of(Some(chat::Id(1)), &config);
+        assert!(chat_config.fold_pivot_diacritics);
+        assert!(chat_config.laughter_pattern.is_some());
+        assert!(
+            !memories
+                .normalization_config_of(Some(chat::Id(2)), &config)
+                .fold_pivot_diacritics
+        );
+        assert_eq!(
+            memories.chat_languages().into_iter().collect::<Vec<_>>(),
+            [(1, "pt")]
+        );
+    }
+
+    #[test]
+    fn should_remove_expired_phrases_along_with_their_lines() {
+        let config = NormalizationConfig::default();
+        let mut memory = Memory::load(Box::new(InMemoryStore(Vec::new())), &config).unwrap();
+
+        for phrase in [
+            Phrase::from("fresh news").with_expiry(200),
+            Phrase::from("stale news").with_expiry(100),
+        ] {
+            memory.phrase_store.append(&phrase.to_line()).unwrap();
+            memory.indexed_phrases.insert_phrase(phrase);
+        }
+
+        assert_eq!(memory.remove_expired_phrases(150, &config).unwrap(), 1);
+        assert_eq!(memory.indexed_phrases.phrase_count(), 1);
+        assert_eq!(memory.phrase_store.load().unwrap(), &["fresh news"]);
+    }
+
+    #[test]
+    fn should_remove_expired_phrases_after_restarting() {
+        let config = NormalizationConfig::default();
+        let store = SnapshottingStore {
+            lines: InMemoryStore(vec![
+                "fresh news\tsource=feed expires=200".into(),
+                "stale news\tsource=feed expires=100".into(),
+                "old news\tsource=feed expires=100".into(),
+                "old news\tsource=chat".into(),
+            ]),
+            snapshot: None,
+        };
+
+        // Compacting takes a snapshot, which the memory is restored from then.
+        let mut memory = Memory::load(Box::new(store), &config).unwrap();
+        memory.flush(&config).unwrap();
+        let mut memory = Memory::load(memory.phrase_store, &config).unwrap();
+
+        assert_eq!(memory.remove_expired_phrases(150, &config).unwrap(), 1);
+        assert_eq!(
+            memory.phrase_store.load().unwrap(),
+            &[
+                "fresh news\tsource=feed expires=200",
+                "old news\tsource=chat count=2"
+            ]
+        );
+    }
+
+    #[test]
+    fn should_share_memory_between_chats_in_global_scope() {
+        let mut memories = memories(MemoryScope::Global);
+
+        memories
+            .get_mut(Some(chat::Id(1)), &NormalizationConfig::default())
+            .unwrap()
+            .indexed_phrases
+            .insert_phrase(Phrase::from("hello there"));
+
+        assert_eq!(phrase_count(&mut memories, Some(chat::Id(2))), 2);
+        assert_eq!(phrase_count(&mut memories, None), 2);
+    }
+
+    #[test]
+    fn should_isolate_memory_of_each_chat_in_per_chat_scope() {
+        let mut memories = memories(MemoryScope::PerChat);
+
+        {
+            let mut memory = memories
+                .get_mut(Some(chat::Id(1)), &NormalizationConfig::default())
+                .unwrap();
+            memory
+                .indexed_phrases
+                .insert_phrase(Phrase::from("hello there"));
+
+            assert!(memory.indexed_phrases.get_word_id("shared").is_none());
+        }
+
+        assert_eq!(phrase_count(&mut memories, Some(chat::Id(1))), 2);
+        assert_eq!(phrase_count(&mut memories, Some(chat::Id(2))), 1);
+        assert_eq!(phrase_count(&mut memories, None), 1);
+    }
+
+    #[test]
+    fn should_nerf_words_in_memory_of_chat() {
+        let config = NormalizationConfig::default();
+        let word_weight = |memories: &mut Memories, chat_id| {
+            let memory = memories.get_mut(Some(chat_id), &config).unwrap();
+            let word_id = memory.indexed_phrases.get_word_id("phrase").unwrap();
+            memory.indexed_phrases.get_word_weight(word_id)
+        };
+
+        let mut per_chat_memories = memories(MemoryScope::PerChat);
+        per_chat_memories.set_word_nerf(chat::Id(1), "phrase", 0.1);
+
+        assert_eq!(word_weight(&mut per_chat_memories, chat::Id(1)), 0.1);
+        assert_eq!(word_weight(&mut per_chat_memories, chat::Id(2)), 1.0);
+
+        let mut global_memories = memories(MemoryScope::Global);
+        global_memories.set_word_nerf(chat::Id(1), "phrase", 0.1);
+        global_memories.set_word_nerf(chat::Id(2), "phrase", 0.5);
+
+        assert_eq!(word_weight(&mut global_memories, chat::Id(2)), 0.1);
+
+        global_memories.set_word_nerf(chat::Id(1), "phrase", 1.0);
+
+        assert_eq!(word_weight(&mut global_memories, chat::Id(1)), 0.5);
+        assert!(global_memories.word_nerfs_of(chat::Id(1)).is_none());
+    }
+
+    #[test]
+    fn should_lock_memory_of_each_chat_on_its_own() {
+        let config = NormalizationConfig::default();
+        let mut memories = memories(MemoryScope::PerChat);
+
+        let memory = memories.get_shared(Some(chat::Id(1)), &config).unwrap();
+        let _locked = memory.write().unwrap();
+
+        assert!(memories
+            .get_shared(Some(chat::Id(2)), &config)
+            .unwrap()
+            .try_write()
+            .is_ok());
+        assert!(memories
+            .get_shared(Some(chat::Id(1)), &config)
+            .unwrap()
+            .try_read()
+            .is_err());
+    }
+
+    #[test]
+    fn should_generate_from_the_same_memory_at_once() {
+        let config = NormalizationConfig::default();
+        let mut memories = memories(MemoryScope::PerChat);
+
+        let memory = memories.get_shared(Some(chat::Id(1)), &config).unwrap();
+        let _generating = memory.read().unwrap();
+
+        assert!(memory.try_read().is_ok());
+        assert!(memory.try_write().is_err());
+    }
+
+    #[test]
+    fn should_keep_chat_phrases_in_short_term_memory_for_a_day() {
+        let mut memories = memories(MemoryScope::PerChat);
+        let config = NormalizationConfig::default();
+
+        memories
+            .remember_short_term(chat::Id(1), &["phrase of 1".into()], 100, &config)
+            .unwrap();
+
+        assert_eq!(
+            memories
+                .fold_short_term_phrases(100 + SHORT_TERM_WINDOW_SECS)
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            memories
+                .fold_short_term_phrases(101 + SHORT_TERM_WINDOW_SECS)
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn should_apply_source_weights_to_memories_loaded_later() {
+        let mut memories = memories(MemoryScope::PerChat);
+
+        memories.set_source_weight(PhraseSource::Chat, 0.25);
+
+        let memory = memories
+            .get_mut(Some(chat::Id(1)), &NormalizationConfig::default())
+            .unwrap();
+        assert_eq!(
+            memory.indexed_phrases.get_source_weight(PhraseSource::Chat),
+            0.25
+        );
+        assert_eq!(
+            memory.indexed_phrases.get_source_weight(PhraseSource::Feed),
+            0.3
+        );
+    }
+}
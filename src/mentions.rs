@@ -0,0 +1,103 @@
+use tbot::types::message::text::{Entity, EntityKind, Text};
+use tbot::types::{Message, User};
+
+/// Whether the text mentions the user, or the message it's in replies to one
+/// of theirs.
+pub(crate) fn is_addressed_to(text: &Text, reply_to: Option<&Message>, user: &User) -> bool {
+    let is_reply_to_user = reply_to
+        .and_then(|message| message.from.as_ref())
+        .is_some_and(|author| author.id == user.id);
+
+    is_reply_to_user || mentions_user(&text.value, &text.entities, user)
+}
+
+/// Whether the text @-mentions the user by username, or mentions them through
+/// a link to their profile, which is how users without a username are
+/// mentioned.
+fn mentions_user(text: &str, entities: &[Entity], user: &User) -> bool {
+    // Entity offsets and lengths count UTF-16 code units.
+    let utf16_text: Vec<u16> = text.encode_utf16().collect();
+
+    entities.iter().any(|entity| match &entity.kind {
+        EntityKind::TextMention(mentioned_user) => mentioned_user.id == user.id,
+        EntityKind::Mention => {
+            let mention = utf16_text
+                .get(entity.offset..entity.offset + entity.length)
+                .map(String::from_utf16_lossy);
+
+            match (mention, &user.username) {
+                (Some(mention), Some(username)) => mention
+                    .trim_start_matches('@')
+                    .eq_ignore_ascii_case(username),
+                _ => false,
+            }
+        }
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod mention_tests {
+    use super::mentions_user;
+    use serde_json::json;
+    use tbot::types::message::text::Entity;
+    use tbot::types::User;
+
+    fn bot_user() -> User {
+        serde_json::from_value(json!({
+            "id": 1,
+            "is_bot": true,
+            "first_name": "Bot",
+            "username": "test_bot",
+        }))
+        .unwrap()
+    }
+
+    fn mention(offset: usize, length: usize) -> Entity {
+        let entity = json!({ "type": "mention", "offset": offset, "length": length });
+
+        // Entities only deserialize from borrowed strings.
+        serde_json::from_str(&entity.to_string()).unwrap()
+    }
+
+    #[test]
+    fn should_find_mention_by_username() {
+        assert!(mentions_user(
+            "hey @Test_Bot, what's up",
+            &[mention(4, 9)],
+            &bot_user()
+        ));
+    }
+
+    #[test]
+    fn should_find_mention_after_wide_characters() {
+        assert!(mentions_user(
+            "😂😂 @test_bot",
+            &[mention(5, 9)],
+            &bot_user()
+        ));
+    }
+
+    #[test]
+    fn should_ignore_mentions_of_others() {
+        assert!(!mentions_user(
+            "hey @other_bot",
+            &[mention(4, 10)],
+            &bot_user()
+        ));
+        assert!(!mentions_user("hey test_bot", &[], &bot_user()));
+    }
+
+    #[test]
+    fn should_find_text_mention() {
+        let text_mention = json!({
+            "type": "text_mention",
+            "offset": 4,
+            "length": 3,
+            "user": { "id": 1, "is_bot": true, "first_name": "Bot" },
+        });
+        let text_mention = serde_json::from_str(&text_mention.to_string()).unwrap();
+
+        assert!(mentions_user("hey Bot", &[text_mention], &bot_user()));
+    }
+}
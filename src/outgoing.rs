@@ -0,0 +1,814 @@
+use crate::error::{self, Error};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tbot::types::chat;
+use tokio::sync::mpsc;
+use tracing::Instrument;
+
+pub(crate) type SendFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<(), tbot::errors::MethodCall>> + Send + 'a>>;
+
+/// How long Telegram shows a chat action for, unless a message is sent first.
+const CHAT_ACTION_DURATION: Duration = Duration::from_secs(5);
+
+type ChatQueues = HashMap<chat::Id, mpsc::UnboundedSender<(String, tracing::Span)>>;
+
+/// Something that can deliver a text message to a chat, e.g. the Telegram bot
+/// itself, or a fake in tests.
+pub(crate) trait MessageSender: Send + Sync + 'static {
+    fn send_text<'a>(&'a self, chat_id: chat::Id, text: &'a str) -> SendFuture<'a>;
+    /// Shows the chat that a message is being typed.
+    fn send_typing(&self, chat_id: chat::Id) -> SendFuture<'_>;
+}
+
+impl MessageSender for tbot::Bot {
+    fn send_text<'a>(&'a self, chat_id: chat::Id, text: &'a str) -> SendFuture<'a> {
+        Box::pin(async move { self.send_message(chat_id, text).call().await.map(|_| ()) })
+    }
+
+    fn send_typing(&self, chat_id: chat::Id) -> SendFuture<'_> {
+        Box::pin(async move {
+            self.send_chat_action(chat_id, chat::Action::Typing)
+                .call()
+                .await
+        })
+    }
+}
+
+impl MessageSender for Box<dyn MessageSender> {
+    fn send_text<'a>(&'a self, chat_id: chat::Id, text: &'a str) -> SendFuture<'a> {
+        (**self).send_text(chat_id, text)
+    }
+
+    fn send_typing(&self, chat_id: chat::Id) -> SendFuture<'_> {
+        (**self).send_typing(chat_id)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct QueueConfig {
+    /// Minimum time between two messages sent to the same chat.
+    pub(crate) min_send_interval: Duration,
+    pub(crate) max_retries: usize,
+    /// Time before the first retry, which doubles on each further retry up to
+    /// `max_retry_delay`.
+    pub(crate) retry_delay: Duration,
+    pub(crate) max_retry_delay: Duration,
+    /// How long the bot is shown typing before each message, for each of its
+    /// characters, up to `max_typing_delay`. Messages are sent right away if
+    /// zero.
+    pub(crate) typing_delay_per_char: Duration,
+    pub(crate) max_typing_delay: Duration,
+    /// How long the task of a chat waits for another message before it ends,
+    /// so that chats the bot no longer talks to don't keep one around. It's
+    /// started again by the next message.
+    pub(crate) idle_timeout: Duration,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            min_send_interval: Duration::from_secs(1),
+            max_retries: 3,
+            retry_delay: Duration::from_secs(2),
+            max_retry_delay: Duration::from_secs(60),
+            typing_delay_per_char: Duration::ZERO,
+            max_typing_delay: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// Serializes outgoing messages per chat, so that messages are delivered in the
+/// order they were enqueued, regardless of rate limiting and retries. Each chat
+/// gets its own task, so a slow chat doesn't hold the others back.
+pub(crate) struct OutgoingQueue<S> {
+    sender: Arc<S>,
+    config: QueueConfig,
+    /// Messages are queued along with the span they were enqueued in, which
+    /// sending them is traced under. Tasks remove their chat's queue once
+    /// idle.
+    chat_queues: Arc<Mutex<ChatQueues>>,
+}
+
+impl<S: MessageSender> OutgoingQueue<S> {
+    pub(crate) fn new(sender: S, config: QueueConfig) -> OutgoingQueue<S> {
+        OutgoingQueue {
+            sender: Arc::new(sender),
+            config,
+            chat_queues: Arc::default(),
+        }
+    }
+
+    /// Must be called from within a tokio runtime.
+    pub(crate) fn enqueue(&mut self, chat_id: chat::Id, text: String) {
+        let chat_queues = &mut *lock_chat_queues(&self.chat_queues);
+
+        let message = match self
+            .chat_queue(chat_queues, chat_id)
+            .send((text, tracing::Span::current()))
+        {
+            Ok(()) => return,
+            Err(mpsc::error::SendError(message)) => message,
+        };
+
+        // The chat's task is gone (e.g. it panicked), so start a new one.
+        chat_queues.remove(&chat_id);
+
+        if self.chat_queue(chat_queues, chat_id).send(message).is_err() {
+            tracing::error!("couldn't enqueue message for chat {}", chat_id);
+        }
+    }
+
+    fn chat_queue<'a>(
+        &self,
+        chat_queues: &'a mut ChatQueues,
+        chat_id: chat::Id,
+    ) -> &'a mpsc::UnboundedSender<(String, tracing::Span)> {
+        chat_queues.entry(chat_id).or_insert_with(|| {
+            let (queue_sender, queue_receiver) = mpsc::unbounded_channel();
+
+            tokio::spawn(process_chat_queue(
+                Arc::clone(&self.sender),
+                self.config.clone(),
+                chat_id,
+                queue_receiver,
+                Arc::clone(&self.chat_queues),
+            ));
+
+            queue_sender
+        })
+    }
+}
+
+fn lock_chat_queues(chat_queues: &Mutex<ChatQueues>) -> std::sync::MutexGuard<'_, ChatQueues> {
+    chat_queues.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+async fn process_chat_queue<S: MessageSender>(
+    sender: Arc<S>,
+    config: QueueConfig,
+    chat_id: chat::Id,
+    mut queue_receiver: mpsc::UnboundedReceiver<(String, tracing::Span)>,
+    chat_queues: Arc<Mutex<ChatQueues>>,
+) {
+    let mut last_sent_at: Option<Instant> = None;
+
+    loop {
+        let message = match tokio::time::timeout(config.idle_timeout, queue_receiver.recv()).await {
+            Ok(message) => message,
+            // Messages are only enqueued with the queues locked, so none can
+            // show up between finding the queue empty and removing it.
+            Err(_) => {
+                let mut chat_queues = lock_chat_queues(&chat_queues);
+
+                match queue_receiver.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => {
+                        chat_queues.remove(&chat_id);
+                        tracing::debug!("chat {} is idle, ending its queue", chat_id);
+                        None
+                    }
+                }
+            }
+        };
+
+        let (text, enqueued_span) = match message {
+            Some(message) => message,
+            None => break,
+        };
+
+        let span = tracing::info_span!(parent: &enqueued_span, "send", chat_id = chat_id.0);
+
+        async {
+            if let Some(last_sent_at) = last_sent_at {
+                let elapsed = last_sent_at.elapsed();
+
+                if elapsed < config.min_send_interval {
+                    tokio::time::delay_for(config.min_send_interval - elapsed).await;
+                }
+            }
+
+            show_typing(&*sender, &config, chat_id, &text).await;
+            send_with_retries(&*sender, &config, chat_id, &text).await;
+        }
+        .instrument(span)
+        .await;
+
+        last_sent_at = Some(Instant::now());
+    }
+}
+
+/// Shows the bot typing for as long as the text would take to type, so that it
+/// doesn't reply instantly.
+async fn show_typing<S: MessageSender>(
+    sender: &S,
+    config: &QueueConfig,
+    chat_id: chat::Id,
+    text: &str,
+) {
+    let typing_delay = config
+        .typing_delay_per_char
+        .checked_mul(text.chars().count() as u32)
+        .unwrap_or(config.max_typing_delay)
+        .min(config.max_typing_delay);
+    let typing_until = Instant::now() + typing_delay;
+
+    loop {
+        let now = Instant::now();
+        if now >= typing_until {
+            break;
+        }
+
+        if let Err(err) = sender.send_typing(chat_id).await {
+            tracing::warn!("couldn't show typing in chat {}: {}", chat_id, err);
+        }
+
+        // The action is shown again once it wears off.
+        tokio::time::delay_for((typing_until - now).min(CHAT_ACTION_DURATION)).await;
+    }
+}
+
+async fn send_with_retries<S: MessageSender>(
+    sender: &S,
+    config: &QueueConfig,
+    chat_id: chat::Id,
+    text: &str,
+) {
+    for attempt in 0..=config.max_retries {
+        match sender.send_text(chat_id, text).await {
+            Ok(()) => {
+                tracing::info!("sent message `{}` to chat {}", text, chat_id);
+                return;
+            }
+            Err(err) if attempt < config.max_retries && is_transient(&err) => {
+                let delay = retry_delay(config, attempt, &err);
+
+                tracing::warn!(
+                    "couldn't send message `{}` to chat {} (attempt {}), retrying in {:?}: {}",
+                    text,
+                    chat_id,
+                    attempt + 1,
+                    delay,
+                    err
+                );
+                tokio::time::delay_for(delay).await;
+            }
+            Err(err) => {
+                error::report_error(&Error::Platform {
+                    context: format!("sending message `{}` to chat {}", text, chat_id),
+                    source: err,
+                });
+                return;
+            }
+        }
+    }
+}
+
+/// Whether sending again may succeed. Requests Telegram refused for any reason
+/// other than flooding or failing itself, such as the bot being blocked, fail
+/// the same way every time.
+fn is_transient(err: &tbot::errors::MethodCall) -> bool {
+    match err {
+        tbot::errors::MethodCall::RequestError { error_code, .. } => {
+            *error_code == 429 || *error_code >= 500
+        }
+        tbot::errors::MethodCall::Network(_) | tbot::errors::MethodCall::OutOfService => true,
+        tbot::errors::MethodCall::Parse { .. } => false,
+    }
+}
+
+/// Backs off exponentially from `retry_delay`, but waits at least as long as
+/// Telegram asks to when the flood limit is exceeded, since trying any sooner
+/// only fails again.
+fn retry_delay(config: &QueueConfig, attempt: usize, err: &tbot::errors::MethodCall) -> Duration {
+    let backoff = config
+        .retry_delay
+        .checked_mul(1 << attempt.min(16))
+        .unwrap_or(config.max_retry_delay)
+        .min(config.max_retry_delay);
+
+    match err {
+        tbot::errors::MethodCall::RequestError {
+            retry_after: Some(retry_after),
+            ..
+        } => backoff.max(Duration::from_secs(*retry_after)),
+        _ => backoff,
+    }
+}
+
+/// Keeps the bot from replying to messages that arrive in quick succession, by
+/// ignoring triggers in a chat for a while after replying there.
+pub(crate) struct ReplySuppression {
+    pub(crate) window: Duration,
+    last_reply_at: HashMap<chat::Id, Instant>,
+}
+
+impl Default for ReplySuppression {
+    fn default() -> Self {
+        ReplySuppression {
+            window: Duration::from_secs(10),
+            last_reply_at: HashMap::new(),
+        }
+    }
+}
+
+impl ReplySuppression {
+    pub(crate) fn is_suppressed(&self, chat_id: chat::Id, now: Instant) -> bool {
+        self.last_reply_at
+            .get(&chat_id)
+            .is_some_and(|&last_reply_at| {
+                now.saturating_duration_since(last_reply_at) < self.window
+            })
+    }
+
+    pub(crate) fn record_reply(&mut self, chat_id: chat::Id, now: Instant) {
+        self.last_reply_at.insert(chat_id, now);
+    }
+}
+
+/// Raises the reply probability of chats that answer the replies of the bot soon
+/// after they're sent, so that the bot leans in when people enjoy it. The boost
+/// then decays back to the baseline.
+pub(crate) struct EngagementBoost {
+    /// Added to the reply probability right after an answer.
+    pub(crate) boost: f32,
+    /// How soon after a reply was sent an answer to it still counts.
+    pub(crate) window: Duration,
+    /// How long the boost takes to decay.
+    pub(crate) decay: Duration,
+    /// Unix time at which each chat last answered a reply in time.
+    engaged_at: HashMap<chat::Id, i64>,
+}
+
+impl EngagementBoost {
+    pub(crate) fn new(boost: f32, window: Duration, decay: Duration) -> EngagementBoost {
+        EngagementBoost {
+            boost,
+            window,
+            decay,
+            engaged_at: HashMap::new(),
+        }
+    }
+
+    /// Records an answer sent at `now` to a reply of the bot sent at
+    /// `replied_at`. Returns whether it was soon enough to boost the chat.
+    pub(crate) fn record_answer(&mut self, chat_id: chat::Id, replied_at: i64, now: i64) -> bool {
+        if now - replied_at > self.window.as_secs() as i64 {
+            return false;
+        }
+
+        self.engaged_at.insert(chat_id, now);
+        true
+    }
+
+    /// The reply probability of the chat at `now`, which is `reply_prob` raised
+    /// by whatever is left of the boost.
+    pub(crate) fn reply_probability(&self, chat_id: chat::Id, reply_prob: f32, now: i64) -> f32 {
+        let decay_secs = self.decay.as_secs() as f32;
+
+        let remaining_boost = match self.engaged_at.get(&chat_id) {
+            Some(&engaged_at) if decay_secs > 0.0 => {
+                let elapsed_secs = (now - engaged_at).max(0) as f32;
+                self.boost * (1.0 - elapsed_secs / decay_secs).max(0.0)
+            }
+            _ => 0.0,
+        };
+
+        (reply_prob + remaining_boost).min(1.0)
+    }
+}
+
+/// Keeps the bot from flooding chats with replies to the messages that piled up
+/// while it was down. Such messages are still learned from.
+pub(crate) struct StartupReplayGuard {
+    /// Unix time at which the process started.
+    started_at: i64,
+    /// How much older than the start a message may be and still be replied to,
+    /// or `None` to reply to messages of any age.
+    max_message_age: Option<Duration>,
+}
+
+impl StartupReplayGuard {
+    pub(crate) fn new(started_at: i64, max_message_age: Option<Duration>) -> StartupReplayGuard {
+        StartupReplayGuard {
+            started_at,
+            max_message_age,
+        }
+    }
+
+    /// Reads the max age from `MAX_MESSAGE_AGE_AT_START_MINS`, which is either
+    /// a number of minutes or `off`, and defaults to five minutes.
+    pub(crate) fn from_env(started_at: i64) -> error::Result<StartupReplayGuard> {
+        let max_message_age = match std::env::var("MAX_MESSAGE_AGE_AT_START_MINS") {
+            Ok(mins) if mins == "off" => None,
+            Ok(mins) => Some(
+                mins.parse()
+                    .map(|mins: u64| Duration::from_secs(mins * 60))
+                    .map_err(|_| Error::parse("max message age at start", mins))?,
+            ),
+            Err(_) => Some(Duration::from_secs(5 * 60)),
+        };
+
+        Ok(StartupReplayGuard::new(started_at, max_message_age))
+    }
+
+    /// Whether the message, sent at the unix time `message_date`, was sent too
+    /// long before the process started to be replied to.
+    pub(crate) fn is_stale(&self, message_date: i64) -> bool {
+        self.max_message_age.is_some_and(|max_message_age| {
+            message_date < self.started_at - max_message_age.as_secs() as i64
+        })
+    }
+}
+
+#[cfg(test)]
+mod startup_replay_guard_tests {
+    use super::StartupReplayGuard;
+    use std::time::Duration;
+
+    #[test]
+    fn should_only_take_messages_older_than_max_age_as_stale() {
+        let guard = StartupReplayGuard::new(1000, Some(Duration::from_secs(60)));
+
+        assert!(guard.is_stale(939));
+        assert!(!guard.is_stale(940));
+        assert!(!guard.is_stale(1000));
+        assert!(!guard.is_stale(2000));
+    }
+
+    #[test]
+    fn should_not_take_any_message_as_stale_without_max_age() {
+        let guard = StartupReplayGuard::new(1000, None);
+
+        assert!(!guard.is_stale(0));
+    }
+}
+
+#[cfg(test)]
+mod reply_suppression_tests {
+    use super::ReplySuppression;
+    use std::time::{Duration, Instant};
+    use tbot::types::chat;
+
+    #[test]
+    fn should_suppress_replies_within_window_of_last_reply() {
+        let mut suppression = ReplySuppression::default();
+        let now = Instant::now();
+
+        assert!(!suppression.is_suppressed(chat::Id(1), now));
+
+        suppression.record_reply(chat::Id(1), now);
+
+        assert!(suppression.is_suppressed(chat::Id(1), now + Duration::from_secs(9)));
+        assert!(!suppression.is_suppressed(chat::Id(1), now + Duration::from_secs(10)));
+        assert!(!suppression.is_suppressed(chat::Id(2), now));
+    }
+
+    #[test]
+    fn should_not_suppress_anything_with_empty_window() {
+        let mut suppression = ReplySuppression {
+            window: Duration::ZERO,
+            ..ReplySuppression::default()
+        };
+        let now = Instant::now();
+
+        suppression.record_reply(chat::Id(1), now);
+
+        assert!(!suppression.is_suppressed(chat::Id(1), now));
+    }
+}
+
+#[cfg(test)]
+mod engagement_boost_tests {
+    use super::EngagementBoost;
+    use std::time::Duration;
+    use tbot::types::chat;
+
+    fn engagement_boost() -> EngagementBoost {
+        EngagementBoost::new(0.5, Duration::from_secs(60), Duration::from_secs(100))
+    }
+
+    #[test]
+    fn should_boost_chat_that_answered_in_time() {
+        let mut engagement_boost = engagement_boost();
+
+        assert!(engagement_boost.record_answer(chat::Id(1), 1000, 1060));
+
+        assert_eq!(
+            engagement_boost.reply_probability(chat::Id(1), 0.1, 1060),
+            0.6
+        );
+        assert_eq!(
+            engagement_boost.reply_probability(chat::Id(2), 0.1, 1060),
+            0.1
+        );
+    }
+
+    #[test]
+    fn should_not_boost_chat_that_answered_late() {
+        let mut engagement_boost = engagement_boost();
+
+        assert!(!engagement_boost.record_answer(chat::Id(1), 1000, 1061));
+
+        assert_eq!(
+            engagement_boost.reply_probability(chat::Id(1), 0.1, 1061),
+            0.1
+        );
+    }
+
+    #[test]
+    fn should_decay_back_to_baseline() {
+        let mut engagement_boost = engagement_boost();
+        engagement_boost.record_answer(chat::Id(1), 1000, 1000);
+
+        assert_eq!(
+            engagement_boost.reply_probability(chat::Id(1), 0.0, 1050),
+            0.25
+        );
+        assert_eq!(
+            engagement_boost.reply_probability(chat::Id(1), 0.0, 1100),
+            0.0
+        );
+        assert_eq!(
+            engagement_boost.reply_probability(chat::Id(1), 0.0, 2000),
+            0.0
+        );
+    }
+
+    #[test]
+    fn should_never_exceed_certainty() {
+        let mut engagement_boost = engagement_boost();
+        engagement_boost.record_answer(chat::Id(1), 1000, 1000);
+
+        assert_eq!(
+            engagement_boost.reply_probability(chat::Id(1), 0.9, 1000),
+            1.0
+        );
+    }
+}
+
+#[cfg(test)]
+mod outgoing_queue_tests {
+    use super::{is_transient, retry_delay, MessageSender, OutgoingQueue, QueueConfig, SendFuture};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tbot::types::chat;
+
+    #[derive(Default)]
+    struct FakeSender {
+        sent_messages: Arc<Mutex<Vec<(chat::Id, String)>>>,
+        failures_left: Arc<Mutex<usize>>,
+        /// Failures are flood limit errors asking to wait this long if set.
+        retry_after: Option<u64>,
+        typing_chat_ids: Arc<Mutex<Vec<chat::Id>>>,
+    }
+
+    impl MessageSender for FakeSender {
+        fn send_text<'a>(&'a self, chat_id: chat::Id, text: &'a str) -> SendFuture<'a> {
+            Box::pin(async move {
+                let mut failures_left = self.failures_left.lock().unwrap();
+
+                if *failures_left > 0 {
+                    *failures_left -= 1;
+
+                    return Err(match self.retry_after {
+                        Some(retry_after) => tbot::errors::MethodCall::RequestError {
+                            description: "Too Many Requests".into(),
+                            error_code: 429,
+                            migrate_to_chat_id: None,
+                            retry_after: Some(retry_after),
+                        },
+                        None => tbot::errors::MethodCall::OutOfService,
+                    });
+                }
+
+                self.sent_messages
+                    .lock()
+                    .unwrap()
+                    .push((chat_id, text.into()));
+
+                Ok(())
+            })
+        }
+
+        fn send_typing(&self, chat_id: chat::Id) -> SendFuture<'_> {
+            Box::pin(async move {
+                self.typing_chat_ids.lock().unwrap().push(chat_id);
+                Ok(())
+            })
+        }
+    }
+
+    fn fast_config() -> QueueConfig {
+        QueueConfig {
+            min_send_interval: Duration::from_millis(20),
+            max_retries: 2,
+            retry_delay: Duration::from_millis(1),
+            max_retry_delay: Duration::from_millis(5),
+            ..QueueConfig::default()
+        }
+    }
+
+    async fn wait_for_messages(
+        sent_messages: &Mutex<Vec<(chat::Id, String)>>,
+        count: usize,
+    ) -> Vec<(chat::Id, String)> {
+        for _ in 0..500 {
+            if sent_messages.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(2)).await;
+        }
+
+        sent_messages.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn should_preserve_order_of_messages_per_chat() {
+        let sender = FakeSender::default();
+        let sent_messages = Arc::clone(&sender.sent_messages);
+        let mut queue = OutgoingQueue::new(sender, fast_config());
+
+        for text in ["first", "second", "third"] {
+            queue.enqueue(chat::Id(1), text.into());
+        }
+
+        let texts: Vec<_> = wait_for_messages(&sent_messages, 3)
+            .await
+            .into_iter()
+            .map(|(_, text)| text)
+            .collect();
+
+        assert_eq!(texts, &["first", "second", "third"]);
+    }
+
+    #[tokio::test]
+    async fn should_end_queues_of_idle_chats() {
+        let sender = FakeSender::default();
+        let sent_messages = Arc::clone(&sender.sent_messages);
+        let config = QueueConfig {
+            idle_timeout: Duration::from_millis(30),
+            ..fast_config()
+        };
+        let mut queue = OutgoingQueue::new(sender, config);
+
+        queue.enqueue(chat::Id(1), "first".into());
+        wait_for_messages(&sent_messages, 1).await;
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert!(queue.chat_queues.lock().unwrap().is_empty());
+
+        queue.enqueue(chat::Id(1), "second".into());
+        let texts: Vec<_> = wait_for_messages(&sent_messages, 2)
+            .await
+            .into_iter()
+            .map(|(_, text)| text)
+            .collect();
+
+        assert_eq!(texts, &["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn should_wait_between_messages_to_the_same_chat() {
+        let sender = FakeSender::default();
+        let sent_messages = Arc::clone(&sender.sent_messages);
+        let mut queue = OutgoingQueue::new(sender, fast_config());
+
+        let start = Instant::now();
+        queue.enqueue(chat::Id(1), "first".into());
+        queue.enqueue(chat::Id(1), "second".into());
+        wait_for_messages(&sent_messages, 2).await;
+
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn should_retry_failed_sends() {
+        let sender = FakeSender {
+            failures_left: Arc::new(Mutex::new(2)),
+            ..FakeSender::default()
+        };
+        let sent_messages = Arc::clone(&sender.sent_messages);
+        let mut queue = OutgoingQueue::new(sender, fast_config());
+
+        queue.enqueue(chat::Id(1), "hello".into());
+
+        assert_eq!(
+            wait_for_messages(&sent_messages, 1).await,
+            &[(chat::Id(1), "hello".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_wait_as_long_as_asked_when_flood_limited() {
+        let sender = FakeSender {
+            failures_left: Arc::new(Mutex::new(1)),
+            retry_after: Some(1),
+            ..FakeSender::default()
+        };
+        let sent_messages = Arc::clone(&sender.sent_messages);
+        let mut queue = OutgoingQueue::new(sender, fast_config());
+
+        let start = Instant::now();
+        queue.enqueue(chat::Id(1), "hello".into());
+
+        for _ in 0..300 {
+            if !sent_messages.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(sent_messages.lock().unwrap().len(), 1);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn should_give_up_after_max_retries_and_send_next_message() {
+        let sender = FakeSender {
+            failures_left: Arc::new(Mutex::new(3)),
+            ..FakeSender::default()
+        };
+        let sent_messages = Arc::clone(&sender.sent_messages);
+        let mut queue = OutgoingQueue::new(sender, fast_config());
+
+        queue.enqueue(chat::Id(1), "dropped".into());
+        queue.enqueue(chat::Id(1), "delivered".into());
+
+        assert_eq!(
+            wait_for_messages(&sent_messages, 1).await,
+            &[(chat::Id(1), "delivered".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_show_typing_before_sending() {
+        let sender = FakeSender::default();
+        let sent_messages = Arc::clone(&sender.sent_messages);
+        let typing_chat_ids = Arc::clone(&sender.typing_chat_ids);
+        let config = QueueConfig {
+            typing_delay_per_char: Duration::from_millis(10),
+            max_typing_delay: Duration::from_millis(30),
+            ..fast_config()
+        };
+        let mut queue = OutgoingQueue::new(sender, config);
+
+        let start = Instant::now();
+        queue.enqueue(chat::Id(1), "hello".into());
+        wait_for_messages(&sent_messages, 1).await;
+
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(*typing_chat_ids.lock().unwrap(), &[chat::Id(1)]);
+    }
+
+    fn request_error(error_code: u16, retry_after: Option<u64>) -> tbot::errors::MethodCall {
+        tbot::errors::MethodCall::RequestError {
+            description: "Error".into(),
+            error_code,
+            migrate_to_chat_id: None,
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn should_back_off_exponentially_up_to_max_retry_delay() {
+        let config = QueueConfig {
+            retry_delay: Duration::from_secs(2),
+            max_retry_delay: Duration::from_secs(10),
+            ..QueueConfig::default()
+        };
+        let err = tbot::errors::MethodCall::OutOfService;
+
+        let delays: Vec<_> = (0..5)
+            .map(|attempt| retry_delay(&config, attempt, &err).as_secs())
+            .collect();
+
+        assert_eq!(delays, &[2, 4, 8, 10, 10]);
+    }
+
+    #[test]
+    fn should_wait_at_least_as_long_as_asked_when_flood_limited() {
+        let config = QueueConfig {
+            retry_delay: Duration::from_secs(2),
+            max_retry_delay: Duration::from_secs(10),
+            ..QueueConfig::default()
+        };
+
+        assert_eq!(
+            retry_delay(&config, 0, &request_error(429, Some(30))),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn should_only_retry_transient_failures() {
+        assert!(is_transient(&tbot::errors::MethodCall::OutOfService));
+        assert!(is_transient(&request_error(429, Some(1))));
+        assert!(is_transient(&request_error(502, None)));
+        assert!(!is_transient(&request_error(403, None)));
+        assert!(!is_transient(&request_error(400, None)));
+    }
+}
@@ -0,0 +1,378 @@
+use rand::{seq::SliceRandom, Rng};
+
+/// Terminators which convey the tone of a phrase, and so are kept in replies.
+pub const EXPRESSIVE_TERMINATORS: [char; 3] = ['?', '!', '…'];
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum ReplyFlavor {
+    Plain,
+    Question,
+    Exclamation,
+}
+
+impl ReplyFlavor {
+    fn terminator(self) -> &'static str {
+        match self {
+            ReplyFlavor::Plain => "",
+            ReplyFlavor::Question => "?",
+            ReplyFlavor::Exclamation => "!",
+        }
+    }
+}
+
+/// Renders generated phrases with a randomly picked flavor, optionally
+/// prepending an interjection, so that replies don't all look alike.
+pub struct ReplyStyler {
+    pub question_prob: f32,
+    pub exclamation_prob: f32,
+    pub interjection_prob: f32,
+    pub interjections: Vec<String>,
+}
+
+impl Default for ReplyStyler {
+    fn default() -> Self {
+        ReplyStyler {
+            question_prob: 0.15,
+            exclamation_prob: 0.15,
+            interjection_prob: 0.1,
+            interjections: ["well", "oh", "hmm"].map(String::from).to_vec(),
+        }
+    }
+}
+
+impl ReplyStyler {
+    pub fn style(&self, phrase: &str, rng: &mut impl Rng) -> String {
+        let flavor = self.pick_flavor(rng);
+
+        let interjection = if rng.gen::<f32>() < self.interjection_prob {
+            self.interjections.choose(rng).map(String::as_str)
+        } else {
+            None
+        };
+
+        style_phrase(phrase, flavor, interjection)
+    }
+
+    fn pick_flavor(&self, rng: &mut impl Rng) -> ReplyFlavor {
+        let roll = rng.gen::<f32>();
+
+        if roll < self.question_prob {
+            ReplyFlavor::Question
+        } else if roll < self.question_prob + self.exclamation_prob {
+            ReplyFlavor::Exclamation
+        } else {
+            ReplyFlavor::Plain
+        }
+    }
+}
+
+fn style_phrase(phrase: &str, flavor: ReplyFlavor, interjection: Option<&str>) -> String {
+    // Phrases that already carry their own tone are left as they are.
+    let flavor = if phrase.ends_with(EXPRESSIVE_TERMINATORS) {
+        ReplyFlavor::Plain
+    } else {
+        flavor
+    };
+
+    match interjection {
+        Some(interjection) => format!("{}, {}{}", interjection, phrase, flavor.terminator()),
+        None => format!("{}{}", phrase, flavor.terminator()),
+    }
+}
+
+/// Joins sentences into a single text, ending all but the last one with a
+/// period, unless they already have a terminator of their own. The last one is
+/// left for the styler to terminate.
+pub fn join_sentences(sentences: &[String]) -> String {
+    let mut text = String::new();
+
+    for (i, sentence) in sentences.iter().enumerate() {
+        text.push_str(sentence);
+
+        if i + 1 < sentences.len() {
+            if !sentence.ends_with(EXPRESSIVE_TERMINATORS) {
+                text.push('.');
+            }
+            text.push(' ');
+        }
+    }
+
+    text
+}
+
+/// Marks the text as a quote of something said before, so that it isn't taken
+/// for a generated reply.
+pub fn quote(text: &str) -> String {
+    format!("“{}”", text)
+}
+
+/// Telegram's limit for the text of a single message, in UTF-16 code units.
+pub const TELEGRAM_MAX_MESSAGE_LEN: usize = 4096;
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum OverflowPolicy {
+    /// Drops everything past the last word that fits.
+    Truncate,
+    /// Sends the remaining text in as many messages as needed.
+    Split,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(OverflowPolicy::Truncate),
+            "split" => Ok(OverflowPolicy::Split),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Makes sure outgoing texts fit in a message, cutting them at word boundaries
+/// whenever possible. Texts are sent without a parse mode, so they're shown as
+/// they are and never need escaping.
+pub struct LengthGuard {
+    pub max_len: usize,
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl Default for LengthGuard {
+    fn default() -> Self {
+        LengthGuard {
+            max_len: TELEGRAM_MAX_MESSAGE_LEN,
+            overflow_policy: OverflowPolicy::Truncate,
+        }
+    }
+}
+
+impl LengthGuard {
+    /// Returns the messages that should be sent for `text`, which is a single
+    /// one unless the text is too long and the policy is to split it. Control
+    /// characters other than line breaks and tabs are left out, as Telegram
+    /// can't show them.
+    pub fn apply(&self, text: &str) -> Vec<String> {
+        let text: String = text
+            .chars()
+            .filter(|&c| !c.is_control() || c == '\n' || c == '\t')
+            .collect();
+
+        let mut messages = Vec::new();
+        let mut remaining_text = text.trim();
+
+        while !remaining_text.is_empty() {
+            let (message, rest) = split_at_word_boundary(remaining_text, self.max_len);
+            messages.push(message.trim_end().into());
+
+            if self.overflow_policy == OverflowPolicy::Truncate {
+                break;
+            }
+
+            remaining_text = rest.trim_start();
+        }
+
+        messages
+    }
+}
+
+/// Splits `text` so that the first half is at most `max_len` UTF-16 code units
+/// long, preferably at the last whitespace that fits. Texts with a single
+/// long word are split at the last character that fits.
+fn split_at_word_boundary(text: &str, max_len: usize) -> (&str, &str) {
+    let mut len = 0;
+    let mut last_whitespace_pos = None;
+
+    for (pos, c) in text.char_indices() {
+        if c.is_whitespace() {
+            last_whitespace_pos = Some(pos);
+        }
+
+        len += c.len_utf16();
+
+        if len > max_len {
+            // Always makes progress, even if a single character doesn't fit.
+            let split_pos = match last_whitespace_pos {
+                Some(whitespace_pos) => whitespace_pos,
+                None if pos == 0 => c.len_utf8(),
+                None => pos,
+            };
+
+            return text.split_at(split_pos);
+        }
+    }
+
+    (text, "")
+}
+
+#[cfg(test)]
+mod length_guard_tests {
+    use super::{LengthGuard, OverflowPolicy};
+
+    #[test]
+    fn should_keep_text_that_fits() {
+        let guard = LengthGuard {
+            max_len: 11,
+            overflow_policy: OverflowPolicy::Truncate,
+        };
+
+        assert_eq!(guard.apply("hello world"), &["hello world"]);
+    }
+
+    #[test]
+    fn should_truncate_at_word_boundary() {
+        let guard = LengthGuard {
+            max_len: 14,
+            overflow_policy: OverflowPolicy::Truncate,
+        };
+
+        assert_eq!(guard.apply("hello there my friend"), &["hello there my"]);
+    }
+
+    #[test]
+    fn should_split_at_word_boundaries() {
+        let guard = LengthGuard {
+            max_len: 11,
+            overflow_policy: OverflowPolicy::Split,
+        };
+
+        assert_eq!(
+            guard.apply("hello there my dear friend"),
+            &["hello there", "my dear", "friend"]
+        );
+    }
+
+    #[test]
+    fn should_cut_long_words_at_char_boundaries() {
+        let guard = LengthGuard {
+            max_len: 3,
+            overflow_policy: OverflowPolicy::Split,
+        };
+
+        assert_eq!(guard.apply("ããããã"), &["ããã", "ãã"]);
+    }
+
+    #[test]
+    fn should_count_length_in_utf16_code_units() {
+        let guard = LengthGuard {
+            max_len: 4,
+            overflow_policy: OverflowPolicy::Split,
+        };
+
+        assert_eq!(guard.apply("😀😀😀 a"), &["😀😀", "😀 a"]);
+    }
+
+    #[test]
+    fn should_not_produce_messages_for_empty_text() {
+        let guard = LengthGuard::default();
+
+        assert!(guard.apply("   ").is_empty());
+    }
+
+    #[test]
+    fn should_leave_out_control_characters() {
+        let guard = LengthGuard::default();
+
+        assert_eq!(
+            guard.apply("hello\u{0}\u{1b} there\nfriend\u{7}"),
+            &["hello there\nfriend"]
+        );
+        assert!(guard.apply("\u{0}\u{0}").is_empty());
+    }
+}
+
+#[cfg(test)]
+mod reply_styling_tests {
+    use super::{style_phrase, ReplyFlavor, ReplyStyler};
+    use rand::SeedableRng;
+
+    #[test]
+    fn should_adjust_terminator_according_to_flavor() {
+        assert_eq!(
+            style_phrase("you are here", ReplyFlavor::Plain, None),
+            "you are here"
+        );
+        assert_eq!(
+            style_phrase("you are here", ReplyFlavor::Question, None),
+            "you are here?"
+        );
+        assert_eq!(
+            style_phrase("you are here", ReplyFlavor::Exclamation, None),
+            "you are here!"
+        );
+    }
+
+    #[test]
+    fn should_keep_existing_expressive_terminator() {
+        assert_eq!(
+            style_phrase("you are here!", ReplyFlavor::Question, Some("oh")),
+            "oh, you are here!"
+        );
+    }
+
+    #[test]
+    fn should_prepend_interjection() {
+        assert_eq!(
+            style_phrase("you are here", ReplyFlavor::Question, Some("oh")),
+            "oh, you are here?"
+        );
+    }
+
+    #[test]
+    fn should_always_render_plain_if_all_probabilities_are_zero() {
+        let styler = ReplyStyler {
+            question_prob: 0.0,
+            exclamation_prob: 0.0,
+            interjection_prob: 0.0,
+            interjections: vec!["oh".into()],
+        };
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        for _ in 0..100 {
+            assert_eq!(styler.style("you are here", &mut rng), "you are here");
+        }
+    }
+
+    #[test]
+    fn should_always_render_flavor_with_probability_one() {
+        let styler = ReplyStyler {
+            question_prob: 0.0,
+            exclamation_prob: 1.0,
+            interjection_prob: 1.0,
+            interjections: vec!["oh".into()],
+        };
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        for _ in 0..100 {
+            assert_eq!(styler.style("you are here", &mut rng), "oh, you are here!");
+        }
+    }
+}
+
+#[cfg(test)]
+mod sentence_joining_tests {
+    use super::{join_sentences, quote};
+
+    #[test]
+    fn should_leave_single_sentence_untouched() {
+        assert_eq!(join_sentences(&["you are here".into()]), "you are here");
+    }
+
+    #[test]
+    fn should_terminate_all_but_the_last_sentence() {
+        let sentences = ["you are here".into(), "i am there".into(), "bye".into()];
+
+        assert_eq!(join_sentences(&sentences), "you are here. i am there. bye");
+    }
+
+    #[test]
+    fn should_keep_expressive_terminators_between_sentences() {
+        let sentences = ["are you here?".into(), "i am there".into()];
+
+        assert_eq!(join_sentences(&sentences), "are you here? i am there");
+    }
+
+    #[test]
+    fn should_mark_quotes() {
+        assert_eq!(quote("you are here"), "“you are here”");
+    }
+}
@@ -0,0 +1,330 @@
+use crate::error::{self, Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use tbot::types::chat;
+
+/// Pending changes beyond this many are dropped, oldest first, so that a chat
+/// nobody reviews doesn't pile them up forever.
+const MAX_PENDING_CHANGES_PER_CHAT: usize = 50;
+
+/// A change that was derived from what a chat says, rather than asked for, and
+/// thus only applies once an admin approves it.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub(crate) enum ProposedChange {
+    /// Two words to pivot for each other.
+    Alias { word: String, alias: String },
+}
+
+impl fmt::Display for ProposedChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProposedChange::Alias { word, alias } => write!(f, "alias {} = {}", word, alias),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingChange {
+    id: usize,
+    chat_id: i64,
+    change: ProposedChange,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ApprovedChange {
+    chat_id: i64,
+    change: ProposedChange,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ChangeQueue {
+    next_id: usize,
+    pending: Vec<PendingChange>,
+    approved: Vec<ApprovedChange>,
+}
+
+/// Changes waiting for an admin of their chat to approve or reject them, along
+/// with those already approved, which are applied again on startup. They're
+/// saved to a JSON file whenever they change.
+pub(crate) struct PendingChanges {
+    path: PathBuf,
+    queue: ChangeQueue,
+}
+
+impl PendingChanges {
+    /// Loads the changes saved at `path`, or starts without changes if there's
+    /// no such file yet.
+    pub(crate) fn load(path: impl Into<PathBuf>) -> error::Result<PendingChanges> {
+        let path = path.into();
+
+        let queue = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|_| Error::parse("pending changes", path.display().to_string()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => ChangeQueue::default(),
+            Err(err) => {
+                return Err(err).context(|| format!("reading pending changes `{}`", path.display()))
+            }
+        };
+
+        Ok(PendingChanges { path, queue })
+    }
+
+    /// Queues the change for review. Returns its id, or `None` if the change
+    /// is already pending or approved in the chat.
+    pub(crate) fn propose(
+        &mut self,
+        chat_id: chat::Id,
+        change: ProposedChange,
+    ) -> error::Result<Option<usize>> {
+        let is_known = self
+            .queue
+            .pending
+            .iter()
+            .map(|pending| (pending.chat_id, &pending.change))
+            .chain(
+                self.queue
+                    .approved
+                    .iter()
+                    .map(|approved| (approved.chat_id, &approved.change)),
+            )
+            .any(|known| known == (chat_id.0, &change));
+
+        if is_known {
+            return Ok(None);
+        }
+
+        let id = self.queue.next_id + 1;
+        self.queue.next_id = id;
+        self.queue.pending.push(PendingChange {
+            id,
+            chat_id: chat_id.0,
+            change,
+        });
+
+        let chat_pending_count = self.pending(chat_id).len();
+        if chat_pending_count > MAX_PENDING_CHANGES_PER_CHAT {
+            let oldest = self
+                .queue
+                .pending
+                .iter()
+                .position(|pending| pending.chat_id == chat_id.0)
+                .unwrap();
+            self.queue.pending.remove(oldest);
+        }
+
+        self.save()?;
+
+        Ok(Some(id))
+    }
+
+    /// The changes waiting for review in the chat, oldest first, along with
+    /// their ids.
+    pub(crate) fn pending(&self, chat_id: chat::Id) -> Vec<(usize, &ProposedChange)> {
+        self.queue
+            .pending
+            .iter()
+            .filter(|pending| pending.chat_id == chat_id.0)
+            .map(|pending| (pending.id, &pending.change))
+            .collect()
+    }
+
+    /// Marks the pending change as approved, and returns it so that it can be
+    /// applied. Returns `None` if the chat has no pending change with that id.
+    pub(crate) fn approve(
+        &mut self,
+        chat_id: chat::Id,
+        id: usize,
+    ) -> error::Result<Option<ProposedChange>> {
+        let change = match self.take_pending(chat_id, id) {
+            Some(change) => change,
+            None => return Ok(None),
+        };
+
+        self.queue.approved.push(ApprovedChange {
+            chat_id: chat_id.0,
+            change: change.clone(),
+        });
+        self.save()?;
+
+        Ok(Some(change))
+    }
+
+    /// Drops the pending change. Returns `None` if the chat has no pending
+    /// change with that id.
+    pub(crate) fn reject(
+        &mut self,
+        chat_id: chat::Id,
+        id: usize,
+    ) -> error::Result<Option<ProposedChange>> {
+        let change = self.take_pending(chat_id, id);
+
+        if change.is_some() {
+            self.save()?;
+        }
+
+        Ok(change)
+    }
+
+    /// Every change approved so far, in the order they were approved.
+    pub(crate) fn approved(&self) -> impl Iterator<Item = (chat::Id, &ProposedChange)> {
+        self.queue
+            .approved
+            .iter()
+            .map(|approved| (chat::Id(approved.chat_id), &approved.change))
+    }
+
+    fn take_pending(&mut self, chat_id: chat::Id, id: usize) -> Option<ProposedChange> {
+        let position = self
+            .queue
+            .pending
+            .iter()
+            .position(|pending| pending.chat_id == chat_id.0 && pending.id == id)?;
+
+        Some(self.queue.pending.remove(position).change)
+    }
+
+    fn save(&self) -> error::Result<()> {
+        let json = serde_json::to_vec(&self.queue).expect("changes are serializable");
+
+        std::fs::write(&self.path, json)
+            .context(|| format!("writing pending changes `{}`", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod pending_changes_tests {
+    use super::{PendingChanges, ProposedChange, MAX_PENDING_CHANGES_PER_CHAT};
+    use std::path::PathBuf;
+    use tbot::types::chat;
+
+    fn temp_pending_changes_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "pending_changes_{}_{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn alias(word: &str, alias: &str) -> ProposedChange {
+        ProposedChange::Alias {
+            word: word.into(),
+            alias: alias.into(),
+        }
+    }
+
+    #[test]
+    fn should_list_pending_changes_of_chat_only() {
+        let path = temp_pending_changes_path("list");
+        let mut pending_changes = PendingChanges::load(&path).unwrap();
+
+        let fridge_id = pending_changes
+            .propose(chat::Id(1), alias("fridge", "geladeira"))
+            .unwrap()
+            .unwrap();
+        pending_changes
+            .propose(chat::Id(2), alias("bob", "robert"))
+            .unwrap();
+
+        assert_eq!(
+            pending_changes.pending(chat::Id(1)),
+            [(fridge_id, &alias("fridge", "geladeira"))]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_not_propose_known_change_again() {
+        let path = temp_pending_changes_path("known");
+        let mut pending_changes = PendingChanges::load(&path).unwrap();
+
+        let id = pending_changes
+            .propose(chat::Id(1), alias("fridge", "geladeira"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            pending_changes
+                .propose(chat::Id(1), alias("fridge", "geladeira"))
+                .unwrap(),
+            None
+        );
+
+        pending_changes.approve(chat::Id(1), id).unwrap();
+        assert_eq!(
+            pending_changes
+                .propose(chat::Id(1), alias("fridge", "geladeira"))
+                .unwrap(),
+            None
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_keep_approved_changes_across_restarts() {
+        let path = temp_pending_changes_path("approve");
+        let mut pending_changes = PendingChanges::load(&path).unwrap();
+
+        let id = pending_changes
+            .propose(chat::Id(1), alias("fridge", "geladeira"))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(pending_changes.approve(chat::Id(2), id).unwrap(), None);
+        assert_eq!(
+            pending_changes.approve(chat::Id(1), id).unwrap(),
+            Some(alias("fridge", "geladeira"))
+        );
+
+        let pending_changes = PendingChanges::load(&path).unwrap();
+        assert!(pending_changes.pending(chat::Id(1)).is_empty());
+        assert_eq!(
+            pending_changes.approved().collect::<Vec<_>>(),
+            [(chat::Id(1), &alias("fridge", "geladeira"))]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_drop_rejected_changes() {
+        let path = temp_pending_changes_path("reject");
+        let mut pending_changes = PendingChanges::load(&path).unwrap();
+
+        let id = pending_changes
+            .propose(chat::Id(1), alias("fridge", "geladeira"))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            pending_changes.reject(chat::Id(1), id).unwrap(),
+            Some(alias("fridge", "geladeira"))
+        );
+        assert!(pending_changes.pending(chat::Id(1)).is_empty());
+        assert_eq!(pending_changes.approved().count(), 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_drop_oldest_pending_changes_past_limit() {
+        let path = temp_pending_changes_path("limit");
+        let mut pending_changes = PendingChanges::load(&path).unwrap();
+
+        for i in 0..=MAX_PENDING_CHANGES_PER_CHAT {
+            pending_changes
+                .propose(chat::Id(1), alias("word", &format!("alias{}", i)))
+                .unwrap();
+        }
+
+        let pending = pending_changes.pending(chat::Id(1));
+        assert_eq!(pending.len(), MAX_PENDING_CHANGES_PER_CHAT);
+        assert_eq!(pending[0].1, &alias("word", "alias1"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
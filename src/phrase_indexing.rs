@@ -1,441 +1,3686 @@
+use crate::interner::{Interner, Symbol};
+use crate::scoring::QualityScorer;
+use crate::sources::{PhraseSource, ALL_PHRASE_SOURCES};
 use lazy_static::lazy_static;
+use rand::Rng;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Punctuation that splits text into phrases by default. Also includes the
+/// Arabic semicolon and full stop.
+pub const DEFAULT_PHRASE_TERMINATORS: [char; 4] = ['.', ';', '؛', '۔'];
+
+/// How much junctions at the very start or end of phrases weigh with the
+/// triangular distribution, so that phrases pivoting only there can still be
+/// spliced.
+const MIN_TRIANGULAR_JUNCTION_WEIGHT: f32 = 0.05;
+/// Standard deviation of the normal distribution of junctions, relative to the
+/// length of phrases.
+const NORMAL_JUNCTION_SPREAD: f32 = 0.2;
+
+/// Letters words in all caps must have to be taken as acronyms, so that words
+/// such as "I" aren't.
+const MIN_ACRONYM_LEN: usize = 2;
+
+/// How likely phrases are to be spliced at each position of the pivot word in
+/// them, from their first word to their last. Splicing near either end makes
+/// for lopsided phrases, e.g. a single word followed by a whole sentence.
+#[derive(Deserialize, PartialEq, Eq, Debug, Default, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum JunctionDistribution {
+    /// Every position is as likely.
+    #[default]
+    Uniform,
+    /// Positions are less likely the farther they are from the middle, down to
+    /// almost never at either end.
+    Triangular,
+    /// Positions fall off from the middle as a bell curve, which favors the
+    /// middle more strongly than `Triangular`.
+    Normal,
+}
+
+impl JunctionDistribution {
+    /// How much splicing at `relative_pos` weighs, where 0.0 is the first word
+    /// of the phrase and 1.0 the last.
+    pub fn weight(self, relative_pos: f32) -> f32 {
+        let distance_from_middle = (relative_pos - 0.5).abs();
+
+        match self {
+            JunctionDistribution::Uniform => 1.0,
+            JunctionDistribution::Triangular => {
+                (1.0 - 2.0 * distance_from_middle).max(MIN_TRIANGULAR_JUNCTION_WEIGHT)
+            }
+            JunctionDistribution::Normal => {
+                (-distance_from_middle.powi(2) / (2.0 * NORMAL_JUNCTION_SPREAD.powi(2))).exp()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NormalizationConfig {
+    pub case_folding: CaseFolding,
+    /// Whether pivot words should match regardless of diacritics (e.g. "não"
+    /// and "nao"). Phrases themselves keep their original forms.
+    pub fold_pivot_diacritics: bool,
+    /// Characters at which text is split into phrases. Any other punctuation is
+    /// treated as whitespace.
+    pub phrase_terminators: Vec<char>,
+    /// Runs of the same letter longer than this are collapsed into two of them
+    /// (e.g. "loooool" becomes "lool"), so that elongated words match each
+    /// other. Runs are kept as they are if `None`.
+    pub max_letter_run: Option<usize>,
+    /// Words fully matching this pattern are considered laughter, and match
+    /// each other as pivots (e.g. "kkkk" and "hahaha").
+    pub laughter_pattern: Option<Regex>,
+    /// Words that can't pivot on their own, such as hashtag-like compounds
+    /// (e.g. "gamenightfriday"), pivot on the longest common word of at least
+    /// this many letters inside them. Words aren't looked into if `None`.
+    pub min_sub_word_len: Option<usize>,
+}
+
+impl Default for NormalizationConfig {
+    fn default() -> Self {
+        NormalizationConfig {
+            case_folding: CaseFolding::default(),
+            fold_pivot_diacritics: false,
+            phrase_terminators: DEFAULT_PHRASE_TERMINATORS.to_vec(),
+            max_letter_run: None,
+            laughter_pattern: None,
+            min_sub_word_len: None,
+        }
+    }
+}
+
+impl NormalizationConfig {
+    pub fn for_language(language_code: &str) -> NormalizationConfig {
+        let primary_language = primary_language_subtag(language_code);
+
+        NormalizationConfig {
+            case_folding: CaseFolding::for_language(language_code),
+            fold_pivot_diacritics: matches!(
+                primary_language.as_str(),
+                "pt" | "es" | "fr" | "it" | "ca" | "gl" | "ro"
+            ),
+            laughter_pattern: match primary_language.as_str() {
+                "pt" => Some(laughter_pattern(PT_LAUGHTER_PATTERN).unwrap()),
+                _ => None,
+            },
+            ..NormalizationConfig::default()
+        }
+    }
+}
+
+/// Laughter as usually written in Portuguese, e.g. "kkkk", "hahaha", "rsrs"
+/// and "huehue".
+pub const PT_LAUGHTER_PATTERN: &str = r"k{3,}|a?(?:ha){2,}h?|(?:he){2,}|(?:rs){2,}|(?:hue){2,}";
+
+/// Words too common to make interesting pivots, by primary language.
+const STOP_WORDS_BY_LANGUAGE: [(&str, &[&str]); 2] = [
+    (
+        "en",
+        &[
+            "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "from", "if", "in",
+            "is", "it", "of", "on", "or", "so", "that", "the", "this", "to", "was", "with",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "a", "as", "com", "da", "das", "de", "do", "dos", "e", "em", "é", "na", "nas", "no",
+            "nos", "o", "os", "ou", "para", "pra", "por", "que", "se", "um", "uma",
+        ],
+    ),
+];
+
+/// Words longer than this, in letters, aren't looked into for sub-words.
+const MAX_SUB_WORD_SEARCH_LEN: usize = 64;
+
+/// The built-in stop words of the language, if there are any.
+pub fn default_stop_words(language_code: &str) -> &'static [&'static str] {
+    let primary_language = primary_language_subtag(language_code);
+
+    STOP_WORDS_BY_LANGUAGE
+        .iter()
+        .find(|(language, _)| *language == primary_language)
+        .map_or(&[], |(_, stop_words)| stop_words)
+}
+
+/// Languages that have built-in stop words.
+pub fn stop_word_languages() -> impl Iterator<Item = &'static str> {
+    STOP_WORDS_BY_LANGUAGE.iter().map(|(language, _)| *language)
+}
+
+/// Compiles a laughter pattern so that it only matches whole words.
+pub fn laughter_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", pattern))
+}
+
+/// Whether the code looks like a language tag, such as "pt" or "pt-BR": a
+/// primary language subtag of 2 or 3 letters, optionally followed by subtags
+/// of up to 8 letters or digits.
+pub fn is_language_code(language_code: &str) -> bool {
+    let mut subtags = language_code.split(&['-', '_']);
+    let primary_language = subtags.next().unwrap_or_default();
+
+    (2..=3).contains(&primary_language.len())
+        && primary_language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+pub fn primary_language_subtag(language_code: &str) -> String {
+    language_code
+        .split(&['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+#[derive(PartialEq, Eq, Debug, Default, Copy, Clone)]
+pub enum CaseFolding {
+    /// Default Unicode lowercase mapping.
+    #[default]
+    Unicode,
+    /// Same as `Unicode`, except that dotted and dotless i are mapped as in
+    /// Turkish and Azerbaijani, i.e. `I` becomes `ı` and `İ` becomes `i`.
+    Turkic,
+    /// Keeps text as is, for scripts which have no case, such as CJK.
+    Preserve,
+}
+
+impl CaseFolding {
+    pub fn for_language(language_code: &str) -> CaseFolding {
+        let primary_language = primary_language_subtag(language_code);
+
+        match primary_language.as_str() {
+            "tr" | "az" => CaseFolding::Turkic,
+            "zh" | "ja" | "ko" => CaseFolding::Preserve,
+            _ => CaseFolding::Unicode,
+        }
+    }
 
-pub(crate) fn normalize_text_into_phrases(text: String) -> Vec<Phrase> {
-    split_text_at_periods(&text)
-        .map(|subtext| {
-            let subtext = normalize_punctuation_to_whitespace(subtext);
+    fn fold(self, text: &str) -> String {
+        match self {
+            CaseFolding::Unicode => text.to_lowercase(),
+            CaseFolding::Turkic => {
+                let mut folded_text = String::with_capacity(text.len());
+
+                for c in text.chars() {
+                    match c {
+                        'I' => folded_text.push('ı'),
+                        'İ' => folded_text.push('i'),
+                        c => folded_text.extend(c.to_lowercase()),
+                    }
+                }
+
+                folded_text
+            }
+            CaseFolding::Preserve => text.into(),
+        }
+    }
+}
+
+pub fn normalize_text_into_phrases(text: String, config: &NormalizationConfig) -> Vec<Phrase> {
+    split_text_at_terminators(&text, &config.phrase_terminators)
+        .map(|(subtext, terminator)| {
+            let subtext = remove_directional_marks(subtext);
+            let subtext = normalize_punctuation_to_whitespace(&subtext);
             let subtext = normalize_extra_whitespaces(&subtext);
-            let subtext = subtext.to_lowercase();
+            let acronym_positions = find_acronym_positions(&subtext);
+            let subtext = config.case_folding.fold(&subtext);
+            let subtext = match config.max_letter_run {
+                Some(max_letter_run) => collapse_letter_runs(&subtext, max_letter_run),
+                None => subtext,
+            };
 
-            Phrase(subtext)
+            // Folding and collapsing keep words apart, so positions still hold.
+            let acronyms = subtext
+                .split(' ')
+                .enumerate()
+                .filter(|(word_pos, _)| acronym_positions.contains(word_pos))
+                .map(|(_, word)| word.to_string())
+                .collect();
+
+            Phrase {
+                content: subtext,
+                terminator,
+                source: PhraseSource::default(),
+                expires_at: None,
+                acronyms,
+            }
         })
+        .filter(|phrase| !phrase.content.is_empty())
         .collect()
 }
 
-fn split_text_at_periods(text: &str) -> impl Iterator<Item = &str> {
-    text.split(&['.', ';']).filter(|s| !s.is_empty())
+/// Positions of the words written in all caps, such as "CPU", which are taken
+/// as acronyms. Single letters are left out, and so are the words of phrases
+/// mostly in all caps, as those are being shouted instead.
+fn find_acronym_positions(text: &str) -> Vec<usize> {
+    let mut word_count = 0;
+    let acronym_positions: Vec<_> = text
+        .split(' ')
+        .enumerate()
+        .filter(|(_, word)| word.chars().any(char::is_alphabetic))
+        .inspect(|_| word_count += 1)
+        .filter(|(_, word)| {
+            word.chars().filter(|c| c.is_alphabetic()).count() >= MIN_ACRONYM_LEN
+                && word.chars().all(|c| !c.is_alphabetic() || c.is_uppercase())
+        })
+        .map(|(word_pos, _)| word_pos)
+        .collect();
+
+    if acronym_positions.len() * 2 >= word_count {
+        return Vec::new();
+    }
+
+    acronym_positions
+}
+
+/// Puts back the spelling of protected words, such as nicknames, wherever text
+/// has them in the form normalization left them in, e.g. "brunoo" for a
+/// protected "Brunooo" when elongation is collapsed. Meant to be the last
+/// step before styling, so that nothing else alters them afterwards.
+pub fn restore_protected_words(
+    text: &str,
+    protected_words: &[String],
+    config: &NormalizationConfig,
+) -> String {
+    if protected_words.is_empty() {
+        return text.into();
+    }
+
+    let spellings: HashMap<String, &str> = protected_words
+        .iter()
+        .map(|word| (normalize_word(word, config), word.as_str()))
+        .collect();
+
+    replace_words(text, |word| {
+        spellings
+            .get(&normalize_word(word, config))
+            .map_or_else(|| word.into(), |&spelling| spelling.into())
+    })
+}
+
+/// Folds and collapses a single word the way phrases are.
+pub fn normalize_word(word: &str, config: &NormalizationConfig) -> String {
+    let word = config.case_folding.fold(word);
+
+    match config.max_letter_run {
+        Some(max_letter_run) => collapse_letter_runs(&word, max_letter_run),
+        None => word,
+    }
+}
+
+/// Replaces each word of the text, leaving whitespace and punctuation as is.
+fn replace_words(text: &str, mut replace: impl FnMut(&str) -> String) -> String {
+    lazy_static! {
+        static ref WORD_PATTERN: Regex = Regex::new(r"[^\s[:punct:]\p{P}]+").unwrap();
+    }
+
+    WORD_PATTERN
+        .replace_all(text, |captures: &regex::Captures| replace(&captures[0]))
+        .into_owned()
+}
+
+/// Splits text at each of the terminators, yielding each subtext along with
+/// the terminator that ended it, if any.
+fn split_text_at_terminators<'t>(
+    text: &'t str,
+    terminators: &'t [char],
+) -> impl Iterator<Item = (&'t str, Option<char>)> {
+    let mut remaining_text = text;
+
+    std::iter::from_fn(move || {
+        if remaining_text.is_empty() {
+            return None;
+        }
+
+        let terminator_pos = remaining_text
+            .char_indices()
+            .find(|(_, c)| terminators.contains(c));
+
+        match terminator_pos {
+            Some((pos, terminator)) => {
+                let subtext = &remaining_text[..pos];
+                remaining_text = &remaining_text[pos + terminator.len_utf8()..];
+                Some((subtext, Some(terminator)))
+            }
+            None => {
+                let subtext = remaining_text;
+                remaining_text = "";
+                Some((subtext, None))
+            }
+        }
+    })
+}
+
+/// Removes invisible bidirectional formatting characters (marks, embeddings and
+/// isolates), which are common in RTL chats, so that they don't end up as part
+/// of indexed words.
+fn remove_directional_marks(text: &str) -> Cow<'_, str> {
+    lazy_static! {
+        static ref DIRECTIONAL_MARK_PATTERN: Regex =
+            Regex::new(r"[\x{200E}\x{200F}\x{061C}\x{202A}-\x{202E}\x{2066}-\x{2069}]").unwrap();
+    }
+
+    DIRECTIONAL_MARK_PATTERN.replace_all(text, "")
 }
 
-fn normalize_punctuation_to_whitespace(text: &str) -> Cow<str> {
+fn normalize_punctuation_to_whitespace(text: &str) -> Cow<'_, str> {
     lazy_static! {
-        static ref PUNCTUATION_PATTERN: Regex = Regex::new(r"[[:punct:]]").unwrap();
+        static ref PUNCTUATION_PATTERN: Regex = Regex::new(r"[[:punct:]\p{P}]").unwrap();
     }
 
     PUNCTUATION_PATTERN.replace_all(text, " ")
 }
 
-fn normalize_extra_whitespaces(text: &str) -> Cow<str> {
+/// Collapses runs of the same letter longer than `max_letter_run` into two of
+/// that letter, which keeps some of the elongation's flavor.
+fn collapse_letter_runs(text: &str, max_letter_run: usize) -> String {
+    let mut collapsed_text = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let mut run_len = 1;
+        while chars.next_if_eq(&c).is_some() {
+            run_len += 1;
+        }
+
+        let kept_len = if c.is_alphabetic() && run_len > max_letter_run {
+            2
+        } else {
+            run_len
+        };
+
+        collapsed_text.extend(std::iter::repeat_n(c, kept_len));
+    }
+
+    collapsed_text
+}
+
+fn normalize_extra_whitespaces(text: &str) -> Cow<'_, str> {
     lazy_static! {
-        static ref EXTRA_WHITESPACE_PATTERN: Regex = Regex::new(r"\s\s+").unwrap();
+        // Single non-ASCII whitespaces (e.g. no-break spaces) are replaced as well, so
+        // that words are always separated by exactly one ASCII space.
+        static ref EXTRA_WHITESPACE_PATTERN: Regex = Regex::new(r"\s\s+|[^\S ]").unwrap();
     }
 
     EXTRA_WHITESPACE_PATTERN.replace_all(text.trim(), " ")
 }
 
 #[derive(PartialEq, Debug, Clone)]
-pub(crate) struct Phrase(String);
+pub struct Phrase {
+    content: String,
+    /// The punctuation that ended this phrase in the original text, if any.
+    terminator: Option<char>,
+    source: PhraseSource,
+    /// Unix time after which the phrase is no longer used, if any.
+    expires_at: Option<i64>,
+    /// Words of the phrase that were written in all caps, as they're folded.
+    acronyms: Vec<String>,
+}
+
+impl Phrase {
+    #[cfg(test)]
+    fn with_terminator(content: &str, terminator: char) -> Phrase {
+        Phrase {
+            content: content.into(),
+            terminator: Some(terminator),
+            source: PhraseSource::default(),
+            expires_at: None,
+            acronyms: Vec::new(),
+        }
+    }
+
+    pub fn with_source(self, source: PhraseSource) -> Phrase {
+        Phrase { source, ..self }
+    }
+
+    pub fn with_expiry(self, expires_at: i64) -> Phrase {
+        Phrase {
+            expires_at: Some(expires_at),
+            ..self
+        }
+    }
+
+    /// Text to be stored in the database, which normalizes back into this very
+    /// phrase, terminator included.
+    pub fn to_line(&self) -> String {
+        // Acronyms are kept in all caps, so that they're found again.
+        let content = if self.acronyms.is_empty() {
+            self.content.clone()
+        } else {
+            self.content
+                .split(' ')
+                .map(|word| {
+                    if self.acronyms.iter().any(|acronym| acronym == word) {
+                        word.to_uppercase()
+                    } else {
+                        word.into()
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        match self.terminator {
+            Some(terminator) if !terminator.is_whitespace() => {
+                format!("{}{}", content, terminator)
+            }
+            _ => content,
+        }
+    }
+}
 
 impl From<Phrase> for String {
     fn from(phrase: Phrase) -> Self {
-        phrase.0
+        phrase.content
+    }
+}
+
+impl AsRef<str> for Phrase {
+    fn as_ref(&self) -> &str {
+        &self.content
+    }
+}
+
+/// Takes the text as is, assuming it's already normalized.
+impl From<&str> for Phrase {
+    fn from(text: &str) -> Self {
+        Phrase {
+            content: text.into(),
+            terminator: None,
+            source: PhraseSource::default(),
+            expires_at: None,
+            acronyms: Vec::new(),
+        }
+    }
+}
+
+/// The index of learned phrases. Serializing it only keeps what was learned,
+/// leaving out how phrases are picked, e.g. source weights, short-term memory
+/// and laughter, which go back to their defaults when deserializing.
+#[derive(Serialize, Deserialize)]
+pub struct IndexedPhrases {
+    /// Phrases and words, which are referred to by their symbols.
+    texts: Interner,
+    indexed_phrases_by_word: HashMap<Symbol, HashSet<IndexedPhrase>>,
+    /// Interned words of each phrase, in the order they appear in it.
+    phrase_words: HashMap<Symbol, Vec<Symbol>>,
+    phrase_qualities: HashMap<Symbol, f32>,
+    phrase_occurrences: HashMap<Symbol, usize>,
+    /// How many times each word was seen in learned phrases, duplicates
+    /// included.
+    word_occurrences: HashMap<Symbol, usize>,
+    /// Biases random choices of words and phrases towards the frequent ones if
+    /// set. See `set_frequency_temperature`.
+    #[serde(skip)]
+    frequency_temperature: Option<f32>,
+    /// Phrases in short-term memory, by when they were learned. See
+    /// `mark_phrase_short_term`.
+    #[serde(skip)]
+    short_term_phrases: HashMap<Symbol, i64>,
+    #[serde(skip, default = "default_recency_bonus")]
+    recency_bonus: f32,
+    #[serde(skip)]
+    junction_distribution: JunctionDistribution,
+    phrase_terminators: HashMap<Symbol, char>,
+    phrase_sources: HashMap<Symbol, PhraseSource>,
+    phrase_expirations: HashMap<Symbol, i64>,
+    #[serde(skip)]
+    source_weights: HashMap<PhraseSource, f32>,
+    words_by_folded_form: HashMap<String, HashSet<Symbol>>,
+    #[serde(skip)]
+    fold_pivot_diacritics: bool,
+    #[serde(skip)]
+    laughter_words: HashSet<Symbol>,
+    #[serde(skip)]
+    laughter_pattern: Option<Regex>,
+    /// Words avoided as pivots, as they make for dull splices.
+    #[serde(skip)]
+    stop_words: HashSet<String>,
+    /// Words whose phrases are never learned nor generated.
+    #[serde(skip)]
+    blocked_words: HashSet<String>,
+    /// How much less likely each word is to be picked as a pivot. See
+    /// `set_word_nerf`.
+    #[serde(skip)]
+    word_nerfs: HashMap<String, f32>,
+    #[serde(skip)]
+    min_sub_word_len: Option<usize>,
+    /// Words seen written in all caps, which are written back in uppercase by
+    /// `restore_acronyms`.
+    acronyms: HashSet<String>,
+    /// Words that pivot for each other, both ways.
+    aliases: HashMap<String, HashSet<String>>,
+    tagged_phrases: HashMap<String, HashSet<Symbol>>,
+    /// When each phrase was last learned, as a tick of `learning_clock`.
+    phrase_learning_ticks: HashMap<Symbol, u64>,
+    /// Phrases by when they were last learned, least recently learned first.
+    phrases_by_learning_tick: BTreeMap<u64, Symbol>,
+    learning_clock: u64,
+    #[serde(skip)]
+    quality_scorer: QualityScorer,
+}
+
+fn default_recency_bonus() -> f32 {
+    1.0
+}
+
+/// Refers to a phrase by its position in the index, along with the position of
+/// the pivot word among the words of the phrase.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Copy, Clone)]
+pub struct IndexedPhrase {
+    interned_phrase_index: Symbol,
+    word_pos_in_phrase: u32,
+}
+
+/// A phrase of the index, along with the position of its pivot word, counted
+/// in words. Phrases are equal if they are the same text pivoting on the same
+/// word.
+#[derive(Copy, Clone)]
+pub struct IndexedPhraseContent<'s> {
+    phrase_index: Symbol,
+    phrase_content: &'s str,
+    phrase_words: &'s [Symbol],
+    word_pos_in_phrase: usize,
+    /// Where the words of the phrase are looked up.
+    texts: &'s Interner,
+}
+
+impl<'s> IndexedPhraseContent<'s> {
+    pub fn content(&self) -> &'s str {
+        self.phrase_content
+    }
+
+    /// The word of the phrase this refers to.
+    pub fn word(&self) -> &'s str {
+        self.phrase_words
+            .get(self.word_pos_in_phrase)
+            .map_or("", |&word_index| self.texts.resolve(word_index))
+    }
+
+    /// Joins the words of the phrase within `range` with single spaces.
+    fn join_words(
+        &self,
+        range: impl std::slice::SliceIndex<[Symbol], Output = [Symbol]>,
+    ) -> String {
+        let words: Vec<_> = self.phrase_words[range]
+            .iter()
+            .map(|&word_index| self.texts.resolve(word_index))
+            .collect();
+
+        words.join(" ")
+    }
+}
+
+impl PartialEq for IndexedPhraseContent<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.phrase_content, self.word_pos_in_phrase)
+            == (other.phrase_content, other.word_pos_in_phrase)
+    }
+}
+
+impl Eq for IndexedPhraseContent<'_> {}
+
+impl Hash for IndexedPhraseContent<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.phrase_content, self.word_pos_in_phrase).hash(state);
+    }
+}
+
+impl fmt::Debug for IndexedPhraseContent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndexedPhraseContent")
+            .field("phrase_content", &self.phrase_content)
+            .field("word_pos_in_phrase", &self.word_pos_in_phrase)
+            .finish()
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
+pub struct Word<'s>(&'s str);
+
+impl std::ops::Deref for Word<'_> {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+/// Owned handle to a word, which, unlike `Word`, doesn't borrow the index and
+/// thus can be held across await points or sent through channels.
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
+pub struct WordId(Symbol);
+
+impl Default for IndexedPhrases {
+    fn default() -> Self {
+        IndexedPhrases::new()
     }
 }
 
-impl AsRef<str> for Phrase {
-    fn as_ref(&self) -> &str {
-        &self.0
+impl IndexedPhrases {
+    pub fn new() -> IndexedPhrases {
+        IndexedPhrases::with_quality_scorer(QualityScorer::default())
+    }
+
+    pub fn with_quality_scorer(quality_scorer: QualityScorer) -> IndexedPhrases {
+        IndexedPhrases {
+            texts: Interner::new(),
+            indexed_phrases_by_word: HashMap::new(),
+            phrase_words: HashMap::new(),
+            phrase_qualities: HashMap::new(),
+            phrase_occurrences: HashMap::new(),
+            word_occurrences: HashMap::new(),
+            frequency_temperature: None,
+            short_term_phrases: HashMap::new(),
+            recency_bonus: default_recency_bonus(),
+            junction_distribution: JunctionDistribution::default(),
+            phrase_terminators: HashMap::new(),
+            phrase_sources: HashMap::new(),
+            phrase_expirations: HashMap::new(),
+            source_weights: HashMap::new(),
+            words_by_folded_form: HashMap::new(),
+            fold_pivot_diacritics: false,
+            laughter_words: HashSet::new(),
+            laughter_pattern: None,
+            stop_words: HashSet::new(),
+            blocked_words: HashSet::new(),
+            word_nerfs: HashMap::new(),
+            min_sub_word_len: None,
+            acronyms: HashSet::new(),
+            aliases: HashMap::new(),
+            tagged_phrases: HashMap::new(),
+            phrase_learning_ticks: HashMap::new(),
+            phrases_by_learning_tick: BTreeMap::new(),
+            learning_clock: 0,
+            quality_scorer,
+        }
+    }
+
+    /// When enabled, `get_phrases_with_word_in_common` also returns phrases
+    /// containing words that only differ from the passed word by diacritics.
+    pub fn set_pivot_diacritic_folding(&mut self, fold_pivot_diacritics: bool) {
+        self.fold_pivot_diacritics = fold_pivot_diacritics;
+    }
+
+    /// When set, `get_phrases_with_word_in_common` also returns phrases
+    /// containing any laughter if the passed word is laughter itself.
+    pub fn set_laughter_pattern(&mut self, laughter_pattern: Option<Regex>) {
+        self.laughter_words = match &laughter_pattern {
+            Some(pattern) => self
+                .indexed_phrases_by_word
+                .keys()
+                .copied()
+                .filter(|&word_index| pattern.is_match(self.texts.resolve(word_index)))
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        self.laughter_pattern = laughter_pattern;
+    }
+
+    /// Blocks the words, regardless of case, so that phrases with any of them
+    /// are no longer inserted. Phrases inserted before are kept.
+    pub fn set_blocked_words(&mut self, blocked_words: impl IntoIterator<Item = String>) {
+        self.blocked_words = blocked_words
+            .into_iter()
+            .map(|word| word.to_lowercase())
+            .collect();
+    }
+
+    /// Whether any word of the normalized text is blocked, punctuation aside.
+    pub fn has_blocked_word(&self, text: &str) -> bool {
+        !self.blocked_words.is_empty()
+            && text
+                .split(|c: char| !c.is_alphanumeric())
+                .any(|word| self.blocked_words.contains(word))
+    }
+
+    /// Multiplies how likely the word is to be picked as a pivot by `factor`,
+    /// which keeps the word, unlike blocking it. A factor of one lifts the nerf.
+    pub fn set_word_nerf(&mut self, word: &str, factor: f32) {
+        if factor == 1.0 {
+            self.word_nerfs.remove(word);
+        } else {
+            self.word_nerfs.insert(word.into(), factor);
+        }
+    }
+
+    pub fn set_stop_words(&mut self, stop_words: impl IntoIterator<Item = String>) {
+        self.stop_words = stop_words.into_iter().collect();
+    }
+
+    pub fn is_stop_word(&self, word_id: WordId) -> bool {
+        self.get_word(word_id)
+            .is_ok_and(|word| self.stop_words.contains(&*word))
+    }
+
+    /// Writes the words of the text seen written in all caps in uppercase, e.g.
+    /// "the cpu is hot" becomes "the CPU is hot".
+    pub fn restore_acronyms(&self, text: &str) -> String {
+        if self.acronyms.is_empty() {
+            return text.into();
+        }
+
+        replace_words(text, |word| {
+            if self.acronyms.contains(word) {
+                word.to_uppercase()
+            } else {
+                word.into()
+            }
+        })
+    }
+
+    /// When set, `find_sub_word` looks for words of at least this many letters.
+    pub fn set_min_sub_word_len(&mut self, min_sub_word_len: Option<usize>) {
+        self.min_sub_word_len = min_sub_word_len;
+    }
+
+    /// Returns the longest word inside the passed one which is in at least two
+    /// phrases, and thus can be spliced on, skipping stop words.
+    pub fn find_sub_word(&self, word_id: WordId) -> Option<WordId> {
+        let min_sub_word_len = self.min_sub_word_len?;
+        let word = self.get_word(word_id).ok()?;
+
+        // Every substring is looked up, so long words are left alone.
+        let char_boundaries: Vec<_> = word
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(word.len()))
+            .collect();
+        let char_count = char_boundaries.len() - 1;
+        if char_count > MAX_SUB_WORD_SEARCH_LEN {
+            return None;
+        }
+
+        (min_sub_word_len..char_count)
+            .rev()
+            .find_map(|sub_word_len| {
+                (0..=char_count - sub_word_len).find_map(|start| {
+                    let sub_word =
+                        &word[char_boundaries[start]..char_boundaries[start + sub_word_len]];
+
+                    self.get_word_id(sub_word).filter(|&sub_word_id| {
+                        self.get_phrase_count_of_word(sub_word_id) >= 2
+                            && !self.is_stop_word(sub_word_id)
+                    })
+                })
+            })
+    }
+
+    pub fn get_common_words(&self) -> impl Iterator<Item = Word<'_>> {
+        self.indexed_phrases_by_word
+            .keys()
+            .map(|&key_index| Word(self.texts.resolve(key_index)))
+    }
+
+    pub fn get_word(&self, word_id: WordId) -> Result<Word<'_>, EngineError> {
+        self.texts
+            .try_resolve(word_id.0)
+            .map(Word)
+            .ok_or(EngineError::UnknownWordId(word_id))
+    }
+
+    /// Returns the id of a word or single-word phrase learned so far.
+    pub fn get_word_id(&self, word: &str) -> Option<WordId> {
+        self.texts.get(word).map(WordId)
+    }
+
+    /// Whether the word is part of any indexed phrase, and thus can be used as a
+    /// pivot.
+    pub fn is_common_word(&self, word_id: WordId) -> bool {
+        self.indexed_phrases_by_word.contains_key(&word_id.0)
+    }
+
+    pub fn get_random_common_word(&self, rng: &mut impl Rng) -> Result<WordId, EngineError> {
+        use rand::seq::{IteratorRandom, SliceRandom};
+
+        let word_index = if self.frequency_temperature.is_none() && self.word_nerfs.is_empty() {
+            self.indexed_phrases_by_word.keys().choose(rng)
+        } else {
+            let word_indices: Vec<_> = self.indexed_phrases_by_word.keys().collect();
+            word_indices
+                .choose_weighted(rng, |&&word_index| self.get_word_weight(WordId(word_index)))
+                .ok()
+                .copied()
+        };
+
+        word_index
+            .map(|&word_index| WordId(word_index))
+            .ok_or(EngineError::EmptyCorpus)
+    }
+
+    /// Picks a phrase as it was stored, rather than spliced from others.
+    pub fn get_random_phrase(&self, rng: &mut impl Rng) -> Result<&str, EngineError> {
+        use rand::seq::IteratorRandom;
+
+        self.phrase_qualities
+            .keys()
+            .choose(rng)
+            .map(|&phrase_index| self.texts.resolve(phrase_index))
+            .ok_or(EngineError::EmptyCorpus)
+    }
+
+    // TODO(feroldi): Maybe return the words that were already interned?
+    // TODO(feroldi): Test the returned words.
+    pub fn insert_phrase(&mut self, phrase: Phrase) -> InsertionResult {
+        if self.has_blocked_word(phrase.as_ref()) {
+            return InsertionResult {
+                has_inserted_phrase: false,
+                word_ids_from_phrase: Vec::new(),
+            };
+        }
+
+        let quality = self.quality_scorer.score(&phrase);
+        let terminator = phrase.terminator;
+        let source = phrase.source;
+        let expires_at = phrase.expires_at;
+        self.acronyms.extend(phrase.acronyms.iter().cloned());
+        let phrase_content = String::from(phrase);
+
+        if !phrase_content.contains(' ') {
+            let interned_word_index = self.texts.intern(&phrase_content);
+            return InsertionResult {
+                has_inserted_phrase: false,
+                word_ids_from_phrase: vec![WordId(interned_word_index)],
+            };
+        }
+
+        let interned_phrase_index = self.texts.intern(&phrase_content);
+        self.phrase_qualities.insert(interned_phrase_index, quality);
+
+        self.phrase_sources.insert(interned_phrase_index, source);
+
+        // Phrases learned again without an expiry, e.g. from a chat, no longer
+        // expire.
+        match expires_at {
+            Some(expires_at) => self
+                .phrase_expirations
+                .insert(interned_phrase_index, expires_at),
+            None => self.phrase_expirations.remove(&interned_phrase_index),
+        };
+
+        if let Some(terminator) = terminator {
+            self.phrase_terminators
+                .insert(interned_phrase_index, terminator);
+        }
+
+        *self
+            .phrase_occurrences
+            .entry(interned_phrase_index)
+            .or_insert(0) += 1;
+        self.touch_phrase(interned_phrase_index);
+
+        let mut word_ids_from_phrase = Vec::new();
+        let mut phrase_words = Vec::new();
+
+        for (word_pos_in_phrase, word) in phrase_content.split_ascii_whitespace().enumerate() {
+            let interned_word_index = self.texts.intern(word);
+
+            self.words_by_folded_form
+                .entry(fold_diacritics(word))
+                .or_default()
+                .insert(interned_word_index);
+
+            if let Some(pattern) = &self.laughter_pattern {
+                if pattern.is_match(word) {
+                    self.laughter_words.insert(interned_word_index);
+                }
+            }
+
+            self.link_phrase_to_word(
+                interned_phrase_index,
+                interned_word_index,
+                word_pos_in_phrase,
+            );
+
+            *self
+                .word_occurrences
+                .entry(interned_word_index)
+                .or_insert(0) += 1;
+
+            word_ids_from_phrase.push(WordId(interned_word_index));
+            phrase_words.push(interned_word_index);
+        }
+
+        self.phrase_words
+            .insert(interned_phrase_index, phrase_words);
+
+        InsertionResult {
+            has_inserted_phrase: true,
+            word_ids_from_phrase,
+        }
+    }
+
+    /// Same as `insert_phrase`, except that phrases which are exact or near
+    /// duplicates (at least `min_similarity` similar) of an already indexed
+    /// phrase only increment the occurrences of that phrase, which is kept as the
+    /// canonical form. In that case, `has_inserted_phrase` is false and no word
+    /// indices are reported.
+    pub fn insert_phrase_merging_near_duplicates(
+        &mut self,
+        phrase: Phrase,
+        min_similarity: f32,
+    ) -> InsertionResult {
+        let canonical_phrase_index = self
+            .texts
+            .get(phrase.as_ref())
+            .filter(|phrase_index| self.phrase_occurrences.contains_key(phrase_index))
+            .or_else(|| self.find_near_duplicate(phrase.as_ref(), min_similarity));
+
+        match canonical_phrase_index {
+            Some(phrase_index) => {
+                // Like phrases learned again, it's taken as learned from where
+                // the duplicate was, and only expires if the duplicate does.
+                self.phrase_sources.insert(phrase_index, phrase.source);
+                match phrase.expires_at {
+                    Some(expires_at) => self.phrase_expirations.insert(phrase_index, expires_at),
+                    None => self.phrase_expirations.remove(&phrase_index),
+                };
+                self.acronyms.extend(phrase.acronyms);
+                *self.phrase_occurrences.entry(phrase_index).or_insert(0) += 1;
+                self.touch_phrase(phrase_index);
+
+                for &word_index in &self.phrase_words[&phrase_index] {
+                    *self.word_occurrences.entry(word_index).or_insert(0) += 1;
+                }
+
+                InsertionResult {
+                    has_inserted_phrase: false,
+                    word_ids_from_phrase: Vec::new(),
+                }
+            }
+            None => self.insert_phrase(phrase),
+        }
+    }
+
+    /// Removes the phrase from the index, along with its occurrences and tags.
+    /// Words left without phrases stop being common words. Returns false if the
+    /// phrase isn't indexed.
+    pub fn remove_phrase(&mut self, phrase_content: &str) -> bool {
+        let phrase_index = match self.texts.get(phrase_content) {
+            Some(phrase_index) if self.phrase_qualities.contains_key(&phrase_index) => phrase_index,
+            _ => return false,
+        };
+
+        self.phrase_qualities.remove(&phrase_index);
+        let phrase_occurrences = self.phrase_occurrences.remove(&phrase_index).unwrap_or(0);
+        self.phrase_terminators.remove(&phrase_index);
+        self.phrase_sources.remove(&phrase_index);
+        self.phrase_expirations.remove(&phrase_index);
+        self.short_term_phrases.remove(&phrase_index);
+        if let Some(learning_tick) = self.phrase_learning_ticks.remove(&phrase_index) {
+            self.phrases_by_learning_tick.remove(&learning_tick);
+        }
+
+        self.tagged_phrases.retain(|_, phrase_indices| {
+            phrase_indices.remove(&phrase_index);
+            !phrase_indices.is_empty()
+        });
+
+        let phrase_words = self.phrase_words.remove(&phrase_index).unwrap_or_default();
+
+        for (word_pos_in_phrase, word_index) in phrase_words.into_iter().enumerate() {
+            if let Some(word_occurrences) = self.word_occurrences.get_mut(&word_index) {
+                *word_occurrences = word_occurrences.saturating_sub(phrase_occurrences);
+            }
+
+            self.unlink_phrase_from_word(phrase_index, word_index, word_pos_in_phrase);
+        }
+
+        true
+    }
+
+    /// Removes every phrase containing the word. Returns the removed phrases.
+    pub fn remove_word(&mut self, word: &str) -> Vec<String> {
+        let phrase_indices: HashSet<_> = self
+            .get_indexed_phrases_of_text(word)
+            .map(|indexed_phrase| indexed_phrase.interned_phrase_index)
+            .collect();
+
+        let removed_phrases: Vec<_> = phrase_indices
+            .into_iter()
+            .map(|phrase_index| self.texts.resolve(phrase_index).to_string())
+            .collect();
+
+        for phrase_content in &removed_phrases {
+            self.remove_phrase(phrase_content);
+        }
+
+        removed_phrases
+    }
+
+    /// Removes the phrases that have expired by `now`. Returns the removed
+    /// phrases.
+    pub fn remove_expired_phrases(&mut self, now: i64) -> Vec<String> {
+        let expired_phrases: Vec<_> = self
+            .phrase_expirations
+            .iter()
+            .filter(|&(_, &expires_at)| expires_at <= now)
+            .map(|(&phrase_index, _)| self.texts.resolve(phrase_index).to_string())
+            .collect();
+
+        for phrase_content in &expired_phrases {
+            self.remove_phrase(phrase_content);
+        }
+
+        expired_phrases
+    }
+
+    /// Removes the least recently learned phrases until there are at most
+    /// `max_phrase_count` phrases and `max_word_count` common words, where
+    /// `None` means there's no limit. The texts of the removed phrases, and of
+    /// the words left without phrases, are forgotten, so that their slots are
+    /// reused by texts learned later, and ids of those words may then refer to
+    /// other words. Returns the removed phrases.
+    pub fn evict_least_recently_learned(
+        &mut self,
+        max_phrase_count: Option<usize>,
+        max_word_count: Option<usize>,
+    ) -> Vec<String> {
+        let is_over_limit =
+            |limit: Option<usize>, count: usize| limit.is_some_and(|limit| count > limit);
+        let mut evicted_phrases = Vec::new();
+
+        while is_over_limit(max_phrase_count, self.phrase_count())
+            || is_over_limit(max_word_count, self.word_count())
+        {
+            let phrase_index = match self.phrases_by_learning_tick.values().next() {
+                Some(&phrase_index) => phrase_index,
+                None => break,
+            };
+            let phrase_content = self.texts.resolve(phrase_index).to_string();
+
+            self.remove_phrase(&phrase_content);
+            self.forget_orphaned_texts(&phrase_content);
+
+            evicted_phrases.push(phrase_content);
+        }
+
+        evicted_phrases
+    }
+
+    /// Fails if the word isn't part of any indexed phrase, which may happen if the
+    /// word came from another `IndexedPhrases`, or if it was only ever learned as
+    /// a single-word phrase.
+    pub fn get_phrases_with_word_in_common(
+        &self,
+        word: Word,
+    ) -> Result<impl Iterator<Item = IndexedPhraseContent<'_>>, EngineError> {
+        let word_id = self
+            .get_word_id(word.0)
+            .ok_or_else(|| EngineError::UnknownWord(word.0.into()))?;
+
+        self.get_phrases_with_word_id_in_common(word_id)
+    }
+
+    /// Same as `get_phrases_with_word_in_common`, but looks the word up by id.
+    pub fn get_phrases_with_word_id_in_common(
+        &self,
+        word_id: WordId,
+    ) -> Result<impl Iterator<Item = IndexedPhraseContent<'_>>, EngineError> {
+        Ok(self
+            .get_indexed_phrases_with_word_id_in_common(word_id)?
+            .map(move |indexed_phrase| self.get_indexed_phrase_content(indexed_phrase)))
+    }
+
+    /// Same as `get_phrases_with_word_id_in_common`, but yields handles which
+    /// don't borrow the phrases, so that they can be kept around.
+    pub fn get_indexed_phrases_with_word_id_in_common(
+        &self,
+        word_id: WordId,
+    ) -> Result<impl Iterator<Item = IndexedPhrase> + '_, EngineError> {
+        let word = self.get_word(word_id)?;
+
+        if !self.is_common_word(word_id) {
+            return Err(EngineError::UnknownWord(word.0.into()));
+        }
+
+        Ok(self
+            .get_pivot_word_ids(word_id)
+            .into_iter()
+            .filter_map(|pivot_word_id| self.indexed_phrases_by_word.get(&pivot_word_id.0))
+            .flatten()
+            .copied())
+    }
+
+    /// Makes the words pivot for each other, e.g. phrases with "geladeira" are
+    /// spliced with phrases with "fridge". Neither has to be indexed yet.
+    pub fn add_alias(&mut self, word: &str, alias: &str) {
+        if word == alias {
+            return;
+        }
+
+        self.aliases
+            .entry(word.into())
+            .or_default()
+            .insert(alias.into());
+        self.aliases
+            .entry(alias.into())
+            .or_default()
+            .insert(word.into());
+    }
+
+    /// Returns the word along with its aliases, the other words that fold into
+    /// the same form if pivot diacritic folding is enabled, and, if the word is
+    /// laughter, every other laughter.
+    pub fn get_pivot_word_ids(&self, word_id: WordId) -> Vec<WordId> {
+        let mut pivot_word_ids = vec![word_id];
+
+        if let Some(aliases) = self
+            .get_word(word_id)
+            .ok()
+            .and_then(|word| self.aliases.get(word.0))
+        {
+            let alias_word_ids = aliases
+                .iter()
+                .filter_map(|alias| self.get_word_id(alias))
+                .filter(|&alias_word_id| self.is_common_word(alias_word_id));
+
+            pivot_word_ids.extend(alias_word_ids);
+        }
+
+        if let (true, Ok(word)) = (self.fold_pivot_diacritics, self.get_word(word_id)) {
+            let folded_word_ids = self
+                .words_by_folded_form
+                .get(&fold_diacritics(word.0))
+                .into_iter()
+                .flatten()
+                .map(|&folded_word_index| WordId(folded_word_index))
+                .filter(|folded_word_id| !pivot_word_ids.contains(folded_word_id))
+                .collect::<Vec<_>>();
+
+            pivot_word_ids.extend(folded_word_ids);
+        }
+
+        if self.laughter_words.contains(&word_id.0) {
+            let laughter_word_ids = self
+                .laughter_words
+                .iter()
+                .map(|&laughter_word_index| WordId(laughter_word_index))
+                .filter(|laughter_word_id| !pivot_word_ids.contains(laughter_word_id))
+                .collect::<Vec<_>>();
+
+            pivot_word_ids.extend(laughter_word_ids);
+        }
+
+        pivot_word_ids
+    }
+
+    /// The `k` words that most often appear in the same phrases as the word,
+    /// along with how many phrases they share with it, most shared first.
+    pub fn get_cooccurring_words(
+        &self,
+        word_id: WordId,
+        k: usize,
+    ) -> Result<Vec<(WordId, usize)>, EngineError> {
+        let phrase_indices: HashSet<_> = self
+            .get_indexed_phrases_with_word_id_in_common(word_id)?
+            .map(|indexed_phrase| indexed_phrase.interned_phrase_index)
+            .collect();
+
+        let mut cooccurrences: HashMap<Symbol, usize> = HashMap::new();
+        for phrase_index in phrase_indices {
+            let word_indices: HashSet<_> = self.phrase_words[&phrase_index]
+                .iter()
+                .copied()
+                .filter(|&word_index| word_index != word_id.0)
+                .collect();
+
+            for word_index in word_indices {
+                *cooccurrences.entry(word_index).or_insert(0) += 1;
+            }
+        }
+
+        let mut cooccurrences: Vec<_> = cooccurrences.into_iter().collect();
+        cooccurrences.sort_by(|(first_index, first_count), (second_index, second_count)| {
+            second_count.cmp(first_count).then_with(|| {
+                self.texts
+                    .resolve(*first_index)
+                    .cmp(self.texts.resolve(*second_index))
+            })
+        });
+        cooccurrences.truncate(k);
+
+        Ok(cooccurrences
+            .into_iter()
+            .map(|(word_index, count)| (WordId(word_index), count))
+            .collect())
+    }
+
+    /// Words that come right after the word in some phrase, i.e. which make up
+    /// a bigram of the corpus with it.
+    pub fn get_following_word_ids(&self, word_id: WordId) -> Vec<WordId> {
+        let mut following_word_ids: Vec<_> = self
+            .indexed_phrases_by_word
+            .get(&word_id.0)
+            .into_iter()
+            .flatten()
+            .filter_map(|&indexed_phrase| {
+                self.phrase_words[&indexed_phrase.interned_phrase_index]
+                    .get(indexed_phrase.word_pos_in_phrase as usize + 1)
+                    .map(|&following_word_index| WordId(following_word_index))
+            })
+            .collect();
+
+        // Sorted, so that picking from them is reproducible.
+        following_word_ids.sort_by_key(|word_id| word_id.0);
+        following_word_ids.dedup();
+
+        following_word_ids
+    }
+
+    /// Returns the phrase with the content, pivoting on its first word, if it's
+    /// indexed.
+    pub fn find_phrase(&self, phrase_content: &str) -> Option<IndexedPhraseContent<'_>> {
+        let phrase_index = self
+            .texts
+            .get(phrase_content)
+            .filter(|phrase_index| self.phrase_qualities.contains_key(phrase_index))?;
+
+        Some(IndexedPhraseContent {
+            phrase_index,
+            phrase_content: self.texts.resolve(phrase_index),
+            phrase_words: &self.phrase_words[&phrase_index],
+            word_pos_in_phrase: 0,
+            texts: &self.texts,
+        })
+    }
+
+    pub fn get_indexed_phrase_content(
+        &self,
+        indexed_phrase: IndexedPhrase,
+    ) -> IndexedPhraseContent<'_> {
+        let phrase_index = indexed_phrase.interned_phrase_index;
+
+        IndexedPhraseContent {
+            phrase_index,
+            phrase_content: self.texts.resolve(phrase_index),
+            phrase_words: &self.phrase_words[&phrase_index],
+            word_pos_in_phrase: indexed_phrase.word_pos_in_phrase as usize,
+            texts: &self.texts,
+        }
+    }
+
+    /// Tags the phrases that `text` could have been spliced from, which is just
+    /// the phrase itself if `text` is an indexed phrase. Returns how many
+    /// phrases were tagged.
+    pub fn tag_phrases_of_text(&mut self, text: &str, tag: &str) -> usize {
+        let phrase_indices = self.find_splice_sources(text);
+        let phrase_count = phrase_indices.len();
+
+        if phrase_count > 0 {
+            self.tagged_phrases
+                .entry(tag.into())
+                .or_default()
+                .extend(phrase_indices);
+        }
+
+        phrase_count
+    }
+
+    pub fn is_phrase_tagged(&self, phrase: IndexedPhraseContent, tag: &str) -> bool {
+        self.tagged_phrases
+            .get(tag)
+            .is_some_and(|phrase_indices| phrase_indices.contains(&phrase.phrase_index))
+    }
+
+    /// Picks a word of a random phrase tagged with `tag`.
+    pub fn get_random_tagged_word(
+        &self,
+        tag: &str,
+        rng: &mut impl Rng,
+    ) -> Result<WordId, EngineError> {
+        use rand::seq::IteratorRandom;
+
+        let phrase_index = self
+            .tagged_phrases
+            .get(tag)
+            .and_then(|phrase_indices| phrase_indices.iter().choose(rng))
+            .ok_or_else(|| EngineError::UnknownTag(tag.into()))?;
+
+        self.phrase_words[phrase_index]
+            .iter()
+            .choose(rng)
+            .map(|&word_index| WordId(word_index))
+            .ok_or_else(|| EngineError::UnknownTag(tag.into()))
+    }
+
+    /// Returns the quality score stored when the phrase was learned, or zero if
+    /// the phrase is unknown.
+    pub fn get_phrase_quality(&self, phrase: IndexedPhraseContent) -> f32 {
+        self.phrase_qualities
+            .get(&phrase.phrase_index)
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    /// Returns the terminator that ended the phrase when it was last learned.
+    pub fn get_phrase_terminator(&self, phrase: IndexedPhraseContent) -> Option<char> {
+        self.phrase_terminators.get(&phrase.phrase_index).copied()
+    }
+
+    /// Returns the source the phrase was last learned from.
+    pub fn get_phrase_source(&self, phrase: IndexedPhraseContent) -> PhraseSource {
+        self.phrase_sources
+            .get(&phrase.phrase_index)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the unix time after which the phrase is no longer used, if any.
+    pub fn get_phrase_expiry(&self, phrase: IndexedPhraseContent) -> Option<i64> {
+        self.phrase_expirations.get(&phrase.phrase_index).copied()
+    }
+
+    /// Whether the phrase has expired by `now`, and thus must not be picked for
+    /// generation even if it hasn't been removed yet.
+    pub fn is_phrase_expired(&self, phrase: IndexedPhraseContent, now: i64) -> bool {
+        self.get_phrase_expiry(phrase)
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Scales how likely phrases of the source are picked for generation. The
+    /// weight of sources is one unless set otherwise.
+    pub fn set_source_weight(&mut self, source: PhraseSource, weight: f32) {
+        self.source_weights.insert(source, weight);
+    }
+
+    pub fn get_source_weight(&self, source: PhraseSource) -> f32 {
+        self.source_weights.get(&source).copied().unwrap_or(1.0)
+    }
+
+    /// How likely the phrase should be picked for generation, which accounts
+    /// for its quality, the weight of its source, where it would be spliced at
+    /// and, if enabled, how often it was seen.
+    pub fn get_phrase_weight(&self, phrase: IndexedPhraseContent) -> f32 {
+        let occurrences = self
+            .phrase_occurrences
+            .get(&phrase.phrase_index)
+            .copied()
+            .unwrap_or(1);
+
+        let recency_weight = if self.is_phrase_short_term(phrase) {
+            self.recency_bonus
+        } else {
+            1.0
+        };
+
+        self.get_phrase_quality(phrase)
+            * self.get_source_weight(self.get_phrase_source(phrase))
+            * self.frequency_weight(occurrences)
+            * recency_weight
+            * self
+                .junction_distribution
+                .weight(relative_pivot_pos(phrase))
+    }
+
+    /// How many times the phrase was learned, near duplicates included.
+    pub fn get_phrase_occurrences(&self, phrase: IndexedPhraseContent) -> usize {
+        self.phrase_occurrences
+            .get(&phrase.phrase_index)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// How many times the word was seen in learned phrases.
+    pub fn get_word_occurrences(&self, word_id: WordId) -> usize {
+        self.word_occurrences.get(&word_id.0).copied().unwrap_or(0)
+    }
+
+    /// How likely the word should be picked as a pivot, which is the same for
+    /// every word unless frequency weighting is enabled, or the word is nerfed.
+    pub fn get_word_weight(&self, word_id: WordId) -> f32 {
+        let nerf = self
+            .get_word(word_id)
+            .ok()
+            .and_then(|word| self.word_nerfs.get(word.0))
+            .copied()
+            .unwrap_or(1.0);
+
+        self.frequency_weight(self.get_word_occurrences(word_id)) * nerf
+    }
+
+    /// Weighs random choices of words and phrases by how often they were seen,
+    /// raised to `1 / temperature`: one makes choices proportional to
+    /// frequency, lower ones favor the most frequent even more, and higher ones
+    /// approach uniform choices, which is what `None` makes them.
+    pub fn set_frequency_temperature(&mut self, temperature: Option<f32>) {
+        self.frequency_temperature = temperature;
+    }
+
+    fn frequency_weight(&self, occurrences: usize) -> f32 {
+        match self.frequency_temperature {
+            Some(temperature) => (occurrences as f32).powf(1.0 / temperature),
+            None => 1.0,
+        }
+    }
+
+    /// Puts the phrase in short-term memory, as learned at `learned_at`, which
+    /// favors it until it's folded back with `fold_short_term_phrases`. Does
+    /// nothing if the phrase isn't indexed.
+    pub fn mark_phrase_short_term(&mut self, phrase_content: &str, learned_at: i64) {
+        if let Some(phrase_index) = self.texts.get(phrase_content) {
+            if self.phrase_qualities.contains_key(&phrase_index) {
+                self.short_term_phrases.insert(phrase_index, learned_at);
+            }
+        }
+    }
+
+    /// Moves the short-term phrases learned before `learned_before` into
+    /// long-term memory. Returns how many phrases were moved.
+    pub fn fold_short_term_phrases(&mut self, learned_before: i64) -> usize {
+        let short_term_phrase_count = self.short_term_phrases.len();
+
+        self.short_term_phrases
+            .retain(|_, &mut learned_at| learned_at >= learned_before);
+
+        short_term_phrase_count - self.short_term_phrases.len()
+    }
+
+    pub fn is_phrase_short_term(&self, phrase: IndexedPhraseContent) -> bool {
+        self.short_term_phrases.contains_key(&phrase.phrase_index)
+    }
+
+    /// How many times as likely short-term phrases are to be picked.
+    pub fn set_recency_bonus(&mut self, recency_bonus: f32) {
+        self.recency_bonus = recency_bonus;
+    }
+
+    /// Weighs phrases by where they would be spliced at, i.e. by the position
+    /// of the pivot word in them.
+    pub fn set_junction_distribution(&mut self, junction_distribution: JunctionDistribution) {
+        self.junction_distribution = junction_distribution;
+    }
+
+    /// Number of distinct phrases learned from each source.
+    pub fn phrase_count_by_source(&self) -> Vec<(PhraseSource, usize)> {
+        ALL_PHRASE_SOURCES
+            .iter()
+            .map(|&source| {
+                let count = self
+                    .phrase_sources
+                    .values()
+                    .filter(|&&phrase_source| phrase_source == source)
+                    .count();
+                (source, count)
+            })
+            .collect()
+    }
+
+    /// Number of phrases learned so far, counting duplicates that were merged
+    /// into a canonical phrase.
+    pub fn total_phrase_occurrences(&self) -> usize {
+        self.phrase_occurrences.values().sum()
+    }
+
+    /// Whether the text is an indexed phrase, as opposed to a word, or a phrase
+    /// that was never learned.
+    pub fn contains_phrase(&self, text: &str) -> bool {
+        self.texts
+            .get(text)
+            .is_some_and(|text_index| self.phrase_qualities.contains_key(&text_index))
+    }
+
+    pub fn phrase_count(&self) -> usize {
+        self.phrase_qualities.len()
+    }
+
+    /// Number of phrases the word is part of.
+    pub fn get_phrase_count_of_word(&self, word_id: WordId) -> usize {
+        self.indexed_phrases_by_word
+            .get(&word_id.0)
+            .map_or(0, |indexed_phrases| indexed_phrases.len())
+    }
+
+    pub fn word_count(&self) -> usize {
+        self.indexed_phrases_by_word.len()
+    }
+
+    pub fn average_quality(&self) -> Option<f32> {
+        if self.phrase_qualities.is_empty() {
+            return None;
+        }
+
+        let quality_sum: f32 = self.phrase_qualities.values().sum();
+
+        Some(quality_sum / self.phrase_qualities.len() as f32)
+    }
+
+    /// What the phrases learned are rated with.
+    pub fn quality_scorer(&self) -> &QualityScorer {
+        &self.quality_scorer
+    }
+
+    /// Rates the phrase the way the phrases learned are rated, without
+    /// learning it.
+    pub fn score_phrase(&self, phrase: &str) -> f32 {
+        self.quality_scorer.score(&Phrase::from(phrase))
+    }
+
+    /// Forgets the text of a removed phrase, along with the texts of its words
+    /// that are no longer part of any phrase.
+    fn forget_orphaned_texts(&mut self, phrase_content: &str) {
+        let orphaned_texts = phrase_content
+            .split_ascii_whitespace()
+            .filter(|word| {
+                self.texts.get(word).is_some_and(|word_index| {
+                    !self.indexed_phrases_by_word.contains_key(&word_index)
+                })
+            })
+            .chain(std::iter::once(phrase_content))
+            .collect::<Vec<_>>();
+
+        for text in orphaned_texts {
+            self.texts.forget(text);
+        }
+    }
+
+    fn touch_phrase(&mut self, phrase_index: Symbol) {
+        if let Some(learning_tick) = self.phrase_learning_ticks.remove(&phrase_index) {
+            self.phrases_by_learning_tick.remove(&learning_tick);
+        }
+
+        self.learning_clock += 1;
+        self.phrase_learning_ticks
+            .insert(phrase_index, self.learning_clock);
+        self.phrases_by_learning_tick
+            .insert(self.learning_clock, phrase_index);
+    }
+
+    /// Looks for an indexed phrase similar to `phrase_content`. Only phrases that
+    /// start with the same first word or end with the same last word are
+    /// considered, so that we don't have to compare against the whole corpus.
+    fn find_near_duplicate(&self, phrase_content: &str, min_similarity: f32) -> Option<Symbol> {
+        let words: Vec<_> = phrase_content.split_ascii_whitespace().collect();
+
+        if words.len() < 2 {
+            return None;
+        }
+
+        let first_word = words[0];
+        let last_word = words[words.len() - 1];
+
+        let candidates_starting_with_first_word = self
+            .get_indexed_phrases_of_text(first_word)
+            .filter(|indexed_phrase| indexed_phrase.word_pos_in_phrase == 0);
+
+        let candidates_ending_with_last_word =
+            self.get_indexed_phrases_of_text(last_word)
+                .filter(|indexed_phrase| {
+                    let candidate_words = &self.phrase_words[&indexed_phrase.interned_phrase_index];
+                    indexed_phrase.word_pos_in_phrase as usize + 1 == candidate_words.len()
+                });
+
+        candidates_starting_with_first_word
+            .chain(candidates_ending_with_last_word)
+            .map(|indexed_phrase| indexed_phrase.interned_phrase_index)
+            .find(|&candidate_index| {
+                let candidate_content = self.texts.resolve(candidate_index);
+                phrase_similarity(phrase_content, candidate_content) >= min_similarity
+            })
+    }
+
+    /// Looks for pairs of phrases whose halves around a common word make up
+    /// `text`, as generated by `concatenate_indexed_phrases`.
+    fn find_splice_sources(&self, text: &str) -> HashSet<Symbol> {
+        let mut splice_sources = HashSet::new();
+
+        // Words that were never learned can't be part of any phrase.
+        let text_words: Option<Vec<_>> = text
+            .split_ascii_whitespace()
+            .map(|word| self.texts.get(word))
+            .collect();
+        let text_words = match text_words {
+            Some(text_words) => text_words,
+            None => return splice_sources,
+        };
+
+        for (word_pos_in_text, &word_index) in text_words.iter().enumerate() {
+            let (text_first_half, text_second_half) = text_words.split_at(word_pos_in_text);
+
+            let mut first_halves = Vec::new();
+            let mut second_halves = Vec::new();
+
+            for indexed_phrase in self
+                .indexed_phrases_by_word
+                .get(&word_index)
+                .into_iter()
+                .flatten()
+            {
+                let phrase_words = &self.phrase_words[&indexed_phrase.interned_phrase_index];
+                let (phrase_first_half, phrase_second_half) =
+                    phrase_words.split_at(indexed_phrase.word_pos_in_phrase as usize);
+
+                if phrase_first_half == text_first_half {
+                    first_halves.push(indexed_phrase.interned_phrase_index);
+                }
+                if phrase_second_half == text_second_half {
+                    second_halves.push(indexed_phrase.interned_phrase_index);
+                }
+            }
+
+            if !first_halves.is_empty() && !second_halves.is_empty() {
+                splice_sources.extend(first_halves);
+                splice_sources.extend(second_halves);
+            }
+        }
+
+        splice_sources
+    }
+
+    fn get_indexed_phrases_of_text(&self, text: &str) -> impl Iterator<Item = &IndexedPhrase> {
+        self.texts
+            .get(text)
+            .and_then(|text_index| self.indexed_phrases_by_word.get(&text_index))
+            .into_iter()
+            .flatten()
+    }
+
+    fn link_phrase_to_word(
+        &mut self,
+        phrase_index: Symbol,
+        word_index: Symbol,
+        word_pos_in_phrase: usize,
+    ) {
+        let phrase_indices = self.indexed_phrases_by_word.entry(word_index).or_default();
+
+        phrase_indices.insert(IndexedPhrase {
+            interned_phrase_index: phrase_index,
+            word_pos_in_phrase: word_pos_in_phrase as u32,
+        });
+    }
+
+    fn unlink_phrase_from_word(
+        &mut self,
+        phrase_index: Symbol,
+        word_index: Symbol,
+        word_pos_in_phrase: usize,
+    ) {
+        let phrase_indices = match self.indexed_phrases_by_word.get_mut(&word_index) {
+            Some(phrase_indices) => phrase_indices,
+            None => return,
+        };
+
+        phrase_indices.remove(&IndexedPhrase {
+            interned_phrase_index: phrase_index,
+            word_pos_in_phrase: word_pos_in_phrase as u32,
+        });
+
+        if !phrase_indices.is_empty() {
+            return;
+        }
+
+        self.indexed_phrases_by_word.remove(&word_index);
+        self.word_occurrences.remove(&word_index);
+        self.laughter_words.remove(&word_index);
+
+        let folded_word = fold_diacritics(self.texts.resolve(word_index));
+        if let Some(word_indices) = self.words_by_folded_form.get_mut(&folded_word) {
+            word_indices.remove(&word_index);
+            if word_indices.is_empty() {
+                self.words_by_folded_form.remove(&folded_word);
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, thiserror::Error)]
+pub enum EngineError {
+    #[error("word `{0}` isn't part of any indexed phrase")]
+    UnknownWord(String),
+    #[error("word id {0:?} doesn't belong to this index")]
+    UnknownWordId(WordId),
+    #[error("no phrase has been indexed yet")]
+    EmptyCorpus,
+    #[error("no phrase has been tagged with `{0}`")]
+    UnknownTag(String),
+    #[error("every phrase containing word `{0}` has expired")]
+    ExpiredWord(String),
+}
+
+pub struct InsertionResult {
+    pub has_inserted_phrase: bool,
+    pub word_ids_from_phrase: Vec<WordId>,
+}
+
+/// Where the pivot word is in the phrase, from 0.0 at its first word to 1.0 at
+/// its last.
+fn relative_pivot_pos(phrase: IndexedPhraseContent) -> f32 {
+    let word_count = phrase.phrase_words.len();
+
+    if word_count < 2 {
+        return 0.5;
+    }
+
+    phrase.word_pos_in_phrase as f32 / (word_count - 1) as f32
+}
+
+/// Removes diacritics from a word, e.g. "não" becomes "nao".
+fn fold_diacritics(word: &str) -> String {
+    word.nfd()
+        .filter(|&c| !is_combining_mark(c))
+        .nfc()
+        .collect()
+}
+
+/// Similarity between two texts based on their character-level edit distance,
+/// where 1.0 means they are identical.
+fn phrase_similarity(first_text: &str, second_text: &str) -> f32 {
+    let first_chars: Vec<_> = first_text.chars().collect();
+    let second_chars: Vec<_> = second_text.chars().collect();
+
+    let max_len = first_chars.len().max(second_chars.len());
+
+    if max_len == 0 {
+        return 1.0;
+    }
+
+    let mut previous_row: Vec<_> = (0..=second_chars.len()).collect();
+    let mut current_row = vec![0; second_chars.len() + 1];
+
+    for (i, first_char) in first_chars.iter().enumerate() {
+        current_row[0] = i + 1;
+
+        for (j, second_char) in second_chars.iter().enumerate() {
+            let substitution_cost = if first_char == second_char { 0 } else { 1 };
+
+            current_row[j + 1] = (previous_row[j] + substitution_cost)
+                .min(previous_row[j + 1] + 1)
+                .min(current_row[j] + 1);
+        }
+
+        std::mem::swap(&mut previous_row, &mut current_row);
+    }
+
+    let edit_distance = previous_row[second_chars.len()];
+
+    1.0 - edit_distance as f32 / max_len as f32
+}
+
+/// Joins the words of the first phrase before its word with the words of the
+/// second phrase from its word on, the other way around if that would only
+/// leave the word in common.
+pub fn concatenate_indexed_phrases<'s>(
+    mut first_phrase: IndexedPhraseContent<'s>,
+    mut second_phrase: IndexedPhraseContent<'s>,
+) -> String {
+    if first_phrase.word_pos_in_phrase == 0
+        && second_phrase.word_pos_in_phrase + 1 == second_phrase.phrase_words.len()
+    {
+        std::mem::swap(&mut first_phrase, &mut second_phrase);
+    }
+
+    let first_phrase_half = first_phrase.join_words(..first_phrase.word_pos_in_phrase);
+    let second_phrase_half = second_phrase.join_words(second_phrase.word_pos_in_phrase..);
+
+    if first_phrase_half.is_empty() {
+        return second_phrase_half;
+    }
+
+    format!("{} {}", first_phrase_half, second_phrase_half)
+}
+
+/// Same as `concatenate_indexed_phrases`, except that the first phrase ends at
+/// its word, and the second one starts at another word, which must follow the
+/// first one somewhere in the corpus for the join to read well.
+pub fn concatenate_indexed_phrases_at_bigram(
+    first_phrase: IndexedPhraseContent,
+    second_phrase: IndexedPhraseContent,
+) -> String {
+    let first_phrase_half = first_phrase.join_words(..=first_phrase.word_pos_in_phrase);
+    let second_phrase_half = second_phrase.join_words(second_phrase.word_pos_in_phrase..);
+
+    format!("{} {}", first_phrase_half, second_phrase_half)
+}
+
+#[cfg(test)]
+mod normalization_tests {
+    use super::{
+        is_language_code, normalize_text_into_phrases, CaseFolding, NormalizationConfig, Phrase,
+    };
+
+    #[test]
+    fn should_do_nothing_if_text_is_considered_to_be_normalized() {
+        let phrases =
+            normalize_text_into_phrases("hello world".into(), &NormalizationConfig::default());
+
+        assert_eq!(phrases, &[Phrase::from("hello world")]);
+    }
+
+    #[test]
+    fn should_collapse_long_letter_runs_if_enabled() {
+        let config = NormalizationConfig {
+            max_letter_run: Some(3),
+            ..NormalizationConfig::default()
+        };
+
+        let phrases = normalize_text_into_phrases("LoOoOoL that's cool 1000000".into(), &config);
+
+        assert_eq!(phrases, &[Phrase::from("lool that s cool 1000000")]);
+    }
+
+    #[test]
+    fn should_keep_letter_runs_within_limit() {
+        let config = NormalizationConfig {
+            max_letter_run: Some(3),
+            ..NormalizationConfig::default()
+        };
+
+        let phrases = normalize_text_into_phrases("sooo good".into(), &config);
+
+        assert_eq!(phrases, &[Phrase::from("sooo good")]);
+    }
+
+    #[test]
+    fn should_keep_letter_runs_by_default() {
+        let phrases =
+            normalize_text_into_phrases("loooool".into(), &NormalizationConfig::default());
+
+        assert_eq!(phrases, &[Phrase::from("loooool")]);
+    }
+
+    #[test]
+    fn should_convert_to_lowercase() {
+        let phrases =
+            normalize_text_into_phrases("HELLO WoRlD".into(), &NormalizationConfig::default());
+
+        assert_eq!(phrases, &[Phrase::from("hello world")]);
+    }
+
+    #[test]
+    fn should_map_dotted_and_dotless_i_for_turkic_languages() {
+        let config = NormalizationConfig {
+            case_folding: CaseFolding::for_language("tr"),
+            ..NormalizationConfig::default()
+        };
+
+        let phrases = normalize_text_into_phrases("İSTANBUL IRMAK".into(), &config);
+
+        assert_eq!(phrases, &[Phrase::from("istanbul ırmak")]);
+    }
+
+    #[test]
+    fn should_keep_text_as_is_when_preserving_case() {
+        let config = NormalizationConfig {
+            case_folding: CaseFolding::for_language("ja-JP"),
+            ..NormalizationConfig::default()
+        };
+
+        let phrases = normalize_text_into_phrases("こんにちは World".into(), &config);
+
+        assert_eq!(phrases, &[Phrase::from("こんにちは World")]);
+    }
+
+    #[test]
+    fn should_pick_case_folding_from_language_code() {
+        assert_eq!(CaseFolding::for_language("tr"), CaseFolding::Turkic);
+        assert_eq!(CaseFolding::for_language("AZ_az"), CaseFolding::Turkic);
+        assert_eq!(CaseFolding::for_language("zh-Hant"), CaseFolding::Preserve);
+        assert_eq!(CaseFolding::for_language("pt-BR"), CaseFolding::Unicode);
+        assert_eq!(CaseFolding::for_language(""), CaseFolding::Unicode);
+    }
+
+    #[test]
+    fn should_tell_language_codes_apart() {
+        assert!(is_language_code("pt"));
+        assert!(is_language_code("pt-BR"));
+        assert!(is_language_code("zh_Hant_TW"));
+        assert!(!is_language_code(""));
+        assert!(!is_language_code("portuguese"));
+        assert!(!is_language_code("pt-"));
+        assert!(!is_language_code("pt br"));
+    }
+
+    #[test]
+    fn should_remove_extra_spaces() {
+        let phrases = normalize_text_into_phrases(
+            "   hello    world    ".into(),
+            &NormalizationConfig::default(),
+        );
+
+        assert_eq!(phrases, &[Phrase::from("hello world")]);
+    }
+
+    #[test]
+    fn should_replace_punctuation_except_period_with_whitespace() {
+        let punctuations_except_period = ('\x00'..='\x7f')
+            .filter(|&c| c.is_ascii_punctuation())
+            .filter(|&c| c != '.' && c != ';')
+            .collect::<String>();
+
+        let phrases = normalize_text_into_phrases(
+            format!("foo{}bar", punctuations_except_period),
+            &NormalizationConfig::default(),
+        );
+
+        assert_eq!(phrases, &[Phrase::from("foo bar")]);
+    }
+
+    #[test]
+    fn should_replace_unicode_punctuation_with_whitespace() {
+        let phrases = normalize_text_into_phrases(
+            "¿qué tal? «bien»، شكرا؟".into(),
+            &NormalizationConfig::default(),
+        );
+
+        assert_eq!(phrases, &[Phrase::from("qué tal bien شكرا")]);
+    }
+
+    #[test]
+    fn should_replace_non_ascii_whitespace_with_space() {
+        let phrases = normalize_text_into_phrases(
+            "hello\u{a0}world\tfoo".into(),
+            &NormalizationConfig::default(),
+        );
+
+        assert_eq!(phrases, &[Phrase::from("hello world foo")]);
+    }
+
+    #[test]
+    fn should_remove_directional_marks() {
+        let phrases = normalize_text_into_phrases(
+            "\u{200f}שלום\u{200e} \u{2067}עולם\u{2069}".into(),
+            &NormalizationConfig::default(),
+        );
+
+        assert_eq!(phrases, &[Phrase::from("שלום עולם")]);
+    }
+
+    #[test]
+    fn should_not_produce_empty_phrases() {
+        let phrases = normalize_text_into_phrases(
+            "hello. \u{200f} . world".into(),
+            &NormalizationConfig::default(),
+        );
+
+        assert_eq!(
+            phrases,
+            &[Phrase::with_terminator("hello", '.'), Phrase::from("world")]
+        );
+    }
+
+    #[test]
+    fn should_split_text_at_arabic_period_punctuations() {
+        let phrases = normalize_text_into_phrases(
+            "مرحبا بكم؛ كيف حالك۔".into(),
+            &NormalizationConfig::default(),
+        );
+
+        assert_eq!(
+            phrases,
+            &[
+                Phrase::with_terminator("مرحبا بكم", '؛'),
+                Phrase::with_terminator("كيف حالك", '۔')
+            ]
+        );
+    }
+
+    #[test]
+    fn should_split_text_at_period_punctuations() {
+        let phrases = normalize_text_into_phrases(
+            "i think; therefore i am... it is hard to believe.".into(),
+            &NormalizationConfig::default(),
+        );
+
+        assert_eq!(
+            phrases,
+            &[
+                Phrase::with_terminator("i think", ';'),
+                Phrase::with_terminator("therefore i am", '.'),
+                Phrase::with_terminator("it is hard to believe", '.')
+            ]
+        );
+    }
+
+    #[test]
+    fn should_split_text_at_configured_terminators() {
+        let config = NormalizationConfig {
+            phrase_terminators: vec!['!', '?', '\n', '…'],
+            ..NormalizationConfig::default()
+        };
+
+        let phrases = normalize_text_into_phrases(
+            "hi there! how are you?\nfine; thanks… bye".into(),
+            &config,
+        );
+
+        assert_eq!(
+            phrases,
+            &[
+                Phrase::with_terminator("hi there", '!'),
+                Phrase::with_terminator("how are you", '?'),
+                Phrase::with_terminator("fine thanks", '…'),
+                Phrase::from("bye")
+            ]
+        );
+    }
+
+    #[test]
+    fn should_not_split_text_if_there_are_no_terminators() {
+        let config = NormalizationConfig {
+            phrase_terminators: Vec::new(),
+            ..NormalizationConfig::default()
+        };
+
+        let phrases = normalize_text_into_phrases("i think. therefore i am".into(), &config);
+
+        assert_eq!(phrases, &[Phrase::from("i think therefore i am")]);
+    }
+
+    #[test]
+    fn should_store_phrases_as_lines_that_normalize_into_the_same_phrases() {
+        let config = NormalizationConfig {
+            phrase_terminators: vec!['?', '\n'],
+            ..NormalizationConfig::default()
+        };
+
+        let phrases = normalize_text_into_phrases("how are you?\nfine\nbye".into(), &config);
+        let lines: Vec<_> = phrases.iter().map(Phrase::to_line).collect();
+
+        assert_eq!(lines, &["how are you?", "fine", "bye"]);
+
+        let renormalized_phrases: Vec<_> = lines
+            .into_iter()
+            .flat_map(|line| normalize_text_into_phrases(line, &config))
+            .collect();
+
+        assert_eq!(
+            renormalized_phrases,
+            &[
+                Phrase::with_terminator("how are you", '?'),
+                Phrase::from("fine"),
+                Phrase::from("bye")
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod common_words_tests {
+    use super::{IndexedPhrases, Phrase, Word};
+    use std::collections::HashSet;
+
+    #[test]
+    fn should_return_empty_vec_if_no_phrase_was_indexed() {
+        let indexed_phrases = IndexedPhrases::new();
+        let common_words: Vec<_> = indexed_phrases.get_common_words().collect();
+
+        assert_eq!(common_words, &[]);
+    }
+
+    #[test]
+    fn should_return_empty_vec_if_indexed_phrase_has_only_one_word() {
+        let mut indexed_phrases = IndexedPhrases::new();
+
+        indexed_phrases.insert_phrase(Phrase::from("hello"));
+        indexed_phrases.insert_phrase(Phrase::from("you"));
+        indexed_phrases.insert_phrase(Phrase::from("all"));
+
+        let common_words: Vec<_> = indexed_phrases.get_common_words().collect();
+
+        assert_eq!(common_words, &[]);
+    }
+
+    #[test]
+    fn should_return_deduplicated_words_from_phrases_with_two_or_more_words() {
+        let mut indexed_phrases = IndexedPhrases::new();
+
+        indexed_phrases.insert_phrase(Phrase::from("hello hello you all"));
+        indexed_phrases.insert_phrase(Phrase::from("nice"));
+        indexed_phrases.insert_phrase(Phrase::from("how are you all doing"));
+
+        let common_words: HashSet<_> = indexed_phrases.get_common_words().collect();
+
+        assert_eq!(
+            common_words,
+            HashSet::from_iter(["hello", "you", "all", "how", "are", "doing"].map(Word))
+        );
+    }
+}
+
+#[cfg(test)]
+mod word_retrieval_tests {
+    use super::{EngineError, IndexedPhrases, Phrase, Word, WordId};
+    use rand::SeedableRng;
+
+    #[test]
+    fn should_return_words_for_ids_from_insertion() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        let insertion_res = indexed_phrases.insert_phrase(Phrase::from("hello there"));
+
+        let words: Result<Vec<_>, _> = insertion_res
+            .word_ids_from_phrase
+            .into_iter()
+            .map(|word_id| indexed_phrases.get_word(word_id))
+            .collect();
+
+        assert_eq!(words, Ok(vec![Word("hello"), Word("there")]));
+    }
+
+    #[test]
+    fn should_fail_if_word_id_is_unknown() {
+        let indexed_phrases = IndexedPhrases::new();
+
+        let word = indexed_phrases.get_word(WordId(42));
+
+        assert_eq!(word, Err(EngineError::UnknownWordId(WordId(42))));
+    }
+
+    #[test]
+    fn should_look_up_word_ids_by_text() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hi"));
+
+        let hello_id = indexed_phrases.get_word_id("hello").unwrap();
+        let hi_id = indexed_phrases.get_word_id("hi").unwrap();
+
+        assert_eq!(indexed_phrases.get_word(hello_id), Ok(Word("hello")));
+        assert!(indexed_phrases.is_common_word(hello_id));
+        assert!(!indexed_phrases.is_common_word(hi_id));
+        assert_eq!(indexed_phrases.get_word_id("bye"), None);
+    }
+
+    #[test]
+    fn should_return_phrases_for_word_id() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+
+        let word_id = indexed_phrases.get_word_id("there").unwrap();
+        let phrases: Vec<_> = indexed_phrases
+            .get_phrases_with_word_id_in_common(word_id)
+            .unwrap()
+            .map(|phrase| phrase.phrase_content)
+            .collect();
+
+        assert_eq!(phrases, &["hello there"]);
+    }
+
+    #[test]
+    fn should_fail_to_pick_random_word_if_corpus_is_empty() {
+        let indexed_phrases = IndexedPhrases::new();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        assert_eq!(
+            indexed_phrases.get_random_common_word(&mut rng),
+            Err(EngineError::EmptyCorpus)
+        );
+    }
+
+    #[test]
+    fn should_pick_random_common_word() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        let word_id = indexed_phrases.get_random_common_word(&mut rng).unwrap();
+        let word = indexed_phrases.get_word(word_id).unwrap();
+
+        assert!(*word == *"hello" || *word == *"there");
+    }
+
+    #[test]
+    fn should_fail_to_pick_random_phrase_if_corpus_is_empty() {
+        let indexed_phrases = IndexedPhrases::new();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        assert_eq!(
+            indexed_phrases.get_random_phrase(&mut rng),
+            Err(EngineError::EmptyCorpus)
+        );
+    }
+
+    #[test]
+    fn should_pick_random_phrase_verbatim() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("good morning"));
+        indexed_phrases.insert_phrase(Phrase::from("hi"));
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        for _ in 0..10 {
+            let phrase = indexed_phrases.get_random_phrase(&mut rng).unwrap();
+
+            assert!(phrase == "hello there" || phrase == "good morning");
+        }
+    }
+}
+
+#[cfg(test)]
+mod retrieval_of_phrases_for_word_in_common_tests {
+    use super::{EngineError, IndexedPhrases, Phrase, Word};
+    use std::collections::HashSet;
+
+    #[test]
+    fn should_fail_if_word_is_unknown() {
+        let indexed_phrases = {
+            let mut ip = IndexedPhrases::new();
+            ip.insert_phrase(Phrase::from("hello there"));
+            ip
+        };
+
+        let result = indexed_phrases.get_phrases_with_word_in_common(Word("hi"));
+
+        assert_eq!(result.err(), Some(EngineError::UnknownWord("hi".into())));
+    }
+
+    #[test]
+    fn should_fail_if_word_was_only_learned_as_single_word_phrase() {
+        let indexed_phrases = {
+            let mut ip = IndexedPhrases::new();
+            ip.insert_phrase(Phrase::from("hi"));
+            ip
+        };
+
+        let result = indexed_phrases.get_phrases_with_word_in_common(Word("hi"));
+
+        assert_eq!(result.err(), Some(EngineError::UnknownWord("hi".into())));
+    }
+
+    #[test]
+    fn should_return_indexed_phrases_that_have_the_passed_word_in_common() {
+        let indexed_phrases = {
+            let mut ip = IndexedPhrases::new();
+            ip.insert_phrase(Phrase::from("hello there friend"));
+            ip.insert_phrase(Phrase::from("hey friend what are you up to"));
+            ip.insert_phrase(Phrase::from("i have got lots of friends"));
+            ip.insert_phrase(Phrase::from("good evening"));
+            ip
+        };
+
+        let phrases: HashSet<_> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("friend"))
+            .unwrap()
+            .map(|phrase| (phrase.phrase_content, phrase.word_pos_in_phrase))
+            .collect();
+
+        assert_eq!(
+            phrases,
+            HashSet::from_iter([
+                ("hello there friend", 2),
+                ("hey friend what are you up to", 1)
+            ])
+        );
+    }
+
+    #[test]
+    fn should_not_duplicate_phrases() {
+        let indexed_phrases = {
+            let mut ip = IndexedPhrases::new();
+            ip.insert_phrase(Phrase::from("hello there friend"));
+            ip.insert_phrase(Phrase::from("hello there friend"));
+            ip.insert_phrase(Phrase::from("hello there friend"));
+            ip
+        };
+
+        let phrases: Vec<_> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("friend"))
+            .unwrap()
+            .map(|phrase| (phrase.phrase_content, phrase.word_pos_in_phrase))
+            .collect();
+
+        assert_eq!(phrases, &[("hello there friend", 2)]);
+    }
+}
+
+#[cfg(test)]
+mod diacritic_folding_tests {
+    use super::{fold_diacritics, IndexedPhrases, NormalizationConfig, Phrase, Word};
+    use std::collections::HashSet;
+
+    fn index_phrases(fold_pivot_diacritics: bool) -> IndexedPhrases {
+        let mut ip = IndexedPhrases::new();
+        ip.set_pivot_diacritic_folding(fold_pivot_diacritics);
+        ip.insert_phrase(Phrase::from("eu não sei"));
+        ip.insert_phrase(Phrase::from("nao faz isso"));
+        ip.insert_phrase(Phrase::from("nau grande"));
+        ip
+    }
+
+    #[test]
+    fn should_remove_diacritics() {
+        assert_eq!(fold_diacritics("não"), "nao");
+        assert_eq!(fold_diacritics("café"), "cafe");
+        assert_eq!(fold_diacritics("hello"), "hello");
+        assert_eq!(fold_diacritics("한국어"), "한국어");
+    }
+
+    #[test]
+    fn should_match_pivots_regardless_of_diacritics_when_enabled() {
+        let indexed_phrases = index_phrases(true);
+
+        let phrases: HashSet<_> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("nao"))
+            .unwrap()
+            .map(|phrase| (phrase.phrase_content, phrase.word_pos_in_phrase))
+            .collect();
+
+        assert_eq!(
+            phrases,
+            HashSet::from_iter([("eu não sei", 1), ("nao faz isso", 0)])
+        );
+    }
+
+    #[test]
+    fn should_match_pivots_exactly_when_disabled() {
+        let indexed_phrases = index_phrases(false);
+
+        let phrases: Vec<_> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("não"))
+            .unwrap()
+            .map(|phrase| phrase.phrase_content)
+            .collect();
+
+        assert_eq!(phrases, &["eu não sei"]);
+    }
+
+    #[test]
+    fn should_enable_folding_for_configured_languages() {
+        assert!(NormalizationConfig::for_language("pt-BR").fold_pivot_diacritics);
+        assert!(!NormalizationConfig::for_language("en").fold_pivot_diacritics);
+        assert!(!NormalizationConfig::for_language("vi").fold_pivot_diacritics);
+    }
+}
+
+#[cfg(test)]
+mod sub_word_tests {
+    use super::{IndexedPhrases, Phrase};
+
+    fn index_phrases(min_sub_word_len: Option<usize>) -> IndexedPhrases {
+        let mut ip = IndexedPhrases::new();
+        ip.set_min_sub_word_len(min_sub_word_len);
+        ip.insert_phrase(Phrase::from("game night was fun"));
+        ip.insert_phrase(Phrase::from("friday night again"));
+        ip.insert_phrase(Phrase::from("what a game"));
+        ip.insert_phrase(Phrase::from("a game of chess"));
+        ip.insert_phrase(Phrase::from("see you at gamenightfriday"));
+        ip
+    }
+
+    #[test]
+    fn should_find_longest_sub_word_in_several_phrases() {
+        let ip = index_phrases(Some(3));
+        let word_id = ip.get_word_id("gamenightfriday").unwrap();
+
+        let sub_word_id = ip.find_sub_word(word_id).unwrap();
+
+        assert_eq!(&*ip.get_word(sub_word_id).unwrap(), "night");
+    }
+
+    #[test]
+    fn should_not_find_sub_words_shorter_than_min_len() {
+        let ip = index_phrases(Some(6));
+
+        assert_eq!(
+            ip.find_sub_word(ip.get_word_id("gamenightfriday").unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn should_not_find_sub_words_when_disabled() {
+        let ip = index_phrases(None);
+
+        assert_eq!(
+            ip.find_sub_word(ip.get_word_id("gamenightfriday").unwrap()),
+            None
+        );
+    }
+}
+
+#[cfg(test)]
+mod acronym_tests {
+    use super::{normalize_text_into_phrases, IndexedPhrases, NormalizationConfig};
+
+    fn index_text(text: &str) -> IndexedPhrases {
+        let mut ip = IndexedPhrases::new();
+        for phrase in normalize_text_into_phrases(text.into(), &NormalizationConfig::default()) {
+            ip.insert_phrase(phrase);
+        }
+        ip
+    }
+
+    #[test]
+    fn should_restore_acronyms_in_uppercase() {
+        let ip = index_text("My CPU is hot, the GPU too. I said it");
+
+        assert_eq!(
+            ip.restore_acronyms("the cpu is hot. i said gpu!"),
+            "the CPU is hot. i said GPU!"
+        );
+    }
+
+    #[test]
+    fn should_not_take_shouted_phrases_as_acronyms() {
+        let ip = index_text("THE CPU IS HOT");
+
+        assert_eq!(ip.restore_acronyms("the cpu is hot"), "the cpu is hot");
+    }
+
+    #[test]
+    fn should_keep_acronyms_in_stored_lines() {
+        let phrases = normalize_text_into_phrases(
+            "O PIB da ONU caiu.".into(),
+            &NormalizationConfig::default(),
+        );
+
+        assert_eq!(phrases[0].as_ref(), "o pib da onu caiu");
+        assert_eq!(phrases[0].to_line(), "o PIB da ONU caiu.");
+        assert_eq!(
+            normalize_text_into_phrases(phrases[0].to_line(), &NormalizationConfig::default()),
+            phrases
+        );
+    }
+}
+
+#[cfg(test)]
+mod protected_word_tests {
+    use super::{restore_protected_words, IndexedPhrases, NormalizationConfig};
+
+    #[test]
+    fn should_restore_spelling_of_protected_words() {
+        let config = NormalizationConfig {
+            max_letter_run: Some(2),
+            ..NormalizationConfig::default()
+        };
+        let protected_words = ["McBrunooo".to_string()];
+
+        assert_eq!(
+            restore_protected_words("oh mcbrunoo, mcbrunoooo!", &protected_words, &config),
+            "oh McBrunooo, McBrunooo!"
+        );
+        assert_eq!(
+            restore_protected_words("mcbruno", &protected_words, &config),
+            "mcbruno"
+        );
+    }
+
+    #[test]
+    fn should_restore_protected_words_over_acronyms() {
+        let mut ip = IndexedPhrases::new();
+        for phrase in super::normalize_text_into_phrases(
+            "ask BOB about it".into(),
+            &NormalizationConfig::default(),
+        ) {
+            ip.insert_phrase(phrase);
+        }
+
+        let response = ip.restore_acronyms("bob knows");
+
+        assert_eq!(response, "BOB knows");
+        assert_eq!(
+            restore_protected_words(
+                &response,
+                &["BoB".to_string()],
+                &NormalizationConfig::default()
+            ),
+            "BoB knows"
+        );
+    }
+}
+
+#[cfg(test)]
+mod blocked_word_tests {
+    use super::{IndexedPhrases, Phrase};
+
+    #[test]
+    fn should_not_insert_phrases_with_blocked_words() {
+        let mut ip = IndexedPhrases::new();
+        ip.set_blocked_words(["Darn".to_string()]);
+
+        let insertion_res = ip.insert_phrase(Phrase::from("oh darn it"));
+
+        assert!(!insertion_res.has_inserted_phrase);
+        assert!(!ip.contains_phrase("oh darn it"));
+        assert_eq!(ip.get_word_id("darn"), None);
+    }
+
+    #[test]
+    fn should_find_blocked_words_next_to_punctuation() {
+        let mut ip = IndexedPhrases::new();
+        ip.set_blocked_words(["darn".to_string()]);
+
+        assert!(ip.has_blocked_word("oh, darn! it"));
+        assert!(!ip.has_blocked_word("darnell is here"));
+    }
+}
+
+#[cfg(test)]
+mod laughter_canonicalization_tests {
+    use super::PT_LAUGHTER_PATTERN;
+    use super::{laughter_pattern, IndexedPhrases, NormalizationConfig, Phrase, Word};
+    use std::collections::HashSet;
+
+    fn index_phrases(ip: &mut IndexedPhrases) {
+        ip.insert_phrase(Phrase::from("kkkk que isso"));
+        ip.insert_phrase(Phrase::from("hahaha muito bom"));
+        ip.insert_phrase(Phrase::from("rsrs entendi"));
+        ip.insert_phrase(Phrase::from("que bom"));
+    }
+
+    fn phrases_with_word_in_common<'s>(ip: &'s IndexedPhrases, word: &str) -> HashSet<&'s str> {
+        ip.get_phrases_with_word_in_common(Word(word))
+            .unwrap()
+            .map(|phrase| phrase.phrase_content)
+            .collect()
+    }
+
+    #[test]
+    fn should_recognize_portuguese_laughter() {
+        let pattern = laughter_pattern(PT_LAUGHTER_PATTERN).unwrap();
+
+        for laughter in ["kkk", "kkkkkkk", "haha", "ahahah", "hehe", "rsrs", "huehue"] {
+            assert!(pattern.is_match(laughter), "{}", laughter);
+        }
+
+        for word in ["kk", "ha", "hahaa", "rs", "risos", "khaki"] {
+            assert!(!pattern.is_match(word), "{}", word);
+        }
+    }
+
+    #[test]
+    fn should_match_any_laughter_as_pivot_when_enabled() {
+        let mut ip = IndexedPhrases::new();
+        ip.set_laughter_pattern(Some(laughter_pattern(PT_LAUGHTER_PATTERN).unwrap()));
+        index_phrases(&mut ip);
+
+        assert_eq!(
+            phrases_with_word_in_common(&ip, "kkkk"),
+            HashSet::from(["kkkk que isso", "hahaha muito bom", "rsrs entendi"])
+        );
+        assert_eq!(
+            phrases_with_word_in_common(&ip, "que"),
+            HashSet::from(["kkkk que isso", "que bom"])
+        );
+    }
+
+    #[test]
+    fn should_recognize_laughter_inserted_before_enabling() {
+        let mut ip = IndexedPhrases::new();
+        index_phrases(&mut ip);
+
+        assert_eq!(
+            phrases_with_word_in_common(&ip, "rsrs"),
+            HashSet::from(["rsrs entendi"])
+        );
+
+        ip.set_laughter_pattern(Some(laughter_pattern(PT_LAUGHTER_PATTERN).unwrap()));
+
+        assert_eq!(phrases_with_word_in_common(&ip, "rsrs").len(), 3);
+
+        ip.set_laughter_pattern(None);
+
+        assert_eq!(phrases_with_word_in_common(&ip, "rsrs").len(), 1);
+    }
+
+    #[test]
+    fn should_enable_laughter_canonicalization_for_portuguese() {
+        assert!(NormalizationConfig::for_language("pt-BR")
+            .laughter_pattern
+            .is_some());
+        assert!(NormalizationConfig::for_language("en")
+            .laughter_pattern
+            .is_none());
+    }
+}
+
+#[cfg(test)]
+mod alias_pivot_tests {
+    use super::{IndexedPhrases, Phrase, Word};
+    use std::collections::HashSet;
+
+    fn phrases_with_word_in_common<'s>(ip: &'s IndexedPhrases, word: &str) -> HashSet<&'s str> {
+        ip.get_phrases_with_word_in_common(Word(word))
+            .unwrap()
+            .map(|phrase| phrase.phrase_content)
+            .collect()
+    }
+
+    #[test]
+    fn should_match_aliases_as_pivots_both_ways() {
+        let mut ip = IndexedPhrases::new();
+        ip.insert_phrase(Phrase::from("the fridge is empty"));
+        ip.insert_phrase(Phrase::from("abre a geladeira"));
+        ip.insert_phrase(Phrase::from("the oven is hot"));
+        ip.add_alias("fridge", "geladeira");
+
+        let expected_phrases = HashSet::from(["the fridge is empty", "abre a geladeira"]);
+        assert_eq!(phrases_with_word_in_common(&ip, "fridge"), expected_phrases);
+        assert_eq!(
+            phrases_with_word_in_common(&ip, "geladeira"),
+            expected_phrases
+        );
+    }
+
+    #[test]
+    fn should_match_aliases_learned_after_they_were_added() {
+        let mut ip = IndexedPhrases::new();
+        ip.insert_phrase(Phrase::from("the fridge is empty"));
+        ip.add_alias("fridge", "geladeira");
+
+        assert_eq!(
+            phrases_with_word_in_common(&ip, "fridge"),
+            HashSet::from(["the fridge is empty"])
+        );
+
+        ip.insert_phrase(Phrase::from("abre a geladeira"));
+
+        assert_eq!(
+            phrases_with_word_in_common(&ip, "fridge"),
+            HashSet::from(["the fridge is empty", "abre a geladeira"])
+        );
+    }
+}
+
+#[cfg(test)]
+mod cooccurring_words_tests {
+    use super::{IndexedPhrases, Phrase};
+
+    fn cooccurring_words(ip: &IndexedPhrases, word: &str, k: usize) -> Vec<(String, usize)> {
+        ip.get_cooccurring_words(ip.get_word_id(word).unwrap(), k)
+            .unwrap()
+            .into_iter()
+            .map(|(word_id, count)| (ip.get_word(word_id).unwrap().to_string(), count))
+            .collect()
+    }
+
+    #[test]
+    fn should_rank_words_by_shared_phrases() {
+        let mut ip = IndexedPhrases::new();
+        ip.insert_phrase(Phrase::from("pizza party tonight"));
+        ip.insert_phrase(Phrase::from("pizza is hot"));
+        ip.insert_phrase(Phrase::from("hot pizza party"));
+        ip.insert_phrase(Phrase::from("party without pizza"));
+
+        assert_eq!(
+            cooccurring_words(&ip, "pizza", 2),
+            [("party".into(), 3), ("hot".into(), 2)]
+        );
+    }
+
+    #[test]
+    fn should_count_each_phrase_once() {
+        let mut ip = IndexedPhrases::new();
+        ip.insert_phrase(Phrase::from("pizza pizza party party"));
+
+        assert_eq!(cooccurring_words(&ip, "pizza", 5), [("party".into(), 1)]);
+    }
+
+    #[test]
+    fn should_fail_for_unknown_word() {
+        let mut ip = IndexedPhrases::new();
+        let word_id = ip.insert_phrase(Phrase::from("pizza")).word_ids_from_phrase[0];
+
+        assert!(ip.get_cooccurring_words(word_id, 5).is_err());
+    }
+}
+
+#[cfg(test)]
+mod phrase_tag_tests {
+    use super::{concatenate_indexed_phrases, EngineError, IndexedPhrases, Phrase, Word};
+    use rand::SeedableRng;
+
+    fn index_phrases() -> IndexedPhrases {
+        let mut ip = IndexedPhrases::new();
+        ip.insert_phrase(Phrase::from("my cat is so fluffy"));
+        ip.insert_phrase(Phrase::from("the dog is very loud"));
+        ip.insert_phrase(Phrase::from("this is fine"));
+        ip
+    }
+
+    fn tagged_phrases<'s>(ip: &'s IndexedPhrases, word: &str, tag: &str) -> Vec<&'s str> {
+        let mut tagged_phrases: Vec<_> = ip
+            .get_phrases_with_word_in_common(Word(word))
+            .unwrap()
+            .filter(|&phrase| ip.is_phrase_tagged(phrase, tag))
+            .map(|phrase| phrase.phrase_content)
+            .collect();
+        tagged_phrases.sort_unstable();
+        tagged_phrases
+    }
+
+    #[test]
+    fn should_tag_phrase_stored_verbatim() {
+        let mut ip = index_phrases();
+
+        assert_eq!(ip.tag_phrases_of_text("this is fine", "meme"), 1);
+
+        assert_eq!(tagged_phrases(&ip, "is", "meme"), &["this is fine"]);
+        assert!(tagged_phrases(&ip, "is", "wholesome").is_empty());
+    }
+
+    #[test]
+    fn should_tag_phrases_a_generated_phrase_was_spliced_from() {
+        let mut ip = index_phrases();
+        let phrases: Vec<_> = ip
+            .get_phrases_with_word_in_common(Word("is"))
+            .unwrap()
+            .filter(|phrase| phrase.phrase_content != "this is fine")
+            .collect();
+        let generated_phrase = concatenate_indexed_phrases(phrases[0], phrases[1]);
+
+        assert_eq!(ip.tag_phrases_of_text(&generated_phrase, "wholesome"), 2);
+
+        assert_eq!(
+            tagged_phrases(&ip, "is", "wholesome"),
+            &["my cat is so fluffy", "the dog is very loud"]
+        );
+    }
+
+    #[test]
+    fn should_not_tag_anything_for_unknown_text() {
+        let mut ip = index_phrases();
+
+        assert_eq!(ip.tag_phrases_of_text("my cat is loud", "meme"), 0);
+        assert_eq!(ip.tag_phrases_of_text("nothing to see", "meme"), 0);
+    }
+
+    #[test]
+    fn should_pick_words_of_tagged_phrases() {
+        let mut ip = index_phrases();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        assert_eq!(
+            ip.get_random_tagged_word("meme", &mut rng),
+            Err(EngineError::UnknownTag("meme".into()))
+        );
+
+        ip.tag_phrases_of_text("this is fine", "meme");
+
+        for _ in 0..20 {
+            let word_id = ip.get_random_tagged_word("meme", &mut rng).unwrap();
+            let word = ip.get_word(word_id).unwrap();
+
+            assert!(["this", "is", "fine"].contains(&&*word));
+        }
+    }
+}
+
+#[cfg(test)]
+mod phrase_removal_tests {
+    use super::{EngineError, IndexedPhrases, Phrase};
+    use rand::SeedableRng;
+
+    #[test]
+    fn should_remove_phrase_from_index() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello world"));
+
+        assert!(indexed_phrases.remove_phrase("hello there"));
+
+        let hello = indexed_phrases.get_word_id("hello").unwrap();
+        let there = indexed_phrases.get_word_id("there").unwrap();
+        let phrases: Vec<_> = indexed_phrases
+            .get_phrases_with_word_id_in_common(hello)
+            .unwrap()
+            .collect();
+
+        assert_eq!(phrases.len(), 1);
+        assert_eq!(phrases[0].phrase_content, "hello world");
+        assert!(!indexed_phrases.is_common_word(there));
+        assert_eq!(indexed_phrases.phrase_count(), 1);
+        assert_eq!(indexed_phrases.total_phrase_occurrences(), 1);
+    }
+
+    #[test]
+    fn should_not_remove_unknown_phrase() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+
+        assert!(!indexed_phrases.remove_phrase("hello world"));
+        assert!(!indexed_phrases.remove_phrase("hello"));
+        assert_eq!(indexed_phrases.phrase_count(), 1);
+    }
+
+    #[test]
+    fn should_remove_every_phrase_containing_word() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("there there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello world"));
+
+        let mut removed_phrases = indexed_phrases.remove_word("there");
+        removed_phrases.sort();
+
+        assert_eq!(removed_phrases, &["hello there", "there there"]);
+        assert_eq!(indexed_phrases.phrase_count(), 1);
+        assert_eq!(indexed_phrases.word_count(), 2);
+    }
+
+    #[test]
+    fn should_untag_removed_phrase() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.tag_phrases_of_text("hello there", "greeting");
+
+        indexed_phrases.remove_phrase("hello there");
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        assert_eq!(
+            indexed_phrases.get_random_tagged_word("greeting", &mut rng),
+            Err(EngineError::UnknownTag("greeting".into()))
+        );
+    }
+}
+
+#[cfg(test)]
+mod phrase_eviction_tests {
+    use super::{IndexedPhrases, Phrase};
+
+    #[test]
+    fn should_evict_least_recently_learned_phrases() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello world"));
+        indexed_phrases.insert_phrase(Phrase::from("good morning"));
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+
+        let evicted_phrases = indexed_phrases.evict_least_recently_learned(Some(2), None);
+
+        assert_eq!(evicted_phrases, &["hello world"]);
+        assert!(indexed_phrases.contains_phrase("hello there"));
+        assert!(indexed_phrases.contains_phrase("good morning"));
+        assert_eq!(indexed_phrases.phrase_count(), 2);
+    }
+
+    #[test]
+    fn should_evict_phrases_until_under_word_limit() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello world"));
+        indexed_phrases.insert_phrase(Phrase::from("good morning"));
+
+        let evicted_phrases = indexed_phrases.evict_least_recently_learned(None, Some(3));
+
+        assert_eq!(evicted_phrases, &["hello there", "hello world"]);
+        assert_eq!(indexed_phrases.word_count(), 2);
+        assert!(indexed_phrases
+            .evict_least_recently_learned(None, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn should_forget_words_of_evicted_phrases_and_reuse_their_slots() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello world"));
+        let text_count = indexed_phrases.texts.symbol_count();
+
+        indexed_phrases.evict_least_recently_learned(Some(1), None);
+
+        assert_eq!(indexed_phrases.get_word_id("there"), None);
+        assert_eq!(indexed_phrases.get_word_id("hello there"), None);
+        assert!(indexed_phrases.get_word_id("hello").is_some());
+
+        indexed_phrases.insert_phrase(Phrase::from("good world"));
+
+        assert_eq!(indexed_phrases.texts.symbol_count(), text_count);
+        let good = indexed_phrases.get_word_id("good").unwrap();
+        assert_eq!(&*indexed_phrases.get_word(good).unwrap(), "good");
+    }
+}
+
+#[cfg(test)]
+mod phrase_expiry_tests {
+    use super::{IndexedPhrases, Phrase};
+
+    fn phrases_with_word(indexed_phrases: &IndexedPhrases, word: &str) -> Vec<String> {
+        let word_id = indexed_phrases.get_word_id(word).unwrap();
+
+        indexed_phrases
+            .get_phrases_with_word_id_in_common(word_id)
+            .map(|phrases| phrases.map(|phrase| phrase.phrase_content.into()).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn should_tell_expired_phrases_apart() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("fresh news").with_expiry(100));
+        indexed_phrases.insert_phrase(Phrase::from("old news"));
+
+        let news = indexed_phrases.get_word_id("news").unwrap();
+        let phrases: Vec<_> = indexed_phrases
+            .get_phrases_with_word_id_in_common(news)
+            .unwrap()
+            .collect();
+
+        for phrase in phrases {
+            match phrase.phrase_content {
+                "fresh news" => {
+                    assert_eq!(indexed_phrases.get_phrase_expiry(phrase), Some(100));
+                    assert!(!indexed_phrases.is_phrase_expired(phrase, 99));
+                    assert!(indexed_phrases.is_phrase_expired(phrase, 100));
+                }
+                _ => {
+                    assert_eq!(indexed_phrases.get_phrase_expiry(phrase), None);
+                    assert!(!indexed_phrases.is_phrase_expired(phrase, i64::MAX));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn should_remove_expired_phrases() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("fresh news").with_expiry(200));
+        indexed_phrases.insert_phrase(Phrase::from("stale news").with_expiry(100));
+        indexed_phrases.insert_phrase(Phrase::from("old news"));
+
+        assert!(indexed_phrases.remove_expired_phrases(99).is_empty());
+        assert_eq!(indexed_phrases.remove_expired_phrases(150), &["stale news"]);
+
+        let mut phrases = phrases_with_word(&indexed_phrases, "news");
+        phrases.sort();
+        assert_eq!(phrases, &["fresh news", "old news"]);
+        assert_eq!(indexed_phrases.phrase_count(), 2);
+    }
+
+    #[test]
+    fn should_stop_expiring_phrase_learned_again_without_expiry() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("fresh news").with_expiry(100));
+        indexed_phrases.insert_phrase(Phrase::from("fresh news"));
+
+        assert!(indexed_phrases.remove_expired_phrases(200).is_empty());
+        assert_eq!(phrases_with_word(&indexed_phrases, "news"), &["fresh news"]);
+    }
+}
+
+#[cfg(test)]
+mod phrase_terminator_tests {
+    use super::{IndexedPhrases, Phrase, Word};
+
+    #[test]
+    fn should_store_terminator_of_inserted_phrases() {
+        let indexed_phrases = {
+            let mut ip = IndexedPhrases::new();
+            ip.insert_phrase(Phrase::with_terminator("how are you", '?'));
+            ip.insert_phrase(Phrase::from("how nice"));
+            ip
+        };
+
+        let mut terminators: Vec<_> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("how"))
+            .unwrap()
+            .map(|phrase| {
+                (
+                    phrase.phrase_content,
+                    indexed_phrases.get_phrase_terminator(phrase),
+                )
+            })
+            .collect();
+        terminators.sort();
+
+        assert_eq!(
+            terminators,
+            &[("how are you", Some('?')), ("how nice", None)]
+        );
+    }
+}
+
+#[cfg(test)]
+mod phrase_source_tests {
+    use super::{IndexedPhrases, Phrase, Word};
+    use crate::sources::PhraseSource;
+
+    #[test]
+    fn should_label_phrases_with_their_source() {
+        let indexed_phrases = {
+            let mut ip = IndexedPhrases::new();
+            ip.insert_phrase(Phrase::from("hello there"));
+            ip.insert_phrase(Phrase::from("hello world").with_source(PhraseSource::Feed));
+            ip
+        };
+
+        let mut sources: Vec<_> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("hello"))
+            .unwrap()
+            .map(|phrase| {
+                (
+                    phrase.phrase_content,
+                    indexed_phrases.get_phrase_source(phrase),
+                )
+            })
+            .collect();
+        sources.sort_by_key(|&(phrase_content, _)| phrase_content);
+
+        assert_eq!(
+            sources,
+            &[
+                ("hello there", PhraseSource::Chat),
+                ("hello world", PhraseSource::Feed)
+            ]
+        );
+        assert!(indexed_phrases
+            .phrase_count_by_source()
+            .contains(&(PhraseSource::Feed, 1)));
+    }
+
+    #[test]
+    fn should_scale_phrase_weight_by_source_weight() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello world").with_source(PhraseSource::Feed));
+        indexed_phrases.set_source_weight(PhraseSource::Feed, 0.5);
+
+        let phrase = indexed_phrases
+            .get_phrases_with_word_in_common(Word("hello"))
+            .unwrap()
+            .next()
+            .unwrap();
+
+        assert_eq!(
+            indexed_phrases.get_phrase_weight(phrase),
+            indexed_phrases.get_phrase_quality(phrase) * 0.5
+        );
+    }
+}
+
+#[cfg(test)]
+mod junction_weighting_tests {
+    use super::{IndexedPhrases, JunctionDistribution, Phrase, Word};
+
+    /// Weights of the phrases with "cat", by the phrase.
+    fn weights_by_phrase(indexed_phrases: &IndexedPhrases) -> Vec<(&str, f32)> {
+        let mut weights: Vec<_> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("cat"))
+            .unwrap()
+            .map(|phrase| {
+                (
+                    phrase.phrase_content,
+                    indexed_phrases.get_phrase_weight(phrase),
+                )
+            })
+            .collect();
+        weights.sort_by(|a, b| a.0.cmp(b.0));
+        weights
+    }
+
+    fn index_phrases() -> IndexedPhrases {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("cat sat on the mat"));
+        indexed_phrases.insert_phrase(Phrase::from("the fat cat sat down"));
+        indexed_phrases
+    }
+
+    #[test]
+    fn should_weigh_every_junction_the_same_by_default() {
+        let indexed_phrases = index_phrases();
+        let weights = weights_by_phrase(&indexed_phrases);
+
+        assert_eq!(weights[0].1, weights[1].1);
     }
-}
 
-// FIXME(feroldi): You can always pass WordIndex around, as that is not a
-// problem.
-pub(crate) struct IndexedPhrases {
-    interned_texts: HashMap<String, usize>,
-    indexed_texts: Vec<String>,
-    indexed_phrases_by_word: HashMap<usize, HashSet<IndexedPhrase>>,
-}
+    #[test]
+    fn should_favor_junctions_near_the_middle() {
+        for junction_distribution in [
+            JunctionDistribution::Triangular,
+            JunctionDistribution::Normal,
+        ] {
+            let mut indexed_phrases = index_phrases();
+            indexed_phrases.set_junction_distribution(junction_distribution);
+
+            let weights = weights_by_phrase(&indexed_phrases);
+
+            assert_eq!(weights[0].0, "cat sat on the mat");
+            assert!(weights[0].1 > 0.0);
+            assert!(weights[0].1 < weights[1].1, "{:?}", junction_distribution);
+        }
+    }
 
-#[derive(PartialEq, Eq, Hash)]
-struct IndexedPhrase {
-    interned_phrase_index: usize,
-    word_pos_in_phrase: usize,
+    #[test]
+    fn should_weigh_junctions_symmetrically() {
+        for junction_distribution in [
+            JunctionDistribution::Triangular,
+            JunctionDistribution::Normal,
+        ] {
+            assert_eq!(
+                junction_distribution.weight(0.25),
+                junction_distribution.weight(0.75)
+            );
+            assert_eq!(junction_distribution.weight(0.5), 1.0);
+        }
+    }
 }
 
-#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
-pub(crate) struct IndexedPhraseContent<'s> {
-    phrase_content: &'s str,
-    word_pos_in_phrase: usize,
-}
+#[cfg(test)]
+mod frequency_weighting_tests {
+    use super::{IndexedPhrases, Phrase, Word};
+    use rand::SeedableRng;
 
-#[derive(PartialEq, Eq, Hash, Debug, Copy, Clone)]
-pub(crate) struct Word<'s>(&'s str);
+    #[test]
+    fn should_count_word_occurrences_including_duplicates() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello world"));
 
-impl std::ops::Deref for Word<'_> {
-    type Target = str;
+        let hello = indexed_phrases.get_word_id("hello").unwrap();
+        let world = indexed_phrases.get_word_id("world").unwrap();
 
-    fn deref(&self) -> &Self::Target {
-        self.0
+        assert_eq!(indexed_phrases.get_word_occurrences(hello), 3);
+        assert_eq!(indexed_phrases.get_word_occurrences(world), 1);
     }
-}
 
-#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
-pub(crate) struct WordIndex(usize);
+    #[test]
+    fn should_count_word_occurrences_of_merged_near_duplicates() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase_merging_near_duplicates(Phrase::from("hello there"), 0.9);
 
-impl IndexedPhrases {
-    pub(crate) fn new() -> IndexedPhrases {
-        IndexedPhrases {
-            interned_texts: HashMap::new(),
-            indexed_texts: Vec::new(),
-            indexed_phrases_by_word: HashMap::new(),
-        }
-    }
+        let there = indexed_phrases.get_word_id("there").unwrap();
 
-    pub(crate) fn get_common_words(&self) -> impl Iterator<Item = Word> {
-        self.indexed_phrases_by_word
-            .keys()
-            .map(|&key_index| Word(&self.indexed_texts[key_index]))
+        assert_eq!(indexed_phrases.get_word_occurrences(there), 2);
     }
 
-    // TODO(feroldi): Test this.
-    pub(crate) fn get_words_for_indices(&self, word_indices: &[WordIndex]) -> Vec<Word> {
-        let mut words = Vec::new();
+    #[test]
+    fn should_forget_word_occurrences_of_removed_phrase() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello world"));
+
+        indexed_phrases.remove_phrase("hello there");
 
-        for word_index in word_indices {
-            words.push(Word(&self.indexed_texts[word_index.0]))
-        }
+        let hello = indexed_phrases.get_word_id("hello").unwrap();
+        let there = indexed_phrases.get_word_id("there").unwrap();
 
-        words
+        assert_eq!(indexed_phrases.get_word_occurrences(hello), 1);
+        assert_eq!(indexed_phrases.get_word_occurrences(there), 0);
     }
 
-    // TODO(feroldi): Maybe return the words that were already interned?
-    // TODO(feroldi): Test the returned words.
-    pub(crate) fn insert_phrase(&mut self, phrase: Phrase) -> InsertionResult {
-        let phrase_content = String::from(phrase);
+    #[test]
+    fn should_weigh_every_word_the_same_without_temperature() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello world"));
 
-        if !phrase_content.contains(' ') {
-            let interned_word_index = self.intern_text(phrase_content);
-            return InsertionResult {
-                has_inserted_phrase: false,
-                word_indices_from_phrase: vec![WordIndex(interned_word_index)],
-            };
-        }
+        let hello = indexed_phrases.get_word_id("hello").unwrap();
+        let world = indexed_phrases.get_word_id("world").unwrap();
 
-        let interned_phrase_index = self.intern_text(phrase_content.clone());
-        let mut word_indices_from_phrase = Vec::new();
+        assert_eq!(indexed_phrases.get_word_weight(hello), 1.0);
+        assert_eq!(indexed_phrases.get_word_weight(world), 1.0);
+    }
 
-        let mut word_pos_in_phrase = 0;
-        for word in phrase_content.split_ascii_whitespace() {
-            let interned_word_index = self.intern_text(word.into());
+    #[test]
+    fn should_weigh_words_and_phrases_by_frequency_with_temperature() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello world"));
+        indexed_phrases.set_frequency_temperature(Some(0.5));
 
-            self.link_phrase_to_word(
-                interned_phrase_index,
-                interned_word_index,
-                word_pos_in_phrase,
-            );
+        let hello = indexed_phrases.get_word_id("hello").unwrap();
+        assert_eq!(indexed_phrases.get_word_weight(hello), 9.0);
 
-            // Adds one to the word length in order to consider the whitespace character
-            // after it.
-            word_pos_in_phrase += word.len() + 1;
+        let phrase = indexed_phrases
+            .get_phrases_with_word_in_common(Word("there"))
+            .unwrap()
+            .next()
+            .unwrap();
+        assert_eq!(
+            indexed_phrases.get_phrase_weight(phrase),
+            indexed_phrases.get_phrase_quality(phrase) * 4.0
+        );
+    }
 
-            word_indices_from_phrase.push(WordIndex(interned_word_index));
+    #[test]
+    fn should_favor_frequent_words_at_low_temperature() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        for _ in 0..50 {
+            indexed_phrases.insert_phrase(Phrase::from("hello there"));
         }
+        indexed_phrases.insert_phrase(Phrase::from("good morning"));
+        indexed_phrases.set_frequency_temperature(Some(0.1));
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
 
-        InsertionResult {
-            has_inserted_phrase: true,
-            word_indices_from_phrase,
+        for _ in 0..20 {
+            let word_id = indexed_phrases.get_random_common_word(&mut rng).unwrap();
+            let word = indexed_phrases.get_word(word_id).unwrap();
+
+            assert!(*word == *"hello" || *word == *"there");
         }
     }
 
-    pub(crate) fn get_phrases_with_word_in_common(
-        &self,
-        word: Word,
-    ) -> impl Iterator<Item = IndexedPhraseContent> {
-        let word_index = self.interned_texts.get(word.0);
+    #[test]
+    fn should_scale_weight_of_nerfed_words_until_lifted() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("good morning"));
+        indexed_phrases.set_word_nerf("hello", 0.0);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+        let hello = indexed_phrases.get_word_id("hello").unwrap();
+        assert_eq!(indexed_phrases.get_word_weight(hello), 0.0);
 
-        // This is always true, because the only way we can get a `Word` value is by
-        // calling `get_common_words()`, which returns indexed words from the very
-        // `phrase_indices_by_word` collection.
-        debug_assert!(word_index.is_some());
+        for _ in 0..20 {
+            let word_id = indexed_phrases.get_random_common_word(&mut rng).unwrap();
+            assert_ne!(word_id, hello);
+        }
 
-        let indexed_phrases_of_word = self.indexed_phrases_by_word.get(word_index.unwrap());
+        indexed_phrases.set_word_nerf("hello", 1.0);
+        assert_eq!(indexed_phrases.get_word_weight(hello), 1.0);
+    }
+}
 
-        // Always true for the same reason above.
-        // FIXME(feroldi): This is not true anymore, because now you're interning
-        // single-word phrases, but you aren't indexing them.
-        debug_assert!(indexed_phrases_of_word.is_some());
+#[cfg(test)]
+mod short_term_memory_tests {
+    use super::{IndexedPhrases, Phrase, Word};
 
-        indexed_phrases_of_word
+    fn phrase_weights(indexed_phrases: &IndexedPhrases) -> Vec<(&str, f32)> {
+        let mut weights: Vec<_> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("hello"))
             .unwrap()
-            .iter()
-            .map(|indexed_phrase| {
-                let phrase_content = &self.indexed_texts[indexed_phrase.interned_phrase_index];
-                IndexedPhraseContent {
-                    phrase_content,
-                    word_pos_in_phrase: indexed_phrase.word_pos_in_phrase,
-                }
+            .map(|phrase| {
+                (
+                    phrase.phrase_content,
+                    indexed_phrases.get_phrase_weight(phrase),
+                )
             })
+            .collect();
+        weights.sort_by_key(|&(phrase_content, _)| phrase_content);
+        weights
     }
 
-    fn intern_text(&mut self, text: String) -> usize {
-        *self.interned_texts.entry(text.clone()).or_insert_with(|| {
-            let new_index = self.indexed_texts.len();
-            self.indexed_texts.push(text);
-            new_index
-        })
-    }
+    #[test]
+    fn should_favor_short_term_phrases() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello world"));
+        indexed_phrases.mark_phrase_short_term("hello world", 100);
+        indexed_phrases.set_recency_bonus(3.0);
 
-    fn link_phrase_to_word(
-        &mut self,
-        phrase_index: usize,
-        word_index: usize,
-        word_pos_in_phrase: usize,
-    ) {
-        let phrase_indices = self
-            .indexed_phrases_by_word
-            .entry(word_index)
-            .or_insert_with(HashSet::new);
+        let weights = phrase_weights(&indexed_phrases);
 
-        phrase_indices.insert(IndexedPhrase {
-            interned_phrase_index: phrase_index,
-            word_pos_in_phrase,
-        });
+        assert_eq!(weights[1].0, "hello world");
+        assert_eq!(weights[1].1, weights[0].1 * 3.0);
     }
-}
 
-pub(crate) struct InsertionResult {
-    pub(crate) has_inserted_phrase: bool,
-    pub(crate) word_indices_from_phrase: Vec<WordIndex>,
-}
+    #[test]
+    fn should_fold_only_phrases_learned_before() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::from("hello world"));
+        indexed_phrases.mark_phrase_short_term("hello there", 100);
+        indexed_phrases.mark_phrase_short_term("hello world", 200);
 
-pub(crate) fn concatenate_indexed_phrases<'s>(
-    mut first_phrase: IndexedPhraseContent<'s>,
-    mut second_phrase: IndexedPhraseContent<'s>,
-) -> String {
-    if first_phrase.word_pos_in_phrase == 0
-        && !second_phrase.phrase_content[second_phrase.word_pos_in_phrase..].contains(' ')
-    {
-        std::mem::swap(&mut first_phrase, &mut second_phrase);
+        assert_eq!(indexed_phrases.fold_short_term_phrases(150), 1);
+
+        let short_term_phrases: Vec<_> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("hello"))
+            .unwrap()
+            .filter(|&phrase| indexed_phrases.is_phrase_short_term(phrase))
+            .map(|phrase| phrase.phrase_content)
+            .collect();
+        assert_eq!(short_term_phrases, ["hello world"]);
     }
 
-    let first_phrase_half = &first_phrase.phrase_content[..first_phrase.word_pos_in_phrase];
-    let second_phrase_half = &second_phrase.phrase_content[second_phrase.word_pos_in_phrase..];
+    #[test]
+    fn should_not_mark_unknown_phrase() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.mark_phrase_short_term("hello world", 100);
 
-    format!("{}{}", first_phrase_half, second_phrase_half)
+        assert_eq!(indexed_phrases.fold_short_term_phrases(i64::MAX), 0);
+    }
 }
 
 #[cfg(test)]
-mod normalization_tests {
-    use super::{normalize_text_into_phrases, Phrase};
+mod serialization_tests {
+    use super::{IndexedPhrases, Phrase, Word};
 
     #[test]
-    fn should_do_nothing_if_text_is_considered_to_be_normalized() {
-        let phrases = normalize_text_into_phrases("hello world".into());
+    fn should_keep_learned_phrases_but_not_settings() {
+        let mut indexed_phrases = IndexedPhrases::new();
+        indexed_phrases.insert_phrase(Phrase::from("hello there"));
+        indexed_phrases.insert_phrase(Phrase::with_terminator("hello world", '?'));
+        indexed_phrases.mark_phrase_short_term("hello world", 100);
+        indexed_phrases.set_recency_bonus(3.0);
+
+        let bytes = bincode::serialize(&indexed_phrases).unwrap();
+        let restored: IndexedPhrases = bincode::deserialize(&bytes).unwrap();
+
+        let mut phrases: Vec<_> = restored
+            .get_phrases_with_word_in_common(Word("hello"))
+            .unwrap()
+            .collect();
+        phrases.sort_by_key(|phrase| phrase.phrase_content);
 
-        assert_eq!(phrases, &[Phrase("hello world".into())]);
+        assert_eq!(phrases.len(), 2);
+        assert_eq!(phrases[1].phrase_content, "hello world");
+        assert_eq!(restored.get_phrase_terminator(phrases[1]), Some('?'));
+        assert!(!restored.is_phrase_short_term(phrases[1]));
     }
+}
 
-    #[test]
-    fn should_convert_to_lowercase() {
-        let phrases = normalize_text_into_phrases("HELLO WoRlD".into());
+#[cfg(test)]
+mod phrase_quality_tests {
+    use super::{IndexedPhrases, Phrase, Word};
+    use crate::scoring::{PhraseScorer, QualityScorer};
+
+    struct WordCountScorer;
 
-        assert_eq!(phrases, &[Phrase("hello world".into())]);
+    impl PhraseScorer for WordCountScorer {
+        fn score(&self, phrase: &Phrase) -> f32 {
+            phrase.as_ref().split_ascii_whitespace().count() as f32 / 10.0
+        }
     }
 
     #[test]
-    fn should_remove_extra_spaces() {
-        let phrases = normalize_text_into_phrases("   hello    world    ".into());
+    fn should_have_no_average_quality_if_no_phrase_was_indexed() {
+        let indexed_phrases = IndexedPhrases::new();
 
-        assert_eq!(phrases, &[Phrase("hello world".into())]);
+        assert_eq!(indexed_phrases.average_quality(), None);
     }
 
     #[test]
-    fn should_replace_punctuation_except_period_with_whitespace() {
-        let punctuations_except_period = ('\x00'..='\x7f')
-            .filter(|&c| c.is_ascii_punctuation())
-            .filter(|&c| c != '.' && c != ';')
-            .collect::<String>();
+    fn should_store_quality_score_of_inserted_phrases() {
+        let indexed_phrases = {
+            let mut ip = IndexedPhrases::with_quality_scorer(
+                QualityScorer::new().with_scorer(1.0, WordCountScorer),
+            );
+            ip.insert_phrase(Phrase::from("hello there"));
+            ip.insert_phrase(Phrase::from("hello there my friend"));
+            ip
+        };
 
-        let phrases = normalize_text_into_phrases(format!("foo{}bar", punctuations_except_period));
+        let qualities: Vec<_> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("friend"))
+            .unwrap()
+            .map(|phrase| indexed_phrases.get_phrase_quality(phrase))
+            .collect();
 
-        assert_eq!(phrases, &[Phrase("foo bar".into())]);
+        assert_eq!(qualities, &[0.4]);
+        assert_eq!(indexed_phrases.average_quality(), Some(0.3));
     }
 
     #[test]
-    fn should_split_text_at_period_punctuations() {
-        let phrases =
-            normalize_text_into_phrases("i think; therefore i am... it is hard to believe.".into());
+    fn should_not_score_single_word_phrases() {
+        let indexed_phrases = {
+            let mut ip = IndexedPhrases::new();
+            ip.insert_phrase(Phrase::from("hello"));
+            ip.insert_phrase(Phrase::from("hello there"));
+            ip
+        };
 
-        assert_eq!(
-            phrases,
-            &[
-                Phrase("i think".into()),
-                Phrase("therefore i am".into()),
-                Phrase("it is hard to believe".into())
-            ]
-        );
+        assert_eq!(indexed_phrases.phrase_count(), 1);
+        assert_eq!(indexed_phrases.word_count(), 2);
     }
 }
 
 #[cfg(test)]
-mod common_words_tests {
-    use super::{IndexedPhrases, Phrase, Word};
+mod near_duplicate_merging_tests {
+    use super::{phrase_similarity, IndexedPhrases, Phrase, Word};
     use std::collections::HashSet;
 
     #[test]
-    fn should_return_empty_vec_if_no_phrase_was_indexed() {
-        let indexed_phrases = IndexedPhrases::new();
-        let common_words: Vec<_> = indexed_phrases.get_common_words().collect();
-
-        assert_eq!(common_words, &[]);
+    fn should_compute_similarity_from_edit_distance() {
+        assert_eq!(phrase_similarity("hello", "hello"), 1.0);
+        assert_eq!(phrase_similarity("hello", "hallo"), 0.8);
+        assert_eq!(phrase_similarity("abc", "xyz"), 0.0);
+        assert_eq!(phrase_similarity("", ""), 1.0);
     }
 
     #[test]
-    fn should_return_empty_vec_if_indexed_phrase_has_only_one_word() {
+    fn should_merge_near_duplicate_into_canonical_phrase() {
         let mut indexed_phrases = IndexedPhrases::new();
 
-        indexed_phrases.insert_phrase(Phrase("hello".into()));
-        indexed_phrases.insert_phrase(Phrase("you".into()));
-        indexed_phrases.insert_phrase(Phrase("all".into()));
+        let first_res = indexed_phrases
+            .insert_phrase_merging_near_duplicates(Phrase::from("hello there friend"), 0.9);
+        let second_res = indexed_phrases
+            .insert_phrase_merging_near_duplicates(Phrase::from("hello there friends"), 0.9);
+        let third_res = indexed_phrases
+            .insert_phrase_merging_near_duplicates(Phrase::from("hello there friend"), 0.9);
 
-        let common_words: Vec<_> = indexed_phrases.get_common_words().collect();
+        assert!(first_res.has_inserted_phrase);
+        assert!(!second_res.has_inserted_phrase);
+        assert!(!third_res.has_inserted_phrase);
 
-        assert_eq!(common_words, &[]);
+        let phrases: Vec<_> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("hello"))
+            .unwrap()
+            .map(|phrase| (phrase.phrase_content, phrase.word_pos_in_phrase))
+            .collect();
+
+        assert_eq!(phrases, &[("hello there friend", 0)]);
+        assert_eq!(indexed_phrases.phrase_count(), 1);
+        assert_eq!(indexed_phrases.total_phrase_occurrences(), 3);
     }
 
     #[test]
-    fn should_return_deduplicated_words_from_phrases_with_two_or_more_words() {
+    fn should_match_near_duplicates_by_last_word() {
         let mut indexed_phrases = IndexedPhrases::new();
 
-        indexed_phrases.insert_phrase(Phrase("hello hello you all".into()));
-        indexed_phrases.insert_phrase(Phrase("nice".into()));
-        indexed_phrases.insert_phrase(Phrase("how are you all doing".into()));
+        indexed_phrases.insert_phrase_merging_near_duplicates(Phrase::from("what a nice day"), 0.8);
+        let insertion_res = indexed_phrases
+            .insert_phrase_merging_near_duplicates(Phrase::from("whta a nice day"), 0.8);
 
-        let common_words: HashSet<_> = indexed_phrases.get_common_words().collect();
+        assert!(!insertion_res.has_inserted_phrase);
+        assert_eq!(indexed_phrases.phrase_count(), 1);
+    }
+
+    #[test]
+    fn should_keep_phrases_that_are_not_similar_enough() {
+        let mut indexed_phrases = IndexedPhrases::new();
+
+        indexed_phrases
+            .insert_phrase_merging_near_duplicates(Phrase::from("hello there friend"), 0.9);
+        indexed_phrases
+            .insert_phrase_merging_near_duplicates(Phrase::from("hello there my old pal"), 0.9);
+
+        let phrases: HashSet<_> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("hello"))
+            .unwrap()
+            .map(|phrase| phrase.phrase_content)
+            .collect();
 
         assert_eq!(
-            common_words,
-            HashSet::from_iter(["hello", "you", "all", "how", "are", "doing"].map(Word))
+            phrases,
+            HashSet::from_iter(["hello there friend", "hello there my old pal"])
         );
     }
 }
 
 #[cfg(test)]
-mod retrieval_of_phrases_for_word_in_common_tests {
-    use super::{IndexedPhraseContent, IndexedPhrases, Phrase, Word};
-    use std::collections::HashSet;
+mod phrase_concatenation_tests {
+    use super::{
+        concatenate_indexed_phrases, concatenate_indexed_phrases_at_bigram,
+        normalize_text_into_phrases, IndexedPhrases, NormalizationConfig, Phrase, Word,
+    };
+    use std::collections::HashMap;
 
     #[test]
-    #[should_panic]
-    fn should_panic_if_word_is_unknown() {
-        let indexed_phrases = {
-            let mut ip = IndexedPhrases::new();
-            ip.insert_phrase(Phrase("hello there".into()));
-            ip
-        };
+    fn should_split_phrases_and_concatenate_at_the_word_in_common() {
+        let ip = index_texts(&[
+            "i have to go to the supermarket",
+            "does anyone need to go first",
+        ]);
 
-        let _: Vec<_> = indexed_phrases
-            .get_phrases_with_word_in_common(Word("hi"))
-            .collect();
+        assert_eq!(
+            splice_at_word(
+                &ip,
+                "go",
+                "i have to go to the supermarket",
+                "does anyone need to go first"
+            ),
+            "i have to go first"
+        );
+
+        assert_eq!(
+            splice_at_word(
+                &ip,
+                "go",
+                "does anyone need to go first",
+                "i have to go to the supermarket"
+            ),
+            "does anyone need to go to the supermarket"
+        );
     }
 
     #[test]
-    fn should_return_indexed_phrases_that_have_the_passed_word_in_common() {
+    fn should_splice_rtl_phrases_at_the_word_in_common() {
         let indexed_phrases = {
             let mut ip = IndexedPhrases::new();
-            ip.insert_phrase(Phrase("hello there friend".into()));
-            ip.insert_phrase(Phrase("hey friend what are you up to".into()));
-            ip.insert_phrase(Phrase("i have got lots of friends".into()));
-            ip.insert_phrase(Phrase("good evening".into()));
+            for text in ["\u{200f}אני הולך לים היום", "מחר הולך לעבודה?"]
+            {
+                for phrase in
+                    normalize_text_into_phrases(text.into(), &NormalizationConfig::default())
+                {
+                    ip.insert_phrase(phrase);
+                }
+            }
             ip
         };
 
-        let phrases: HashSet<_> = indexed_phrases
-            .get_phrases_with_word_in_common(Word("friend"))
+        let phrases: HashMap<_, _> = indexed_phrases
+            .get_phrases_with_word_in_common(Word("הולך"))
+            .unwrap()
+            .map(|phrase| (phrase.phrase_content, phrase))
             .collect();
 
         assert_eq!(
-            phrases,
-            HashSet::from_iter([
-                IndexedPhraseContent {
-                    phrase_content: "hello there friend",
-                    word_pos_in_phrase: 12,
-                },
-                IndexedPhraseContent {
-                    phrase_content: "hey friend what are you up to",
-                    word_pos_in_phrase: 4,
-                }
-            ])
+            concatenate_indexed_phrases(phrases["אני הולך לים היום"], phrases["מחר הולך לעבודה"]),
+            "אני הולך לעבודה"
         );
     }
 
     #[test]
-    fn should_not_duplicate_phrases() {
-        let indexed_phrases = {
-            let mut ip = IndexedPhrases::new();
-            ip.insert_phrase(Phrase("hello there friend".into()));
-            ip.insert_phrase(Phrase("hello there friend".into()));
-            ip.insert_phrase(Phrase("hello there friend".into()));
-            ip
+    fn should_join_phrases_at_different_words_of_a_bigram() {
+        let ip = index_texts(&["we love you", "cold pizza is great"]);
+        let phrase_with = |word, phrase_content| {
+            ip.get_phrases_with_word_in_common(Word(word))
+                .unwrap()
+                .find(|phrase| phrase.phrase_content == phrase_content)
+                .unwrap()
         };
 
-        let phrases: HashSet<_> = indexed_phrases
-            .get_phrases_with_word_in_common(Word("friend"))
+        assert_eq!(
+            concatenate_indexed_phrases_at_bigram(
+                phrase_with("love", "we love you"),
+                phrase_with("pizza", "cold pizza is great")
+            ),
+            "we love pizza is great"
+        );
+    }
+
+    #[test]
+    fn should_find_words_following_a_word() {
+        let ip = index_texts(&["we love pizza", "i love you", "love pizza", "no love"]);
+        let following_words: Vec<_> = ip
+            .get_following_word_ids(ip.get_word_id("love").unwrap())
+            .into_iter()
+            .map(|word_id| ip.get_word(word_id).unwrap().to_string())
+            .collect();
+
+        assert_eq!(following_words, &["pizza", "you"]);
+    }
+
+    fn index_texts(texts: &[&str]) -> IndexedPhrases {
+        let mut ip = IndexedPhrases::new();
+        for &text in texts {
+            for phrase in normalize_text_into_phrases(text.into(), &NormalizationConfig::default())
+            {
+                ip.insert_phrase(phrase);
+            }
+        }
+        ip
+    }
+
+    fn splice_at_word(ip: &IndexedPhrases, word: &str, first: &str, second: &str) -> String {
+        let phrases: HashMap<_, _> = ip
+            .get_phrases_with_word_in_common(Word(word))
+            .unwrap()
+            .map(|phrase| (phrase.phrase_content, phrase))
             .collect();
 
+        concatenate_indexed_phrases(phrases[first], phrases[second])
+    }
+
+    #[test]
+    fn should_splice_accented_phrases_at_the_word_in_common() {
+        let ip = index_texts(&["não é fácil viver aqui", "é difícil às vezes"]);
+
         assert_eq!(
-            phrases,
-            HashSet::from_iter([IndexedPhraseContent {
-                phrase_content: "hello there friend",
-                word_pos_in_phrase: 12,
-            }])
+            splice_at_word(&ip, "é", "não é fácil viver aqui", "é difícil às vezes"),
+            "não é difícil às vezes"
         );
     }
-}
-
-#[cfg(test)]
-mod phrase_concatenation_tests {
-    use super::{concatenate_indexed_phrases, IndexedPhraseContent};
 
     #[test]
-    fn should_split_phrases_and_concatenate_at_the_word_in_common() {
-        let phrase_a = IndexedPhraseContent {
-            phrase_content: "i have to go to the supermarket",
-            word_pos_in_phrase: 10,
-        };
+    fn should_splice_cyrillic_phrases_at_the_word_in_common() {
+        let ip = index_texts(&["Я иду домой сейчас", "мы идём домой вместе"]);
 
-        let phrase_b = IndexedPhraseContent {
-            phrase_content: "does anyone need to go first",
-            word_pos_in_phrase: 20,
-        };
+        assert_eq!(
+            splice_at_word(&ip, "домой", "я иду домой сейчас", "мы идём домой вместе"),
+            "я иду домой вместе"
+        );
+    }
+
+    #[test]
+    fn should_splice_phrases_with_emojis_at_the_word_in_common() {
+        let ip = index_texts(&["bom dia 🌞 pessoal", "boa noite 🌞 galera 🎉"]);
 
         assert_eq!(
-            concatenate_indexed_phrases(phrase_a, phrase_b),
-            "i have to go first"
+            splice_at_word(&ip, "🌞", "boa noite 🌞 galera 🎉", "bom dia 🌞 pessoal"),
+            "boa noite 🌞 pessoal"
         );
+    }
+
+    #[test]
+    fn should_splice_phrases_separated_by_irregular_whitespace_with_single_spaces() {
+        let mut ip = IndexedPhrases::new();
+        ip.insert_phrase(Phrase::from("só  mais\tum café"));
+        ip.insert_phrase(Phrase::from("um pão de queijo"));
 
         assert_eq!(
-            concatenate_indexed_phrases(phrase_b, phrase_a),
-            "does anyone need to go to the supermarket"
+            splice_at_word(&ip, "um", "só  mais\tum café", "um pão de queijo"),
+            "só mais um pão de queijo"
         );
     }
 
     #[test]
     fn should_swap_phrases_if_the_first_starts_with_word_and_the_second_ends_with_word() {
-        let phrase_a = IndexedPhraseContent {
-            phrase_content: "go to the supermarket",
-            word_pos_in_phrase: 0,
-        };
-
-        let phrase_b = IndexedPhraseContent {
-            phrase_content: "does anyone need to go",
-            word_pos_in_phrase: 20,
-        };
+        let ip = index_texts(&["go to the supermarket", "does anyone need to go"]);
 
-        let phrase_result = concatenate_indexed_phrases(phrase_a, phrase_b);
+        let phrase_result =
+            splice_at_word(&ip, "go", "go to the supermarket", "does anyone need to go");
 
         assert_eq!(phrase_result, "does anyone need to go to the supermarket");
     }
+
+    #[test]
+    fn should_splice_phrases_repeating_the_word_in_common_at_each_of_its_positions() {
+        let ip = index_texts(&["no no and no", "say no more"]);
+        let mut spliced_phrases: Vec<_> = ip
+            .get_phrases_with_word_in_common(Word("no"))
+            .unwrap()
+            .filter(|phrase| phrase.phrase_content == "no no and no")
+            .map(|phrase| {
+                let second_phrase = ip
+                    .get_phrases_with_word_in_common(Word("no"))
+                    .unwrap()
+                    .find(|phrase| phrase.phrase_content == "say no more")
+                    .unwrap();
+                concatenate_indexed_phrases(phrase, second_phrase)
+            })
+            .collect();
+        spliced_phrases.sort();
+
+        assert_eq!(
+            spliced_phrases,
+            &["no more", "no no and no more", "no no more"]
+        );
+    }
 }
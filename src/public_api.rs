@@ -0,0 +1,314 @@
+use crate::config::PublicApiConfig;
+use crate::error::{self, Error};
+use feroldinhobot::sources::RateCap;
+use hyper::body::Bytes;
+use hyper::header::{
+    HeaderMap, ACCESS_CONTROL_ALLOW_ORIGIN, CACHE_CONTROL, CONTENT_TYPE, RETRY_AFTER,
+};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+/// How many clients are tracked before forgetting the ones that went quiet.
+const MAX_TRACKED_CLIENTS: usize = 1024;
+/// How many candidates `GET /generate/stream` streams unless asked for more or
+/// fewer.
+const DEFAULT_STREAMED_CANDIDATES: usize = 10;
+/// Candidates generated but not streamed yet, past which generating waits for
+/// the client to catch up.
+const MAX_QUEUED_CANDIDATES: usize = 4;
+
+/// A reply generated for `GET /generate/stream` the way `GET /generate` makes
+/// them, along with how it's rated.
+pub(crate) struct Candidate {
+    pub(crate) text: String,
+    pub(crate) score: f32,
+}
+
+/// Limits how many requests each client address may make within a minute.
+struct ClientRateLimiter {
+    max_requests: usize,
+    rate_caps_by_client: HashMap<IpAddr, RateCap>,
+}
+
+impl ClientRateLimiter {
+    fn new(max_requests: usize) -> ClientRateLimiter {
+        ClientRateLimiter {
+            max_requests,
+            rate_caps_by_client: HashMap::new(),
+        }
+    }
+
+    fn try_allow(&mut self, client: IpAddr, now: Instant) -> bool {
+        if self.rate_caps_by_client.len() >= MAX_TRACKED_CLIENTS {
+            self.rate_caps_by_client
+                .retain(|_, rate_cap| !rate_cap.is_idle(now));
+        }
+
+        let max_requests = self.max_requests;
+
+        self.rate_caps_by_client
+            .entry(client)
+            .or_insert_with(|| RateCap::new(max_requests, RATE_LIMIT_WINDOW))
+            .try_allow(now)
+    }
+}
+
+/// The address of the client, which is the last one in `X-Forwarded-For` if
+/// the reverse proxy that sets it is trusted, as anything before it may have
+/// been made up by the client.
+fn client_ip(remote_ip: IpAddr, headers: &HeaderMap, trust_forwarded_for: bool) -> IpAddr {
+    if !trust_forwarded_for {
+        return remote_ip;
+    }
+
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .next_back()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|forwarded_ip| forwarded_ip.trim().parse().ok())
+        .unwrap_or(remote_ip)
+}
+
+fn response(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+
+    // Lets websites call the endpoint from their pages.
+    response
+        .headers_mut()
+        .insert(ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
+
+    response
+}
+
+/// How many candidates the query of `GET /generate/stream` asks for, e.g.
+/// `count=20`, up to `max_count`.
+fn requested_candidate_count(query: Option<&str>, max_count: usize) -> usize {
+    query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("count="))
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(DEFAULT_STREAMED_CANDIDATES)
+        .min(max_count)
+}
+
+/// A server-sent event, which ends with an empty line.
+fn server_sent_event(event: &str, data: &serde_json::Value) -> Bytes {
+    format!("event: {}\ndata: {}\n\n", event, data).into()
+}
+
+/// Streams the candidates as `candidate` events as they come, followed by a
+/// `done` event, and stops generating them as soon as the client goes away.
+fn stream_response(mut candidates: mpsc::Receiver<Candidate>) -> Response<Body> {
+    let (mut body_sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        while let Some(candidate) = candidates.recv().await {
+            let data = serde_json::json!({ "text": candidate.text, "score": candidate.score });
+
+            if body_sender
+                .send_data(server_sent_event("candidate", &data))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+
+        let _ = body_sender
+            .send_data(server_sent_event("done", &serde_json::json!({})))
+            .await;
+    });
+
+    let mut response = response(StatusCode::OK, body);
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, "text/event-stream".parse().unwrap());
+    headers.insert(CACHE_CONTROL, "no-cache".parse().unwrap());
+    response
+}
+
+async fn respond<F, S>(
+    request: Request<Body>,
+    client: IpAddr,
+    rate_limiter: &Mutex<ClientRateLimiter>,
+    max_streamed_candidates: usize,
+    generate: impl Fn() -> F,
+    stream_candidates: impl Fn(usize, mpsc::Sender<Candidate>) -> S,
+) -> Response<Body>
+where
+    F: Future<Output = Option<String>>,
+    S: Future<Output = ()> + Send + 'static,
+{
+    let is_stream = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/generate") => false,
+        (&Method::GET, "/generate/stream") => true,
+        _ => return response(StatusCode::NOT_FOUND, Body::empty()),
+    };
+
+    if !rate_limiter
+        .lock()
+        .unwrap()
+        .try_allow(client, Instant::now())
+    {
+        let mut response = response(StatusCode::TOO_MANY_REQUESTS, Body::empty());
+        response.headers_mut().insert(
+            RETRY_AFTER,
+            RATE_LIMIT_WINDOW.as_secs().to_string().parse().unwrap(),
+        );
+        return response;
+    }
+
+    if is_stream {
+        let count = requested_candidate_count(request.uri().query(), max_streamed_candidates);
+        let (sender, candidates) = mpsc::channel(MAX_QUEUED_CANDIDATES);
+        tokio::spawn(stream_candidates(count, sender));
+
+        return stream_response(candidates);
+    }
+
+    match generate().await {
+        Some(text) => {
+            let json = serde_json::json!({ "text": text }).to_string();
+            let mut response = response(StatusCode::OK, json);
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+            response
+        }
+        None => response(StatusCode::NO_CONTENT, Body::empty()),
+    }
+}
+
+/// Serves `GET /generate`, which answers with a phrase made by `generate`, or
+/// with nothing if it couldn't make any, and `GET /generate/stream`, which
+/// streams the candidates that `stream_candidates` sends, until the server
+/// fails.
+pub(crate) async fn serve<G, F, C, S>(
+    config: &PublicApiConfig,
+    generate: G,
+    stream_candidates: C,
+) -> error::Result<()>
+where
+    G: Fn() -> F + Send + Sync + 'static,
+    F: Future<Output = Option<String>> + Send + 'static,
+    C: Fn(usize, mpsc::Sender<Candidate>) -> S + Send + Sync + 'static,
+    S: Future<Output = ()> + Send + 'static,
+{
+    let rate_limiter = Arc::new(Mutex::new(ClientRateLimiter::new(
+        config.max_requests_per_minute,
+    )));
+    let generate = Arc::new(generate);
+    let stream_candidates = Arc::new(stream_candidates);
+    let trust_forwarded_for = config.trust_forwarded_for;
+    let max_streamed_candidates = config.max_streamed_candidates;
+
+    let make_service = make_service_fn(move |connection: &AddrStream| {
+        let remote_ip = connection.remote_addr().ip();
+        let rate_limiter = Arc::clone(&rate_limiter);
+        let generate = Arc::clone(&generate);
+        let stream_candidates = Arc::clone(&stream_candidates);
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let client = client_ip(remote_ip, request.headers(), trust_forwarded_for);
+                let rate_limiter = Arc::clone(&rate_limiter);
+                let generate = Arc::clone(&generate);
+                let stream_candidates = Arc::clone(&stream_candidates);
+
+                async move {
+                    let response = respond(
+                        request,
+                        client,
+                        &rate_limiter,
+                        max_streamed_candidates,
+                        &*generate,
+                        &*stream_candidates,
+                    )
+                    .await;
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+
+    let address = SocketAddr::new(config.bind_address, config.port);
+    let network_error = |source: hyper::Error| Error::Network {
+        context: "serving public API".into(),
+        source: source.into(),
+    };
+
+    let server = Server::try_bind(&address).map_err(network_error)?;
+    tracing::info!("serving public API on {}", address);
+
+    server.serve(make_service).await.map_err(network_error)
+}
+
+#[cfg(test)]
+mod public_api_tests {
+    use super::{
+        client_ip, requested_candidate_count, server_sent_event, ClientRateLimiter,
+        RATE_LIMIT_WINDOW,
+    };
+    use hyper::header::HeaderMap;
+    use std::net::IpAddr;
+    use std::time::Instant;
+
+    #[test]
+    fn should_limit_requests_of_each_client() {
+        let mut rate_limiter = ClientRateLimiter::new(2);
+        let first_client: IpAddr = "10.0.0.1".parse().unwrap();
+        let second_client: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(rate_limiter.try_allow(first_client, now));
+        assert!(rate_limiter.try_allow(first_client, now));
+        assert!(!rate_limiter.try_allow(first_client, now));
+        assert!(rate_limiter.try_allow(second_client, now));
+
+        assert!(rate_limiter.try_allow(first_client, now + RATE_LIMIT_WINDOW));
+    }
+
+    #[test]
+    fn should_only_trust_forwarded_address_if_asked_to() {
+        let remote_ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.2.3.4, 5.6.7.8".parse().unwrap());
+
+        assert_eq!(client_ip(remote_ip, &headers, false), remote_ip);
+        assert_eq!(
+            client_ip(remote_ip, &headers, true),
+            "5.6.7.8".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(client_ip(remote_ip, &HeaderMap::new(), true), remote_ip);
+    }
+
+    #[test]
+    fn should_cap_requested_candidate_count() {
+        assert_eq!(requested_candidate_count(Some("count=20"), 50), 20);
+        assert_eq!(requested_candidate_count(Some("seed=1&count=80"), 50), 50);
+        assert_eq!(requested_candidate_count(Some("count=many"), 50), 10);
+        assert_eq!(requested_candidate_count(None, 5), 5);
+    }
+
+    #[test]
+    fn should_format_server_sent_events() {
+        let data = serde_json::json!({ "text": "hello there", "score": 0.5 });
+
+        assert_eq!(
+            &server_sent_event("candidate", &data)[..],
+            b"event: candidate\ndata: {\"score\":0.5,\"text\":\"hello there\"}\n\n"
+        );
+    }
+}
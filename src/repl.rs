@@ -0,0 +1,149 @@
+use crate::error::{self, ResultExt};
+use crate::learn_filter;
+use crate::memory::Memory;
+use feroldinhobot::answer_pool::AnswerPoolCache;
+use feroldinhobot::generation::{self, SentenceConfig};
+use feroldinhobot::phrase_indexing::{self, IndexedPhrases, NormalizationConfig};
+use feroldinhobot::sources::PhraseSource;
+use rand::Rng;
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+
+/// Learns every line read from `input` into the memory, and writes what the bot
+/// would reply to it into `output`, until `input` runs out. What's learned is
+/// kept in memory only, so that trying things out never touches the database.
+pub(crate) fn run(
+    memory: &mut Memory,
+    input: impl BufRead,
+    mut output: impl Write,
+    now: i64,
+    rng: &mut impl Rng,
+) -> error::Result<()> {
+    let normalization_config = NormalizationConfig::default();
+    let sentence_config = SentenceConfig::default();
+
+    for line in input.lines() {
+        let line = line.context(|| "reading line".into())?;
+
+        let reply = reply_to(
+            &mut memory.indexed_phrases,
+            &memory.answer_pools,
+            &line,
+            &normalization_config,
+            &sentence_config,
+            now,
+            rng,
+        );
+
+        writeln!(output, "{}", reply.as_deref().unwrap_or("(no reply)"))
+            .context(|| "writing reply".into())?;
+    }
+
+    Ok(())
+}
+
+/// Learns the phrases of the text, the same way chat messages are learned, and
+/// generates a reply around their words.
+fn reply_to(
+    indexed_phrases: &mut IndexedPhrases,
+    answer_pools: &AnswerPoolCache,
+    text: &str,
+    normalization_config: &NormalizationConfig,
+    sentence_config: &SentenceConfig,
+    now: i64,
+    rng: &mut impl Rng,
+) -> Option<String> {
+    if let Err(junk_kind) = learn_filter::check_text(text) {
+        tracing::info!("not learning text, it looks like {}", junk_kind);
+        return None;
+    }
+
+    let mut word_ids_from_phrases = HashSet::new();
+    let mut incoming_phrases = Vec::new();
+
+    for phrase in phrase_indexing::normalize_text_into_phrases(text.into(), normalization_config) {
+        if learn_filter::check_phrase(phrase.as_ref()).is_err() {
+            continue;
+        }
+
+        incoming_phrases.push(phrase.as_ref().to_string());
+        let insertion_res = indexed_phrases.insert_phrase(phrase.with_source(PhraseSource::Chat));
+
+        answer_pools.invalidate(
+            indexed_phrases,
+            insertion_res.word_ids_from_phrase.iter().copied(),
+        );
+        word_ids_from_phrases.extend(insertion_res.word_ids_from_phrase);
+    }
+
+    let first_sentence = match generation::generate_phrase(
+        indexed_phrases,
+        answer_pools,
+        word_ids_from_phrases.into_iter().collect(),
+        &incoming_phrases
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>(),
+        now,
+        rng,
+    ) {
+        Ok(first_sentence) => first_sentence?,
+        Err(err) => {
+            error::report_error(&err.into());
+            return None;
+        }
+    };
+
+    Some(generation::extend_into_sentences(
+        indexed_phrases,
+        answer_pools,
+        first_sentence,
+        sentence_config,
+        now,
+        rng,
+    ))
+}
+
+#[cfg(test)]
+mod repl_tests {
+    use super::reply_to;
+    use feroldinhobot::answer_pool::{AnswerPoolCache, AnswerPoolConfig};
+    use feroldinhobot::generation::SentenceConfig;
+    use feroldinhobot::phrase_indexing::{IndexedPhrases, NormalizationConfig};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn reply_to_lines(lines: &[&str]) -> Vec<Option<String>> {
+        let mut indexed_phrases = IndexedPhrases::new();
+        let answer_pools = AnswerPoolCache::new(AnswerPoolConfig::default());
+        let mut rng = StdRng::seed_from_u64(42);
+
+        lines
+            .iter()
+            .map(|line| {
+                reply_to(
+                    &mut indexed_phrases,
+                    &answer_pools,
+                    line,
+                    &NormalizationConfig::default(),
+                    &SentenceConfig::default(),
+                    0,
+                    &mut rng,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_reply_with_what_was_learned_from_previous_lines() {
+        let replies = reply_to_lines(&["the cat sleeps all day", "my cat eats fish"]);
+
+        assert!(replies[1].as_ref().unwrap().contains("cat"));
+    }
+
+    #[test]
+    fn should_not_learn_junk_lines() {
+        let replies = reply_to_lines(&["sooooooooo goooooood", "sooooooooo goooooood"]);
+
+        assert_eq!(replies, [None, None]);
+    }
+}
@@ -0,0 +1,277 @@
+use crate::config::ReplyScheduleConfig;
+use crate::error::{self, Error};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use tbot::types::chat;
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const EVERY_DAY: u8 = 0b111_1111;
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+/// Days start from Monday, and unix time starts on a Thursday.
+const EPOCH_WEEKDAY: i64 = 3;
+
+/// A reply probability for some hours of some days of the week.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct ScheduleRule {
+    /// One bit for each day, from Monday on.
+    days: u8,
+    start_hour: u8,
+    /// Up to which hour the rule applies, which is before `start_hour` across
+    /// midnight.
+    end_hour: u8,
+    probability: f32,
+}
+
+impl ScheduleRule {
+    /// Parses a rule as written in `/schedule`, e.g. `sat,sun 10-23 0.2`.
+    pub(crate) fn parse(text: &str) -> error::Result<ScheduleRule> {
+        match text.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [days, hours, probability] => {
+                let probability = probability
+                    .parse()
+                    .map_err(|_| Error::parse("schedule probability", *probability))?;
+                ScheduleRule::new(days, hours, probability)
+            }
+            _ => Err(Error::parse("schedule rule", text)),
+        }
+    }
+
+    fn new(days: &str, hours: &str, probability: f32) -> error::Result<ScheduleRule> {
+        let parsed_days = parse_days(days).ok_or_else(|| Error::parse("schedule days", days))?;
+        let (start_hour, end_hour) =
+            parse_hours(hours).ok_or_else(|| Error::parse("schedule hours", hours))?;
+
+        if !(0.0..=1.0).contains(&probability) {
+            return Err(Error::parse(
+                "schedule probability",
+                probability.to_string(),
+            ));
+        }
+
+        Ok(ScheduleRule {
+            days: parsed_days,
+            start_hour,
+            end_hour,
+            probability,
+        })
+    }
+
+    /// Whether the rule applies at the hour of the weekday, counted from
+    /// Monday.
+    fn applies_at(&self, weekday: u32, hour: u8) -> bool {
+        let is_within_hours = if self.start_hour < self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        };
+
+        self.days & (1 << weekday) != 0 && is_within_hours
+    }
+}
+
+impl fmt::Display for ScheduleRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = if self.days == EVERY_DAY {
+            "*".to_string()
+        } else {
+            (0..DAY_NAMES.len())
+                .filter(|&day| self.days & (1 << day) != 0)
+                .map(|day| DAY_NAMES[day])
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        write!(
+            f,
+            "{} {}-{} {}",
+            days, self.start_hour, self.end_hour, self.probability
+        )
+    }
+}
+
+/// E.g. `*`, `mon-fri`, `fri-mon` or `sat,sun`.
+fn parse_days(days: &str) -> Option<u8> {
+    if days == "*" {
+        return Some(EVERY_DAY);
+    }
+
+    let day_of = |name: &str| DAY_NAMES.iter().position(|&day| day == name);
+
+    days.split(',').try_fold(0, |parsed_days, day_range| {
+        let (first_day, last_day) = match day_range.split_once('-') {
+            Some((first_day, last_day)) => (day_of(first_day)?, day_of(last_day)?),
+            None => (day_of(day_range)?, day_of(day_range)?),
+        };
+        let day_count = (last_day + DAY_NAMES.len() - first_day) % DAY_NAMES.len() + 1;
+
+        Some(
+            (first_day..first_day + day_count)
+                .fold(parsed_days, |days, day| days | 1 << (day % DAY_NAMES.len())),
+        )
+    })
+}
+
+/// E.g. `9-18`, `22-6` or `0-24`.
+fn parse_hours(hours: &str) -> Option<(u8, u8)> {
+    let (start_hour, end_hour) = hours.split_once('-')?;
+    let (start_hour, end_hour) = (start_hour.parse().ok()?, end_hour.parse().ok()?);
+
+    (start_hour < 24 && end_hour <= 24).then_some((start_hour, end_hour))
+}
+
+/// Reply probabilities by weekday and hour, of every chat and of each chat.
+#[derive(Default)]
+pub(crate) struct ReplySchedule {
+    /// How many seconds ahead of UTC the hours of the rules are.
+    utc_offset_secs: i64,
+    rules: Vec<ScheduleRule>,
+    configured_rules_by_chat: HashMap<chat::Id, Vec<ScheduleRule>>,
+    /// Rules set with `/schedule`, which take the place of those configured
+    /// for the chat.
+    scheduled_rules_by_chat: HashMap<chat::Id, Vec<ScheduleRule>>,
+}
+
+impl ReplySchedule {
+    pub(crate) fn from_config(config: &ReplyScheduleConfig) -> error::Result<ReplySchedule> {
+        let mut schedule = ReplySchedule {
+            utc_offset_secs: i64::from(config.utc_offset_hours) * 60 * 60,
+            ..ReplySchedule::default()
+        };
+
+        for rule_config in &config.rules {
+            let rule = ScheduleRule::new(
+                &rule_config.days,
+                &rule_config.hours,
+                rule_config.probability,
+            )?;
+
+            match rule_config.chat_id {
+                Some(chat_id) => schedule
+                    .configured_rules_by_chat
+                    .entry(chat::Id(chat_id))
+                    .or_default()
+                    .push(rule),
+                None => schedule.rules.push(rule),
+            }
+        }
+
+        Ok(schedule)
+    }
+
+    /// The reply probability of the chat at `now`, which is that of the last
+    /// rule of the chat that applies then, or else of the last rule of every
+    /// chat that does, or else `reply_prob`.
+    pub(crate) fn reply_probability(&self, chat_id: chat::Id, reply_prob: f32, now: i64) -> f32 {
+        let local_now = now + self.utc_offset_secs;
+        let weekday = (local_now.div_euclid(SECS_PER_DAY) + EPOCH_WEEKDAY).rem_euclid(7) as u32;
+        let hour = (local_now.rem_euclid(SECS_PER_DAY) / (60 * 60)) as u8;
+
+        let applies = |rule: &&ScheduleRule| rule.applies_at(weekday, hour);
+
+        self.rules_of(chat_id)
+            .iter()
+            .rev()
+            .find(applies)
+            .or_else(|| self.rules.iter().rev().find(applies))
+            .map_or(reply_prob, |rule| rule.probability)
+    }
+
+    /// The rules only for the chat.
+    pub(crate) fn rules_of(&self, chat_id: chat::Id) -> &[ScheduleRule] {
+        self.scheduled_rules_by_chat
+            .get(&chat_id)
+            .or_else(|| self.configured_rules_by_chat.get(&chat_id))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Replaces the rules only for the chat.
+    pub(crate) fn set_rules_of(&mut self, chat_id: chat::Id, rules: Vec<ScheduleRule>) {
+        self.scheduled_rules_by_chat.insert(chat_id, rules);
+    }
+
+    /// The rules set with `/schedule`, as they're saved.
+    pub(crate) fn scheduled_rules(&self) -> BTreeMap<i64, Vec<String>> {
+        self.scheduled_rules_by_chat
+            .iter()
+            .map(|(chat_id, rules)| (chat_id.0, rules.iter().map(ToString::to_string).collect()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod schedule_tests {
+    use super::{ReplySchedule, ScheduleRule};
+    use crate::config::{ReplyScheduleConfig, ScheduleRuleConfig};
+    use tbot::types::chat;
+
+    #[test]
+    fn should_parse_rules_as_they_are_written() {
+        for rule in [
+            "sat,sun 10-23 0.2",
+            "mon,tue,wed,thu,fri 22-6 0",
+            "* 0-24 0.5",
+        ] {
+            assert_eq!(ScheduleRule::parse(rule).unwrap().to_string(), rule);
+        }
+
+        assert_eq!(
+            ScheduleRule::parse("fri-mon 9-18 0.1").unwrap().to_string(),
+            "mon,fri,sat,sun 9-18 0.1"
+        );
+        assert!(ScheduleRule::parse("sat 9-25 0.1").is_err());
+        assert!(ScheduleRule::parse("someday 9-18 0.1").is_err());
+        assert!(ScheduleRule::parse("sat 9-18 2").is_err());
+    }
+
+    #[test]
+    fn should_pick_probability_of_last_rule_applying_at_local_time() {
+        let rule = |chat_id, days: &str, hours: &str, probability| ScheduleRuleConfig {
+            chat_id,
+            days: days.into(),
+            hours: hours.into(),
+            probability,
+        };
+        let mut schedule = ReplySchedule::from_config(&ReplyScheduleConfig {
+            utc_offset_hours: -3,
+            rules: vec![
+                rule(None, "*", "0-24", 0.1),
+                rule(None, "sat,sun", "10-23", 0.2),
+                rule(Some(-100123), "mon-fri", "22-6", 0.0),
+            ],
+        })
+        .unwrap();
+
+        // Saturday, 2024-01-06, at 12h UTC, which is 9h locally.
+        let saturday_morning = 1_704_542_400;
+        // Friday, 2024-01-05, at 2h UTC, which is Thursday at 23h locally.
+        let thursday_night = 1_704_420_000;
+        let (chat_id, other_chat_id) = (chat::Id(-100123), chat::Id(-100456));
+
+        assert_eq!(
+            schedule.reply_probability(other_chat_id, 0.5, saturday_morning),
+            0.1
+        );
+        assert_eq!(
+            schedule.reply_probability(other_chat_id, 0.5, saturday_morning + 60 * 60),
+            0.2
+        );
+        assert_eq!(
+            schedule.reply_probability(chat_id, 0.5, thursday_night),
+            0.0
+        );
+        assert_eq!(
+            schedule.reply_probability(other_chat_id, 0.5, thursday_night),
+            0.1
+        );
+
+        schedule.set_rules_of(chat_id, Vec::new());
+        assert_eq!(
+            schedule.reply_probability(chat_id, 0.5, thursday_night),
+            0.1
+        );
+        assert_eq!(
+            ReplySchedule::default().reply_probability(chat_id, 0.5, thursday_night),
+            0.5
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use crate::phrase_indexing::Phrase;
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
+
+/// Rates a phrase in the `[0, 1]` range, where higher means a better source for
+/// generation.
+pub trait PhraseScorer: Send + Sync {
+    fn score(&self, phrase: &Phrase) -> f32;
+}
+
+/// Combines several scorers into a single weighted average.
+///
+/// This is shared between the learning path, which stores the resulting score
+/// alongside each phrase, and the learn filters, which reject phrases scoring
+/// below some threshold.
+pub struct QualityScorer {
+    weighted_scorers: Vec<(f32, Box<dyn PhraseScorer>)>,
+}
+
+impl QualityScorer {
+    pub fn new() -> QualityScorer {
+        QualityScorer {
+            weighted_scorers: Vec::new(),
+        }
+    }
+
+    pub fn with_scorer(mut self, weight: f32, scorer: impl PhraseScorer + 'static) -> Self {
+        self.weighted_scorers.push((weight, Box::new(scorer)));
+        self
+    }
+
+    pub fn score(&self, phrase: &Phrase) -> f32 {
+        let total_weight: f32 = self.weighted_scorers.iter().map(|(w, _)| w).sum();
+
+        if total_weight <= 0.0 {
+            return 1.0;
+        }
+
+        let weighted_sum: f32 = self
+            .weighted_scorers
+            .iter()
+            .map(|(weight, scorer)| weight * scorer.score(phrase).clamp(0.0, 1.0))
+            .sum();
+
+        weighted_sum / total_weight
+    }
+}
+
+impl Default for QualityScorer {
+    fn default() -> Self {
+        QualityScorer::new()
+            .with_scorer(1.0, LengthScorer::default())
+            .with_scorer(1.0, DictionaryWordScorer)
+            .with_scorer(1.0, RepetitionScorer)
+    }
+}
+
+/// Prefers phrases whose word count falls inside an ideal range, penalizing
+/// phrases that are too short to splice or too long to read.
+pub struct LengthScorer {
+    ideal_word_count: RangeInclusive<usize>,
+}
+
+impl LengthScorer {
+    pub fn new(ideal_word_count: RangeInclusive<usize>) -> LengthScorer {
+        LengthScorer { ideal_word_count }
+    }
+}
+
+impl Default for LengthScorer {
+    fn default() -> Self {
+        LengthScorer::new(3..=20)
+    }
+}
+
+impl PhraseScorer for LengthScorer {
+    fn score(&self, phrase: &Phrase) -> f32 {
+        let word_count = phrase.as_ref().split_ascii_whitespace().count();
+
+        if word_count < *self.ideal_word_count.start() {
+            word_count as f32 / *self.ideal_word_count.start() as f32
+        } else if word_count > *self.ideal_word_count.end() {
+            *self.ideal_word_count.end() as f32 / word_count as f32
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Ratio of words that look like they could come from a dictionary, as opposed
+/// to numbers, links leftovers or keyboard mashing.
+pub struct DictionaryWordScorer;
+
+const MAX_DICTIONARY_WORD_LEN: usize = 20;
+
+impl DictionaryWordScorer {
+    fn is_dictionary_ish(word: &str) -> bool {
+        let char_count = word.chars().count();
+
+        char_count <= MAX_DICTIONARY_WORD_LEN
+            && word.chars().all(char::is_alphabetic)
+            && (!word.is_ascii() || word.contains(['a', 'e', 'i', 'o', 'u', 'y']))
+    }
+}
+
+impl PhraseScorer for DictionaryWordScorer {
+    fn score(&self, phrase: &Phrase) -> f32 {
+        let words: Vec<_> = phrase.as_ref().split_ascii_whitespace().collect();
+
+        if words.is_empty() {
+            return 0.0;
+        }
+
+        let dictionary_ish_count = words
+            .iter()
+            .filter(|word| DictionaryWordScorer::is_dictionary_ish(word))
+            .count();
+
+        dictionary_ish_count as f32 / words.len() as f32
+    }
+}
+
+/// Ratio of distinct words in the phrase, so that "spam spam spam spam" scores
+/// low.
+pub struct RepetitionScorer;
+
+impl PhraseScorer for RepetitionScorer {
+    fn score(&self, phrase: &Phrase) -> f32 {
+        let words: Vec<_> = phrase.as_ref().split_ascii_whitespace().collect();
+
+        if words.is_empty() {
+            return 0.0;
+        }
+
+        let distinct_words: HashSet<_> = words.iter().collect();
+
+        distinct_words.len() as f32 / words.len() as f32
+    }
+}
+
+#[cfg(test)]
+mod quality_scorer_tests {
+    use super::{
+        DictionaryWordScorer, LengthScorer, PhraseScorer, QualityScorer, RepetitionScorer,
+    };
+    use crate::phrase_indexing::Phrase;
+
+    struct ConstantScorer(f32);
+
+    impl PhraseScorer for ConstantScorer {
+        fn score(&self, _: &Phrase) -> f32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn should_score_one_if_there_are_no_scorers() {
+        let scorer = QualityScorer::new();
+
+        assert_eq!(scorer.score(&Phrase::from("hello world")), 1.0);
+    }
+
+    #[test]
+    fn should_compute_weighted_average_of_scorers() {
+        let scorer = QualityScorer::new()
+            .with_scorer(3.0, ConstantScorer(1.0))
+            .with_scorer(1.0, ConstantScorer(0.0));
+
+        assert_eq!(scorer.score(&Phrase::from("hello world")), 0.75);
+    }
+
+    #[test]
+    fn should_penalize_phrases_outside_of_ideal_length() {
+        let scorer = LengthScorer::new(2..=4);
+
+        assert_eq!(scorer.score(&Phrase::from("hello")), 0.5);
+        assert_eq!(scorer.score(&Phrase::from("hello there")), 1.0);
+        assert_eq!(scorer.score(&Phrase::from("a b c d e f g h")), 0.5);
+    }
+
+    #[test]
+    fn should_penalize_words_that_dont_look_like_dictionary_words() {
+        let scorer = DictionaryWordScorer;
+
+        assert_eq!(scorer.score(&Phrase::from("hello there")), 1.0);
+        assert_eq!(scorer.score(&Phrase::from("não é")), 1.0);
+        assert_eq!(scorer.score(&Phrase::from("hello 1234 xzcvb world")), 0.5);
+    }
+
+    #[test]
+    fn should_penalize_repeated_words() {
+        let scorer = RepetitionScorer;
+
+        assert_eq!(scorer.score(&Phrase::from("how are you")), 1.0);
+        assert_eq!(scorer.score(&Phrase::from("spam spam spam spam")), 0.25);
+    }
+}
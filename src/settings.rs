@@ -0,0 +1,71 @@
+use crate::error::{self, Error, ResultExt};
+use crate::store;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Small pieces of state that must survive restarts, which are kept as a JSON
+/// object and saved whenever one of them changes.
+pub(crate) struct SettingsStore {
+    path: PathBuf,
+    values: BTreeMap<String, serde_json::Value>,
+}
+
+impl SettingsStore {
+    /// Loads the settings saved at `path`, or starts without settings if
+    /// there's no such file yet.
+    pub(crate) fn load(path: impl Into<PathBuf>) -> error::Result<SettingsStore> {
+        let path = path.into();
+
+        let values = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|_| Error::parse("settings", path.display().to_string()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                return Err(err).context(|| format!("reading settings `{}`", path.display()))
+            }
+        };
+
+        Ok(SettingsStore { path, values })
+    }
+
+    pub(crate) fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.values
+            .get(key)
+            .and_then(|value| T::deserialize(value).ok())
+    }
+
+    pub(crate) fn set<T: Serialize>(&mut self, key: &str, value: T) -> error::Result<()> {
+        let value = serde_json::to_value(value).expect("settings are serializable");
+        self.values.insert(key.into(), value);
+
+        let json = serde_json::to_vec_pretty(&self.values).expect("settings are serializable");
+
+        store::replace_file(&self.path, |file| file.write_all(&json))
+            .context(|| format!("writing settings `{}`", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod settings_store_tests {
+    use super::SettingsStore;
+
+    #[test]
+    fn should_keep_settings_across_restarts() {
+        let path = std::env::temp_dir().join(format!("settings_{}.json", std::process::id()));
+        let mut settings = SettingsStore::load(&path).unwrap();
+
+        assert_eq!(settings.get::<isize>("update_offset"), None);
+
+        settings.set("update_offset", 42).unwrap();
+        settings.set("name", "bot").unwrap();
+
+        let settings = SettingsStore::load(&path).unwrap();
+        assert_eq!(settings.get::<isize>("update_offset"), Some(42));
+        assert_eq!(settings.get::<String>("name"), Some("bot".into()));
+        assert_eq!(settings.get::<isize>("name"), None);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+use crate::error::{self, Error, ResultExt};
+use crate::store::FlatFileStore;
+use std::future::Future;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use tbot::Bot;
+
+const TOKEN_ENV_VAR: &str = "BOT_TOKEN";
+const ENV_FILE_PATH: &str = ".env";
+
+/// What the wizard asked for.
+#[derive(PartialEq, Debug)]
+struct SetupAnswers {
+    token: String,
+    data_dir: PathBuf,
+    reply_probability: f32,
+    per_chat_memory: bool,
+    mentions_only: bool,
+}
+
+/// Asks the question, returning the trimmed answer, or `default` if the answer
+/// is empty. Fails if `input` runs out.
+fn ask(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    question: &str,
+    default: &str,
+) -> error::Result<String> {
+    if default.is_empty() {
+        write!(output, "{}: ", question)
+    } else {
+        write!(output, "{} [{}]: ", question, default)
+    }
+    .and_then(|()| output.flush())
+    .context(|| "writing question".into())?;
+
+    let mut answer = String::new();
+    let read_byte_count = input
+        .read_line(&mut answer)
+        .context(|| "reading answer".into())?;
+
+    if read_byte_count == 0 {
+        return Err(Error::parse("answer", question));
+    }
+
+    match answer.trim() {
+        "" => Ok(default.into()),
+        answer => Ok(answer.into()),
+    }
+}
+
+fn ask_yes_no(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    question: &str,
+) -> error::Result<bool> {
+    loop {
+        match ask(input, output, &format!("{} (y/n)", question), "n")?
+            .to_lowercase()
+            .as_str()
+        {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => writeln!(output, "please answer y or n").context(|| "writing hint".into())?,
+        }
+    }
+}
+
+/// Asks everything the bot needs to get going, checking the token with
+/// `check_token`, which returns the username of the bot.
+async fn ask_answers<F>(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    check_token: impl Fn(String) -> F,
+) -> error::Result<SetupAnswers>
+where
+    F: Future<Output = error::Result<String>>,
+{
+    let token = loop {
+        let token = ask(input, output, "Bot token, as given by @BotFather", "")?;
+
+        if token.is_empty() {
+            continue;
+        }
+
+        match check_token(token.clone()).await {
+            Ok(username) => {
+                writeln!(output, "found bot @{}", username).context(|| "writing bot".into())?;
+                break token;
+            }
+            Err(err) => writeln!(output, "couldn't use that token: {}", err)
+                .context(|| "writing token error".into())?,
+        }
+    };
+
+    let data_dir = ask(input, output, "Directory to keep data in", "data")?.into();
+
+    let reply_probability = loop {
+        let answer = ask(
+            input,
+            output,
+            "Probability of replying to a message on its own, from 0 to 1",
+            "0.05",
+        )?;
+
+        match answer.parse::<f32>() {
+            Ok(probability) if (0.0..=1.0).contains(&probability) => break probability,
+            _ => writeln!(output, "please answer a number from 0 to 1")
+                .context(|| "writing hint".into())?,
+        }
+    };
+
+    let per_chat_memory = ask_yes_no(
+        input,
+        output,
+        "Keep what each chat says to that chat, rather than sharing it between chats?",
+    )?;
+    let mentions_only = ask_yes_no(input, output, "Only reply when mentioned or replied to?")?;
+
+    Ok(SetupAnswers {
+        token,
+        data_dir,
+        reply_probability,
+        per_chat_memory,
+        mentions_only,
+    })
+}
+
+fn toml_string(value: &str) -> String {
+    toml::Value::String(value.into()).to_string()
+}
+
+/// The config the answers make up, which leaves the token to `TOKEN_ENV_VAR`.
+fn config_toml(answers: &SetupAnswers) -> String {
+    let data_path =
+        |file_name: &str| toml_string(&answers.data_dir.join(file_name).to_string_lossy());
+
+    format!(
+        "# Made by `feroldinhobot init`. See config.example.toml for everything else\n\
+         # that can be set up.\n\
+         token_env_var = {}\n\
+         database_path = {}\n\
+         database_kind = \"flat-file\"\n\
+         memory_scope = {}\n\
+         reply_probability = {}\n\
+         reply_to_mentions_only = {}\n\
+         settings_path = {}\n\
+         favorites_path = {}\n\
+         pending_changes_path = {}\n\
+         short_term_memory_path = {}\n\
+         word_trends_path = {}\n\
+         contributions_path = {}\n",
+        toml_string(TOKEN_ENV_VAR),
+        data_path("bot_memory.txt"),
+        toml_string(if answers.per_chat_memory {
+            "per-chat"
+        } else {
+            "global"
+        }),
+        answers.reply_probability,
+        answers.mentions_only,
+        data_path("settings.json"),
+        data_path("favorite_replies.json"),
+        data_path("pending_changes.json"),
+        data_path("short_term_memory.json"),
+        data_path("word_trends.json"),
+        data_path("contributions.json"),
+    )
+}
+
+/// Writes a file only the current user may read, as it holds the token.
+fn write_private_file(path: &Path, contents: &str) -> error::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .context(|| format!("writing `{}`", path.display()))
+}
+
+/// Writes the config, the token and an empty database.
+fn apply_answers(answers: &SetupAnswers, config_path: &Path) -> error::Result<()> {
+    std::fs::create_dir_all(&answers.data_dir)
+        .context(|| format!("creating `{}`", answers.data_dir.display()))?;
+    FlatFileStore::create_if_missing(answers.data_dir.join("bot_memory.txt"))?;
+
+    std::fs::write(config_path, config_toml(answers))
+        .context(|| format!("writing `{}`", config_path.display()))?;
+
+    write_private_file(
+        Path::new(ENV_FILE_PATH),
+        &format!("{}={}\n", TOKEN_ENV_VAR, answers.token),
+    )
+}
+
+async fn fetch_bot_username(token: String) -> error::Result<String> {
+    let me = Bot::new(token)
+        .get_me()
+        .call()
+        .await
+        .context(|| "checking the bot token".into())?;
+
+    Ok(me.user.username.unwrap_or(me.user.first_name))
+}
+
+/// Walks through setting up the bot from the terminal, writing the config at
+/// `config_path`, the token into `.env`, and an empty database.
+pub(crate) async fn run(config_path: &Path) -> error::Result<()> {
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+    let mut output = std::io::stdout();
+
+    if config_path.exists()
+        && !ask_yes_no(
+            &mut input,
+            &mut output,
+            &format!("`{}` already exists, overwrite it?", config_path.display()),
+        )?
+    {
+        return Ok(());
+    }
+
+    let answers = ask_answers(&mut input, &mut output, fetch_bot_username).await?;
+    apply_answers(&answers, config_path)?;
+
+    writeln!(
+        output,
+        "all set! load `{}` into the environment and start the bot, e.g. with\n  \
+         set -a; . ./{}; set +a; feroldinhobot",
+        ENV_FILE_PATH, ENV_FILE_PATH
+    )
+    .context(|| "writing instructions".into())
+}
+
+#[cfg(test)]
+mod setup_tests {
+    use super::{ask_answers, config_toml, SetupAnswers};
+    use crate::config::Config;
+    use crate::error::{self, Error};
+    use crate::memory::MemoryScope;
+    use std::io::Cursor;
+
+    async fn check_token(token: String) -> error::Result<String> {
+        match token.as_str() {
+            "123:good" => Ok("feroldinhobot".into()),
+            _ => Err(Error::parse("bot token", token)),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_ask_again_until_answers_are_valid() {
+        let mut input = Cursor::new("bad\n123:good\n\n2\n0.5\nmaybe\ny\n\n");
+        let mut output = Vec::new();
+
+        let answers = ask_answers(&mut input, &mut output, check_token)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            answers,
+            SetupAnswers {
+                token: "123:good".into(),
+                data_dir: "data".into(),
+                reply_probability: 0.5,
+                per_chat_memory: true,
+                mentions_only: false,
+            }
+        );
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("found bot @feroldinhobot"));
+    }
+
+    #[tokio::test]
+    async fn should_fail_if_input_runs_out() {
+        let mut input = Cursor::new("123:good\n");
+
+        assert!(ask_answers(&mut input, &mut Vec::new(), check_token)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn should_write_config_from_answers() {
+        let answers = SetupAnswers {
+            token: "123:good".into(),
+            data_dir: "bot data".into(),
+            reply_probability: 0.1,
+            per_chat_memory: true,
+            mentions_only: true,
+        };
+
+        let config: Config = toml::from_str(&config_toml(&answers)).unwrap();
+
+        assert_eq!(config.token_env_var, "BOT_TOKEN");
+        assert_eq!(
+            config.database_path,
+            std::path::Path::new("bot data/bot_memory.txt")
+        );
+        assert_eq!(config.memory_scope, MemoryScope::PerChat);
+        assert_eq!(config.reply_probability, 0.1);
+        assert!(config.reply_to_mentions_only);
+    }
+}
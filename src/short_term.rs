@@ -0,0 +1,196 @@
+use crate::error::{self, Error, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tbot::types::chat;
+
+/// How long phrases stay in short-term memory before being folded into
+/// long-term memory.
+pub(crate) const SHORT_TERM_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct ShortTermPhrase {
+    /// Chat whose memory the phrase was learned into, or `None` for the shared
+    /// memory.
+    chat_id: Option<i64>,
+    phrase: String,
+    learned_at: i64,
+}
+
+/// The phrases in short-term memory, which the phrase stores don't keep track
+/// of, so that they're put back there after restarting. They're saved to a
+/// JSON file whenever they change, unless there's no file to save them to.
+#[derive(Default)]
+pub(crate) struct ShortTermLog {
+    path: Option<PathBuf>,
+    phrases: Vec<ShortTermPhrase>,
+}
+
+impl ShortTermLog {
+    /// Loads the phrases saved at `path`, or starts without phrases if there's
+    /// no such file yet.
+    pub(crate) fn load(path: impl Into<PathBuf>) -> error::Result<ShortTermLog> {
+        let path = path.into();
+
+        let phrases = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|_| Error::parse("short-term memory", path.display().to_string()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(err)
+                    .context(|| format!("reading short-term memory `{}`", path.display()))
+            }
+        };
+
+        Ok(ShortTermLog {
+            path: Some(path),
+            phrases,
+        })
+    }
+
+    /// Records the phrases as learned at `learned_at` into the memory of the
+    /// chat, or into the shared memory if there's no chat.
+    pub(crate) fn record(
+        &mut self,
+        chat_id: Option<chat::Id>,
+        phrases: &[String],
+        learned_at: i64,
+    ) -> error::Result<()> {
+        let chat_id = chat_id.map(|chat_id| chat_id.0);
+
+        self.phrases.retain(|short_term_phrase| {
+            short_term_phrase.chat_id != chat_id || !phrases.contains(&short_term_phrase.phrase)
+        });
+        self.phrases
+            .extend(phrases.iter().map(|phrase| ShortTermPhrase {
+                chat_id,
+                phrase: phrase.clone(),
+                learned_at,
+            }));
+
+        self.save()
+    }
+
+    /// The phrases of the memory of the chat, or of the shared memory if
+    /// there's no chat, along with when they were learned.
+    pub(crate) fn phrases_of(
+        &self,
+        chat_id: Option<chat::Id>,
+    ) -> impl Iterator<Item = (&str, i64)> {
+        let chat_id = chat_id.map(|chat_id| chat_id.0);
+
+        self.phrases
+            .iter()
+            .filter(move |short_term_phrase| short_term_phrase.chat_id == chat_id)
+            .map(|short_term_phrase| {
+                (
+                    short_term_phrase.phrase.as_str(),
+                    short_term_phrase.learned_at,
+                )
+            })
+    }
+
+    /// Forgets the phrases learned before `learned_before`.
+    pub(crate) fn fold(&mut self, learned_before: i64) -> error::Result<()> {
+        let phrase_count = self.phrases.len();
+
+        self.phrases
+            .retain(|short_term_phrase| short_term_phrase.learned_at >= learned_before);
+
+        if self.phrases.len() == phrase_count {
+            return Ok(());
+        }
+
+        self.save()
+    }
+
+    fn save(&self) -> error::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let json = serde_json::to_vec(&self.phrases).expect("phrases are serializable");
+
+        std::fs::write(path, json)
+            .context(|| format!("writing short-term memory `{}`", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod short_term_log_tests {
+    use super::ShortTermLog;
+    use std::path::PathBuf;
+    use tbot::types::chat;
+
+    fn temp_short_term_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "short_term_memory_{}_{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn should_keep_phrases_across_restarts() {
+        let path = temp_short_term_path("restart");
+        let mut short_term_log = ShortTermLog::load(&path).unwrap();
+
+        short_term_log
+            .record(Some(chat::Id(1)), &["hello there".into()], 100)
+            .unwrap();
+        short_term_log
+            .record(None, &["hello world".into()], 200)
+            .unwrap();
+
+        let short_term_log = ShortTermLog::load(&path).unwrap();
+        assert_eq!(
+            short_term_log
+                .phrases_of(Some(chat::Id(1)))
+                .collect::<Vec<_>>(),
+            [("hello there", 100)]
+        );
+        assert_eq!(
+            short_term_log.phrases_of(None).collect::<Vec<_>>(),
+            [("hello world", 200)]
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_keep_latest_time_phrase_was_learned() {
+        let mut short_term_log = ShortTermLog::default();
+
+        short_term_log
+            .record(None, &["hello there".into()], 100)
+            .unwrap();
+        short_term_log
+            .record(None, &["hello there".into()], 200)
+            .unwrap();
+
+        assert_eq!(
+            short_term_log.phrases_of(None).collect::<Vec<_>>(),
+            [("hello there", 200)]
+        );
+    }
+
+    #[test]
+    fn should_fold_phrases_learned_before() {
+        let mut short_term_log = ShortTermLog::default();
+
+        short_term_log
+            .record(None, &["hello there".into()], 100)
+            .unwrap();
+        short_term_log
+            .record(None, &["hello world".into()], 200)
+            .unwrap();
+        short_term_log.fold(150).unwrap();
+
+        assert_eq!(
+            short_term_log.phrases_of(None).collect::<Vec<_>>(),
+            [("hello world", 200)]
+        );
+    }
+}
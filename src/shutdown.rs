@@ -0,0 +1,27 @@
+use crate::error::{self, ResultExt};
+
+/// Resolves once the process is asked to stop, by SIGINT (e.g. Ctrl+C) or, on
+/// Unix, by SIGTERM.
+pub(crate) async fn signal() -> error::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate =
+            signal(SignalKind::terminate()).context(|| "listening for SIGTERM".into())?;
+
+        tokio::select! {
+            interrupt = tokio::signal::ctrl_c() => {
+                interrupt.context(|| "listening for SIGINT".into())
+            }
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .context(|| "listening for SIGINT".into())
+    }
+}
@@ -0,0 +1,127 @@
+use feroldinhobot::phrase_indexing::{IndexedPhrases, NormalizationConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Bumped whenever the layout of snapshots changes, so that older ones are
+/// ignored.
+const SNAPSHOT_FORMAT_VERSION: u32 = 5;
+
+/// What a snapshot was taken from, which must still hold for the snapshot to
+/// be restored.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct SnapshotHeader {
+    format_version: u32,
+    /// How the lines were normalized into phrases.
+    normalization: String,
+    /// How many stored lines the snapshot covers, from the first one.
+    line_count: usize,
+    lines_hash: u64,
+}
+
+impl SnapshotHeader {
+    fn new(lines: &[String], normalization_config: &NormalizationConfig) -> SnapshotHeader {
+        SnapshotHeader {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            normalization: format!("{:?}", normalization_config),
+            line_count: lines.len(),
+            lines_hash: hash_lines(lines),
+        }
+    }
+}
+
+/// Hashes the lines, which is far faster than indexing them again. The hash is
+/// only stable within a build, so a new build starts over from the lines.
+fn hash_lines(lines: &[String]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    lines.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Takes a snapshot of the phrases indexed from every one of `lines`.
+pub(crate) fn take(
+    indexed_phrases: &IndexedPhrases,
+    lines: &[String],
+    normalization_config: &NormalizationConfig,
+) -> Vec<u8> {
+    let header = SnapshotHeader::new(lines, normalization_config);
+
+    bincode::serialize(&(header, indexed_phrases)).expect("snapshots are serializable")
+}
+
+/// Restores the indexed phrases from a snapshot taken from the first lines of
+/// `lines`. Returns them along with how many lines they cover, or `None` if the
+/// snapshot was taken from anything else.
+pub(crate) fn restore(
+    snapshot: &[u8],
+    lines: &[String],
+    normalization_config: &NormalizationConfig,
+) -> Option<(IndexedPhrases, usize)> {
+    let mut reader = snapshot;
+
+    let header: SnapshotHeader = bincode::deserialize_from(&mut reader).ok()?;
+    let covered_lines = lines.get(..header.line_count)?;
+
+    if header != SnapshotHeader::new(covered_lines, normalization_config) {
+        return None;
+    }
+
+    let indexed_phrases = bincode::deserialize_from(&mut reader).ok()?;
+
+    Some((indexed_phrases, header.line_count))
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::{restore, take};
+    use feroldinhobot::phrase_indexing::{IndexedPhrases, NormalizationConfig, Phrase};
+
+    fn indexed_lines(lines: &[String]) -> IndexedPhrases {
+        let mut indexed_phrases = IndexedPhrases::new();
+        for line in lines {
+            indexed_phrases.insert_phrase(Phrase::from(line.as_str()));
+        }
+        indexed_phrases
+    }
+
+    #[test]
+    fn should_restore_snapshot_of_first_lines() {
+        let lines = vec!["hello there".to_string(), "how are you".to_string()];
+        let config = NormalizationConfig::default();
+        let snapshot = take(&indexed_lines(&lines[..1]), &lines[..1], &config);
+
+        let (indexed_phrases, line_count) = restore(&snapshot, &lines, &config).unwrap();
+
+        assert_eq!(line_count, 1);
+        assert!(indexed_phrases.contains_phrase("hello there"));
+        assert!(!indexed_phrases.contains_phrase("how are you"));
+    }
+
+    #[test]
+    fn should_not_restore_snapshot_of_other_lines() {
+        let lines = vec!["hello there".to_string(), "how are you".to_string()];
+        let config = NormalizationConfig::default();
+        let snapshot = take(&indexed_lines(&lines), &lines, &config);
+
+        let rewritten_lines = vec!["how are you".to_string(), "hello there".to_string()];
+        assert!(restore(&snapshot, &rewritten_lines, &config).is_none());
+        assert!(restore(&snapshot, &lines[..1], &config).is_none());
+        assert!(restore(b"garbage", &lines, &config).is_none());
+    }
+
+    #[test]
+    fn should_not_restore_snapshot_normalized_differently() {
+        let lines = vec!["hello there".to_string()];
+        let snapshot = take(
+            &indexed_lines(&lines),
+            &lines,
+            &NormalizationConfig::default(),
+        );
+
+        let config = NormalizationConfig {
+            max_letter_run: Some(2),
+            ..NormalizationConfig::default()
+        };
+        assert!(restore(&snapshot, &lines, &config).is_none());
+    }
+}
@@ -0,0 +1,333 @@
+use crate::error::{self, Error};
+use crate::feeds;
+use crate::http::{self, HttpsClient};
+use feroldinhobot::phrase_indexing;
+use feroldinhobot::sources::RateCap;
+use hyper::Body;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+pub(crate) enum SocialSource {
+    /// The home timeline of the account owning the access token.
+    Mastodon {
+        instance_url: String,
+        access_token: String,
+    },
+    /// A Bluesky feed generator, e.g. `at://did:plc:.../app.bsky.feed.generator/...`.
+    Bluesky { feed_uri: String },
+}
+
+pub(crate) struct SocialConfig {
+    pub(crate) source: Option<SocialSource>,
+    pub(crate) poll_interval: Duration,
+    /// Maximum number of posts learned per hour, however busy the source is.
+    pub(crate) max_posts_per_hour: usize,
+    /// Languages of the posts to learn from, or empty to learn from all of them.
+    pub(crate) languages: Vec<String>,
+}
+
+impl SocialConfig {
+    /// Ingestion is opt-in: it's only enabled if either `MASTODON_INSTANCE_URL`
+    /// and `MASTODON_ACCESS_TOKEN`, or `BLUESKY_FEED_URI` are set.
+    pub(crate) fn from_env() -> error::Result<SocialConfig> {
+        let mastodon_instance_url = std::env::var("MASTODON_INSTANCE_URL").ok();
+        let mastodon_access_token = std::env::var("MASTODON_ACCESS_TOKEN").ok();
+        let bluesky_feed_uri = std::env::var("BLUESKY_FEED_URI").ok();
+
+        let source = match (
+            mastodon_instance_url,
+            mastodon_access_token,
+            bluesky_feed_uri,
+        ) {
+            (Some(instance_url), Some(access_token), _) => Some(SocialSource::Mastodon {
+                instance_url: instance_url.trim_end_matches('/').into(),
+                access_token,
+            }),
+            (_, _, Some(feed_uri)) => Some(SocialSource::Bluesky { feed_uri }),
+            _ => None,
+        };
+
+        let poll_interval = match std::env::var("SOCIAL_POLL_INTERVAL_SECS") {
+            Ok(secs) => secs
+                .parse()
+                .map(Duration::from_secs)
+                .map_err(|_| Error::parse("social poll interval", secs))?,
+            Err(_) => Duration::from_secs(60),
+        };
+
+        let max_posts_per_hour = match std::env::var("SOCIAL_MAX_POSTS_PER_HOUR") {
+            Ok(count) => count
+                .parse()
+                .map_err(|_| Error::parse("social max posts per hour", count))?,
+            Err(_) => 120,
+        };
+
+        let languages = std::env::var("SOCIAL_LANGUAGES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|language| !language.is_empty())
+            .map(phrase_indexing::primary_language_subtag)
+            .collect();
+
+        Ok(SocialConfig {
+            source,
+            poll_interval,
+            max_posts_per_hour,
+            languages,
+        })
+    }
+
+    fn accepts_language(&self, post_languages: &[String]) -> bool {
+        self.languages.is_empty()
+            || post_languages.iter().any(|post_language| {
+                let post_language = phrase_indexing::primary_language_subtag(post_language);
+                self.languages.contains(&post_language)
+            })
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub(crate) struct SocialPost {
+    pub(crate) id: String,
+    pub(crate) text: String,
+    pub(crate) languages: Vec<String>,
+}
+
+/// Polls the configured source forever, passing the text of every new post
+/// that passes the language filter and the rate cap to `learn_text`.
+pub(crate) async fn poll_social_posts<F, Fut>(config: SocialConfig, mut learn_text: F)
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let source = match &config.source {
+        Some(source) => source,
+        None => return,
+    };
+
+    let client = http::new_client();
+    let mut rate_cap = RateCap::new(config.max_posts_per_hour, Duration::from_secs(60 * 60));
+    let mut seen_post_ids = HashSet::new();
+
+    loop {
+        match fetch_posts(&client, source).await {
+            Ok(posts) => {
+                let mut learned_post_count = 0;
+
+                for post in &posts {
+                    if seen_post_ids.contains(&post.id) || !config.accepts_language(&post.languages)
+                    {
+                        continue;
+                    }
+
+                    if !rate_cap.try_allow(Instant::now()) {
+                        tracing::info!("reached the cap of social posts learned per hour");
+                        break;
+                    }
+
+                    learn_text(post.text.clone()).await;
+                    learned_post_count += 1;
+                }
+
+                // Posts that were skipped due to the rate cap are skipped for
+                // good, otherwise we would never catch up with a busy source.
+                seen_post_ids = posts.into_iter().map(|post| post.id).collect();
+
+                tracing::info!("learned {} new social posts", learned_post_count);
+            }
+            Err(err) => error::report_error(&err),
+        }
+
+        tokio::time::delay_for(config.poll_interval).await;
+    }
+}
+
+async fn fetch_posts(
+    client: &HttpsClient,
+    source: &SocialSource,
+) -> error::Result<Vec<SocialPost>> {
+    match source {
+        SocialSource::Mastodon {
+            instance_url,
+            access_token,
+        } => {
+            let url = format!("{}/api/v1/timelines/home?limit=40", instance_url);
+            let request = http::get_request(&url)?
+                .header("Authorization", format!("Bearer {}", access_token))
+                .body(Body::empty())
+                .map_err(|_| Error::parse("mastodon access token", "<redacted>"))?;
+
+            let body = http::fetch(client, request, || "fetching mastodon timeline".into()).await?;
+
+            parse_mastodon_statuses(&body).map_err(|_| Error::parse("mastodon statuses", url))
+        }
+        SocialSource::Bluesky { feed_uri } => {
+            let url = format!(
+                "https://public.api.bsky.app/xrpc/app.bsky.feed.getFeed?limit=50&feed={}",
+                feed_uri
+            );
+            let request = http::get_request(&url)?
+                .body(Body::empty())
+                .map_err(|_| Error::parse("bluesky feed uri", feed_uri))?;
+
+            let body = http::fetch(client, request, || {
+                format!("fetching bluesky feed `{}`", feed_uri)
+            })
+            .await?;
+
+            parse_bluesky_feed(&body).map_err(|_| Error::parse("bluesky feed", url))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MastodonStatus {
+    id: String,
+    content: String,
+    language: Option<String>,
+    reblog: Option<Box<MastodonStatus>>,
+}
+
+fn parse_mastodon_statuses(json: &[u8]) -> serde_json::Result<Vec<SocialPost>> {
+    let statuses: Vec<MastodonStatus> = serde_json::from_slice(json)?;
+
+    Ok(statuses
+        .into_iter()
+        .map(|status| {
+            // Boosts have no content of their own.
+            let status = match status.reblog {
+                Some(reblogged_status) => *reblogged_status,
+                None => status,
+            };
+
+            SocialPost {
+                id: status.id,
+                text: feeds::strip_html(&status.content),
+                languages: status.language.into_iter().collect(),
+            }
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct BlueskyFeed {
+    feed: Vec<BlueskyFeedItem>,
+}
+
+#[derive(Deserialize)]
+struct BlueskyFeedItem {
+    post: BlueskyPost,
+}
+
+#[derive(Deserialize)]
+struct BlueskyPost {
+    uri: String,
+    record: BlueskyRecord,
+}
+
+#[derive(Deserialize)]
+struct BlueskyRecord {
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    langs: Vec<String>,
+}
+
+fn parse_bluesky_feed(json: &[u8]) -> serde_json::Result<Vec<SocialPost>> {
+    let feed: BlueskyFeed = serde_json::from_slice(json)?;
+
+    Ok(feed
+        .feed
+        .into_iter()
+        .map(|item| SocialPost {
+            id: item.post.uri,
+            text: item.post.record.text,
+            languages: item.post.record.langs,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod social_ingestion_tests {
+    use super::{parse_bluesky_feed, parse_mastodon_statuses, SocialConfig, SocialPost};
+    use std::time::Duration;
+
+    #[test]
+    fn should_parse_mastodon_statuses() {
+        let json = br#"[
+            {"id": "1", "content": "<p>hello there</p>", "language": "en", "reblog": null},
+            {"id": "2", "content": "", "language": null, "reblog":
+                {"id": "3", "content": "<p>oi gente</p>", "language": "pt", "reblog": null}}
+        ]"#;
+
+        assert_eq!(
+            parse_mastodon_statuses(json).unwrap(),
+            &[
+                SocialPost {
+                    id: "1".into(),
+                    text: " hello there ".into(),
+                    languages: vec!["en".into()],
+                },
+                SocialPost {
+                    id: "3".into(),
+                    text: " oi gente ".into(),
+                    languages: vec!["pt".into()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_parse_bluesky_feed() {
+        let json = br#"{"feed": [
+            {"post": {"uri": "at://a/1", "record": {"text": "hello there", "langs": ["en"]}}},
+            {"post": {"uri": "at://a/2", "record": {"text": "no language"}}}
+        ]}"#;
+
+        assert_eq!(
+            parse_bluesky_feed(json).unwrap(),
+            &[
+                SocialPost {
+                    id: "at://a/1".into(),
+                    text: "hello there".into(),
+                    languages: vec!["en".into()],
+                },
+                SocialPost {
+                    id: "at://a/2".into(),
+                    text: "no language".into(),
+                    languages: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_filter_posts_by_primary_language() {
+        let config = SocialConfig {
+            source: None,
+            poll_interval: Duration::from_secs(60),
+            max_posts_per_hour: 10,
+            languages: vec!["pt".into()],
+        };
+
+        assert!(config.accepts_language(&["pt-BR".into()]));
+        assert!(!config.accepts_language(&["en".into()]));
+        assert!(!config.accepts_language(&[]));
+    }
+
+    #[test]
+    fn should_accept_any_language_if_none_is_configured() {
+        let config = SocialConfig {
+            source: None,
+            poll_interval: Duration::from_secs(60),
+            max_posts_per_hour: 10,
+            languages: vec![],
+        };
+
+        assert!(config.accepts_language(&[]));
+        assert!(config.accepts_language(&["en".into()]));
+    }
+}
@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Where a phrase was learned from.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Default, Copy, Clone)]
+pub enum PhraseSource {
+    #[default]
+    Chat,
+    Channel,
+    Feed,
+    Social,
+    /// Phrases stored without a source, e.g. before sources were kept.
+    Import,
+}
+
+pub const ALL_PHRASE_SOURCES: [PhraseSource; 5] = [
+    PhraseSource::Chat,
+    PhraseSource::Channel,
+    PhraseSource::Feed,
+    PhraseSource::Social,
+    PhraseSource::Import,
+];
+
+/// External material is picked less often than the chats' own phrases.
+pub const DEFAULT_SOURCE_WEIGHTS: [(PhraseSource, f32); 3] = [
+    (PhraseSource::Channel, 0.5),
+    (PhraseSource::Feed, 0.3),
+    (PhraseSource::Social, 0.3),
+];
+
+impl fmt::Display for PhraseSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PhraseSource::Chat => "chat",
+            PhraseSource::Channel => "channel",
+            PhraseSource::Feed => "feed",
+            PhraseSource::Social => "social",
+            PhraseSource::Import => "import",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for PhraseSource {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ALL_PHRASE_SOURCES
+            .iter()
+            .copied()
+            .find(|source| source.to_string() == s)
+            .ok_or(())
+    }
+}
+
+/// Allows at most `max_count` events within any `window` of time.
+pub struct RateCap {
+    max_count: usize,
+    window: Duration,
+    allowed_at: VecDeque<Instant>,
+}
+
+impl RateCap {
+    pub fn new(max_count: usize, window: Duration) -> RateCap {
+        RateCap {
+            max_count,
+            window,
+            allowed_at: VecDeque::new(),
+        }
+    }
+
+    pub fn try_allow(&mut self, now: Instant) -> bool {
+        while let Some(&oldest) = self.allowed_at.front() {
+            if now.duration_since(oldest) < self.window {
+                break;
+            }
+            self.allowed_at.pop_front();
+        }
+
+        if self.allowed_at.len() >= self.max_count {
+            return false;
+        }
+
+        self.allowed_at.push_back(now);
+        true
+    }
+
+    /// Whether nothing was allowed within the last window, i.e. the cap is as
+    /// good as new.
+    pub fn is_idle(&self, now: Instant) -> bool {
+        self.allowed_at
+            .back()
+            .is_none_or(|&latest| now.duration_since(latest) >= self.window)
+    }
+}
+
+/// Limits how many phrases external sources may teach per hour, so that they
+/// season the corpus without drowning out the chat's own voice.
+pub struct SourceQuotas {
+    rate_caps: HashMap<PhraseSource, RateCap>,
+    rejected_counts: HashMap<PhraseSource, usize>,
+}
+
+impl Default for SourceQuotas {
+    fn default() -> Self {
+        let mut quotas = SourceQuotas {
+            rate_caps: HashMap::new(),
+            rejected_counts: HashMap::new(),
+        };
+
+        quotas.set_max_phrases_per_hour(PhraseSource::Feed, Some(100));
+        quotas.set_max_phrases_per_hour(PhraseSource::Social, Some(100));
+
+        quotas
+    }
+}
+
+impl SourceQuotas {
+    /// `None` lifts the quota of the source.
+    pub fn set_max_phrases_per_hour(&mut self, source: PhraseSource, max_phrases: Option<usize>) {
+        match max_phrases {
+            Some(max_phrases) => {
+                let rate_cap = RateCap::new(max_phrases, Duration::from_secs(60 * 60));
+                self.rate_caps.insert(source, rate_cap);
+            }
+            None => {
+                self.rate_caps.remove(&source);
+            }
+        }
+    }
+
+    /// Whether another phrase from the source may be learned right now.
+    pub fn try_learn(&mut self, source: PhraseSource) -> bool {
+        let allowed = match self.rate_caps.get_mut(&source) {
+            Some(rate_cap) => rate_cap.try_allow(Instant::now()),
+            None => true,
+        };
+
+        if !allowed {
+            *self.rejected_counts.entry(source).or_insert(0) += 1;
+        }
+
+        allowed
+    }
+
+    pub fn rejected_count(&self, source: PhraseSource) -> usize {
+        self.rejected_counts.get(&source).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod source_quota_tests {
+    use super::{PhraseSource, RateCap, SourceQuotas};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn should_cap_events_within_window() {
+        let mut rate_cap = RateCap::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(rate_cap.try_allow(start));
+        assert!(rate_cap.try_allow(start + Duration::from_secs(1)));
+        assert!(!rate_cap.try_allow(start + Duration::from_secs(2)));
+        assert!(rate_cap.try_allow(start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn should_only_limit_sources_with_quota() {
+        let mut quotas = SourceQuotas::default();
+        quotas.set_max_phrases_per_hour(PhraseSource::Feed, Some(1));
+
+        assert!(quotas.try_learn(PhraseSource::Feed));
+        assert!(!quotas.try_learn(PhraseSource::Feed));
+
+        for _ in 0..1000 {
+            assert!(quotas.try_learn(PhraseSource::Chat));
+        }
+
+        assert_eq!(quotas.rejected_count(PhraseSource::Feed), 1);
+        assert_eq!(quotas.rejected_count(PhraseSource::Chat), 0);
+    }
+
+    #[test]
+    fn should_parse_source_names() {
+        assert_eq!("feed".parse(), Ok(PhraseSource::Feed));
+        assert_eq!("nope".parse::<PhraseSource>(), Err(()));
+    }
+}
@@ -0,0 +1,668 @@
+use crate::error::{self, ResultExt};
+use feroldinhobot::sources::PhraseSource;
+use rusqlite::{Connection, OptionalExtension};
+use std::fs::File;
+use std::io::{prelude::*, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use tbot::types::chat;
+
+/// Where the memory of a chat is kept, next to the shared memory at `path`,
+/// e.g. `bot_memory_-1001234.txt` for `bot_memory.txt`.
+pub(crate) fn chat_store_path(path: &Path, chat_id: chat::Id) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    let file_name = match path.extension() {
+        Some(extension) => format!("{}_{}.{}", stem, chat_id, extension.to_string_lossy()),
+        None => format!("{}_{}", stem, chat_id),
+    };
+
+    path.with_file_name(file_name)
+}
+
+/// The shared memory at `path`, if it exists, along with the memories of every
+/// chat kept next to it.
+pub(crate) fn database_paths(path: &Path) -> error::Result<Vec<PathBuf>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut chat_paths = Vec::new();
+    let entries =
+        std::fs::read_dir(dir).context(|| format!("listing databases in `{}`", dir.display()))?;
+
+    for entry in entries {
+        let entry = entry.context(|| format!("listing databases in `{}`", dir.display()))?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+
+        let is_chat_database = file_name
+            .strip_prefix(&format!("{}_", stem))
+            .and_then(|rest| rest.strip_suffix(&extension))
+            .is_some_and(|chat_id| chat_id.parse::<i64>().is_ok());
+
+        if is_chat_database {
+            chat_paths.push(path.with_file_name(file_name));
+        }
+    }
+
+    chat_paths.sort();
+
+    let shared_path = Some(path.to_path_buf()).filter(|path| path.exists());
+
+    Ok(shared_path.into_iter().chain(chat_paths).collect())
+}
+
+/// What's kept about a phrase along with the text of its line, after a tab, as
+/// space separated `key=value` pairs. Attributes left at their defaults are
+/// left out, so lines stored before attributes were kept have none.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub(crate) struct LineAttributes {
+    pub(crate) source: PhraseSource,
+    /// Unix time after which the phrase is no longer used, if any.
+    pub(crate) expires_at: Option<i64>,
+    /// How many times the phrase was learned, which compaction keeps in a
+    /// single line.
+    pub(crate) occurrences: usize,
+}
+
+/// Lines without attributes are taken as imported, since where they were
+/// learned from was never kept.
+impl Default for LineAttributes {
+    fn default() -> Self {
+        LineAttributes {
+            source: PhraseSource::Import,
+            expires_at: None,
+            occurrences: 1,
+        }
+    }
+}
+
+/// Makes the line to store for the text of a phrase, e.g. from
+/// `Phrase::to_line`, with its attributes.
+pub(crate) fn format_line(text: &str, attributes: LineAttributes) -> String {
+    let mut pairs = Vec::new();
+    if attributes.source != PhraseSource::Import {
+        pairs.push(format!("source={}", attributes.source));
+    }
+    if let Some(expires_at) = attributes.expires_at {
+        pairs.push(format!("expires={}", expires_at));
+    }
+    if attributes.occurrences != 1 {
+        pairs.push(format!("count={}", attributes.occurrences));
+    }
+
+    if pairs.is_empty() {
+        text.into()
+    } else {
+        format!("{}\t{}", text, pairs.join(" "))
+    }
+}
+
+/// Splits a stored line into its text and its attributes. Normalized phrases
+/// never have tabs, so lines whose attributes can't be read are taken as text,
+/// which is what lines written by hand are.
+pub(crate) fn parse_line(line: &str) -> (&str, LineAttributes) {
+    let parse_attributes = |pairs: &str| {
+        let mut attributes = LineAttributes::default();
+
+        for pair in pairs.split(' ') {
+            match pair.split_once('=')? {
+                ("source", source) => attributes.source = source.parse().ok()?,
+                ("expires", expires_at) => attributes.expires_at = Some(expires_at.parse().ok()?),
+                ("count", occurrences) => attributes.occurrences = occurrences.parse().ok()?,
+                _ => return None,
+            }
+        }
+
+        Some(attributes)
+    };
+
+    line.rsplit_once('\t')
+        .and_then(|(text, pairs)| Some((text, parse_attributes(pairs)?)))
+        .unwrap_or((line, LineAttributes::default()))
+}
+
+/// Where learned phrases are kept between runs. Phrases are stored as lines,
+/// which normalize back into the phrases they came from. Stores are shared
+/// between threads along with the memories they belong to, which only ever use
+/// them while writing to the memory.
+pub(crate) trait PhraseStore: Send + Sync {
+    /// Returns every stored line, in the order they were appended.
+    fn load(&mut self) -> error::Result<Vec<String>>;
+
+    fn append(&mut self, line: &str) -> error::Result<()>;
+
+    /// Makes sure that every appended line is on disk.
+    fn flush(&mut self) -> error::Result<()>;
+
+    /// Stores the canonical form of what was loaded, i.e. without duplicates
+    /// and junk.
+    fn compact(&mut self, lines: &[String]) -> error::Result<()>;
+
+    /// Deletes the stored lines for which `keep` returns false.
+    fn retain(&mut self, keep: &mut dyn FnMut(&str) -> bool) -> error::Result<()>;
+
+    /// Returns the last snapshot saved, if any. Stores that don't keep
+    /// snapshots never have one.
+    fn load_snapshot(&mut self) -> error::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Keeps a snapshot of the index, which is what makes loading fast.
+    fn save_snapshot(&mut self, _snapshot: &[u8]) -> error::Result<()> {
+        Ok(())
+    }
+}
+
+/// Atomically replaces the file at `path` with what `write` writes. It's
+/// written to a sibling file with the `tmp` extension added, which is then
+/// renamed over the original, so that a crash midway never leaves a partially
+/// written file behind.
+pub(crate) fn replace_file(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    let mut file = File::create(&temp_path)?;
+    write(&mut file)?;
+    file.sync_all()?;
+
+    std::fs::rename(&temp_path, path)?;
+
+    // Makes the rename itself durable.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+fn write_lines(file: &mut File, lines: &[String]) -> std::io::Result<()> {
+    let mut writer = std::io::BufWriter::new(file);
+    for line in lines {
+        writeln!(writer, "{}", line)?;
+    }
+    writer.flush()
+}
+
+/// Stores one line per phrase in a text file, which is only ever rewritten
+/// atomically. Snapshots are kept in a sibling file with the `snapshot`
+/// extension.
+pub(crate) struct FlatFileStore {
+    path: PathBuf,
+}
+
+impl FlatFileStore {
+    pub(crate) fn new(path: impl Into<PathBuf>) -> FlatFileStore {
+        FlatFileStore { path: path.into() }
+    }
+
+    pub(crate) fn create_if_missing(path: impl Into<PathBuf>) -> error::Result<FlatFileStore> {
+        let path = path.into();
+
+        File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context(|| format!("creating database `{}`", path.display()))?;
+
+        Ok(FlatFileStore { path })
+    }
+
+    fn snapshot_path(&self) -> PathBuf {
+        self.path.with_extension("snapshot")
+    }
+}
+
+impl PhraseStore for FlatFileStore {
+    fn load(&mut self) -> error::Result<Vec<String>> {
+        let file = File::open(&self.path)
+            .context(|| format!("opening database `{}`", self.path.display()))?;
+
+        BufReader::new(file)
+            .lines()
+            .collect::<std::io::Result<_>>()
+            .context(|| "reading database line".into())
+    }
+
+    fn append(&mut self, line: &str) -> error::Result<()> {
+        let store_line = || -> std::io::Result<()> {
+            let mut file = File::options().append(true).open(&self.path)?;
+
+            // Written at once, so that the line can't be split between writes.
+            file.write_all(format!("{}\n", line).as_bytes())?;
+            file.flush()
+        };
+
+        store_line().context(|| format!("storing line `{}` in database", line))
+    }
+
+    fn flush(&mut self) -> error::Result<()> {
+        File::options()
+            .append(true)
+            .open(&self.path)
+            .and_then(|file| file.sync_all())
+            .context(|| format!("syncing database `{}`", self.path.display()))
+    }
+
+    fn compact(&mut self, lines: &[String]) -> error::Result<()> {
+        replace_file(&self.path, |file| write_lines(file, lines))
+            .context(|| format!("compacting database `{}`", self.path.display()))
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&str) -> bool) -> error::Result<()> {
+        let mut lines = self.load()?;
+        lines.retain(|line| keep(line));
+
+        replace_file(&self.path, |file| write_lines(file, &lines))
+            .context(|| format!("rewriting database `{}`", self.path.display()))
+    }
+
+    fn load_snapshot(&mut self) -> error::Result<Option<Vec<u8>>> {
+        let snapshot_path = self.snapshot_path();
+
+        match std::fs::read(&snapshot_path) {
+            Ok(snapshot) => Ok(Some(snapshot)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => {
+                Err(err).context(|| format!("reading snapshot `{}`", snapshot_path.display()))
+            }
+        }
+    }
+
+    fn save_snapshot(&mut self, snapshot: &[u8]) -> error::Result<()> {
+        let snapshot_path = self.snapshot_path();
+
+        replace_file(&snapshot_path, |file| file.write_all(snapshot))
+            .context(|| format!("writing snapshot `{}`", snapshot_path.display()))
+    }
+}
+
+/// Stores phrases in a SQLite database. Compaction only touches the phrases
+/// that changed.
+pub(crate) struct SqliteStore {
+    /// Behind a lock only because connections can't be shared between threads
+    /// otherwise.
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub(crate) fn open(path: impl AsRef<Path>) -> error::Result<SqliteStore> {
+        let path = path.as_ref();
+        let connection =
+            Connection::open(path).context(|| format!("opening database `{}`", path.display()))?;
+
+        SqliteStore::with_connection(connection)
+    }
+
+    fn with_connection(connection: Connection) -> error::Result<SqliteStore> {
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS phrases (
+                    id INTEGER PRIMARY KEY,
+                    line TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS phrases_by_line ON phrases (line);
+                DROP TABLE IF EXISTS phrase_words;",
+            )
+            .context(|| "creating database schema".into())?;
+
+        Ok(SqliteStore {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&mut self) -> &mut Connection {
+        self.connection
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn insert_line(connection: &Connection, line: &str) -> rusqlite::Result<()> {
+        connection
+            .prepare_cached("INSERT INTO phrases (line) VALUES (?1)")?
+            .execute([line])?;
+
+        Ok(())
+    }
+}
+
+impl PhraseStore for SqliteStore {
+    fn load(&mut self) -> error::Result<Vec<String>> {
+        let mut load_lines = || -> rusqlite::Result<Vec<String>> {
+            let mut statement = self
+                .connection()
+                .prepare_cached("SELECT line FROM phrases ORDER BY id")?;
+
+            let lines = statement.query_map([], |row| row.get(0))?;
+            lines.collect()
+        };
+
+        load_lines().context(|| "loading lines from database".into())
+    }
+
+    fn append(&mut self, line: &str) -> error::Result<()> {
+        let mut store_line = || -> rusqlite::Result<()> {
+            let transaction = self.connection().transaction()?;
+            SqliteStore::insert_line(&transaction, line)?;
+            transaction.commit()
+        };
+
+        store_line().context(|| format!("storing line `{}` in database", line))
+    }
+
+    /// Every line is committed as soon as it's appended.
+    fn flush(&mut self) -> error::Result<()> {
+        Ok(())
+    }
+
+    fn compact(&mut self, lines: &[String]) -> error::Result<()> {
+        let mut compact_lines = || -> rusqlite::Result<()> {
+            let transaction = self.connection().transaction()?;
+
+            transaction
+                .execute_batch("CREATE TEMP TABLE compacted_lines (line TEXT PRIMARY KEY);")?;
+            for line in lines {
+                transaction
+                    .prepare_cached("INSERT OR IGNORE INTO compacted_lines (line) VALUES (?1)")?
+                    .execute([line])?;
+            }
+
+            // Drops the lines that are gone, as well as the repeated ones.
+            transaction.execute_batch(
+                "DELETE FROM phrases
+                WHERE line NOT IN (SELECT line FROM compacted_lines)
+                    OR id NOT IN (SELECT MIN(id) FROM phrases GROUP BY line);
+                DROP TABLE temp.compacted_lines;",
+            )?;
+
+            for line in lines {
+                let is_stored = transaction
+                    .prepare_cached("SELECT 1 FROM phrases WHERE line = ?1")?
+                    .query_row([line], |_| Ok(()))
+                    .optional()?
+                    .is_some();
+
+                if !is_stored {
+                    SqliteStore::insert_line(&transaction, line)?;
+                }
+            }
+
+            transaction.commit()
+        };
+
+        compact_lines().context(|| "compacting database".into())
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&str) -> bool) -> error::Result<()> {
+        let mut delete_lines = || -> rusqlite::Result<()> {
+            let transaction = self.connection().transaction()?;
+
+            let deleted_ids = {
+                let mut statement = transaction.prepare_cached("SELECT id, line FROM phrases")?;
+                let rows = statement.query_map([], |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                })?;
+
+                let mut deleted_ids = Vec::new();
+                for row in rows {
+                    let (id, line) = row?;
+                    if !keep(&line) {
+                        deleted_ids.push(id);
+                    }
+                }
+                deleted_ids
+            };
+
+            for id in deleted_ids {
+                transaction
+                    .prepare_cached("DELETE FROM phrases WHERE id = ?1")?
+                    .execute([id])?;
+            }
+
+            transaction.commit()
+        };
+
+        delete_lines().context(|| "deleting lines from database".into())
+    }
+}
+
+#[cfg(test)]
+mod line_tests {
+    use super::{format_line, parse_line, LineAttributes};
+    use feroldinhobot::sources::PhraseSource;
+
+    #[test]
+    fn should_parse_formatted_lines_back() {
+        let attributes = LineAttributes {
+            source: PhraseSource::Feed,
+            expires_at: Some(100),
+            occurrences: 3,
+        };
+
+        let line = format_line("hello there!", attributes);
+        assert_eq!(line, "hello there!\tsource=feed expires=100 count=3");
+        assert_eq!(parse_line(&line), ("hello there!", attributes));
+    }
+
+    #[test]
+    fn should_take_lines_without_attributes_as_imported() {
+        assert_eq!(
+            format_line("hello there", LineAttributes::default()),
+            "hello there"
+        );
+        assert_eq!(
+            parse_line("hello there"),
+            ("hello there", LineAttributes::default())
+        );
+        assert_eq!(
+            parse_line("hello\tthere"),
+            ("hello\tthere", LineAttributes::default())
+        );
+    }
+}
+
+#[cfg(test)]
+mod flat_file_store_tests {
+    use super::{chat_store_path, database_paths, FlatFileStore, PhraseStore};
+    use std::path::{Path, PathBuf};
+    use tbot::types::chat;
+
+    fn temp_database_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "flat_file_store_{}_{}.txt",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, "").unwrap();
+        path
+    }
+
+    #[test]
+    fn should_load_appended_lines_in_order() {
+        let path = temp_database_path("append");
+        let mut store = FlatFileStore::new(&path);
+
+        store.append("hello there").unwrap();
+        store.append("how are you?").unwrap();
+
+        assert_eq!(store.load().unwrap(), &["hello there", "how are you?"]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_replace_database_with_compacted_lines() {
+        let path = temp_database_path("compact");
+        let mut store = FlatFileStore::new(&path);
+
+        store.append("hello there").unwrap();
+        store.append("hello there").unwrap();
+        store.append("how are you?").unwrap();
+        store
+            .compact(&["hello there".into(), "how are you?".into()])
+            .unwrap();
+
+        assert_eq!(store.load().unwrap(), &["hello there", "how are you?"]);
+        assert!(!path.with_extension("txt.tmp").exists());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_keep_snapshot_next_to_database() {
+        let path = temp_database_path("snapshot");
+        let mut store = FlatFileStore::new(&path);
+
+        assert_eq!(store.load_snapshot().unwrap(), None);
+
+        store.save_snapshot(b"snapshot").unwrap();
+
+        assert_eq!(store.load_snapshot().unwrap().unwrap(), b"snapshot");
+        assert_eq!(store.load().unwrap(), Vec::<String>::new());
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(store.snapshot_path()).unwrap();
+    }
+
+    #[test]
+    fn should_delete_lines_from_database() {
+        let path = temp_database_path("retain");
+        let mut store = FlatFileStore::new(&path);
+
+        store.append("hello there").unwrap();
+        store.append("how are you?").unwrap();
+        store.append("hello again").unwrap();
+        store
+            .retain(&mut |line| !line.starts_with("hello"))
+            .unwrap();
+
+        assert_eq!(store.load().unwrap(), &["how are you?"]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_create_missing_database_of_chat() {
+        let path = chat_store_path(
+            &std::env::temp_dir().join("flat_file_store.txt"),
+            chat::Id(std::process::id().into()),
+        );
+        let mut store = FlatFileStore::create_if_missing(&path).unwrap();
+
+        assert!(store.load().unwrap().is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn should_name_chat_database_after_shared_database() {
+        assert_eq!(
+            chat_store_path(Path::new("data/bot_memory.txt"), chat::Id(-100123)),
+            Path::new("data/bot_memory_-100123.txt")
+        );
+        assert_eq!(
+            chat_store_path(Path::new("bot_memory"), chat::Id(42)),
+            Path::new("bot_memory_42")
+        );
+    }
+
+    #[test]
+    fn should_list_shared_database_before_those_of_chats() {
+        let dir = std::env::temp_dir().join(format!("database_paths_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for file_name in [
+            "bot_memory.txt",
+            "bot_memory_42.txt",
+            "bot_memory_-100123.txt",
+            "bot_memory_-100123.snapshot",
+            "bot_memory_old.txt",
+        ] {
+            std::fs::write(dir.join(file_name), "").unwrap();
+        }
+
+        assert_eq!(
+            database_paths(&dir.join("bot_memory.txt")).unwrap(),
+            [
+                dir.join("bot_memory.txt"),
+                dir.join("bot_memory_-100123.txt"),
+                dir.join("bot_memory_42.txt"),
+            ]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_fail_to_load_missing_database() {
+        let mut store = FlatFileStore::new(std::env::temp_dir().join("missing_database.txt"));
+
+        assert!(store.load().is_err());
+    }
+}
+
+#[cfg(test)]
+mod sqlite_store_tests {
+    use super::{PhraseStore, SqliteStore};
+    use rusqlite::Connection;
+
+    fn in_memory_store() -> SqliteStore {
+        SqliteStore::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn should_load_appended_lines_in_order() {
+        let mut store = in_memory_store();
+
+        store.append("hello there").unwrap();
+        store.append("how are you?").unwrap();
+
+        assert_eq!(store.load().unwrap(), &["hello there", "how are you?"]);
+    }
+
+    #[test]
+    fn should_compact_in_place() {
+        let mut store = in_memory_store();
+
+        store.append("hello there").unwrap();
+        store.append("junk").unwrap();
+        store.append("hello there").unwrap();
+        store.append("how are you?").unwrap();
+        store
+            .compact(&["hello there".into(), "how are you?".into(), "hi".into()])
+            .unwrap();
+
+        assert_eq!(
+            store.load().unwrap(),
+            &["hello there", "how are you?", "hi"]
+        );
+    }
+
+    #[test]
+    fn should_delete_lines_not_kept() {
+        let mut store = in_memory_store();
+
+        store.append("hello there").unwrap();
+        store.append("how are you").unwrap();
+        store.append("hello you").unwrap();
+        store
+            .retain(&mut |line| !line.starts_with("hello"))
+            .unwrap();
+
+        assert_eq!(store.load().unwrap(), &["how are you"]);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::latency::SlowMessageLayer;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Exports the spans of the bot until dropped, which exports those that are
+/// still waiting to be.
+pub(crate) struct TelemetryGuard {
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(tracer_provider) = self.tracer_provider.take() {
+            if let Err(err) = tracer_provider.shutdown() {
+                tracing::warn!("couldn't export the remaining spans: {}", err);
+            }
+        }
+    }
+}
+
+/// Logs to stderr, filtered by `RUST_LOG`, along with the messages handled
+/// slowly, and, if `OTEL_EXPORTER_OTLP_ENDPOINT`
+/// is set, also exports spans over OTLP/HTTP to the collector there, along with
+/// the rest of the `OTEL_*` variables of the exporter.
+pub(crate) fn init() -> TelemetryGuard {
+    let exporter = std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .map(|_| SpanExporter::builder().with_http().build())
+        .transpose();
+    let (exporter, exporter_err) = match exporter {
+        Ok(exporter) => (exporter, None),
+        Err(err) => (None, Some(err)),
+    };
+
+    let tracer_provider = exporter.map(|exporter| {
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name("feroldinhobot")
+                    .build(),
+            )
+            .build()
+    });
+
+    // Spans are exported regardless of what's logged.
+    let otel_layer = tracer_provider.as_ref().map(|tracer_provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer_provider.tracer("feroldinhobot"))
+            .with_filter(LevelFilter::INFO)
+    });
+
+    // Records logged by dependencies through `log` end up here as well.
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env()))
+        .with(otel_layer)
+        .with(SlowMessageLayer)
+        .init();
+
+    if let Some(err) = exporter_err {
+        tracing::error!("couldn't export spans over OTLP: {}", err);
+    }
+
+    TelemetryGuard { tracer_provider }
+}
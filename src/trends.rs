@@ -0,0 +1,274 @@
+use crate::error::{self, Error, ResultExt};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use tbot::types::chat;
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+/// Days whose usage is compared against the usage of the days before them.
+const TRENDING_WINDOW_DAYS: i64 = 7;
+/// Days of usage kept, which make up the trending window and the baseline it's
+/// compared against.
+const RETENTION_DAYS: i64 = 4 * TRENDING_WINDOW_DAYS;
+/// Uses within the trending window count half as much this many days later.
+const USAGE_HALF_LIFE_DAYS: f32 = 3.0;
+/// Words used fewer times than this in the trending window aren't trending,
+/// however rarely they were used before.
+const MIN_TRENDING_USES: u32 = 3;
+
+/// How many times each word was used in each day, by day since the epoch.
+type DailyUsage = BTreeMap<i64, HashMap<String, u32>>;
+
+/// A word used more often lately than before.
+#[derive(PartialEq, Debug)]
+pub(crate) struct TrendingWord {
+    pub(crate) word: String,
+    /// Uses in the trending window.
+    pub(crate) uses: u32,
+    /// How many times more it was used in the trending window than in an
+    /// average window before it, with uses decaying with age.
+    pub(crate) spike: f32,
+}
+
+/// Counts how often words are used in each chat, in daily buckets, which are
+/// saved to a JSON file with `save`, unless there's no file to save them to.
+#[derive(Default)]
+pub(crate) struct WordTrends {
+    path: Option<PathBuf>,
+    usage_by_chat: HashMap<i64, DailyUsage>,
+}
+
+impl WordTrends {
+    /// Loads the usage saved at `path`, or starts without usage if there's no
+    /// such file yet.
+    pub(crate) fn load(path: impl Into<PathBuf>) -> error::Result<WordTrends> {
+        let path = path.into();
+
+        let usage_by_chat = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|_| Error::parse("word trends", path.display().to_string()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).context(|| format!("reading word trends `{}`", path.display()))
+            }
+        };
+
+        Ok(WordTrends {
+            path: Some(path),
+            usage_by_chat,
+        })
+    }
+
+    /// Counts the words as used in the chat at `used_at`, forgetting the days
+    /// of the chat that are no longer kept.
+    pub(crate) fn record<'w>(
+        &mut self,
+        chat_id: chat::Id,
+        words: impl IntoIterator<Item = &'w str>,
+        used_at: i64,
+    ) {
+        let day = used_at.div_euclid(SECS_PER_DAY);
+        let daily_usage = self.usage_by_chat.entry(chat_id.0).or_default();
+
+        let usage = daily_usage.entry(day).or_default();
+        for word in words {
+            *usage.entry(word.into()).or_default() += 1;
+        }
+
+        *daily_usage = daily_usage.split_off(&(day - RETENTION_DAYS + 1));
+    }
+
+    /// The words of the chat that were used more often in the last week than in
+    /// the weeks before it, as of `now`, the most spiking first.
+    pub(crate) fn trending(&self, chat_id: chat::Id, now: i64, count: usize) -> Vec<TrendingWord> {
+        let daily_usage = match self.usage_by_chat.get(&chat_id.0) {
+            Some(daily_usage) => daily_usage,
+            None => return Vec::new(),
+        };
+
+        let today = now.div_euclid(SECS_PER_DAY);
+        let window_start = today - TRENDING_WINDOW_DAYS + 1;
+        let baseline_start = today - RETENTION_DAYS + 1;
+
+        let mut window_usage: HashMap<&str, (u32, f32)> = HashMap::new();
+        let mut baseline_usage: HashMap<&str, u32> = HashMap::new();
+
+        for (&day, usage) in daily_usage.range(baseline_start..=today) {
+            let decay = 0.5f32.powf((today - day) as f32 / USAGE_HALF_LIFE_DAYS);
+
+            for (word, &uses) in usage {
+                if day >= window_start {
+                    let (window_uses, decayed_uses) = window_usage.entry(word).or_default();
+                    *window_uses += uses;
+                    *decayed_uses += uses as f32 * decay;
+                } else {
+                    *baseline_usage.entry(word).or_default() += uses;
+                }
+            }
+        }
+
+        let baseline_window_count = ((window_start - baseline_start) / TRENDING_WINDOW_DAYS) as f32;
+
+        let mut trending_words: Vec<_> = window_usage
+            .into_iter()
+            .filter(|&(_, (uses, _))| uses >= MIN_TRENDING_USES)
+            .map(|(word, (uses, decayed_uses))| {
+                let baseline_uses = baseline_usage.get(word).copied().unwrap_or(0) as f32;
+
+                // Words never used before would spike infinitely, so the
+                // baseline is as if they were used once.
+                TrendingWord {
+                    word: word.into(),
+                    uses,
+                    spike: decayed_uses / (baseline_uses / baseline_window_count).max(1.0),
+                }
+            })
+            .filter(|trending_word| trending_word.spike > 1.0)
+            .collect();
+
+        trending_words.sort_by(|a, b| {
+            b.spike
+                .total_cmp(&a.spike)
+                .then(b.uses.cmp(&a.uses))
+                .then_with(|| a.word.cmp(&b.word))
+        });
+        trending_words.truncate(count);
+
+        trending_words
+    }
+
+    /// The chats whose words were counted.
+    pub(crate) fn chat_ids(&self) -> impl Iterator<Item = chat::Id> + '_ {
+        self.usage_by_chat.keys().map(|&chat_id| chat::Id(chat_id))
+    }
+
+    /// The words of the chat used the most today and yesterday, as of `now`,
+    /// the most used first.
+    pub(crate) fn recent_words(&self, chat_id: chat::Id, now: i64, count: usize) -> Vec<&str> {
+        let today = now.div_euclid(SECS_PER_DAY);
+        let mut uses_by_word: HashMap<&str, u32> = HashMap::new();
+
+        if let Some(daily_usage) = self.usage_by_chat.get(&chat_id.0) {
+            for usage in daily_usage.range(today - 1..=today).map(|(_, usage)| usage) {
+                for (word, &uses) in usage {
+                    *uses_by_word.entry(word).or_default() += uses;
+                }
+            }
+        }
+
+        let mut recent_words: Vec<_> = uses_by_word.into_iter().collect();
+        recent_words.sort_by(|(a_word, a_uses), (b_word, b_uses)| {
+            b_uses.cmp(a_uses).then_with(|| a_word.cmp(b_word))
+        });
+
+        recent_words
+            .into_iter()
+            .take(count)
+            .map(|(word, _)| word)
+            .collect()
+    }
+
+    pub(crate) fn save(&self) -> error::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let json = serde_json::to_vec(&self.usage_by_chat).expect("usage is serializable");
+
+        std::fs::write(path, json).context(|| format!("writing word trends `{}`", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod word_trends_tests {
+    use super::{WordTrends, SECS_PER_DAY};
+    use std::path::PathBuf;
+    use tbot::types::chat;
+
+    const TODAY: i64 = 1000 * SECS_PER_DAY;
+
+    fn trending_words(word_trends: &WordTrends, now: i64) -> Vec<String> {
+        word_trends
+            .trending(chat::Id(1), now, 10)
+            .into_iter()
+            .map(|trending_word| trending_word.word)
+            .collect()
+    }
+
+    #[test]
+    fn should_find_words_used_more_than_before() {
+        let mut word_trends = WordTrends::default();
+
+        for days_ago in 7..28 {
+            word_trends.record(
+                chat::Id(1),
+                ["coffee", "coffee", "rain"],
+                TODAY - days_ago * SECS_PER_DAY,
+            );
+        }
+        for days_ago in 0..3 {
+            word_trends.record(
+                chat::Id(1),
+                ["coffee", "rain", "rain", "rain", "rain"],
+                TODAY - days_ago * SECS_PER_DAY,
+            );
+        }
+        word_trends.record(chat::Id(1), ["football"; 3], TODAY);
+
+        assert_eq!(trending_words(&word_trends, TODAY), &["football", "rain"]);
+        assert!(word_trends.trending(chat::Id(2), TODAY, 10).is_empty());
+    }
+
+    #[test]
+    fn should_not_trend_words_used_only_a_few_times() {
+        let mut word_trends = WordTrends::default();
+
+        word_trends.record(chat::Id(1), ["football", "football"], TODAY);
+
+        assert!(trending_words(&word_trends, TODAY).is_empty());
+    }
+
+    #[test]
+    fn should_list_most_used_words_of_today_and_yesterday() {
+        let mut word_trends = WordTrends::default();
+
+        word_trends.record(chat::Id(1), ["rain"; 5], TODAY - 2 * SECS_PER_DAY);
+        word_trends.record(chat::Id(1), ["coffee", "tea"], TODAY - SECS_PER_DAY);
+        word_trends.record(chat::Id(1), ["tea", "cake"], TODAY);
+
+        assert_eq!(
+            word_trends.recent_words(chat::Id(1), TODAY, 2),
+            &["tea", "cake"]
+        );
+        assert!(word_trends.recent_words(chat::Id(2), TODAY, 2).is_empty());
+    }
+
+    #[test]
+    fn should_forget_usage_past_retention() {
+        let mut word_trends = WordTrends::default();
+
+        word_trends.record(chat::Id(1), ["football"; 3], TODAY);
+        word_trends.record(chat::Id(1), ["rain"], TODAY + 28 * SECS_PER_DAY);
+
+        assert_eq!(
+            word_trends.usage_by_chat[&1].keys().collect::<Vec<_>>(),
+            &[&(1000 + 28)]
+        );
+    }
+
+    #[test]
+    fn should_keep_usage_across_restarts() {
+        let path: PathBuf =
+            std::env::temp_dir().join(format!("word_trends_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut word_trends = WordTrends::load(&path).unwrap();
+        word_trends.record(chat::Id(1), ["football"; 3], TODAY);
+        word_trends.save().unwrap();
+
+        let word_trends = WordTrends::load(&path).unwrap();
+        assert_eq!(trending_words(&word_trends, TODAY), &["football"]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,27 @@
+use crate::error::{self, Error};
+use crate::http;
+use hyper::{Body, Request};
+
+/// Setting that keeps the id of the update after the last one that was handled.
+pub(crate) const UPDATE_OFFSET_SETTING: &str = "update_offset";
+
+/// Confirms to Telegram that every update before `offset` was handled, so that
+/// polling resumes from `offset` instead of receiving those updates again.
+pub(crate) async fn confirm_updates_before(token: &str, offset: isize) -> error::Result<()> {
+    let url = format!(
+        "https://api.telegram.org/bot{}/getUpdates?offset={}&limit=1&timeout=0",
+        token, offset
+    );
+
+    // The url contains the token, so it's kept out of errors.
+    let request = Request::get(url)
+        .body(Body::empty())
+        .map_err(|_| Error::parse("update offset", offset.to_string()))?;
+
+    http::fetch(&http::new_client(), request, || {
+        format!("confirming updates before {}", offset)
+    })
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use crate::config::WebhookConfig;
+use crate::error::{self, Error, ResultExt};
+use tbot::event_loop::{webhook::https::Identity, EventLoop};
+
+/// Registers the webhook with Telegram and serves it, handling updates until
+/// the server fails.
+pub(crate) async fn serve(event_loop: EventLoop, config: &WebhookConfig) -> error::Result<()> {
+    if !config.path.starts_with('/') {
+        return Err(Error::parse("webhook path", &config.path));
+    }
+
+    let webhook = event_loop
+        .webhook(&config.url, config.port)
+        .ip(config.bind_address)
+        .accept_updates_on(config.path.clone());
+
+    let source: Box<dyn std::error::Error + Send + Sync> = match &config.tls {
+        None => {
+            tracing::info!("serving webhook over HTTP on port {}", config.port);
+            let Err(err) = webhook.http().start().await;
+            err.into()
+        }
+        Some(tls) => {
+            let certificate = std::fs::read_to_string(&tls.certificate_path)
+                .context(|| format!("reading certificate `{}`", tls.certificate_path.display()))?;
+            let key = std::fs::read(&tls.key_path)
+                .context(|| format!("reading key `{}`", tls.key_path.display()))?;
+            let identity = Identity::from_pkcs8(certificate.as_bytes(), &key).map_err(|err| {
+                Error::parse(
+                    format!("webhook TLS identity ({})", err),
+                    tls.key_path.display().to_string(),
+                )
+            })?;
+
+            let webhook = if tls.self_signed {
+                webhook.certificate(&certificate)
+            } else {
+                webhook
+            };
+
+            tracing::info!("serving webhook over HTTPS on port {}", config.port);
+            let Err(err) = webhook.https(identity).start().await;
+            err.into()
+        }
+    };
+
+    Err(Error::Network {
+        context: "serving webhook".into(),
+        source,
+    })
+}
@@ -0,0 +1,496 @@
+use crate::error::{self, Error};
+use crate::store::PhraseStore;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// How long the writer waits for more lines before writing a batch.
+const BATCH_DELAY: Duration = Duration::from_millis(100);
+/// How often written lines are synced to disk.
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+/// How often writing is retried while the database is unavailable.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What to do with lines appended while the queue is full, i.e. while the
+/// database can't keep up with what's learned.
+#[derive(Deserialize, PartialEq, Eq, Debug, Default, Copy, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum QueueOverflowPolicy {
+    /// Writes the queued lines right away, holding up learning until they're
+    /// written.
+    #[default]
+    Block,
+    /// Drops the line, which is still learned, but is forgotten on restart.
+    Drop,
+    /// Drops the line, reporting a storage error whenever the queue fills up.
+    Alert,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct WriteQueueConfig {
+    pub(crate) max_queued_lines: usize,
+    pub(crate) overflow_policy: QueueOverflowPolicy,
+}
+
+/// Lines queued across every store.
+static QUEUED_LINES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_QUEUED_LINES: AtomicUsize = AtomicUsize::new(0);
+static DROPPED_LINES: AtomicUsize = AtomicUsize::new(0);
+static UNAVAILABLE_STORES: AtomicUsize = AtomicUsize::new(0);
+
+/// How the write queues of every store are doing.
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct QueueMetrics {
+    pub(crate) queued_lines: usize,
+    pub(crate) peak_queued_lines: usize,
+    pub(crate) dropped_lines: usize,
+    /// Stores that failed to be written to, whose lines are kept queued until
+    /// they can be written again.
+    pub(crate) unavailable_stores: usize,
+}
+
+pub(crate) fn queue_metrics() -> QueueMetrics {
+    QueueMetrics {
+        queued_lines: QUEUED_LINES.load(Ordering::Relaxed),
+        peak_queued_lines: PEAK_QUEUED_LINES.load(Ordering::Relaxed),
+        dropped_lines: DROPPED_LINES.load(Ordering::Relaxed),
+        unavailable_stores: UNAVAILABLE_STORES.load(Ordering::Relaxed),
+    }
+}
+
+#[derive(Default)]
+struct PendingLines {
+    lines: Vec<String>,
+    /// Whether lines were dropped since the queue was last written.
+    has_overflowed: bool,
+}
+
+struct SharedStore {
+    store: Mutex<Box<dyn PhraseStore>>,
+    /// Lines appended but not yet written. Only taken while holding `store`,
+    /// so that lines are written in the order they were appended.
+    pending_lines: Mutex<PendingLines>,
+    /// Whether the last write failed, in which case lines are only written
+    /// again when retrying.
+    is_unavailable: AtomicBool,
+}
+
+impl SharedStore {
+    /// Writes the queued lines in order. If any of them fails to be written,
+    /// it's queued back along with the ones after it, ahead of the lines queued
+    /// since, and the store becomes unavailable until a write succeeds again.
+    fn write_pending_lines(&self, store: &mut dyn PhraseStore) -> error::Result<()> {
+        let pending_lines = std::mem::take(&mut *self.pending_lines.lock().unwrap()).lines;
+
+        for (line_index, line) in pending_lines.iter().enumerate() {
+            if let Err(err) = store.append(line) {
+                QUEUED_LINES.fetch_sub(line_index, Ordering::Relaxed);
+                self.pending_lines
+                    .lock()
+                    .unwrap()
+                    .lines
+                    .splice(0..0, pending_lines.into_iter().skip(line_index));
+                self.mark_unavailable(&err);
+                return Err(err);
+            }
+        }
+
+        QUEUED_LINES.fetch_sub(pending_lines.len(), Ordering::Relaxed);
+        self.mark_available(pending_lines.len());
+
+        Ok(())
+    }
+
+    fn is_unavailable(&self) -> bool {
+        self.is_unavailable.load(Ordering::Relaxed)
+    }
+
+    /// Reports the error only when the store becomes unavailable, rather than
+    /// on every failed write.
+    fn mark_unavailable(&self, err: &Error) {
+        if self.is_unavailable.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        UNAVAILABLE_STORES.fetch_add(1, Ordering::Relaxed);
+        error::report_error(err);
+        tracing::warn!("pausing writes to the database, learned lines are kept queued meanwhile");
+    }
+
+    fn mark_available(&self, written_line_count: usize) {
+        if !self.is_unavailable.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        UNAVAILABLE_STORES.fetch_sub(1, Ordering::Relaxed);
+        tracing::info!(
+            "database is available again, wrote the {} lines queued meanwhile",
+            written_line_count
+        );
+    }
+}
+
+/// Wraps a store so that appending only queues the line, which a background
+/// task then writes in batches, syncing them to disk every now and then. This
+/// keeps file I/O out of update handlers. Everything else is done right away,
+/// after writing the queued lines. The queue holds a bounded number of lines,
+/// past which the overflow policy kicks in. If the database fails to be written
+/// to, lines stay queued, and writing them is retried every now and then.
+pub(crate) struct BackgroundWriteStore {
+    shared: Arc<SharedStore>,
+    wake_writer: mpsc::Sender<()>,
+    queue_config: WriteQueueConfig,
+}
+
+impl BackgroundWriteStore {
+    /// Spawns the writer of the store, which stops once the returned store is
+    /// dropped.
+    pub(crate) fn spawn(
+        store: Box<dyn PhraseStore>,
+        queue_config: WriteQueueConfig,
+    ) -> BackgroundWriteStore {
+        let shared = Arc::new(SharedStore {
+            store: Mutex::new(store),
+            pending_lines: Mutex::new(PendingLines::default()),
+            is_unavailable: AtomicBool::new(false),
+        });
+        // A single wakeup is enough for the writer to take every queued line.
+        let (wake_writer, writer_wakeups) = mpsc::channel(1);
+
+        tokio::spawn(run_writer(Arc::clone(&shared), writer_wakeups));
+
+        BackgroundWriteStore {
+            shared,
+            wake_writer,
+            queue_config,
+        }
+    }
+
+    fn drop_line(&self, line: &str) {
+        DROPPED_LINES.fetch_add(1, Ordering::Relaxed);
+
+        let has_overflowed = std::mem::replace(
+            &mut self.shared.pending_lines.lock().unwrap().has_overflowed,
+            true,
+        );
+        if has_overflowed {
+            return;
+        }
+
+        match self.queue_config.overflow_policy {
+            QueueOverflowPolicy::Alert => error::report_error(&Error::Storage {
+                context: format!("queueing line `{}` for the database", line),
+                source: "the write queue is full, dropping lines until it's written".into(),
+            }),
+            _ => tracing::warn!("the write queue is full, dropping lines until it's written"),
+        }
+    }
+
+    /// Runs `op` on the store once the queued lines are written.
+    fn with_store<T>(
+        &self,
+        op: impl FnOnce(&mut dyn PhraseStore) -> error::Result<T>,
+    ) -> error::Result<T> {
+        let mut store = self.shared.store.lock().unwrap();
+
+        self.shared.write_pending_lines(&mut **store)?;
+        op(&mut **store)
+    }
+}
+
+impl PhraseStore for BackgroundWriteStore {
+    fn load(&mut self) -> error::Result<Vec<String>> {
+        self.with_store(|store| store.load())
+    }
+
+    fn append(&mut self, line: &str) -> error::Result<()> {
+        let is_full = self.shared.pending_lines.lock().unwrap().lines.len()
+            >= self.queue_config.max_queued_lines;
+
+        if is_full {
+            match self.queue_config.overflow_policy {
+                // There's no point in holding up learning while the database is
+                // unavailable, so lines pile up until it's back instead.
+                QueueOverflowPolicy::Block if self.shared.is_unavailable() => {}
+                QueueOverflowPolicy::Block => self.with_store(|_| Ok(()))?,
+                QueueOverflowPolicy::Drop | QueueOverflowPolicy::Alert => {
+                    self.drop_line(line);
+                    return Ok(());
+                }
+            }
+        }
+
+        self.shared
+            .pending_lines
+            .lock()
+            .unwrap()
+            .lines
+            .push(line.into());
+
+        let queued_lines = QUEUED_LINES.fetch_add(1, Ordering::Relaxed) + 1;
+        PEAK_QUEUED_LINES.fetch_max(queued_lines, Ordering::Relaxed);
+
+        // Either the writer is already due to wake up, or it has stopped, which
+        // only happens once this store is dropped.
+        let _ = self.wake_writer.try_send(());
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> error::Result<()> {
+        self.with_store(|store| store.flush())
+    }
+
+    fn compact(&mut self, lines: &[String]) -> error::Result<()> {
+        self.with_store(|store| store.compact(lines))
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&str) -> bool) -> error::Result<()> {
+        self.with_store(|store| store.retain(keep))
+    }
+
+    fn load_snapshot(&mut self) -> error::Result<Option<Vec<u8>>> {
+        self.with_store(|store| store.load_snapshot())
+    }
+
+    fn save_snapshot(&mut self, snapshot: &[u8]) -> error::Result<()> {
+        self.with_store(|store| store.save_snapshot(snapshot))
+    }
+}
+
+/// Writes the queued lines whenever woken up, and syncs them periodically,
+/// until every sender of wakeups is gone.
+async fn run_writer(shared: Arc<SharedStore>, mut wakeups: mpsc::Receiver<()>) {
+    let mut sync_interval = tokio::time::interval(SYNC_INTERVAL);
+    let mut retry = tokio::time::delay_for(RETRY_INTERVAL);
+    let mut has_unsynced_lines = false;
+
+    loop {
+        tokio::select! {
+            wakeup = wakeups.recv() => {
+                if wakeup.is_none() {
+                    break;
+                }
+
+                // Lines are left queued until the next retry.
+                if shared.is_unavailable() {
+                    continue;
+                }
+
+                tokio::time::delay_for(BATCH_DELAY).await;
+                write_pending_lines(&shared).await;
+
+                retry.reset(Instant::now() + RETRY_INTERVAL);
+                has_unsynced_lines = true;
+            }
+            _ = &mut retry, if shared.is_unavailable() => {
+                write_pending_lines(&shared).await;
+
+                retry.reset(Instant::now() + RETRY_INTERVAL);
+                has_unsynced_lines = true;
+            }
+            _ = sync_interval.tick(), if has_unsynced_lines && !shared.is_unavailable() => {
+                let shared = Arc::clone(&shared);
+                run_blocking(move || shared.store.lock().unwrap().flush()).await;
+
+                has_unsynced_lines = false;
+            }
+        }
+    }
+
+    run_blocking(move || {
+        let mut store = shared.store.lock().unwrap();
+        shared.write_pending_lines(&mut **store)?;
+        store.flush()
+    })
+    .await;
+}
+
+/// Writes the queued lines off the async runtime. Failures are reported as the
+/// store becomes unavailable.
+async fn write_pending_lines(shared: &Arc<SharedStore>) {
+    let shared = Arc::clone(shared);
+
+    run_blocking(move || {
+        let mut store = shared.store.lock().unwrap();
+        let _ = shared.write_pending_lines(&mut **store);
+        Ok(())
+    })
+    .await;
+}
+
+async fn run_blocking(op: impl FnOnce() -> error::Result<()> + Send + 'static) {
+    match tokio::task::spawn_blocking(op).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => error::report_error(&err),
+        Err(err) => tracing::error!("database writer failed: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod background_write_store_tests {
+    use super::{BackgroundWriteStore, QueueOverflowPolicy, WriteQueueConfig, BATCH_DELAY};
+    use crate::error::{self, Error};
+    use crate::store::PhraseStore;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedLinesStore(Arc<Mutex<Vec<String>>>, Arc<AtomicBool>);
+
+    impl SharedLinesStore {
+        fn set_unavailable(&self, is_unavailable: bool) {
+            self.1.store(is_unavailable, Ordering::Relaxed);
+        }
+    }
+
+    impl PhraseStore for SharedLinesStore {
+        fn load(&mut self) -> error::Result<Vec<String>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+
+        fn append(&mut self, line: &str) -> error::Result<()> {
+            if self.1.load(Ordering::Relaxed) {
+                return Err(Error::Storage {
+                    context: format!("appending `{}`", line),
+                    source: "disk is full".into(),
+                });
+            }
+
+            self.0.lock().unwrap().push(line.into());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> error::Result<()> {
+            Ok(())
+        }
+
+        fn compact(&mut self, lines: &[String]) -> error::Result<()> {
+            *self.0.lock().unwrap() = lines.to_vec();
+            Ok(())
+        }
+
+        fn retain(&mut self, keep: &mut dyn FnMut(&str) -> bool) -> error::Result<()> {
+            self.0.lock().unwrap().retain(|line| keep(line));
+            Ok(())
+        }
+    }
+
+    fn queue_config(overflow_policy: QueueOverflowPolicy) -> WriteQueueConfig {
+        WriteQueueConfig {
+            max_queued_lines: 2,
+            overflow_policy,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_write_appended_lines_in_background() {
+        let lines_store = SharedLinesStore::default();
+        let mut store = BackgroundWriteStore::spawn(
+            Box::new(lines_store.clone()),
+            queue_config(QueueOverflowPolicy::Block),
+        );
+
+        store.append("hello there").unwrap();
+        store.append("hello world").unwrap();
+
+        tokio::time::delay_for(BATCH_DELAY * 5).await;
+
+        assert_eq!(
+            *lines_store.0.lock().unwrap(),
+            ["hello there", "hello world"]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_write_queued_lines_before_anything_else() {
+        let lines_store = SharedLinesStore::default();
+        let mut store = BackgroundWriteStore::spawn(
+            Box::new(lines_store.clone()),
+            queue_config(QueueOverflowPolicy::Block),
+        );
+
+        store.append("hello there").unwrap();
+        store.append("hello world").unwrap();
+        store.retain(&mut |line| line != "hello there").unwrap();
+
+        assert_eq!(*lines_store.0.lock().unwrap(), ["hello world"]);
+        assert_eq!(store.load().unwrap(), ["hello world"]);
+    }
+
+    #[tokio::test]
+    async fn should_write_queued_lines_right_away_when_blocking_on_full_queue() {
+        let lines_store = SharedLinesStore::default();
+        let mut store = BackgroundWriteStore::spawn(
+            Box::new(lines_store.clone()),
+            queue_config(QueueOverflowPolicy::Block),
+        );
+
+        store.append("hello there").unwrap();
+        store.append("hello world").unwrap();
+        store.append("how are you").unwrap();
+
+        assert_eq!(
+            *lines_store.0.lock().unwrap(),
+            ["hello there", "hello world"]
+        );
+        assert_eq!(
+            store.load().unwrap(),
+            ["hello there", "hello world", "how are you"]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_drop_lines_appended_to_full_queue() {
+        let lines_store = SharedLinesStore::default();
+        let mut store = BackgroundWriteStore::spawn(
+            Box::new(lines_store.clone()),
+            queue_config(QueueOverflowPolicy::Drop),
+        );
+
+        store.append("hello there").unwrap();
+        store.append("hello world").unwrap();
+        store.append("how are you").unwrap();
+
+        assert_eq!(store.load().unwrap(), ["hello there", "hello world"]);
+
+        store.append("how are you").unwrap();
+
+        assert_eq!(
+            store.load().unwrap(),
+            ["hello there", "hello world", "how are you"]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_keep_lines_queued_until_store_is_available_again() {
+        let lines_store = SharedLinesStore::default();
+        let mut store = BackgroundWriteStore::spawn(
+            Box::new(lines_store.clone()),
+            queue_config(QueueOverflowPolicy::Block),
+        );
+
+        lines_store.set_unavailable(true);
+        store.append("hello there").unwrap();
+        tokio::time::delay_for(BATCH_DELAY * 5).await;
+
+        assert!(store.shared.is_unavailable());
+
+        store.append("hello world").unwrap();
+        store.append("how are you").unwrap();
+
+        assert!(store.load().is_err());
+        assert!(lines_store.0.lock().unwrap().is_empty());
+
+        lines_store.set_unavailable(false);
+
+        assert_eq!(
+            store.load().unwrap(),
+            ["hello there", "hello world", "how are you"]
+        );
+        assert!(!store.shared.is_unavailable());
+    }
+}
@@ -0,0 +1,256 @@
+//! Runs the bot binary against a fake Bot API, checking what it learns and
+//! what it replies.
+
+mod fake_bot_api;
+
+use fake_bot_api::FakeBotApi;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+const USER_ID: i64 = 42;
+
+/// The bot process, which is killed once the test is over.
+struct RunningBot {
+    process: Child,
+    working_dir: PathBuf,
+}
+
+impl RunningBot {
+    /// Starts the bot in an empty directory of its own, talking to `api`.
+    fn start(name: &str, api: &FakeBotApi) -> RunningBot {
+        let working_dir =
+            std::env::temp_dir().join(format!("end_to_end_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&working_dir);
+        std::fs::create_dir_all(&working_dir).unwrap();
+        std::fs::write(working_dir.join("bot_memory.txt"), "").unwrap();
+
+        let process = Command::new(env!("CARGO_BIN_EXE_feroldinhobot"))
+            .current_dir(&working_dir)
+            .env_clear()
+            .env("BOT_TOKEN", "123456:TEST")
+            .env("BOT_API_PROXY", &api.proxy_url)
+            .env(
+                "SSL_CERT_FILE",
+                fake_bot_api::fixtures_path().join("ca.pem"),
+            )
+            .env("REPLY_PROBABILITY", "1")
+            .env("EMOJI_REPLY_PROBABILITY", "0")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        RunningBot {
+            process,
+            working_dir,
+        }
+    }
+
+    fn path(&self, file_name: &str) -> PathBuf {
+        self.working_dir.join(file_name)
+    }
+
+    /// Asks the bot to stop with SIGTERM, and waits for it to exit. Returns
+    /// whether it exited successfully, or `None` if it didn't exit in time.
+    #[cfg(unix)]
+    async fn terminate(&mut self) -> Option<bool> {
+        Command::new("kill")
+            .arg("-TERM")
+            .arg(self.process.id().to_string())
+            .status()
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+
+        while Instant::now() < deadline {
+            if let Some(status) = self.process.try_wait().unwrap() {
+                return Some(status.success());
+            }
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+
+        None
+    }
+}
+
+impl Drop for RunningBot {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_dir_all(&self.working_dir);
+    }
+}
+
+/// Waits until the file has the line, returning its lines either way.
+async fn wait_for_line(path: &Path, line: &str) -> Vec<String> {
+    let deadline = Instant::now() + Duration::from_secs(30);
+
+    loop {
+        let lines: Vec<String> = std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect();
+
+        if lines.iter().any(|stored_line| stored_line == line) || Instant::now() >= deadline {
+            return lines;
+        }
+
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn should_learn_message_and_reply_to_it() {
+    let api = FakeBotApi::start().await;
+    let bot = RunningBot::start("learn_and_reply", &api);
+
+    // A lone phrase could only be echoed back, so there's nothing to reply to
+    // it with until there's another phrase to splice it with.
+    api.send_text_message(USER_ID, "my good friend is here");
+    wait_for_line(
+        &bot.path("bot_memory.txt"),
+        "my good friend is here\tsource=chat",
+    )
+    .await;
+
+    api.send_text_message(USER_ID, "Hello there, my good friend");
+
+    let lines = wait_for_line(
+        &bot.path("bot_memory.txt"),
+        "hello there my good friend\tsource=chat",
+    )
+    .await;
+    assert_eq!(
+        lines,
+        &[
+            "my good friend is here\tsource=chat",
+            "hello there my good friend\tsource=chat"
+        ]
+    );
+
+    let sent_messages = api.wait_for_sent_messages(1).await;
+    assert_eq!(sent_messages.len(), 1);
+    assert_eq!(sent_messages[0].chat_id, USER_ID);
+    assert!(!sent_messages[0].text.is_empty());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn should_learn_captions_of_photos() {
+    let api = FakeBotApi::start().await;
+    let bot = RunningBot::start("learn_caption", &api);
+
+    api.send_photo(USER_ID, "Sunset at the beach");
+
+    let lines = wait_for_line(
+        &bot.path("bot_memory.txt"),
+        "sunset at the beach\tsource=chat",
+    )
+    .await;
+    assert_eq!(lines, &["sunset at the beach\tsource=chat"]);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn should_not_learn_from_users_who_opted_out() {
+    let api = FakeBotApi::start().await;
+    let bot = RunningBot::start("opt_out", &api);
+
+    api.send_text_message(USER_ID, "/optout");
+    api.wait_for_sent_messages(1).await;
+
+    api.send_text_message(USER_ID, "my secret is safe here");
+    tokio::time::delay_for(Duration::from_millis(500)).await;
+
+    api.send_text_message(USER_ID, "/optin");
+    api.wait_for_sent_messages(2).await;
+
+    api.send_text_message(USER_ID, "the weather is nice today");
+
+    let lines = wait_for_line(
+        &bot.path("bot_memory.txt"),
+        "the weather is nice today\tsource=chat",
+    )
+    .await;
+    assert_eq!(lines, &["the weather is nice today\tsource=chat"]);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn should_delete_phrases_of_user_on_request() {
+    let api = FakeBotApi::start().await;
+    let bot = RunningBot::start("delete_my_data", &api);
+
+    api.send_text_message(USER_ID, "the weather is nice today");
+    wait_for_line(
+        &bot.path("bot_memory.txt"),
+        "the weather is nice today\tsource=chat",
+    )
+    .await;
+
+    api.send_text_message(USER_ID, "/deletemydata");
+
+    let sent_messages = api.wait_for_sent_messages(1).await;
+    assert_eq!(sent_messages[0].text, "forgot 1 phrases of yours");
+
+    let lines = std::fs::read_to_string(bot.path("bot_memory.txt")).unwrap();
+    assert_eq!(lines, "");
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn should_think_of_something_it_learned() {
+    let api = FakeBotApi::start().await;
+    let bot = RunningBot::start("think", &api);
+
+    api.send_text_message(USER_ID, "the weather is nice today");
+    wait_for_line(
+        &bot.path("bot_memory.txt"),
+        "the weather is nice today\tsource=chat",
+    )
+    .await;
+
+    api.send_text_message(USER_ID, "/think");
+
+    let sent_messages = api.wait_for_sent_messages(1).await;
+    assert_eq!(sent_messages.len(), 1);
+    assert!(sent_messages[0].text.contains("weather"));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn should_answer_commands_suffixed_with_its_username() {
+    let api = FakeBotApi::start().await;
+    let bot = RunningBot::start("think_at_username", &api);
+
+    api.send_text_message(USER_ID, "the weather is nice today");
+    wait_for_line(
+        &bot.path("bot_memory.txt"),
+        "the weather is nice today\tsource=chat",
+    )
+    .await;
+
+    api.send_text_message(USER_ID, "/think@test_bot");
+
+    let sent_messages = api.wait_for_sent_messages(1).await;
+    assert_eq!(sent_messages.len(), 1);
+    assert!(sent_messages[0].text.contains("weather"));
+}
+
+#[cfg(unix)]
+#[tokio::test(threaded_scheduler)]
+async fn should_compact_memory_when_terminated() {
+    let api = FakeBotApi::start().await;
+    let mut bot = RunningBot::start("terminate", &api);
+
+    api.send_text_message(USER_ID, "Good morning, everyone");
+    wait_for_line(
+        &bot.path("bot_memory.txt"),
+        "good morning everyone\tsource=chat",
+    )
+    .await;
+
+    assert_eq!(bot.terminate().await, Some(true));
+    assert_eq!(
+        std::fs::read_to_string(bot.path("bot_memory.txt")).unwrap(),
+        "good morning everyone\tsource=chat\n"
+    );
+    assert!(!bot.path("bot_memory.txt.tmp").exists());
+}
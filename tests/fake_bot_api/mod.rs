@@ -0,0 +1,287 @@
+//! A fake Telegram Bot API, speaking just enough of it (`deleteWebhook`,
+//! `getMe`, `getUpdates` and `sendMessage`) to run the bot against it.
+//!
+//! The bot always talks to `https://api.telegram.org`, so the fake is reached
+//! as an HTTP proxy: it accepts the `CONNECT` tunnel, and then poses as
+//! `api.telegram.org` with a certificate signed by the test CA in
+//! `tests/fixtures`, which the bot must be told to trust through
+//! `SSL_CERT_FILE`.
+
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Where the certificates used by the fake live.
+pub fn fixtures_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SentMessage {
+    pub chat_id: i64,
+    pub text: String,
+}
+
+#[derive(Default)]
+struct ApiState {
+    pending_updates: VecDeque<Value>,
+    next_update_id: i64,
+    next_message_id: i64,
+    sent_messages: Vec<SentMessage>,
+}
+
+pub struct FakeBotApi {
+    pub proxy_url: String,
+    state: Arc<Mutex<ApiState>>,
+}
+
+impl FakeBotApi {
+    /// Starts serving on a random local port. Must be called from within a
+    /// tokio runtime.
+    pub async fn start() -> FakeBotApi {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("http://{}", listener.local_addr().unwrap());
+
+        let state = Arc::new(Mutex::new(ApiState {
+            next_update_id: 1,
+            next_message_id: 1,
+            ..ApiState::default()
+        }));
+
+        let fixtures_path = fixtures_path();
+        let identity = native_tls::Identity::from_pkcs8(
+            &std::fs::read(fixtures_path.join("api.telegram.org.pem")).unwrap(),
+            &std::fs::read(fixtures_path.join("api.telegram.org.key")).unwrap(),
+        )
+        .unwrap();
+        let tls_acceptor = tokio_tls::TlsAcceptor::from(
+            native_tls::TlsAcceptor::builder(identity).build().unwrap(),
+        );
+
+        let server_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(_) => continue,
+                };
+
+                let tls_acceptor = tls_acceptor.clone();
+                let state = Arc::clone(&server_state);
+
+                tokio::spawn(async move {
+                    let _ = serve_tunnel(stream, tls_acceptor, state).await;
+                });
+            }
+        });
+
+        FakeBotApi { proxy_url, state }
+    }
+
+    /// Queues a text message sent by a user in a private chat with the bot,
+    /// which is delivered on the next `getUpdates`.
+    pub fn send_text_message(&self, user_id: i64, text: &str) {
+        let mut message = json!({ "text": text });
+
+        if text.starts_with('/') {
+            let command_len = text.split(' ').next().unwrap().encode_utf16().count();
+            message["entities"] =
+                json!([{ "type": "bot_command", "offset": 0, "length": command_len }]);
+        }
+
+        self.send_message(user_id, message);
+    }
+
+    /// Queues a photo with a caption, sent like `send_text_message` does.
+    pub fn send_photo(&self, user_id: i64, caption: &str) {
+        self.send_message(
+            user_id,
+            json!({
+                "photo": [{
+                    "file_id": "photo",
+                    "file_unique_id": "photo",
+                    "width": 1,
+                    "height": 1,
+                }],
+                "caption": caption,
+            }),
+        );
+    }
+
+    /// Queues a message with the `content` fields, such as `text`.
+    fn send_message(&self, user_id: i64, content: Value) {
+        let mut state = self.state.lock().unwrap();
+
+        let update_id = state.next_update_id;
+        let message_id = state.next_message_id;
+        state.next_update_id += 1;
+        state.next_message_id += 1;
+
+        let mut message = json!({
+            "message_id": message_id,
+            "from": { "id": user_id, "is_bot": false, "first_name": "Tester" },
+            "chat": { "id": user_id, "type": "private", "first_name": "Tester" },
+            "date": unix_now(),
+        });
+
+        for (key, value) in content.as_object().unwrap() {
+            message[key] = value.clone();
+        }
+
+        state
+            .pending_updates
+            .push_back(json!({ "update_id": update_id, "message": message }));
+    }
+
+    /// Waits until the bot has sent at least `count` messages, and returns all
+    /// of them. Gives up after a while, returning what was sent so far.
+    pub async fn wait_for_sent_messages(&self, count: usize) -> Vec<SentMessage> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+
+        while Instant::now() < deadline {
+            if self.state.lock().unwrap().sent_messages.len() >= count {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+
+        self.state.lock().unwrap().sent_messages.clone()
+    }
+}
+
+/// Accepts the `CONNECT` request of the bot, and then serves the Bot API over
+/// TLS through the tunnel.
+async fn serve_tunnel(
+    mut stream: TcpStream,
+    tls_acceptor: tokio_tls::TlsAcceptor,
+    state: Arc<Mutex<ApiState>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut request_head = Vec::new();
+    let mut byte = [0; 1];
+
+    while !request_head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).await? == 0 {
+            return Ok(());
+        }
+        request_head.push(byte[0]);
+    }
+
+    stream
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .await?;
+
+    let tls_stream = tls_acceptor.accept(stream).await?;
+
+    Http::new()
+        .serve_connection(
+            tls_stream,
+            service_fn(move |request| handle_request(request, Arc::clone(&state))),
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn handle_request(
+    request: Request<Body>,
+    state: Arc<Mutex<ApiState>>,
+) -> Result<Response<Body>, Infallible> {
+    let method = request
+        .uri()
+        .path()
+        .rsplit('/')
+        .next()
+        .unwrap_or("")
+        .to_owned();
+    let body = hyper::body::to_bytes(request.into_body())
+        .await
+        .unwrap_or_default();
+    let params: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+
+    let result = match method.as_str() {
+        "deleteWebhook" => json!(true),
+        "getMe" => json!({
+            "id": 1,
+            "is_bot": true,
+            "first_name": "Bot",
+            "username": "test_bot",
+            "can_join_groups": true,
+            "can_read_all_group_messages": false,
+            "supports_inline_queries": false,
+        }),
+        "getUpdates" => get_updates(&params, &state).await,
+        "sendMessage" => send_message(&params, &state),
+        _ => {
+            let response = json!({
+                "ok": false,
+                "error_code": 404,
+                "description": format!("Not Found: method {} isn't faked", method),
+            });
+            return Ok(Response::new(Body::from(response.to_string())));
+        }
+    };
+
+    let response = json!({ "ok": true, "result": result });
+    Ok(Response::new(Body::from(response.to_string())))
+}
+
+async fn get_updates(params: &Value, state: &Mutex<ApiState>) -> Value {
+    let offset = params["offset"].as_i64().unwrap_or(0);
+
+    for _ in 0..10 {
+        {
+            let mut state = state.lock().unwrap();
+
+            // Updates before the offset are confirmed, so they're never sent
+            // again.
+            state
+                .pending_updates
+                .retain(|update| update["update_id"].as_i64().unwrap() >= offset);
+
+            if !state.pending_updates.is_empty() {
+                return Value::Array(state.pending_updates.iter().cloned().collect());
+            }
+        }
+
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+    }
+
+    json!([])
+}
+
+fn send_message(params: &Value, state: &Mutex<ApiState>) -> Value {
+    let mut state = state.lock().unwrap();
+
+    let chat_id = params["chat_id"].as_i64().unwrap();
+    let text = params["text"].as_str().unwrap_or_default().to_owned();
+
+    let message_id = state.next_message_id;
+    state.next_message_id += 1;
+    state.sent_messages.push(SentMessage {
+        chat_id,
+        text: text.clone(),
+    });
+
+    json!({
+        "message_id": message_id,
+        "from": { "id": 1, "is_bot": true, "first_name": "Bot", "username": "test_bot" },
+        "chat": { "id": chat_id, "type": "private", "first_name": "Tester" },
+        "date": unix_now(),
+        "text": text,
+    })
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}